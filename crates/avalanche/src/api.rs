@@ -1,8 +1,8 @@
 use std::sync::atomic::{self, AtomicBool};
 
-use service::{api, database, endpoint, Endpoint, State};
+use service::{api, database, endpoint, request_id::RequestId, Endpoint, State};
 use thiserror::Error;
-use tracing::{error, info};
+use tracing::{error, info, Instrument};
 
 use crate::Config;
 
@@ -58,16 +58,24 @@ async fn build(request: api::Request<api::v1::avalanche::Build>, context: Contex
         return Err(Error::BuildInProgress);
     }
 
+    // Correlate the build & it's result sent to summit w/ this request
+    let request_id = RequestId::current().unwrap_or_else(RequestId::generate);
+
     // Build time!
-    tokio::spawn(async move {
-        crate::build(build, endpoint, context.state, context.config).await;
-        BUILD_IN_PROGRESS.store(false, atomic::Ordering::Relaxed);
-    });
+    tokio::spawn(
+        request_id
+            .scope(async move {
+                crate::build(build, endpoint, context.state, context.config).await;
+                BUILD_IN_PROGRESS.store(false, atomic::Ordering::Relaxed);
+            })
+            .in_current_span(),
+    );
 
     Ok(())
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum Error {
     /// Required token is missing from the request
     #[error("Token missing from request")]
@@ -89,6 +97,12 @@ pub enum Error {
     Database(#[from] database::Error),
 }
 
+impl api::ErrorCode for Error {
+    fn code(&self) -> &'static str {
+        self.into()
+    }
+}
+
 impl From<&Error> for http::StatusCode {
     fn from(error: &Error) -> Self {
         match error {
@@ -1,21 +1,102 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use color_eyre::eyre::{eyre, Context, OptionExt, Result};
 use http::Uri;
 use itertools::Itertools;
 use service::{
     api::{self, v1::avalanche::PackageBuild},
+    crypto::{EncodedSignature, KeyPair},
+    endpoint::builder::Capabilities,
     error, Endpoint, State,
 };
-use service::{collectable, Collectable, Remote};
-use sha2::{Digest, Sha256};
+use service::{collectable, remote, Collectable, Remote};
+use thiserror::Error;
 use tokio::{
     fs::{self, File},
+    io::AsyncWriteExt,
     process,
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{Avalanche, Boulder},
+    retention, Config,
+};
+
+/// Replaces secret values found in build logs
+const REDACTED: &[u8] = b"[REDACTED]";
+/// How long boulder is given to exit after a timed out build is
+/// terminated, before it's killed
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Recipe failed to parse
+#[derive(Debug, Error)]
+#[error("invalid recipe {path}")]
+struct InvalidRecipe {
+    path: String,
+    #[source]
+    source: stone_recipe::Error,
+}
+
+/// Build exceeded it's timeout
+#[derive(Debug, Error)]
+#[error("build timed out after {}m", .0.as_secs() / 60)]
+struct TimedOut(Duration);
+
+/// Build failed, w/ the collectables still worth uploading (i.e. it's log)
+struct Failed {
+    error: color_eyre::Report,
+    collectables: Vec<Collectable>,
+}
+
+impl From<color_eyre::Report> for Failed {
+    fn from(error: color_eyre::Report) -> Self {
+        Self {
+            error,
+            collectables: vec![],
+        }
+    }
+}
+
+/// Detect the capabilities of this builder to advertise to the hub on enrollment
+pub async fn capabilities() -> Capabilities {
+    let boulder_version = boulder_version()
+        .await
+        .inspect_err(|e| {
+            let error = error::chain(e.as_ref() as &dyn std::error::Error);
+            warn!(%error, "Failed to detect boulder version");
+        })
+        .ok();
+
+    Capabilities {
+        boulder_version,
+        features: vec![],
+        architectures: vec![std::env::consts::ARCH.to_string()],
+    }
+}
+
+async fn boulder_version() -> Result<String> {
+    let output = process::Command::new("boulder")
+        .arg("version")
+        .output()
+        .await
+        .context("spawn boulder version")?;
 
-use crate::Config;
+    if !output.status.success() {
+        return Err(eyre!("boulder version exited with {}", output.status));
+    }
+
+    // i.e. `boulder 0.24.0`
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .last()
+        .map(ToString::to_string)
+        .ok_or_eyre("boulder version output is empty")
+}
 
 #[tracing::instrument(
     skip_all,
@@ -28,11 +109,11 @@ pub async fn build(request: PackageBuild, endpoint: Endpoint, state: State, conf
     info!("Starting build");
 
     let client =
-        service::Client::new(endpoint.host_address.clone()).with_endpoint_auth(endpoint.id, state.service_db.clone());
+        service::Client::new(endpoint.host_address.clone()).with_endpoint_auth(&endpoint, state.service_db.clone());
 
     let task_id = request.build_id;
 
-    let status = match run(request, endpoint, state, config).await {
+    let status = match run(request, endpoint, state.clone(), config).await {
         Ok(collectables) => {
             info!("Build succeeded");
 
@@ -40,15 +121,12 @@ pub async fn build(request: PackageBuild, endpoint: Endpoint, state: State, conf
                 .send::<api::v1::summit::BuildSucceeded>(&api::v1::summit::BuildBody { task_id, collectables })
                 .await
         }
-        Err(e) => {
-            let error = error::chain(e.as_ref() as &dyn std::error::Error);
+        Err(Failed { error, collectables }) => {
+            let error = error::chain(error.as_ref() as &dyn std::error::Error);
             error!(%error, "Build failed");
 
             client
-                .send::<api::v1::summit::BuildFailed>(&api::v1::summit::BuildBody {
-                    task_id,
-                    collectables: vec![],
-                })
+                .send::<api::v1::summit::BuildFailed>(&api::v1::summit::BuildBody { task_id, collectables })
                 .await
         }
     };
@@ -56,10 +134,21 @@ pub async fn build(request: PackageBuild, endpoint: Endpoint, state: State, conf
     if let Err(e) = status {
         let error = error::chain(e);
         error!(%error, "Failed to send build status response");
+        return;
+    }
+
+    if let Err(e) = retention::acknowledge(&state, task_id).await {
+        let error = error::chain(e);
+        error!(%error, "Failed to record build acknowledgement");
     }
 }
 
-async fn run(request: PackageBuild, _endpoint: Endpoint, state: State, config: Config) -> Result<Vec<Collectable>> {
+async fn run(
+    request: PackageBuild,
+    _endpoint: Endpoint,
+    state: State,
+    config: Config,
+) -> Result<Vec<Collectable>, Failed> {
     let uri = request.uri.parse::<Uri>().context("invalid upstream URI")?;
 
     let cache_dir = state.state_dir.join("cache");
@@ -82,7 +171,9 @@ async fn run(request: PackageBuild, _endpoint: Endpoint, state: State, config: C
     let asset_dir = state.root.join("assets").join(request.build_id.to_string());
     recreate_dir(&asset_dir).await.context("recreate asset dir")?;
 
-    let log_file = asset_dir.join("build.log");
+    // Assets are served while building, so the log is only
+    // moved into the asset dir once redacted
+    let log_file = work_dir.join("build.log");
 
     mirror_recipe_repo(&uri, &mirror_dir)
         .await
@@ -92,23 +183,86 @@ async fn run(request: PackageBuild, _endpoint: Endpoint, state: State, config: C
         .await
         .context("checkout commit as worktree")?;
 
-    create_boulder_config(&work_dir, &request.remotes)
-        .await
-        .context("create boulder config")?;
+    let boulder = &config.avalanche.boulder;
 
-    build_recipe(&work_dir, &asset_dir, &worktree_dir, &request.relative_path, &log_file)
-        .await
-        .context("build recipe")?;
+    // Read per build so rotated secrets are picked up without a restart
+    let secrets = read_build_secrets(&config.avalanche).await?;
+    let env = config
+        .avalanche
+        .build_env
+        .clone()
+        .into_iter()
+        .chain(secrets.clone())
+        .collect::<BTreeMap<_, _>>();
 
-    tokio::task::spawn_blocking(move || compress_file(&log_file))
+    let timeout = request
+        .timeout_minutes
+        .or(config.avalanche.build_timeout_minutes)
+        .map(|minutes| Duration::from_secs(minutes.saturating_mul(60)));
+
+    let built = async {
+        // Fail fast before setting up the build environment
+        validate_recipe(&worktree_dir, &request.relative_path, &log_file).await?;
+
+        create_boulder_config(&work_dir, &boulder.profile, &request.remotes)
+            .await
+            .context("create boulder config")?;
+
+        build_recipe(
+            boulder,
+            &env,
+            timeout,
+            &work_dir,
+            &asset_dir,
+            &worktree_dir,
+            &request.relative_path,
+            &log_file,
+        )
         .await
-        .context("spawn blocking")?
-        .context("compress log file")?;
+        .context("build recipe")
+    }
+    .await;
 
-    let collectables = scan_collectables(request.build_id, &config.host_address, &asset_dir)
+    // Published even if the build failed
+    let values = secrets.into_values().collect::<Vec<_>>();
+    publish_log(log_file, asset_dir.join("build.log.gz"), values)
+        .await
+        .context("publish log file")?;
+
+    if let Err(error) = built {
+        // Upload the log so the failure can be diagnosed
+        let collectables = scan_collectables(
+            request.build_id,
+            &config.service.host_address,
+            &asset_dir,
+            &state.key_pair,
+        )
         .await
         .context("scan collectables")?;
 
+        return Err(Failed {
+            error,
+            collectables: logs(collectables),
+        });
+    }
+
+    let collectables = scan_collectables(
+        request.build_id,
+        &config.service.host_address,
+        &asset_dir,
+        &state.key_pair,
+    )
+    .await
+    .context("scan collectables")?;
+
+    // Never report success for a build the hub can't import
+    if let Err(error) = collectable::validate(&collectables) {
+        return Err(Failed {
+            error: color_eyre::Report::new(error).wrap_err("incomplete build"),
+            collectables: logs(collectables),
+        });
+    }
+
     remove_worktree(&mirror_dir, &worktree_dir)
         .await
         .context("remove worktree")?;
@@ -116,6 +270,14 @@ async fn run(request: PackageBuild, _endpoint: Endpoint, state: State, config: C
     Ok(collectables)
 }
 
+/// Only the log collectables, which are uploaded for failed builds
+fn logs(collectables: Vec<Collectable>) -> Vec<Collectable> {
+    collectables
+        .into_iter()
+        .filter(|c| matches!(c.kind, collectable::Kind::Log))
+        .collect()
+}
+
 async fn ensure_dir_exists(path: &Path) -> Result<()> {
     Ok(fs::create_dir_all(path).await?)
 }
@@ -204,9 +366,16 @@ async fn remove_worktree(mirror_dir: &Path, worktree_dir: &Path) -> Result<()> {
     )
 }
 
-async fn create_boulder_config(work_dir: &Path, remotes: &[Remote]) -> Result<()> {
+async fn create_boulder_config(work_dir: &Path, profile: &str, remotes: &[Remote]) -> Result<()> {
     info!("Creating boulder config");
 
+    remote::validate(remotes).context("invalid remotes")?;
+
+    let shared = remote::shared_priorities(remotes);
+    if !shared.is_empty() {
+        warn!(priorities = ?shared, "Multiple remotes share a priority, their order is ambiguous");
+    }
+
     let remotes = remotes
         .iter()
         .map(|remote| {
@@ -224,7 +393,7 @@ async fn create_boulder_config(work_dir: &Path, remotes: &[Remote]) -> Result<()
 
     let config = format!(
         "
-avalanche:
+{profile}:
     repositories:
 {remotes}
         "
@@ -235,14 +404,56 @@ avalanche:
         .await
         .context("create boulder config dir")?;
 
-    fs::write(config_dir.join("avalanche.yaml"), config)
+    fs::write(config_dir.join(format!("{profile}.yaml")), config)
         .await
         .context("write boulder config")?;
 
     Ok(())
 }
 
+/// Parse the recipe so syntax errors are reported in the build log
+/// without waiting on boulder
+async fn validate_recipe(worktree_dir: &Path, relative_path: &str, log_path: &Path) -> Result<()> {
+    let content = fs::read_to_string(worktree_dir.join(relative_path))
+        .await
+        .context("read recipe")?;
+
+    if let Err(source) = stone_recipe::from_str(&content) {
+        let error = InvalidRecipe {
+            path: relative_path.to_string(),
+            source,
+        };
+
+        fs::write(log_path, format!("{}\n", error::chain(&error)))
+            .await
+            .context("write log file")?;
+
+        return Err(error.into());
+    }
+
+    Ok(())
+}
+
+/// Read the configured [`Avalanche::build_secrets`], keyed by environment variable
+async fn read_build_secrets(config: &Avalanche) -> Result<BTreeMap<String, String>> {
+    let mut secrets = BTreeMap::new();
+
+    for (name, path) in &config.build_secrets {
+        let value = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("read build secret {name}"))?;
+
+        secrets.insert(name.clone(), value.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    Ok(secrets)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn build_recipe(
+    boulder: &Boulder,
+    env: &BTreeMap<String, String>,
+    timeout: Option<Duration>,
     work_dir: &Path,
     asset_dir: &Path,
     worktree_dir: &Path,
@@ -255,46 +466,278 @@ async fn build_recipe(
         .into_std()
         .await;
 
-    info!("Building recipe");
+    // Names only, values may be secret
+    info!(env = ?env.keys().collect::<Vec<_>>(), ?timeout, "Building recipe");
+
+    let mut child = boulder_command(boulder, env.keys())
+        .envs(env)
+        .args(["build", "-p", &boulder.profile, "--update", "-o"])
+        .arg(asset_dir)
+        .arg("--config-dir")
+        .arg(work_dir.join("etc/boulder"))
+        .args(&boulder.extra_args)
+        .arg("--")
+        .arg(relative_path)
+        .current_dir(worktree_dir)
+        .stdout(log_file.try_clone()?)
+        .stderr(log_file)
+        // Own process group so the whole build can be killed on timeout
+        .process_group(0)
+        .spawn()
+        .context("boulder")?;
+
+    let status = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => status,
+            Err(_) => {
+                let error = TimedOut(timeout);
+
+                warn!(%error, "Killing boulder");
+                kill_process_group(&mut child, boulder.sudo).await;
+
+                let mut log = fs::OpenOptions::new()
+                    .append(true)
+                    .open(log_path)
+                    .await
+                    .context("open log file")?;
+                log.write_all(format!("{error}\n").as_bytes())
+                    .await
+                    .context("write log file")?;
+
+                return Err(error.into());
+            }
+        },
+        None => child.wait().await,
+    };
 
-    validate_status(
-        "boulder",
-        process::Command::new("sudo")
-            .args(["nice", "-n20", "boulder", "build", "-p", "avalanche", "--update", "-o"])
-            .arg(asset_dir)
-            .arg("--config-dir")
-            .arg(work_dir.join("etc/boulder"))
-            .arg("--")
-            .arg(relative_path)
-            .current_dir(worktree_dir)
-            .stdout(log_file.try_clone()?)
-            .stderr(log_file)
+    validate_status("boulder", status)
+}
+
+/// Terminate all processes spawned by `child`, killing any left once it exits
+/// or [`KILL_GRACE_PERIOD`] elapses, then wait for it to exit.
+///
+/// When boulder runs via `sudo` it's in a process group owned by root, which can
+/// only be signalled via `sudo kill`.
+async fn kill_process_group(child: &mut process::Child, sudo: bool) {
+    // Already exited
+    let Some(pid) = child.id() else {
+        return;
+    };
+
+    signal_process_groups(pid, sudo, libc::SIGTERM).await;
+
+    if tokio::time::timeout(KILL_GRACE_PERIOD, child.wait()).await.is_ok() {
+        return;
+    }
+
+    signal_process_groups(pid, sudo, libc::SIGKILL).await;
+
+    if tokio::time::timeout(KILL_GRACE_PERIOD, child.wait()).await.is_err() {
+        error!(pid, "Boulder still running after being killed, waiting for it to exit");
+
+        let _ = child.wait().await;
+    }
+}
+
+/// Send `signal` to the process group of `pid` & those of all it's descendants
+async fn signal_process_groups(pid: u32, sudo: bool, signal: libc::c_int) {
+    let groups = process_groups(pid).await;
+
+    if sudo {
+        let result = process::Command::new("sudo")
+            .args(["-n", "kill", "-s", &signal.to_string(), "--"])
+            .args(groups.iter().map(|pgid| format!("-{pgid}")))
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
             .status()
-            .await,
-    )
+            .await;
+
+        // Fails if any group has already exited, which is expected
+        if let Err(e) = result {
+            warn!(error = %error::chain(e), signal, "Failed to signal boulder process group");
+        }
+
+        return;
+    }
+
+    for pgid in groups {
+        // SAFETY: killpg has no memory safety preconditions
+        let result = unsafe { libc::killpg(pgid as libc::pid_t, signal) };
+
+        if result != 0 {
+            let error = std::io::Error::last_os_error();
+
+            // Process group has already exited
+            if error.raw_os_error() != Some(libc::ESRCH) {
+                warn!(error = %error::chain(error), signal, "Failed to signal boulder process group");
+            }
+        }
+    }
+}
+
+/// Process groups of `pid` & all it's descendants, as found in `/proc`
+async fn process_groups(pid: u32) -> BTreeSet<u32> {
+    // (pid, parent pid, process group)
+    let mut processes = vec![];
+
+    if let Ok(mut entries) = fs::read_dir("/proc").await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Some(id) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+                continue;
+            };
+            // Process may have exited since listing it
+            let Ok(stat) = fs::read_to_string(entry.path().join("stat")).await else {
+                continue;
+            };
+            // Command name is in parenthesis & may contain anything, fields follow it
+            let mut fields = stat
+                .rsplit_once(')')
+                .map(|(_, fields)| fields)
+                .unwrap_or_default()
+                .split_whitespace()
+                .skip(1)
+                .map(|field| field.parse::<u32>().ok());
+
+            if let (Some(Some(parent)), Some(Some(group))) = (fields.next(), fields.next()) {
+                processes.push((id, parent, group));
+            }
+        }
+    }
+
+    let mut descendants = BTreeSet::from([pid]);
+    let mut groups = BTreeSet::from([pid]);
+
+    // Parents can be listed after their children, so repeat until no more are found
+    loop {
+        let found = processes
+            .iter()
+            .filter(|(id, parent, _)| descendants.contains(parent) && !descendants.contains(id))
+            .copied()
+            .collect::<Vec<_>>();
+
+        if found.is_empty() {
+            break;
+        }
+
+        for (id, _, group) in found {
+            descendants.insert(id);
+            groups.insert(group);
+        }
+    }
+
+    groups
+}
+
+/// Command running boulder, wrapped in `sudo` & `nice` as configured.
+///
+/// `sudo` resets the environment, so the `env` variable names to pass
+/// through to boulder must be preserved explicitly.
+fn boulder_command<'a>(boulder: &Boulder, env: impl IntoIterator<Item = &'a String>) -> process::Command {
+    let mut wrappers = vec![];
+
+    if boulder.sudo {
+        wrappers.push("sudo".to_string());
+
+        let names = env.into_iter().join(",");
+        if !names.is_empty() {
+            wrappers.push(format!("--preserve-env={names}"));
+        }
+    }
+    if boulder.nice != 0 {
+        wrappers.extend(["nice".to_string(), format!("-n{}", boulder.nice)]);
+    }
+
+    match wrappers.split_first() {
+        Some((program, args)) => {
+            let mut command = process::Command::new(program);
+            command.args(args).arg("boulder");
+            command
+        }
+        None => process::Command::new("boulder"),
+    }
+}
+
+/// Redact the `secrets` values from the log at `path`, then compress it to `published`
+async fn publish_log(path: PathBuf, published: PathBuf, secrets: Vec<String>) -> Result<()> {
+    // Nothing was logged, such as if the build environment couldn't be set up
+    if !path.exists() {
+        return Ok(());
+    }
+
+    tokio::task::spawn_blocking(move || {
+        redact_file(&path, &secrets).context("redact log file")?;
+        compress_file(&path, &published).context("compress log file")
+    })
+    .await
+    .context("spawn blocking")?
+}
+
+/// Replace all occurrences of the `secrets` values in `file`
+fn redact_file(file: &Path, secrets: &[String]) -> Result<()> {
+    use service::atomic_file::AtomicFile;
+    use std::io::Write;
+
+    let secrets = secrets.iter().filter(|secret| !secret.is_empty()).collect::<Vec<_>>();
+
+    if secrets.is_empty() {
+        return Ok(());
+    }
+
+    let content = std::fs::read(file).context("read file")?;
+    let redacted = secrets
+        .into_iter()
+        .fold(content, |content, secret| redact(&content, secret.as_bytes()));
+
+    let mut redacted_file = AtomicFile::create(file).context("create redacted file")?;
+    redacted_file.write_all(&redacted)?;
+    redacted_file.commit().context("commit redacted file")?;
+
+    Ok(())
+}
+
+/// Replace all occurrences of `secret` in `content` with [`REDACTED`]
+fn redact(content: &[u8], secret: &[u8]) -> Vec<u8> {
+    let mut redacted = Vec::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(index) = rest.windows(secret.len()).position(|window| window == secret) {
+        redacted.extend_from_slice(&rest[..index]);
+        redacted.extend_from_slice(REDACTED);
+        rest = &rest[index + secret.len()..];
+    }
+
+    redacted.extend_from_slice(rest);
+    redacted
 }
 
-fn compress_file(file: &Path) -> Result<()> {
+/// Compress `file` to `compressed`, removing `file` once done
+fn compress_file(file: &Path, compressed: &Path) -> Result<()> {
     use flate2::write::GzEncoder;
+    use service::atomic_file::AtomicFile;
     use std::fs::{self, File};
-    use std::io::{self, Write};
+    use std::io;
 
     let mut plain_file = File::open(file).context("open plain file")?;
-    let mut gz_file = File::create(format!("{}.gz", file.display())).context("create compressed file")?;
+    let mut gz_file = AtomicFile::create(compressed).context("create compressed file")?;
 
     let mut encoder = GzEncoder::new(&mut gz_file, flate2::Compression::new(9));
 
     io::copy(&mut plain_file, &mut encoder)?;
 
     encoder.finish()?;
-    gz_file.flush()?;
+    gz_file.commit().context("commit compressed file")?;
 
     fs::remove_file(file).context("remove plain file")?;
 
     Ok(())
 }
 
-async fn scan_collectables(build_id: u64, host_address: &Uri, asset_dir: &Path) -> Result<Vec<Collectable>> {
+async fn scan_collectables(
+    build_id: u64,
+    host_address: &Uri,
+    asset_dir: &Path,
+    key_pair: &KeyPair,
+) -> Result<Vec<Collectable>> {
     let mut collectables = vec![];
 
     let mut contents = fs::read_dir(asset_dir).await.context("read asset dir")?;
@@ -322,25 +765,153 @@ async fn scan_collectables(build_id: u64, host_address: &Uri, asset_dir: &Path)
             .parse()
             .context("invalid asset URI")?;
 
-        let sha256sum = tokio::task::spawn_blocking(move || compute_sha256(&path))
+        let sha256sum = tokio::task::spawn_blocking(move || collectable::sha256sum(&path))
             .await
             .context("spawn blocking")?
             .context("compute asset sha256")?;
 
-        collectables.push(Collectable { kind, uri, sha256sum })
+        // Packages are signed so the repository can verify they came from us
+        let signature = matches!(kind, collectable::Kind::Package)
+            .then(|| EncodedSignature::encode(&key_pair.sign(sha256sum.as_bytes())).to_string());
+
+        collectables.push(Collectable {
+            kind,
+            uri,
+            sha256sum,
+            signature,
+        })
     }
 
     Ok(collectables)
 }
 
-fn compute_sha256(file: &Path) -> Result<String> {
-    use std::fs::File;
-    use std::io;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    let file = File::open(file).context("open file")?;
-    let mut hasher = Sha256::default();
+    #[tokio::test]
+    async fn kill_timed_out_group() {
+        let mut child = process::Command::new("sh")
+            .args(["-c", "sleep 30 & wait"])
+            .process_group(0)
+            .spawn()
+            .unwrap();
 
-    io::copy(&mut &file, &mut hasher)?;
+        let started = std::time::Instant::now();
+        kill_process_group(&mut child, false).await;
+
+        assert!(started.elapsed() < KILL_GRACE_PERIOD);
+        assert!(child.try_wait().unwrap().is_some());
+
+        // Already exited
+        kill_process_group(&mut child, false).await;
+    }
+
+    #[tokio::test]
+    async fn descendant_process_groups() {
+        // Inner shell has it's own process group, like boulder under `sudo`
+        let mut child = process::Command::new("sh")
+            .args(["-c", "setsid sh -c 'sleep 30' & wait"])
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+
+        let mut groups = BTreeSet::new();
+        for _ in 0..50 {
+            groups = process_groups(pid).await;
+            if groups.len() > 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(groups.contains(&pid));
+        assert_eq!(groups.len(), 2);
 
-    Ok(hex::encode(hasher.finalize()))
+        let started = std::time::Instant::now();
+        kill_process_group(&mut child, false).await;
+
+        assert!(started.elapsed() < KILL_GRACE_PERIOD);
+        assert!(child.try_wait().unwrap().is_some());
+        assert_eq!(process_groups(pid).await, BTreeSet::from([pid]));
+    }
+
+    #[test]
+    fn boulder_wrappers() {
+        let command = |boulder: &Boulder| {
+            let command = boulder_command(boulder, &[]);
+            let command = command.as_std();
+
+            std::iter::once(command.get_program())
+                .chain(command.get_args())
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(command(&Boulder::default()), ["sudo", "nice", "-n20", "boulder"]);
+        assert_eq!(
+            command(&Boulder {
+                sudo: false,
+                nice: 0,
+                ..Default::default()
+            }),
+            ["boulder"]
+        );
+        assert_eq!(
+            command(&Boulder {
+                sudo: false,
+                nice: 5,
+                ..Default::default()
+            }),
+            ["nice", "-n5", "boulder"]
+        );
+
+        let env = ["SOURCE_TOKEN".to_string(), "PROXY".to_string()];
+        let command = boulder_command(&Boulder::default(), &env);
+        assert_eq!(
+            command.as_std().get_args().next().unwrap(),
+            "--preserve-env=SOURCE_TOKEN,PROXY"
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_redacted_log() {
+        use std::io::Read;
+
+        let dir = std::env::temp_dir().join(format!("avalanche-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let log = dir.join("build.log");
+        let published = dir.join("assets").join("build.log.gz");
+        std::fs::create_dir_all(published.parent().unwrap()).unwrap();
+        std::fs::write(&log, "fetching w/ token abc123\n").unwrap();
+
+        publish_log(log.clone(), published.clone(), vec!["abc123".to_string()])
+            .await
+            .unwrap();
+
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&published).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+
+        assert_eq!(content, "fetching w/ token [REDACTED]\n");
+        assert!(!log.exists());
+
+        // Nothing logged
+        publish_log(log, dir.join("missing.log.gz"), vec![]).await.unwrap();
+        assert!(!dir.join("missing.log.gz").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn redact_secrets() {
+        assert_eq!(
+            redact(b"token=abc123 & abc123", b"abc123"),
+            b"token=[REDACTED] & [REDACTED]"
+        );
+        assert_eq!(redact(b"nothing here", b"abc123"), b"nothing here");
+        assert_eq!(redact(b"ab", b"abc123"), b"ab");
+    }
 }
@@ -0,0 +1,110 @@
+//! Avalanche configuration
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use service::config::Error;
+use tokio::fs;
+
+/// Avalanche configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Shared service configuration
+    #[serde(flatten)]
+    pub service: service::Config,
+    /// Avalanche specific configuration
+    ///
+    /// Not reloaded upon SIGHUP, changes require a restart
+    #[serde(default)]
+    pub avalanche: Avalanche,
+}
+
+impl Config {
+    /// Load configuration from the provided `path`
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let content = fs::read_to_string(path).await?;
+        let config = toml::from_str(&content)?;
+        Ok(config)
+    }
+}
+
+/// Avalanche specific configuration, under the `[avalanche]` section
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Avalanche {
+    /// Number of most recent builds to keep assets for. Assets of older
+    /// builds are pruned once acknowledged by summit, but always kept for
+    /// a day after so vessel can import them.
+    pub keep_builds: Option<usize>,
+    /// Maximum age of build assets, in days. Assets of older builds are
+    /// pruned once acknowledged by summit, but always kept for a day
+    /// after so vessel can import them.
+    pub max_age_days: Option<u64>,
+    /// Maximum duration of a build, in minutes, before boulder is killed
+    /// and the build fails. Builds can run indefinitely if not set.
+    ///
+    /// Can be overridden per build by summit.
+    pub build_timeout_minutes: Option<u64>,
+    /// How boulder is invoked, under the `[avalanche.boulder]` section
+    #[serde(default)]
+    pub boulder: Boulder,
+    /// Environment variables set for the boulder build process
+    #[serde(default)]
+    pub build_env: BTreeMap<String, String>,
+    /// Environment variables set for the boulder build process from the
+    /// contents of the referenced files, such as credentials to fetch
+    /// private sources. Values are never logged and are redacted from
+    /// build logs.
+    #[serde(default)]
+    pub build_secrets: BTreeMap<String, PathBuf>,
+}
+
+/// Boulder invocation options
+#[derive(Debug, Clone, Deserialize)]
+pub struct Boulder {
+    /// Niceness boulder is run with, or `0` to run it without `nice`
+    #[serde(default = "default_nice")]
+    pub nice: i32,
+    /// Name of the boulder profile builds use
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    /// Run boulder via `sudo`. Disable for rootless deployments
+    /// where boulder can already create it's build namespace.
+    ///
+    /// [`Avalanche::build_env`] & [`Avalanche::build_secrets`] are passed
+    /// through w/ `sudo --preserve-env`, which sudoers must permit using the
+    /// `SETENV` tag, i.e. `avalanche ALL=(root) NOPASSWD:SETENV: /usr/bin/boulder`.
+    ///
+    /// Timed out builds are killed w/ `sudo kill`, so sudoers must also permit
+    /// it, i.e. `avalanche ALL=(root) NOPASSWD: /usr/bin/kill`
+    #[serde(default = "default_sudo")]
+    pub sudo: bool,
+    /// Additional arguments passed to `boulder build`
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+impl Default for Boulder {
+    fn default() -> Self {
+        Self {
+            nice: default_nice(),
+            profile: default_profile(),
+            sudo: default_sudo(),
+            extra_args: vec![],
+        }
+    }
+}
+
+fn default_nice() -> i32 {
+    20
+}
+
+fn default_profile() -> String {
+    "avalanche".to_string()
+}
+
+fn default_sudo() -> bool {
+    true
+}
@@ -5,12 +5,14 @@ use service::{Role, Server, State};
 use tracing::info;
 
 pub type Result<T, E = color_eyre::eyre::Error> = std::result::Result<T, E>;
-pub type Config = service::Config;
+pub use self::config::Config;
 
 use self::build::build;
 
 mod api;
 mod build;
+mod config;
+mod retention;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -19,17 +21,39 @@ async fn main() -> Result<()> {
         port,
         config,
         root,
+        check_migrations,
+        force_regenerate_key,
     } = Args::parse();
 
-    let config = Config::load(config.unwrap_or_else(|| root.join("config.toml"))).await?;
+    if check_migrations {
+        let status = State::migration_status(&root, &[]).await?;
 
-    service::tracing::init(&config.tracing);
+        println!("{status}");
 
-    let state = State::load(root).await?;
+        return Ok(status.check()?);
+    }
+
+    let config_path = config.unwrap_or_else(|| root.join("config.toml"));
+    let config = Config::load(&config_path).await?;
+    config.service.validate(Role::Builder)?;
+
+    service::tracing::init(&config.service.tracing);
+
+    let state = State::load_with(root, force_regenerate_key).await?;
 
     info!("avalanche listening on {host}:{port}");
 
-    Server::new(Role::Builder, &config, &state)
+    let capabilities = build::capabilities().await;
+
+    Server::new(Role::Builder, &config.service, &state)
+        .with_capabilities(capabilities)
+        .with_config_reload(config_path)
+        .with_openapi()
+        .with_metrics()
+        .with_task(
+            "build retention",
+            retention::run(state.clone(), config.avalanche.clone()),
+        )
         .merge_api(api::service(state.clone(), config.clone()))
         .serve_directory("/assets", "assets")
         .start((host, port))
@@ -42,10 +66,17 @@ async fn main() -> Result<()> {
 struct Args {
     #[arg(default_value = "127.0.0.1")]
     host: IpAddr,
-    #[arg(long, default_value = "5003")]
+    #[arg(long, default_value_t = Role::Builder.default_port())]
     port: u16,
     #[arg(long, short)]
     config: Option<PathBuf>,
     #[arg(long, short, default_value = ".")]
     root: PathBuf,
+    /// Print the database migration status and exit without starting the server
+    #[arg(long)]
+    check_migrations: bool,
+    /// Replace an invalid private key w/ a newly generated one. This invalidates
+    /// all tokens issued by this service, so endpoints must re-enroll
+    #[arg(long)]
+    force_regenerate_key: bool,
 }
@@ -0,0 +1,195 @@
+//! Prune build assets which have been acknowledged by summit
+//! according to the configured retention policy
+//!
+//! An acknowledgement only confirms summit received the build status,
+//! vessel downloads the assets afterwards. They're always kept for
+//! [`IMPORT_GRACE_PERIOD`] after being acknowledged to give it time
+//! to do so.
+
+use std::{
+    cmp::Reverse,
+    convert::Infallible,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use service::{error, State};
+use tokio::fs;
+use tracing::{debug, error, info};
+
+use crate::config::Avalanche;
+
+/// How often build assets are checked for pruning
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Minimum time assets are kept after being acknowledged, so vessel
+/// can import them
+const IMPORT_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Record that summit has acknowledged the result of `build_id`,
+/// allowing it's assets to be pruned
+pub async fn acknowledge(state: &State, build_id: u64) -> io::Result<()> {
+    let dir = acknowledged_dir(state);
+
+    fs::create_dir_all(&dir).await?;
+    fs::write(dir.join(build_id.to_string()), []).await?;
+
+    Ok(())
+}
+
+/// Periodically prune build assets until the task is cancelled
+pub async fn run(state: State, config: Avalanche) -> Result<(), Infallible> {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = prune(&state, &config).await {
+            error!(error = %error::chain(e), "Pruning build assets failed");
+        }
+    }
+}
+
+async fn prune(state: &State, config: &Avalanche) -> io::Result<()> {
+    let assets_dir = state.root.join("assets");
+    let acknowledged_dir = acknowledged_dir(state);
+
+    if !assets_dir.exists() {
+        return Ok(());
+    }
+
+    let mut builds = vec![];
+
+    let mut contents = fs::read_dir(&assets_dir).await?;
+
+    while let Some(entry) = contents.next_entry().await? {
+        let Some(id) = entry.file_name().to_str().and_then(|name| name.parse::<u64>().ok()) else {
+            continue;
+        };
+
+        let metadata = entry.metadata().await?;
+
+        if !metadata.is_dir() {
+            continue;
+        }
+
+        let acknowledged = match fs::metadata(acknowledged_dir.join(id.to_string())).await {
+            Ok(metadata) => Some(metadata.modified()?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        builds.push(Build {
+            id,
+            modified: metadata.modified()?,
+            acknowledged,
+        });
+    }
+
+    for id in expired(builds, config, SystemTime::now()) {
+        fs::remove_dir_all(assets_dir.join(id.to_string())).await?;
+        remove_if_exists(&acknowledged_dir.join(id.to_string())).await?;
+
+        info!(build_id = id, "Pruned build assets");
+    }
+
+    debug!("Build assets pruned");
+
+    Ok(())
+}
+
+/// Builds acknowledged over [`IMPORT_GRACE_PERIOD`] ago which fall outside the retention policy
+fn expired(mut builds: Vec<Build>, config: &Avalanche, now: SystemTime) -> Vec<u64> {
+    let max_age = config.max_age_days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+
+    // Newest first
+    builds.sort_by_key(|build| Reverse(build.modified));
+
+    builds
+        .into_iter()
+        .enumerate()
+        .filter(|(_, build)| {
+            build.acknowledged.is_some_and(|acknowledged| {
+                now.duration_since(acknowledged)
+                    .is_ok_and(|elapsed| elapsed >= IMPORT_GRACE_PERIOD)
+            })
+        })
+        .filter(|(index, build)| {
+            let over_limit = config.keep_builds.is_some_and(|keep| *index >= keep);
+            let too_old =
+                max_age.is_some_and(|max_age| now.duration_since(build.modified).is_ok_and(|age| age > max_age));
+
+            over_limit || too_old
+        })
+        .map(|(_, build)| build.id)
+        .collect()
+}
+
+async fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn acknowledged_dir(state: &State) -> PathBuf {
+    state.state_dir.join("acknowledged")
+}
+
+#[derive(Debug)]
+struct Build {
+    id: u64,
+    modified: SystemTime,
+    /// When summit acknowledged the build, if it has
+    acknowledged: Option<SystemTime>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expired_builds() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        let builds = || {
+            vec![
+                Build {
+                    id: 1,
+                    modified: now - day * 10,
+                    acknowledged: Some(now - day * 10),
+                },
+                Build {
+                    id: 2,
+                    modified: now - day * 5,
+                    acknowledged: None,
+                },
+                Build {
+                    id: 3,
+                    modified: now - day * 2,
+                    acknowledged: Some(now - day * 2),
+                },
+                Build {
+                    id: 4,
+                    modified: now - day,
+                    acknowledged: Some(now - IMPORT_GRACE_PERIOD / 2),
+                },
+            ]
+        };
+
+        let config = |keep_builds, max_age_days| Avalanche {
+            keep_builds,
+            max_age_days,
+            ..Default::default()
+        };
+
+        assert!(expired(builds(), &config(None, None), now).is_empty());
+        // Unacknowledged builds count towards the limit but are never pruned
+        assert_eq!(expired(builds(), &config(Some(1), None), now), vec![3, 1]);
+        assert_eq!(expired(builds(), &config(None, Some(3)), now), vec![1]);
+        assert_eq!(expired(builds(), &config(Some(3), Some(7)), now), vec![1]);
+        // Recently acknowledged builds are kept until vessel has had time to import them
+        assert_eq!(expired(builds(), &config(Some(0), Some(0)), now), vec![3, 1]);
+    }
+}
@@ -1,5 +1,6 @@
 //! An API operation
 use http;
+use schemars::JsonSchema;
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::api::Version;
@@ -8,9 +9,9 @@ use crate::auth;
 /// An API operation
 pub trait Operation {
     /// Request body
-    type RequestBody: Serialize + DeserializeOwned;
+    type RequestBody: Serialize + DeserializeOwned + JsonSchema;
     /// Response body
-    type ResponseBody: Serialize + DeserializeOwned;
+    type ResponseBody: Serialize + DeserializeOwned + JsonSchema;
 
     /// API version
     const VERSION: Version;
@@ -22,6 +23,21 @@ pub trait Operation {
     const PATH: &'static str;
     /// Required authentication flags
     const AUTH: auth::Flags;
+    /// Does calling this operation mutate state?
+    ///
+    /// Tokens restricted to [`auth::Flags::READ_ONLY`] can only call
+    /// operations which don't
+    const MUTATING: bool;
+}
+
+/// An [`Operation`] which streams it's response as newline delimited JSON
+/// [`Streaming::Item`]s rather than a single body, so large responses don't
+/// need to be buffered in memory
+///
+/// The [`Operation::ResponseBody`] of a streaming operation is `Vec<Item>`
+pub trait Streaming: Operation {
+    /// Streamed response item
+    type Item: Serialize + DeserializeOwned + JsonSchema;
 }
 
 /// Define an [`Operation`]
@@ -39,6 +55,12 @@ macro_rules! operation {
     ($ty:ident, $method:ident, $path:literal, req: $req:ty, resp: $resp:ty) => {
         operation!($ty, $method, $path, NO_AUTH, req: $req, resp: $resp);
     };
+    ($ty:ident, $method:ident, $path:literal, stream: $item:ty) => {
+        operation!($ty, $method, $path, NO_AUTH, req: (), stream: $item);
+    };
+    ($ty:ident, $method:ident, $path:literal, req: $req:ty, stream: $item:ty) => {
+        operation!($ty, $method, $path, NO_AUTH, req: $req, stream: $item);
+    };
     ($ty:ident, $method:ident, $path:literal, $first:ident $(| $other:ident)*) => {
         operation!($ty, $method, $path, $first $(| $other)*, req: (), resp: ());
     };
@@ -48,6 +70,16 @@ macro_rules! operation {
     ($ty:ident, $method:ident, $path:literal, $first:ident $(| $other:ident)*, resp: $resp:ty) => {
         operation!($ty, $method, $path, $first $(| $other)*, req: (), resp: $resp);
     };
+    ($ty:ident, $method:ident, $path:literal, $first:ident $(| $other:ident)*, stream: $item:ty) => {
+        operation!($ty, $method, $path, $first $(| $other)*, req: (), stream: $item);
+    };
+    ($ty:ident, $method:ident, $path:literal, $first:ident $(| $other:ident)*, req: $req:ty, stream: $item:ty) => {
+        operation!($ty, $method, $path, $first $(| $other)*, req: $req, resp: Vec<$item>);
+
+        impl $crate::api::operation::Streaming for $ty {
+            type Item = $item;
+        }
+    };
     ($ty:ident, $method:ident, $path:literal, $first:ident $(| $other:ident)*, req: $req:ty, resp: $resp:ty) => {
         pub struct $ty;
 
@@ -60,6 +92,22 @@ macro_rules! operation {
             const METHOD: http::Method = http::Method::$method;
             const PATH: &'static str = $path;
             const AUTH: $crate::auth::Flags = $crate::auth!($first $(| $other)*);
+            const MUTATING: bool = $crate::operation_mutating!($method);
         }
     };
 }
+
+/// Operations are declared mutating unless their method is safe (`GET` / `HEAD`)
+#[doc(hidden)]
+#[macro_export]
+macro_rules! operation_mutating {
+    (GET) => {
+        false
+    };
+    (HEAD) => {
+        false
+    };
+    ($method:ident) => {
+        true
+    };
+}
@@ -1,15 +1,16 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::{operation, Remote};
 
 operation!(Build, POST, "avalanche/build", ACCESS_TOKEN | SERVICE_ACCOUNT | NOT_EXPIRED, req: BuildRequestBody);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct BuildRequestBody {
     pub request: PackageBuild,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct PackageBuild {
     #[serde(rename = "buildID")]
@@ -20,4 +21,7 @@ pub struct PackageBuild {
     pub build_architecture: String,
     #[serde(rename = "collections")]
     pub remotes: Vec<Remote>,
+    /// Overrides the builder's configured build timeout, in minutes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_minutes: Option<u64>,
 }
@@ -1,7 +1,8 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::endpoint::enrollment;
-use crate::operation;
+use crate::endpoint::{enrollment, Labels};
+use crate::{operation, Role};
 
 operation!(
     Enroll,
@@ -41,12 +42,186 @@ operation!(
     resp: String
 );
 
-#[derive(Debug, Serialize, Deserialize)]
+operation!(
+    IssueOperationToken,
+    POST,
+    "services/operation_token",
+    NOT_EXPIRED | BEARER_TOKEN | SERVICE_ACCOUNT,
+    req: IssueOperationTokenBody,
+    resp: String
+);
+
+operation!(
+    CancelEnrollment,
+    POST,
+    "services/cancel_enrollment",
+    req: CancelEnrollmentBody
+);
+
+operation!(Ping, GET, "services/ping", ACCESS_TOKEN | SERVICE_ACCOUNT | NOT_EXPIRED);
+
+operation!(
+    ListEndpoints,
+    GET,
+    "services/endpoints",
+    ACCESS_TOKEN | ADMIN_ACCOUNT | NOT_EXPIRED,
+    resp: Vec<EndpointSummary>
+);
+
+operation!(
+    RevokeEndpoint,
+    POST,
+    "services/revoke_endpoint",
+    ACCESS_TOKEN | ADMIN_ACCOUNT | NOT_EXPIRED,
+    req: RevokeEndpointBody
+);
+
+operation!(
+    SetEndpointLabels,
+    POST,
+    "services/endpoint_labels",
+    ACCESS_TOKEN | ADMIN_ACCOUNT | NOT_EXPIRED,
+    req: SetEndpointLabelsBody
+);
+
+operation!(
+    ResolvePendingEnrollments,
+    POST,
+    "services/resolve_pending",
+    ACCESS_TOKEN | ADMIN_ACCOUNT | NOT_EXPIRED,
+    req: ResolvePendingBody,
+    resp: Vec<ResolvedEnrollment>
+);
+
+operation!(
+    CancelSentEnrollment,
+    POST,
+    "services/cancel_sent_enrollment",
+    ACCESS_TOKEN | ADMIN_ACCOUNT | NOT_EXPIRED,
+    req: CancelSentEnrollmentBody
+);
+
+operation!(
+    ListAuditLog,
+    GET,
+    "services/audit_log",
+    ACCESS_TOKEN | ADMIN_ACCOUNT | NOT_EXPIRED,
+    resp: Vec<AuditRecord>
+);
+
+operation!(
+    IssueReadOnlyToken,
+    GET,
+    "services/read_only_token",
+    ACCESS_TOKEN | ADMIN_ACCOUNT | NOT_EXPIRED,
+    resp: String
+);
+
+operation!(
+    SetMaintenance,
+    POST,
+    "services/maintenance",
+    ACCESS_TOKEN | ADMIN_ACCOUNT | NOT_EXPIRED,
+    req: SetMaintenanceBody
+);
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct EnrollRequestBody {
     pub request: enrollment::Request,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct AcceptRequestBody {
     pub request: enrollment::Request,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct IssueOperationTokenBody {
+    /// Path of the operation the token is restricted to, i.e. `summit/importSucceeded`
+    pub path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CancelEnrollmentBody {
+    pub issue_token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointSummary {
+    pub id: String,
+    pub host_address: String,
+    pub role: Role,
+    pub status: String,
+    pub error: Option<String>,
+    /// Fingerprint of the endpoint's public key, i.e. `SHA256:...`
+    #[serde(default)]
+    pub fingerprint: String,
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RevokeEndpointBody {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SetEndpointLabelsBody {
+    pub id: String,
+    pub labels: Labels,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ResolvePendingBody {
+    pub ids: Vec<String>,
+    pub action: PendingAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CancelSentEnrollmentBody {
+    /// Endpoint id of the enrollment sent to the target
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PendingAction {
+    Accept,
+    Decline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ResolvedEnrollment {
+    pub id: String,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    pub id: i64,
+    /// RFC 3339 timestamp of the decision
+    pub timestamp: String,
+    pub account: Option<i64>,
+    pub operation: String,
+    pub decision: AuditDecision,
+    pub reason: Option<String>,
+    pub source_ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, strum::Display, strum::EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AuditDecision {
+    Allowed,
+    Denied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SetMaintenanceBody {
+    /// Reject mutating operations while enabled
+    pub enabled: bool,
+}
@@ -1,3 +1,4 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::{operation, Collectable};
@@ -8,14 +9,14 @@ operation!(BuildFailed, POST, "summit/buildFailed", ACCESS_TOKEN | SERVICE_ACCOU
 operation!(ImportSucceeded, POST, "summit/importSucceeded", ACCESS_TOKEN | SERVICE_ACCOUNT | NOT_EXPIRED, req: ImportBody);
 operation!(ImportFailed, POST, "summit/importFailed", ACCESS_TOKEN | SERVICE_ACCOUNT | NOT_EXPIRED, req: ImportBody);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct BuildBody {
     #[serde(rename = "taskID")]
     pub task_id: u64,
     pub collectables: Vec<Collectable>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ImportBody {
     #[serde(rename = "taskID")]
     pub task_id: u64,
@@ -23,6 +23,8 @@ bitflags! {
         const EXPIRED = 1 << 6;
         /// Token is not expired
         const NOT_EXPIRED = 1 << 7;
+        /// Token is restricted to read-only operations via it's `scope` claim
+        const READ_ONLY = 1 << 8;
     }
 }
 
@@ -1,6 +1,11 @@
+use std::{fs::File, io, path::Path};
+
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 pub enum Kind {
     Log,
@@ -10,10 +15,154 @@ pub enum Kind {
     Unknown,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct Collectable {
     #[serde(rename = "type")]
     pub kind: Kind,
     pub uri: String,
     pub sha256sum: String,
+    /// Base64 (url safe, no padding) encoded Ed25519 signature of the
+    /// [`Collectable::sha256sum`] by the builder which produced it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Collectable {
+    /// Verify the file at `path` matches the expected [`Collectable::sha256sum`]
+    pub fn verify(&self, path: &Path) -> Result<(), VerifyError> {
+        verify(path, &self.sha256sum)
+    }
+}
+
+/// Ensure `collectables` uploaded for a successful build form a complete set, at
+/// least one [`Kind::Package`] along w/ the build [`Kind::Log`] and a manifest
+pub fn validate(collectables: &[Collectable]) -> Result<(), IncompleteError> {
+    let has = |f: fn(&Kind) -> bool| collectables.iter().any(|c| f(&c.kind));
+
+    if !has(|kind| matches!(kind, Kind::Package)) {
+        return Err(IncompleteError::MissingPackage);
+    }
+    if !has(|kind| matches!(kind, Kind::Log)) {
+        return Err(IncompleteError::MissingLog);
+    }
+    if !has(|kind| matches!(kind, Kind::BinaryManifest | Kind::JsonManifest)) {
+        return Err(IncompleteError::MissingManifest);
+    }
+
+    Ok(())
+}
+
+/// Compute the hex encoded sha256sum of the file at `path`
+pub fn sha256sum(path: &Path) -> Result<String, io::Error> {
+    let mut hasher = Sha256::default();
+
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Verify the file at `path` has the `expected` hex encoded sha256sum
+pub fn verify(path: &Path, expected: &str) -> Result<(), VerifyError> {
+    let actual = sha256sum(path).map_err(VerifyError::Read)?;
+
+    if actual != expected {
+        return Err(VerifyError::Sha256Mismatch {
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// A verification error
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    /// Error reading the file
+    #[error("read")]
+    Read(#[source] io::Error),
+    /// Sha256 mismatch
+    #[error("invalid sha256, expected {expected} actual {actual}")]
+    Sha256Mismatch {
+        /// Expected hash
+        expected: String,
+        /// Actual hash
+        actual: String,
+    },
+}
+
+/// Collectables of a successful build are incomplete
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum IncompleteError {
+    /// No package was collected
+    #[error("no package collected")]
+    MissingPackage,
+    /// No build log was collected
+    #[error("no build log collected")]
+    MissingLog,
+    /// No binary or json manifest was collected
+    #[error("no manifest collected")]
+    MissingManifest,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_sha256() {
+        let path = std::env::temp_dir().join(format!("collectable-{}", std::process::id()));
+        std::fs::write(&path, b"hello").unwrap();
+
+        let mut collectable = Collectable {
+            kind: Kind::Log,
+            uri: "https://example.com/build.log.gz".to_string(),
+            sha256sum: "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string(),
+            signature: None,
+        };
+
+        let verified = collectable.verify(&path);
+
+        collectable.sha256sum = "0".repeat(64);
+        let mismatch = collectable.verify(&path);
+
+        std::fs::remove_file(&path).unwrap();
+
+        verified.unwrap();
+        assert!(matches!(mismatch, Err(VerifyError::Sha256Mismatch { actual, .. }) if actual.starts_with("2cf24dba")));
+    }
+
+    #[test]
+    fn validate_complete() {
+        let collectable = |kind| Collectable {
+            kind,
+            uri: "https://example.com/collectable".to_string(),
+            sha256sum: "0".repeat(64),
+            signature: None,
+        };
+
+        let complete = [
+            collectable(Kind::Log),
+            collectable(Kind::BinaryManifest),
+            collectable(Kind::JsonManifest),
+            collectable(Kind::Package),
+        ];
+        validate(&complete).unwrap();
+
+        // Missing manifest
+        assert_eq!(
+            validate(&[collectable(Kind::Log), collectable(Kind::Package)]),
+            Err(IncompleteError::MissingManifest)
+        );
+        // Missing package
+        assert_eq!(
+            validate(&[collectable(Kind::Log), collectable(Kind::BinaryManifest)]),
+            Err(IncompleteError::MissingPackage)
+        );
+        assert_eq!(
+            validate(&[collectable(Kind::Package), collectable(Kind::JsonManifest)]),
+            Err(IncompleteError::MissingLog)
+        );
+    }
 }
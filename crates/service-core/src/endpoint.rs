@@ -1 +1,70 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub mod builder;
 pub mod enrollment;
+
+/// Arbitrary key / value labels of an endpoint, such as `memory = "high"`,
+/// used to route work to specific endpoints
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct Labels(BTreeMap<String, String>);
+
+impl Labels {
+    /// Returns true if there are no labels
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Value of the label `key`, if set
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Set label `key` to `value`, returning the previous value if set
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.0.insert(key.into(), value.into())
+    }
+
+    /// Iterate over all label keys & values
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Returns true if every label in `required` is set to the same value
+    pub fn satisfies(&self, required: &Labels) -> bool {
+        required.iter().all(|(key, value)| self.get(key) == Some(value))
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for Labels
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn satisfies() {
+        let labels = Labels::from_iter([("memory", "high"), ("region", "eu")]);
+
+        assert!(labels.satisfies(&Labels::default()));
+        assert!(labels.satisfies(&Labels::from_iter([("memory", "high")])));
+        assert!(!labels.satisfies(&Labels::from_iter([("memory", "low")])));
+        assert!(!labels.satisfies(&Labels::from_iter([("gpu", "yes")])));
+        assert!(!Labels::default().satisfies(&Labels::from_iter([("memory", "high")])));
+    }
+}
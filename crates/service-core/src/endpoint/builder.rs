@@ -0,0 +1,120 @@
+use std::cmp::Ordering;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Capabilities advertised by a builder when it enrolls
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    /// Version of `boulder` the builder builds recipes with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boulder_version: Option<String>,
+    /// Build features supported by the builder
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    /// Architectures the builder can build for, i.e. `x86_64`
+    ///
+    /// [`DEFAULT_ARCH`] is assumed if none are advertised
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub architectures: Vec<String>,
+}
+
+/// Architecture assumed for builders which don't advertise any
+pub const DEFAULT_ARCH: &str = "x86_64";
+
+impl Capabilities {
+    /// Returns true if these capabilities meet everything `required` asks for
+    ///
+    /// A required `boulder_version` is treated as a minimum version
+    pub fn satisfies(&self, required: &Capabilities) -> bool {
+        let version_ok = match (&required.boulder_version, &self.boulder_version) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(required), Some(actual)) => compare_versions(actual, required) != Ordering::Less,
+        };
+
+        version_ok
+            && required.features.iter().all(|feature| self.features.contains(feature))
+            && required.architectures.iter().all(|arch| self.supports_arch(arch))
+    }
+
+    /// Returns true if the builder can build for `arch`
+    pub fn supports_arch(&self, arch: &str) -> bool {
+        if self.architectures.is_empty() {
+            arch == DEFAULT_ARCH
+        } else {
+            self.architectures.iter().any(|supported| supported == arch)
+        }
+    }
+}
+
+/// Compare dotted numeric versions segment by segment, i.e. `0.10.0` > `0.9.2`
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let segments = |version: &str| {
+        version
+            .trim_start_matches('v')
+            .split(['.', '-', '+'])
+            .map(|segment| segment.parse::<u64>().unwrap_or_default())
+            .collect::<Vec<_>>()
+    };
+
+    let (a, b) = (segments(a), segments(b));
+    let len = a.len().max(b.len());
+
+    (0..len)
+        .map(|i| a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn capabilities(version: Option<&str>, features: &[&str]) -> Capabilities {
+        Capabilities {
+            boulder_version: version.map(String::from),
+            features: features.iter().map(|f| f.to_string()).collect(),
+            architectures: vec![],
+        }
+    }
+
+    #[test]
+    fn satisfies() {
+        let required = capabilities(Some("0.10.0"), &["cross-arch"]);
+
+        assert!(capabilities(Some("0.10.0"), &["cross-arch"]).satisfies(&required));
+        assert!(capabilities(Some("0.10.2"), &["cross-arch", "ccache"]).satisfies(&required));
+        // Outdated builder is excluded even if it reports the feature
+        assert!(!capabilities(Some("0.9.4"), &["cross-arch"]).satisfies(&required));
+        assert!(!capabilities(None, &["cross-arch"]).satisfies(&required));
+        assert!(!capabilities(Some("0.11.0"), &[]).satisfies(&required));
+
+        // Nothing required
+        assert!(capabilities(None, &[]).satisfies(&Capabilities::default()));
+    }
+
+    #[test]
+    fn architectures() {
+        let builder = |architectures: &[&str]| Capabilities {
+            architectures: architectures.iter().map(|a| a.to_string()).collect(),
+            ..Default::default()
+        };
+        let builders = [builder(&["x86_64"]), builder(&["x86_64", "aarch64"])];
+
+        // An aarch64 task skips the x86_64 only builder
+        let eligible = builders
+            .iter()
+            .position(|builder| builder.supports_arch("aarch64"))
+            .unwrap();
+        assert_eq!(eligible, 1);
+
+        // Builders advertising nothing are assumed to only support the default
+        assert!(builder(&[]).supports_arch(DEFAULT_ARCH));
+        assert!(!builder(&[]).supports_arch("aarch64"));
+
+        assert!(!builder(&["x86_64"]).satisfies(&builder(&["aarch64"])));
+        assert!(builder(&["aarch64"]).satisfies(&Capabilities::default()));
+    }
+}
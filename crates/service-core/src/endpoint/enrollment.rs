@@ -1,9 +1,13 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::Role;
+use crate::{
+    endpoint::{builder::Capabilities, Labels},
+    Role,
+};
 
 /// An endpoint enrollment request
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Request {
     /// The issuer of the request
@@ -15,7 +19,7 @@ pub struct Request {
 }
 
 /// Contains details of the service issuing the enrollment request
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Issuer {
     /// Encoded public key for the issuer
@@ -24,4 +28,10 @@ pub struct Issuer {
     pub url: String,
     /// The service issuers role, i.e. Hub
     pub role: Role,
+    /// Capabilities of the issuer, only sent by builders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+    /// Labels the issuer should be assigned
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
 }
@@ -12,3 +12,4 @@ pub mod collectable;
 pub mod endpoint;
 pub mod remote;
 pub mod role;
+pub mod signature;
@@ -1,9 +1,78 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Remote {
     #[serde(rename = "indexURI")]
     pub index_uri: String,
     pub name: String,
     pub priority: u32,
 }
+
+/// Ensure `remotes` of a profile can be unambiguously written to a builder
+/// config, where each remote is keyed by it's name
+pub fn validate(remotes: &[Remote]) -> Result<(), ConflictError> {
+    let mut names = BTreeSet::new();
+
+    for remote in remotes {
+        if !names.insert(remote.name.as_str()) {
+            return Err(ConflictError::DuplicateName(remote.name.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Priorities shared by more than one of the `remotes`, leaving
+/// the order of those remotes non-deterministic
+pub fn shared_priorities(remotes: &[Remote]) -> Vec<u32> {
+    let mut counts = BTreeMap::<u32, usize>::new();
+
+    for remote in remotes {
+        *counts.entry(remote.priority).or_default() += 1;
+    }
+
+    counts
+        .into_iter()
+        .filter_map(|(priority, count)| (count > 1).then_some(priority))
+        .collect()
+}
+
+/// Remotes of a profile conflict w/ each other
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConflictError {
+    /// More than one remote has the same name
+    #[error("duplicate remote name {0:?}")]
+    DuplicateName(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conflicts() {
+        let remote = |name: &str, priority| Remote {
+            index_uri: format!("https://example.com/{name}/stone.index"),
+            name: name.to_string(),
+            priority,
+        };
+
+        let remotes = [remote("volatile", 0), remote("local", 10)];
+        assert_eq!(validate(&remotes), Ok(()));
+        assert!(shared_priorities(&remotes).is_empty());
+
+        let remotes = [remote("volatile", 0), remote("local", 10), remote("volatile", 20)];
+        assert_eq!(
+            validate(&remotes),
+            Err(ConflictError::DuplicateName("volatile".to_string()))
+        );
+
+        let remotes = [remote("volatile", 10), remote("local", 10), remote("extra", 10)];
+        assert_eq!(validate(&remotes), Ok(()));
+        assert_eq!(shared_priorities(&remotes), [10]);
+    }
+}
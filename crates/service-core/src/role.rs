@@ -1,4 +1,7 @@
 //! Defines the role a service plays in the infrastructure
+use std::borrow::Cow;
+
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -25,6 +28,22 @@ impl Role {
             Role::Builder => "avalanche",
         }
     }
+
+    /// Role associated to the provided service name, the reverse of [`Role::service_name`]
+    pub fn from_service_name(name: &str) -> Option<Self> {
+        [Role::Hub, Role::RepositoryManager, Role::Builder]
+            .into_iter()
+            .find(|role| role.service_name() == name)
+    }
+
+    /// Port each role's service listens on by convention
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Role::Hub => 5000,
+            Role::RepositoryManager => 5001,
+            Role::Builder => 5002,
+        }
+    }
 }
 
 impl From<Role> for u8 {
@@ -46,6 +65,21 @@ impl TryFrom<u8> for Role {
     }
 }
 
+// Serialized as it's `u8` discriminant
+impl JsonSchema for Role {
+    fn schema_name() -> Cow<'static, str> {
+        "Role".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "Service role: 1 = builder, 2 = repository manager, 3 = hub",
+            "type": "integer",
+            "enum": [1, 2, 3],
+        })
+    }
+}
+
 /// Unknown [`Role`] from [`u8`]
 #[derive(Debug, Error)]
 #[error("Unkown role: {0}")]
@@ -0,0 +1,84 @@
+//! Verify detached signatures produced by a service, such as
+//! the signature of a repository index
+
+use base64::Engine;
+use thiserror::Error;
+
+/// An ED25519 public key of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(ed25519_dalek::VerifyingKey);
+
+impl PublicKey {
+    /// Decode a public key as published by a service, i.e. the base64
+    /// (url safe, no padding) encoded key bytes
+    pub fn decode(encoded: &str) -> Result<Self, Error> {
+        let bytes = base64::prelude::BASE64_URL_SAFE_NO_PAD.decode(encoded.trim())?;
+        let bytes = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::InvalidPublicKeyLength { actual: bytes.len() })?;
+
+        Ok(Self(
+            ed25519_dalek::VerifyingKey::from_bytes(bytes).map_err(Error::InvalidPublicKey)?,
+        ))
+    }
+
+    /// Verify the detached `signature` of `message` was produced
+    /// by this public key
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), Error> {
+        let signature = ed25519_dalek::Signature::from_slice(signature).map_err(Error::InvalidSignature)?;
+
+        self.0
+            .verify_strict(message, &signature)
+            .map_err(Error::VerifySignature)
+    }
+}
+
+/// A signature error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Base64 decoding failed
+    #[error("base64 decode")]
+    Base64Decode(#[from] base64::DecodeError),
+    /// Invalid public key length
+    #[error(
+        "invalid public key length, expected {} got {actual}",
+        ed25519_dalek::PUBLIC_KEY_LENGTH
+    )]
+    InvalidPublicKeyLength {
+        /// Actual size
+        actual: usize,
+    },
+    /// Public key is invalid
+    #[error("invalid public key")]
+    InvalidPublicKey(#[source] ed25519_dalek::SignatureError),
+    /// Signature is malformed
+    #[error("invalid signature")]
+    InvalidSignature(#[source] ed25519_dalek::SignatureError),
+    /// Signature doesn't match the message
+    #[error("signature verification")]
+    VerifySignature(#[source] ed25519_dalek::SignatureError),
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::Signer;
+
+    use super::*;
+
+    #[test]
+    fn verify_detached() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; ed25519_dalek::SECRET_KEY_LENGTH]);
+        let encoded = base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(key.verifying_key().as_bytes());
+
+        let index = b"stone index contents";
+        let signature = key.sign(index).to_bytes();
+
+        let public_key = PublicKey::decode(&encoded).unwrap();
+
+        public_key.verify(index, &signature).unwrap();
+        assert!(public_key.verify(b"tampered index", &signature).is_err());
+        assert!(public_key.verify(index, &signature[..32]).is_err());
+        assert!(PublicKey::decode("not a key").is_err());
+    }
+}
@@ -0,0 +1,2 @@
+-- Capabilities advertised by builder endpoints (JSON encoded)
+ALTER TABLE endpoint ADD COLUMN capabilities TEXT;
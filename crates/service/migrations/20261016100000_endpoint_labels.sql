@@ -0,0 +1,8 @@
+-- Key / value labels of endpoints, used to route work to specific endpoints
+CREATE TABLE IF NOT EXISTS endpoint_label (
+    endpoint_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY(endpoint_id, key),
+    FOREIGN KEY(endpoint_id) REFERENCES endpoint(endpoint_id) ON DELETE CASCADE
+);
@@ -0,0 +1,12 @@
+-- Append-only trail of authentication, enrollment & authorization decisions.
+-- Not tied to accounts so records outlive them.
+CREATE TABLE IF NOT EXISTS audit_log (
+    audit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp DATETIME NOT NULL,
+    account_id INT,
+    operation TEXT NOT NULL,
+    decision TEXT NOT NULL,
+    reason TEXT,
+    source_ip TEXT,
+    request_id TEXT
+);
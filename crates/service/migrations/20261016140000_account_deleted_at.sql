@@ -0,0 +1,2 @@
+-- Accounts are soft deleted so rows referencing them are kept for history
+ALTER TABLE account ADD COLUMN deleted_at DATETIME;
@@ -0,0 +1,3 @@
+-- Entries are periodically deleted by age
+
+CREATE INDEX IF NOT EXISTS audit_log_timestamp ON audit_log (timestamp);
@@ -0,0 +1,2 @@
+-- Labels an admin set are kept when the endpoint re-enrolls
+ALTER TABLE endpoint ADD COLUMN labels_set_by_admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -1,14 +1,19 @@
 //! Manage data for admin, user, bot & service accounts
 
+use std::{convert::Infallible, time::Duration};
+
 use chrono::{DateTime, Utc};
 use derive_more::{Display, From, Into};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use strum::EnumString;
 use thiserror::Error;
-use tracing::debug;
+use tracing::{debug, error, info};
+
+use crate::{crypto::EncodedPublicKey, database, error, Database};
 
-use crate::{crypto::EncodedPublicKey, database, Database};
+/// How often expired account tokens are deleted
+const TOKEN_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 /// Unique identifier of an [`Account`]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, From, Into, Display, FromRow)]
@@ -43,6 +48,9 @@ pub struct Account {
     /// Public key used for authentication
     #[sqlx(try_from = "String")]
     pub public_key: EncodedPublicKey,
+    /// When the account was soft deleted, see [`Account::soft_delete`]
+    #[serde(skip)]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 impl Account {
@@ -55,11 +63,22 @@ impl Account {
             email: None,
             name: None,
             public_key,
+            deleted_at: None,
         }
     }
 
-    /// Get the account for [`Id`] from the provided [`Database`]
+    /// Get the account for [`Id`] from the provided [`Database`], excluding
+    /// soft deleted accounts
     pub async fn get<'a, T>(conn: &'a mut T, id: Id) -> Result<Self, Error>
+    where
+        &'a mut T: database::Executor<'a>,
+    {
+        Self::get_with(conn, id, false).await
+    }
+
+    /// Get the account for [`Id`] from the provided [`Database`], including
+    /// soft deleted accounts if `include_deleted`
+    pub async fn get_with<'a, T>(conn: &'a mut T, id: Id, include_deleted: bool) -> Result<Self, Error>
     where
         &'a mut T: database::Executor<'a>,
     {
@@ -71,12 +90,16 @@ impl Account {
               username,
               email,
               name,
-              public_key
+              public_key,
+              deleted_at
             FROM account
-            WHERE account_id = ?;
+            WHERE
+              account_id = ?
+              AND (? OR deleted_at IS NULL);
             ",
         )
         .bind(id.0)
+        .bind(include_deleted)
         .fetch_one(conn)
         .await?;
 
@@ -100,12 +123,14 @@ impl Account {
               username,
               email,
               name,
-              public_key
+              public_key,
+              deleted_at
             FROM account
             WHERE 
               username = ?
               AND public_key = ?
-              AND (type = 'admin' OR type = 'standard');
+              AND (type = 'admin' OR type = 'standard')
+              AND deleted_at IS NULL;
             ",
         )
         .bind(username)
@@ -116,6 +141,56 @@ impl Account {
         Ok(account)
     }
 
+    /// Delete the account for [`Id`] from the provided [`Database`]
+    ///
+    /// Any tokens and endpoints related to the account are also deleted
+    pub async fn delete(tx: &mut database::Transaction, id: Id) -> Result<(), Error> {
+        sqlx::query(
+            "
+            DELETE FROM account
+            WHERE account_id = ?;
+            ",
+        )
+        .bind(id.0)
+        .execute(tx.as_mut())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Soft delete the account for [`Id`] from the provided [`Database`]
+    ///
+    /// The account is kept so rows referencing it remain intact for history,
+    /// but it's excluded from lookups by default. Any tokens issued to the
+    /// account are deleted.
+    pub async fn soft_delete(tx: &mut database::Transaction, id: Id) -> Result<(), Error> {
+        sqlx::query(
+            "
+            UPDATE account
+            SET deleted_at = ?
+            WHERE
+              account_id = ?
+              AND deleted_at IS NULL;
+            ",
+        )
+        .bind(Utc::now())
+        .bind(id.0)
+        .execute(tx.as_mut())
+        .await?;
+
+        sqlx::query(
+            "
+            DELETE FROM account_token
+            WHERE account_id = ?;
+            ",
+        )
+        .bind(id.0)
+        .execute(tx.as_mut())
+        .await?;
+
+        Ok(())
+    }
+
     /// Create / update this account to the provided [`Database`]
     pub async fn save(&self, tx: &mut database::Transaction) -> Result<(), Error> {
         sqlx::query(
@@ -252,6 +327,69 @@ impl Token {
 
         Ok(token)
     }
+
+    /// List the unexpired account tokens for [`Id`] from the provided [`Database`]
+    pub async fn list<'a, T>(conn: &'a mut T, id: Id) -> Result<Vec<Token>, Error>
+    where
+        &'a mut T: database::Executor<'a>,
+    {
+        let tokens: Vec<Token> = sqlx::query_as(
+            "
+            SELECT
+              encoded,
+              expiration
+            FROM account_token
+            WHERE
+              account_id = ?
+              AND datetime(expiration) > datetime(?);
+            ",
+        )
+        .bind(id.0)
+        .bind(Utc::now())
+        .fetch_all(conn)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    /// Delete all expired account tokens, returning how many were deleted
+    pub async fn delete_expired(tx: &mut database::Transaction) -> Result<u64, Error> {
+        let result = sqlx::query(
+            "
+            DELETE FROM account_token
+            WHERE datetime(expiration) <= datetime(?);
+            ",
+        )
+        .bind(Utc::now())
+        .execute(tx.as_mut())
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+/// Periodically delete expired account tokens until the task is cancelled
+pub(crate) async fn sweep_expired_tokens(db: Database) -> Result<(), Infallible> {
+    let mut interval = tokio::time::interval(TOKEN_SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let result = async {
+            let mut tx = db.begin().await?;
+            let deleted = Token::delete_expired(&mut tx).await?;
+            tx.commit().await?;
+
+            Ok::<_, Error>(deleted)
+        }
+        .await;
+
+        match result {
+            Ok(0) => {}
+            Ok(deleted) => info!(deleted, "Deleted expired account tokens"),
+            Err(e) => error!(error = %error::chain(e), "Failed to delete expired account tokens"),
+        }
+    }
 }
 
 /// Admin account details
@@ -272,7 +410,7 @@ pub struct Admin {
     skip_all,
     fields(
         username = %admin.username,
-        public_key = %admin.public_key
+        public_key = %admin.public_key.fingerprint()
     )
 )]
 pub(crate) async fn sync_admin(db: &Database, admin: Admin) -> Result<(), Error> {
@@ -318,6 +456,7 @@ pub(crate) async fn sync_admin(db: &Database, admin: Admin) -> Result<(), Error>
         name: Some(admin.name.clone()),
         email: Some(admin.email.clone()),
         public_key: admin.public_key.clone(),
+        deleted_at: None,
     }
     .save(&mut tx)
     .await?;
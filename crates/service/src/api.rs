@@ -2,28 +2,32 @@
 use std::{any, marker::PhantomData};
 
 use axum::{
-    extract::{FromRequest, FromRequestParts, State},
-    http::{HeaderMap, StatusCode},
+    body::{Body, Bytes},
+    extract::{FromRequest, FromRequestParts, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
     routing::{MethodFilter, MethodRouter},
     Json, Router,
 };
-use futures_util::{future::BoxFuture, FutureExt};
+use futures_util::{future::BoxFuture, FutureExt, StreamExt};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use service_core::auth;
-use tracing::warn;
+use tracing::{error, warn};
 
-use crate::{middleware, token::VerifiedToken};
+use crate::{audit, middleware, token::VerifiedToken};
 
 pub use service_core::api::{
-    operation::{self, Operation},
+    operation::{self, Operation, Streaming},
     Version,
 };
 
-pub use self::handler::Handler;
+pub use self::encoding::Encoding;
+pub use self::handler::{Handler, StreamHandler};
 
+pub mod encoding;
 pub mod handler;
+pub mod openapi;
 pub mod v1;
 
 type RawRequest = axum::extract::Request;
@@ -32,6 +36,7 @@ type RawResponse = axum::response::Response;
 /// Register API operations with handlers
 pub struct Service<S = ()> {
     router: Router<S>,
+    operations: Vec<openapi::Entry>,
 }
 
 impl<S> Default for Service<S>
@@ -39,7 +44,10 @@ where
     S: Clone + Send + Sync + 'static,
 {
     fn default() -> Self {
-        Self { router: Router::new() }
+        Self {
+            router: Router::new(),
+            operations: vec![],
+        }
     }
 }
 
@@ -57,15 +65,65 @@ where
     where
         O: Operation + 'static,
         H: Handler<O, S> + Clone + Send + Sync + 'static,
-        <H as Handler<O, S>>::Error: std::error::Error + Send + Sync + 'static,
+        <H as Handler<O, S>>::Error: std::error::Error + ErrorCode + Send + Sync + 'static,
+        StatusCode: for<'a> From<&'a <H as Handler<O, S>>::Error>,
+    {
+        let filter = MethodFilter::try_from(O::METHOD).expect("unknown method");
+
+        self.router = self.router.route(
+            &format!("/api/{}/{}", O::VERSION, O::PATH),
+            MethodRouter::new().on(filter, OperationHandler::new(handler, false)),
+        );
+        self.operations.push(openapi::Entry::new::<O>());
+        self
+    }
+
+    /// Register a [`Handler`] to an [`Operation`] which handles dry runs itself,
+    /// returning what would happen without mutating any state when [`Request::dry_run`]
+    /// is set.
+    ///
+    /// Dry runs of mutating operations registered via [`Service::register`] never
+    /// reach their handler, instead responding w/ `204 No Content` once authorized
+    /// and the body is decoded.
+    pub fn register_dry_run<O, E, H>(mut self, handler: H) -> Self
+    where
+        O: Operation + 'static,
+        H: Handler<O, S> + Clone + Send + Sync + 'static,
+        <H as Handler<O, S>>::Error: std::error::Error + ErrorCode + Send + Sync + 'static,
         StatusCode: for<'a> From<&'a <H as Handler<O, S>>::Error>,
     {
         let filter = MethodFilter::try_from(O::METHOD).expect("unknown method");
 
         self.router = self.router.route(
             &format!("/api/{}/{}", O::VERSION, O::PATH),
-            MethodRouter::new().on(filter, OperationHandler::new(handler)),
+            MethodRouter::new().on(filter, OperationHandler::new(handler, true)),
         );
+        self.operations.push(openapi::Entry::new::<O>());
+        self
+    }
+
+    /// Register a [`StreamHandler`] to a [`Streaming`] operation, whose response
+    /// items are sent as newline delimited JSON as they're produced
+    pub fn register_stream<O, E, H>(mut self, handler: H) -> Self
+    where
+        O: Streaming + 'static,
+        H: StreamHandler<O, S> + Clone + Send + Sync + 'static,
+        <H as StreamHandler<O, S>>::Error: std::error::Error + ErrorCode + Send + Sync + 'static,
+        StatusCode: for<'a> From<&'a <H as StreamHandler<O, S>>::Error>,
+    {
+        let filter = MethodFilter::try_from(O::METHOD).expect("unknown method");
+
+        self.router = self.router.route(
+            &format!("/api/{}/{}", O::VERSION, O::PATH),
+            MethodRouter::new().on(
+                filter,
+                StreamOperationHandler {
+                    handler,
+                    _marker: PhantomData,
+                },
+            ),
+        );
+        self.operations.push(openapi::Entry::streaming::<O>());
         self
     }
 
@@ -73,11 +131,12 @@ where
     pub fn with_state(self, state: S) -> Service<()> {
         Service {
             router: self.router.with_state(state),
+            operations: self.operations,
         }
     }
 
-    pub(crate) fn into_router(self) -> Router<S> {
-        self.router
+    pub(crate) fn into_parts(self) -> (Router<S>, Vec<openapi::Entry>) {
+        (self.router, self.operations)
     }
 }
 
@@ -97,18 +156,27 @@ where
     /// request will be rejected before reaching
     /// it's [`Handler`]
     pub token: Option<VerifiedToken>,
+    /// Validate the request without mutating any state, set via
+    /// the `dry_run=true` query parameter
+    ///
+    /// Only handlers registered via [`Service::register_dry_run`]
+    /// receive dry runs of mutating operations
+    pub dry_run: bool,
 }
 
 #[derive(Debug)]
 struct OperationHandler<O, H, S> {
     handler: H,
+    /// Handler supports dry runs
+    dry_run: bool,
     _marker: PhantomData<fn() -> (O, S)>,
 }
 
 impl<O, H, S> OperationHandler<O, H, S> {
-    fn new(handler: H) -> Self {
+    fn new(handler: H, dry_run: bool) -> Self {
         Self {
             handler,
+            dry_run,
             _marker: PhantomData,
         }
     }
@@ -121,6 +189,7 @@ where
     fn clone(&self) -> Self {
         Self {
             handler: self.handler.clone(),
+            dry_run: self.dry_run,
             _marker: PhantomData,
         }
     }
@@ -131,80 +200,250 @@ where
     S: Clone + Sync + Send + 'static,
     O: Operation + 'static,
     H: Handler<O, S> + Clone + Send + Sync + 'static,
-    <H as Handler<O, S>>::Error: std::error::Error + Send + Sync + 'static,
+    <H as Handler<O, S>>::Error: std::error::Error + ErrorCode + Send + Sync + 'static,
     StatusCode: for<'a> From<&'a <H as Handler<O, S>>::Error>,
 {
     type Future = BoxFuture<'static, RawResponse>;
 
     fn call(self, req: axum::extract::Request, state: S) -> Self::Future {
         async move {
-            let (mut parts, body) = req.into_parts();
-
-            let headers = parts.headers.clone();
-            let token = parts.extensions.get().cloned();
-            let flags = parts
-                .extensions
-                .get::<auth::Flags>()
-                .copied()
-                .expect("auth middleware set");
-
-            match verify_auth(flags, O::AUTH) {
-                Ok(_) => {}
+            let (request, state, response_encoding) = match prepare::<O, S>(req, state).await {
+                Ok(prepared) => prepared,
                 Err(r) => return r,
-            }
-
-            let State(state) = match State::from_request_parts(&mut parts, &state).await {
-                Ok(v) => v,
-                Err(_) => unreachable!("infallible"),
             };
 
-            // Support empty body into ()
-            let body = if any::TypeId::of::<O::RequestBody>() == any::TypeId::of::<()>() {
-                serde_json::from_slice(b"null").expect("null is ()")
-            } else {
-                match Json::<O::RequestBody>::from_request(RawRequest::from_parts(parts, body), &state).await {
-                    Ok(Json(body)) => body,
-                    Err(e) => return error(e.status(), e),
-                }
-            };
+            if request.dry_run && O::MUTATING && !self.dry_run {
+                return StatusCode::NO_CONTENT.into_response();
+            }
 
-            match self.handler.handle(Request { headers, body, token }, state).await {
+            match self.handler.handle(request, state).await {
                 Ok(resp) => {
                     // Send empty body if ()
                     if any::TypeId::of::<O::ResponseBody>() == any::TypeId::of::<()>() {
                         ().into_response()
+                    } else if response_encoding == Encoding::MessagePack {
+                        match response_encoding.encode(&resp) {
+                            Ok(bytes) => ([(header::CONTENT_TYPE, encoding::MESSAGEPACK)], bytes).into_response(),
+                            Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, "encode_response", e),
+                        }
                     } else {
                         Json(resp).into_response()
                     }
                 }
-                Err(e) => error(StatusCode::from(&e), e),
+                Err(e) => error(StatusCode::from(&e), e.code(), e),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct StreamOperationHandler<O, H, S> {
+    handler: H,
+    _marker: PhantomData<fn() -> (O, S)>,
+}
+
+impl<O, H, S> Clone for StreamOperationHandler<O, H, S>
+where
+    H: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            handler: self.handler.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<O, H, S> axum::handler::Handler<(), S> for StreamOperationHandler<O, H, S>
+where
+    S: Clone + Sync + Send + 'static,
+    O: Streaming + 'static,
+    H: StreamHandler<O, S> + Clone + Send + Sync + 'static,
+    <H as StreamHandler<O, S>>::Error: std::error::Error + ErrorCode + Send + Sync + 'static,
+    StatusCode: for<'a> From<&'a <H as StreamHandler<O, S>>::Error>,
+{
+    type Future = BoxFuture<'static, RawResponse>;
+
+    fn call(self, req: axum::extract::Request, state: S) -> Self::Future {
+        async move {
+            let (request, state, _) = match prepare::<O, S>(req, state).await {
+                Ok(prepared) => prepared,
+                Err(r) => return r,
+            };
+
+            if request.dry_run && O::MUTATING {
+                return StatusCode::NO_CONTENT.into_response();
+            }
+
+            match self.handler.handle(request, state).await {
+                Ok(stream) => {
+                    let lines = stream.map(|item| match item {
+                        Ok(item) => {
+                            let mut line = serde_json::to_vec(&item)?;
+                            line.push(b'\n');
+                            Ok(Bytes::from(line))
+                        }
+                        // Headers are already sent, so the response is aborted
+                        // and the client sees an incomplete body
+                        Err(e) => {
+                            error!(error = %crate::error::chain(&e), path = O::PATH, "Response stream failed");
+                            Err(axum::BoxError::from(e))
+                        }
+                    });
+
+                    ([(header::CONTENT_TYPE, encoding::NDJSON)], Body::from_stream(lines)).into_response()
+                }
+                Err(e) => error(StatusCode::from(&e), e.code(), e),
             }
         }
         .boxed()
     }
 }
 
+/// Authorize the request and decode it's body, returning it alongside the
+/// handler state & the encoding the response should use
+async fn prepare<O, S>(req: RawRequest, state: S) -> Result<(Request<O>, S, Encoding), RawResponse>
+where
+    S: Clone + Sync + Send + 'static,
+    O: Operation + 'static,
+{
+    let (mut parts, body) = req.into_parts();
+
+    #[derive(Deserialize)]
+    struct Params {
+        #[serde(default)]
+        dry_run: bool,
+    }
+
+    // Rejected rather than ignored, otherwise a malformed dry run would mutate state
+    let dry_run = match Query::<Params>::try_from_uri(&parts.uri) {
+        Ok(Query(params)) => params.dry_run,
+        Err(e) => return Err(error(e.status(), INVALID_QUERY, e)),
+    };
+
+    let headers = parts.headers.clone();
+    let token = parts.extensions.get().cloned();
+    let flags = parts
+        .extensions
+        .get::<auth::Flags>()
+        .copied()
+        .expect("auth middleware set");
+
+    let account = token
+        .as_ref()
+        .map(|token: &VerifiedToken| token.decoded.payload.account_id);
+    let audit = |record: audit::Record| {
+        audit::record(match account {
+            Some(account) => record.with_account(account),
+            None => record,
+        })
+    };
+
+    let authorized = verify_auth(flags, O::AUTH, O::MUTATING).and_then(|_| {
+        // Operation scoped tokens can't be used for other operations
+        if token
+            .as_ref()
+            .is_some_and(|token| !token.decoded.payload.permits(O::PATH))
+        {
+            warn!(path = O::PATH, "token not scoped for operation");
+            Err(AuthError::OutOfScope)
+        } else {
+            Ok(())
+        }
+    });
+
+    match authorized {
+        Err(e) => {
+            audit(audit::Record::denied(O::PATH, e.code()));
+            return Err(error(StatusCode::from(&e), e.code(), e));
+        }
+        // Unauthenticated operations, such as ping, aren't worth recording
+        Ok(()) if O::AUTH != auth::Flags::NO_AUTH => audit(audit::Record::allowed(O::PATH)),
+        Ok(()) => {}
+    }
+
+    let State(state) = match State::from_request_parts(&mut parts, &state).await {
+        Ok(v) => v,
+        Err(_) => unreachable!("infallible"),
+    };
+
+    let request_encoding = Encoding::from_content_type(&headers);
+    let response_encoding = Encoding::from_accept(&headers);
+
+    // Support empty body into ()
+    let body = if any::TypeId::of::<O::RequestBody>() == any::TypeId::of::<()>() {
+        serde_json::from_slice(b"null").expect("null is ()")
+    } else if request_encoding == Encoding::MessagePack {
+        match Bytes::from_request(RawRequest::from_parts(parts, body), &state).await {
+            Ok(bytes) => match request_encoding.decode(&bytes) {
+                Ok(body) => body,
+                Err(e) => return Err(error(StatusCode::UNPROCESSABLE_ENTITY, INVALID_BODY, e)),
+            },
+            Err(e) => return Err(error(e.status(), INVALID_BODY, e)),
+        }
+    } else {
+        match Json::<O::RequestBody>::from_request(RawRequest::from_parts(parts, body), &state).await {
+            Ok(Json(body)) => body,
+            Err(e) => return Err(error(e.status(), INVALID_BODY, e)),
+        }
+    };
+
+    Ok((
+        Request {
+            headers,
+            body,
+            token,
+            dry_run,
+        },
+        state,
+        response_encoding,
+    ))
+}
+
+/// A stable, machine readable code identifying an error variant, returned
+/// alongside the error message in API error responses
+///
+/// Error enums can derive [`strum::IntoStaticStr`] w/ `serialize_all = "snake_case"`
+/// and return `self.into()`. Variants wrapping an error which also has a code
+/// should return it's code instead, so callers see the most specific one.
+pub trait ErrorCode {
+    /// Code of this error, such as `role_mismatch`
+    fn code(&self) -> &'static str;
+}
+
+/// Code returned when the request body can't be decoded
+const INVALID_BODY: &str = "invalid_body";
+/// Code returned when the query parameters can't be decoded
+const INVALID_QUERY: &str = "invalid_query";
+
 // All API endpoints should return error as JSON payload
-fn error(status: StatusCode, error: impl std::error::Error + Send + Sync + 'static) -> RawResponse {
+fn error(status: StatusCode, code: &'static str, error: impl std::error::Error + Send + Sync + 'static) -> RawResponse {
     #[derive(Serialize)]
     struct Error {
         error: String,
+        code: &'static str,
+        status: u16,
     }
 
-    let body = format!("{error}");
+    let body = Error {
+        error: format!("{error}"),
+        code,
+        status: status.as_u16(),
+    };
 
-    let mut resp = (status, Json(Error { error: body })).into_response();
+    let mut resp = (status, Json(body)).into_response();
     resp.extensions_mut().insert(middleware::log::Error::new(error));
     resp
 }
 
-fn verify_auth(request_flags: auth::Flags, validation_flags: auth::Flags) -> Result<(), RawResponse> {
-    #[derive(Debug, thiserror::Error)]
-    enum Error {
-        #[error("unauthenticated")]
-        Unauthenticated,
-        #[error("permission denied")]
-        PermissionDenied,
+/// Verify the request is authorized to call an operation requiring `validation_flags`.
+///
+/// Read-only tokens are only authorized for operations which aren't `mutating`
+fn verify_auth(request_flags: auth::Flags, validation_flags: auth::Flags, mutating: bool) -> Result<(), AuthError> {
+    if request_flags.contains(auth::Flags::READ_ONLY) && mutating {
+        warn!("read-only token used for mutating operation");
+        return Err(AuthError::ReadOnly);
     }
 
     let validation_names = auth::flag_names(validation_flags);
@@ -216,9 +455,96 @@ fn verify_auth(request_flags: auth::Flags, validation_flags: auth::Flags) -> Res
         Ok(())
     } else if request_flags == auth::Flags::NO_AUTH {
         warn!(expected = ?validation_names, received = ?token_names, "unauthenticated");
-        Err(error(StatusCode::UNAUTHORIZED, Error::Unauthenticated))
+        Err(AuthError::Unauthenticated)
     } else {
         warn!(expected = ?validation_names, received = ?token_names, "permission denied");
-        Err(error(StatusCode::FORBIDDEN, Error::PermissionDenied))
+        Err(AuthError::PermissionDenied)
+    }
+}
+
+/// Request isn't authorized to call an operation
+#[derive(Debug, thiserror::Error, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+enum AuthError {
+    #[error("unauthenticated")]
+    Unauthenticated,
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("read-only token")]
+    ReadOnly,
+    #[error("token not scoped for operation")]
+    OutOfScope,
+}
+
+impl ErrorCode for AuthError {
+    fn code(&self) -> &'static str {
+        self.into()
+    }
+}
+
+impl From<&AuthError> for StatusCode {
+    fn from(error: &AuthError) -> Self {
+        match error {
+            AuthError::Unauthenticated => StatusCode::UNAUTHORIZED,
+            AuthError::PermissionDenied | AuthError::ReadOnly | AuthError::OutOfScope => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::stream;
+    use http::Method;
+    use service_core::operation;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    operation!(Count, GET, "test/count", stream: u64);
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("count failed")]
+    struct CountError;
+
+    impl ErrorCode for CountError {
+        fn code(&self) -> &'static str {
+            "count_failed"
+        }
+    }
+
+    impl From<&CountError> for StatusCode {
+        fn from(_: &CountError) -> Self {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    async fn count(
+        _: Request<Count>,
+        _: (),
+    ) -> Result<impl futures_util::Stream<Item = Result<u64, CountError>>, CountError> {
+        Ok(stream::iter((1..=3).map(Ok)))
+    }
+
+    #[tokio::test]
+    async fn stream_ndjson() {
+        let router = Service::new()
+            .register_stream::<Count, CountError, _>(count)
+            .into_parts()
+            .0;
+
+        let mut request = axum::extract::Request::builder()
+            .method(Method::GET)
+            .uri(format!("/api/v1/{}", Count::PATH))
+            .body(Body::empty())
+            .unwrap();
+        request.extensions_mut().insert(auth::Flags::NO_AUTH);
+
+        let resp = router.oneshot(request).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], encoding::NDJSON);
+
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"1\n2\n3\n");
     }
 }
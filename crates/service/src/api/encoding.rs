@@ -0,0 +1,160 @@
+//! Negotiate how [`Operation`] request & response bodies are encoded
+//!
+//! Bodies are JSON unless a client opts into MessagePack by sending the
+//! [`MESSAGEPACK`] content type, so external clients such as the CLI
+//! continue to work unchanged. Services list the encodings they accept in
+//! the [`HEADER`] response header, and clients only send MessagePack to
+//! those which advertised it.
+//!
+//! [`Operation`]: super::Operation
+
+use http::{header, HeaderMap};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+/// MessagePack media type
+pub const MESSAGEPACK: &str = "application/msgpack";
+/// Response header listing the media types a service accepts for operation
+/// bodies, besides JSON
+pub const HEADER: &str = "x-accept-content-type";
+/// JSON media type
+pub const JSON: &str = "application/json";
+/// Newline delimited JSON media type, used by [`Streaming`] operations
+///
+/// [`Streaming`]: super::operation::Streaming
+pub const NDJSON: &str = "application/x-ndjson";
+
+/// Encoding of an operation body
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// JSON
+    #[default]
+    Json,
+    /// MessagePack
+    MessagePack,
+}
+
+impl Encoding {
+    /// Media type of this encoding
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => JSON,
+            Encoding::MessagePack => MESSAGEPACK,
+        }
+    }
+
+    /// Encoding of a body based on it's `Content-Type` header, falling back to JSON
+    pub fn from_content_type(headers: &HeaderMap) -> Self {
+        Self::negotiate(headers, header::CONTENT_TYPE)
+    }
+
+    /// Encoding the response body should use based on the request's `Accept` header,
+    /// falling back to JSON
+    pub fn from_accept(headers: &HeaderMap) -> Self {
+        Self::negotiate(headers, header::ACCEPT)
+    }
+
+    /// Encoding a service advertised accepting via it's [`HEADER`] response header,
+    /// falling back to JSON
+    pub fn from_advertised(headers: &HeaderMap) -> Self {
+        Self::negotiate(headers, header::HeaderName::from_static(HEADER))
+    }
+
+    fn negotiate(headers: &HeaderMap, name: header::HeaderName) -> Self {
+        let messagepack = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media_type| media_type.split(';').next().unwrap_or_default().trim() == MESSAGEPACK);
+
+        if messagepack {
+            Encoding::MessagePack
+        } else {
+            Encoding::Json
+        }
+    }
+
+    /// Encode `value` with this encoding
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+            // Encode structs as maps so serde renames, flattening & tagging behave like JSON
+            Encoding::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+        }
+    }
+
+    /// Decode `bytes` with this encoding
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(bytes)?),
+            Encoding::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+        }
+    }
+}
+
+/// An encoding error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// JSON error
+    #[error("json")]
+    Json(#[from] serde_json::Error),
+    /// MessagePack encoding failed
+    #[error("encode messagepack")]
+    EncodeMessagePack(#[from] rmp_serde::encode::Error),
+    /// MessagePack decoding failed
+    #[error("decode messagepack")]
+    DecodeMessagePack(#[from] rmp_serde::decode::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use http::HeaderValue;
+    use service_core::{api::v1::summit::BuildBody, collectable, Collectable};
+
+    use super::*;
+
+    #[test]
+    fn negotiate_build_body() {
+        let body = BuildBody {
+            task_id: 42,
+            collectables: vec![Collectable {
+                kind: collectable::Kind::Package,
+                uri: "https://avalanche/assets/42/nano-8.2-1-1-x86_64.stone".to_string(),
+                sha256sum: "0".repeat(64),
+                signature: None,
+            }],
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(MESSAGEPACK));
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/msgpack;q=1.0, */*"),
+        );
+
+        let encoding = Encoding::from_content_type(&headers);
+        assert_eq!(encoding, Encoding::MessagePack);
+        assert_eq!(Encoding::from_accept(&headers), Encoding::MessagePack);
+
+        let encoded = encoding.encode(&body).unwrap();
+        assert!(serde_json::from_slice::<serde_json::Value>(&encoded).is_err());
+
+        let decoded = encoding.decode::<BuildBody>(&encoded).unwrap();
+        assert_eq!(decoded.task_id, body.task_id);
+        assert_eq!(decoded.collectables[0].uri, body.collectables[0].uri);
+
+        // Not negotiated
+        let headers = HeaderMap::new();
+        let encoding = Encoding::from_content_type(&headers);
+        assert_eq!(encoding, Encoding::Json);
+        assert_eq!(Encoding::from_accept(&headers), Encoding::Json);
+
+        let encoded = encoding.encode(&body).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&encoded).unwrap()["taskID"],
+            42
+        );
+    }
+}
@@ -1,6 +1,6 @@
 //! Define a handler for an API [`Operation`]
-use futures_util::Future;
-use service_core::api::Operation;
+use futures_util::{Future, Stream};
+use service_core::api::{operation::Streaming, Operation};
 
 use super::Request;
 
@@ -36,3 +36,32 @@ where
         (self)(req, state)
     }
 }
+
+/// Handle a [`Streaming`] API [`Operation`]
+pub trait StreamHandler<O, S>
+where
+    O: Streaming,
+{
+    /// Handler error
+    type Error;
+    /// Stream of response items
+    type Stream: Stream<Item = Result<<O as Streaming>::Item, Self::Error>> + Send + 'static;
+
+    /// Handle an incoming request and return a stream of response items
+    fn handle(self, req: Request<O>, state: S) -> impl Future<Output = Result<Self::Stream, Self::Error>> + Send;
+}
+
+impl<O, FN, F, St, E, S> StreamHandler<O, S> for FN
+where
+    O: Streaming,
+    FN: Fn(Request<O>, S) -> F,
+    F: Future<Output = Result<St, E>> + Send,
+    St: Stream<Item = Result<<O as Streaming>::Item, E>> + Send + 'static,
+{
+    type Error = E;
+    type Stream = St;
+
+    fn handle(self, req: Request<O>, state: S) -> impl Future<Output = Result<Self::Stream, Self::Error>> + Send {
+        (self)(req, state)
+    }
+}
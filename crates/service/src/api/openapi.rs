@@ -0,0 +1,171 @@
+//! Describe registered [`Operation`]s as an OpenAPI document
+//!
+//! Request & response body schemas are generated from their
+//! [`JsonSchema`] implementations.
+use std::any;
+
+use axum::{routing::get, Json, Router};
+use schemars::{generate::SchemaSettings, JsonSchema, Schema, SchemaGenerator};
+use serde_json::{json, Map, Value};
+use service_core::auth;
+
+use super::{encoding, operation::Streaming, Operation};
+
+/// Path the OpenAPI document is served at
+pub const PATH: &str = "/openapi.json";
+
+/// Description of a registered [`Operation`]
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    name: &'static str,
+    method: http::Method,
+    path: String,
+    auth: auth::Flags,
+    mutating: bool,
+    request: Option<fn(&mut SchemaGenerator) -> Schema>,
+    response: Option<fn(&mut SchemaGenerator) -> Schema>,
+    content_type: &'static str,
+}
+
+impl Entry {
+    /// Describe [`Operation`] `O`
+    pub(crate) fn new<O: Operation + 'static>() -> Self {
+        Self {
+            name: type_name::<O>(),
+            method: O::METHOD,
+            path: format!("/api/{}/{}", O::VERSION, O::PATH),
+            auth: O::AUTH,
+            mutating: O::MUTATING,
+            request: body::<O::RequestBody>(),
+            response: body::<O::ResponseBody>(),
+            content_type: encoding::JSON,
+        }
+    }
+
+    /// Describe [`Streaming`] operation `O`, whose response is a stream of items
+    pub(crate) fn streaming<O: Streaming + 'static>() -> Self {
+        Self {
+            response: body::<O::Item>(),
+            content_type: encoding::NDJSON,
+            ..Self::new::<O>()
+        }
+    }
+}
+
+/// Generate an OpenAPI document describing `entries`
+pub(crate) fn document(title: &str, entries: &[Entry]) -> Value {
+    let mut generator = SchemaSettings::draft2020_12()
+        .with(|settings| settings.definitions_path = "#/components/schemas/".into())
+        .into_generator();
+
+    let mut paths = Map::new();
+
+    for entry in entries {
+        let mut operation = json!({
+            "operationId": entry.name,
+            "responses": {
+                "200": match entry.response {
+                    Some(schema) => json!({
+                        "description": "Success",
+                        "content": { (entry.content_type): { "schema": schema(&mut generator) } },
+                    }),
+                    None => json!({ "description": "Success" }),
+                },
+                "default": { "description": "Error" },
+            },
+        });
+
+        if let Some(schema) = entry.request {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { (encoding::JSON): { "schema": schema(&mut generator) } },
+            });
+        }
+
+        if entry.mutating {
+            operation["parameters"] = json!([{
+                "name": "dry_run",
+                "in": "query",
+                "description": "Validate the request without mutating any state",
+                "schema": { "type": "boolean" },
+            }]);
+        }
+
+        if entry.auth != auth::Flags::NO_AUTH {
+            operation["security"] = json!([{ "bearer": [] }]);
+            operation["x-auth-flags"] = json!(auth::flag_names(entry.auth));
+        }
+
+        let path = paths
+            .entry(entry.path.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        path[entry.method.as_str().to_lowercase()] = operation;
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": title,
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": generator.take_definitions(true),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+            },
+        },
+    })
+}
+
+/// Serve `document` at [`PATH`]
+pub(crate) fn router(document: Value) -> Router {
+    Router::new().route(PATH, get(move || async move { Json(document) }))
+}
+
+/// Schema of body `T`, unless it's `()` which is sent as an empty body
+fn body<T: JsonSchema + 'static>() -> Option<fn(&mut SchemaGenerator) -> Schema> {
+    (any::TypeId::of::<T>() != any::TypeId::of::<()>()).then_some(SchemaGenerator::subschema_for::<T>)
+}
+
+/// Unqualified name of the operation type
+fn type_name<O>() -> &'static str {
+    let name = any::type_name::<O>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+#[cfg(test)]
+mod test {
+    use service_core::api::v1::services::{Enroll, Ping, ResolvePendingEnrollments};
+
+    use super::*;
+
+    #[test]
+    fn describe_operations() {
+        let document = document(
+            "summit",
+            &[
+                Entry::new::<Ping>(),
+                Entry::new::<Enroll>(),
+                Entry::new::<ResolvePendingEnrollments>(),
+            ],
+        );
+
+        let ping = &document["paths"]["/api/v1/services/ping"]["get"];
+        assert_eq!(ping["operationId"], "Ping");
+        assert!(ping.get("requestBody").is_none());
+        assert_eq!(ping["security"], json!([{ "bearer": [] }]));
+
+        let enroll = &document["paths"]["/api/v1/services/enrol"]["post"];
+        assert!(enroll.get("security").is_none());
+
+        let resolve = &document["paths"]["/api/v1/services/resolve_pending"]["post"];
+        assert_eq!(
+            resolve["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/ResolvePendingBody"
+        );
+        assert_eq!(resolve["security"], json!([{ "bearer": [] }]));
+        assert_eq!(resolve["parameters"][0]["name"], "dry_run");
+        assert!(document["components"]["schemas"]["PendingAction"].is_object());
+    }
+}
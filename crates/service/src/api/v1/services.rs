@@ -1,7 +1,14 @@
 //! An implementation of endpoint service operations
 
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use futures_util::future;
 use http::Uri;
 use thiserror::Error;
 use tracing::{debug, error, info};
@@ -9,33 +16,54 @@ use tracing::{debug, error, info};
 pub use service_core::api::v1::services::*;
 
 use crate::{
-    account, api,
+    account,
+    api::{self, Operation},
+    audit, config,
     crypto::{EncodedPublicKey, PublicKey},
+    database,
     endpoint::{
         self,
         enrollment::{self, Issuer},
     },
     error,
     sync::SharedMap,
-    token, Config, Database, Role, Token,
+    token, Account, Database, Endpoint, Role, Token,
 };
 
 /// An implementation of the shared service operations
 //
 // Provided by shared [`Server`](crate::Server)
 // so doesn't need to be public
-pub(crate) fn services(role: Role, config: &Config, state: &crate::State) -> api::Service {
+pub(crate) fn services(
+    issuer: Issuer,
+    config: config::Live,
+    maintenance: Arc<AtomicBool>,
+    state: &crate::State,
+) -> api::Service {
     api::Service::new()
-        .register::<Enroll, Error, _>(enroll)
+        .register_dry_run::<Enroll, Error, _>(enroll)
         .register::<Accept, Error, _>(accept)
         .register::<Decline, Error, _>(decline)
         .register::<RefreshToken, Error, _>(refresh_token)
         .register::<RefreshIssueToken, Error, _>(refresh_issue_token)
+        .register::<IssueOperationToken, Error, _>(issue_operation_token)
+        .register::<CancelEnrollment, Error, _>(cancel_enrollment)
+        .register::<Ping, Error, _>(ping)
+        .register::<ListEndpoints, Error, _>(list_endpoints)
+        .register::<RevokeEndpoint, Error, _>(revoke_endpoint)
+        .register::<SetEndpointLabels, Error, _>(set_endpoint_labels)
+        .register::<ResolvePendingEnrollments, Error, _>(resolve_pending_enrollments)
+        .register::<CancelSentEnrollment, Error, _>(cancel_sent_enrollment)
+        .register::<ListAuditLog, Error, _>(list_audit_log)
+        .register::<IssueReadOnlyToken, Error, _>(issue_read_only_token)
+        .register::<SetMaintenance, Error, _>(set_maintenance)
         .with_state(State {
-            issuer: config.issuer(role, state.key_pair.clone()),
+            issuer,
             db: state.service_db.clone(),
             pending_sent: state.pending_sent.clone(),
-            upstream: config.upstream,
+            pending_received: state.pending_received.clone(),
+            config,
+            maintenance,
         })
 }
 
@@ -43,6 +71,8 @@ pub(crate) fn services(role: Role, config: &Config, state: &crate::State) -> api
 #[derive(Debug, Clone)]
 struct State {
     /// Issuer details of this service
+    ///
+    /// Use [`State::issuer`] for details which reflect the current [`config::Live`]
     issuer: Issuer,
     /// Shared database of this service
     db: Database,
@@ -50,46 +80,53 @@ struct State {
     ///
     /// Only applicable for hub service
     pending_sent: SharedMap<endpoint::Id, enrollment::Sent>,
-    /// Upstream hub to auto-accept enrollment with
+    /// Received enrollment requests that haven't been accepted yet
     ///
     /// Only applicable for non-hub services
-    upstream: Option<PublicKey>,
+    pending_received: SharedMap<String, enrollment::Received>,
+    /// Service configuration, swapped when reloaded
+    config: config::Live,
+    /// Mutating operations are rejected while set
+    maintenance: Arc<AtomicBool>,
 }
 
 impl State {
     fn role(&self) -> Role {
         self.issuer.role
     }
+
+    /// Issuer details of this service from the current configuration
+    fn issuer(&self) -> Issuer {
+        Issuer {
+            capabilities: self.issuer.capabilities.clone(),
+            ..self
+                .config
+                .load()
+                .issuer(self.issuer.role, self.issuer.key_pair.clone())
+        }
+    }
+
+    /// Upstream hub to auto-accept enrollment with
+    ///
+    /// Only applicable for non-hub services
+    fn upstream(&self) -> Option<PublicKey> {
+        self.config.load().upstream
+    }
 }
 
 async fn enroll(request: api::Request<Enroll>, state: State) -> Result<(), Error> {
-    let upstream = *state.upstream.as_ref().ok_or(Error::UpstreamNotSet)?;
-
+    let dry_run = request.dry_run;
     let request = request.body.request;
-    let issuer = request.issuer;
-    let issue_token = request.issue_token;
 
-    let public_key = EncodedPublicKey::decode(&issuer.public_key).map_err(|_| Error::InvalidPublicKey)?;
+    let (public_key, verified_token) = verify_enrollment(&request, &state)
+        .inspect_err(|e| audit::record(audit::Record::denied(Enroll::PATH, api::ErrorCode::code(e))))?;
 
-    if public_key != upstream {
-        return Err(Error::UpstreamMismatch {
-            expected: upstream,
-            provided: public_key,
-        });
+    if dry_run {
+        info!(public_key = request.issuer.public_key, "Enrollment dry run accepted");
+        return Ok(());
     }
 
-    let verified_token =
-        Token::verify(&issue_token, &public_key, &token::Validation::new()).map_err(Error::VerifyToken)?;
-
-    if !matches!(verified_token.decoded.payload.purpose, token::Purpose::Authorization) {
-        return Err(Error::RequireBearerToken);
-    }
-    if request.role != state.role() {
-        return Err(Error::RoleMismatch {
-            expected: state.role(),
-            provided: request.role,
-        });
-    }
+    let issuer = request.issuer;
 
     info!(
         public_key = issuer.public_key,
@@ -103,6 +140,15 @@ async fn enroll(request: api::Request<Enroll>, state: State) -> Result<(), Error
 
     debug!(%endpoint, %account, "Generated endpoint & account IDs for enrollment request");
 
+    audit::record(
+        audit::Record::allowed(Enroll::PATH)
+            .with_account(account)
+            .with_reason(format!("enrollment requested by {}", public_key.fingerprint())),
+    );
+
+    // Subject of the issuer's token identifies this enrollment if it's cancelled
+    let subject = verified_token.decoded.payload.sub.clone();
+
     let recieved = enrollment::Received {
         endpoint,
         account,
@@ -111,6 +157,8 @@ async fn enroll(request: api::Request<Enroll>, state: State) -> Result<(), Error
             public_key,
             role: issuer.role,
             bearer_token: verified_token,
+            capabilities: issuer.capabilities,
+            labels: issuer.labels,
         },
     };
 
@@ -118,10 +166,17 @@ async fn enroll(request: api::Request<Enroll>, state: State) -> Result<(), Error
     //
     // D infra expects this operation returns before we
     // respond w/ acceptance
+    state.pending_received.insert(subject.clone(), recieved).await;
+
     tokio::spawn(async move {
         tokio::time::sleep(Duration::from_secs(1)).await;
 
-        if let Err(e) = recieved.accept(&state.db, state.issuer.clone()).await {
+        let Some(recieved) = state.pending_received.remove(&subject).await else {
+            info!(%endpoint, "Enrollment cancelled or resolved before acceptance");
+            return;
+        };
+
+        if let Err(e) = recieved.accept(&state.db, state.issuer()).await {
             error!(error=%error::chain(e), "Auto accept failed")
         };
     });
@@ -129,6 +184,52 @@ async fn enroll(request: api::Request<Enroll>, state: State) -> Result<(), Error
     Ok(())
 }
 
+/// Verify the enrollment request was sent by our upstream hub, returning
+/// it's public key & the verified issue token
+fn verify_enrollment(request: &enrollment::Request, state: &State) -> Result<(PublicKey, token::VerifiedToken), Error> {
+    let upstream = state.upstream().ok_or(Error::UpstreamNotSet)?;
+
+    let public_key = EncodedPublicKey::decode(&request.issuer.public_key).map_err(|_| Error::InvalidPublicKey)?;
+
+    if public_key != upstream {
+        return Err(Error::UpstreamMismatch {
+            expected: upstream.fingerprint(),
+            provided: public_key.fingerprint(),
+        });
+    }
+
+    let verified_token = verify_issue_token(&request.issue_token, &public_key, state)?;
+
+    if request.role != state.role() {
+        return Err(Error::RoleMismatch {
+            expected: state.role(),
+            provided: request.role,
+        });
+    }
+
+    Ok((public_key, verified_token))
+}
+
+/// Verify the bearer token our upstream hub issued us w/ an enrollment request
+fn verify_issue_token(issue_token: &str, upstream: &PublicKey, state: &State) -> Result<token::VerifiedToken, Error> {
+    let mut validation = token::Validation::new()
+        .iss(Role::Hub.service_name())
+        .aud_service(Role::Hub.service_name());
+
+    if state.config.load().token.legacy_audience {
+        // Hubs of the previous release issue these w/ our role as the audience
+        validation = validation.legacy_aud_service(state.role().service_name());
+    }
+
+    let verified_token = Token::verify(issue_token, upstream, &validation).map_err(Error::VerifyToken)?;
+
+    if !matches!(verified_token.decoded.payload.purpose, token::Purpose::Authorization) {
+        return Err(Error::RequireBearerToken);
+    }
+
+    Ok(verified_token)
+}
+
 async fn accept(request: api::Request<Accept>, state: State) -> Result<(), Error> {
     let token = request.token.clone().ok_or(Error::MissingRequestToken)?;
 
@@ -164,9 +265,10 @@ async fn accept(request: api::Request<Accept>, state: State) -> Result<(), Error
         "Enrollment accepted"
     );
 
+    // Only removed once accepted, so it can be retried if this is interrupted
     state
         .pending_sent
-        .remove(&endpoint)
+        .get(&endpoint)
         .await
         .ok_or(Error::MissingPendingEnrollment(endpoint))?
         .accepted(
@@ -176,10 +278,14 @@ async fn accept(request: api::Request<Accept>, state: State) -> Result<(), Error
                 public_key,
                 role: issuer.role,
                 bearer_token: verified_token,
+                capabilities: issuer.capabilities,
+                labels: issuer.labels,
             },
         )
         .await?;
 
+    state.pending_sent.remove(&endpoint).await;
+
     Ok(())
 }
 
@@ -196,7 +302,7 @@ async fn decline(request: api::Request<Decline>, state: State) -> Result<(), Err
     if let Some(enrollment) = state.pending_sent.remove(&endpoint).await {
         info!(
             %endpoint,
-            public_key = %enrollment.target.public_key,
+            public_key = %enrollment.target.public_key.fingerprint(),
             url = %enrollment.target.host_address,
             role = %enrollment.target.role,
             "Enrollment declined"
@@ -206,33 +312,301 @@ async fn decline(request: api::Request<Decline>, state: State) -> Result<(), Err
     Ok(())
 }
 
+async fn cancel_enrollment(request: api::Request<CancelEnrollment>, state: State) -> Result<(), Error> {
+    let upstream = state.upstream().ok_or(Error::UpstreamNotSet)?;
+
+    // Issue token we were sent w/ the enrollment request proves the upstream cancelled it
+    let verified_token = verify_issue_token(&request.body.issue_token, &upstream, &state)?;
+
+    if let Some(received) = state.pending_received.remove(&verified_token.decoded.payload.sub).await {
+        info!(
+            endpoint = %received.endpoint,
+            public_key = %received.remote.public_key.fingerprint(),
+            url = %received.remote.host_address,
+            role = %received.remote.role,
+            "Enrollment cancelled"
+        );
+    }
+
+    Ok(())
+}
+
+async fn cancel_sent_enrollment(request: api::Request<CancelSentEnrollment>, state: State) -> Result<(), Error> {
+    let endpoint = request
+        .body
+        .id
+        .parse::<endpoint::Id>()
+        .map_err(Error::InvalidEndpoint)?;
+
+    let sent = state
+        .pending_sent
+        .get(&endpoint)
+        .await
+        .ok_or(Error::MissingPendingEnrollment(endpoint))?;
+
+    sent.cancel(&state.pending_sent).await?;
+
+    Ok(())
+}
+
 // Middleware already validates this token is valid for this endpoint
 async fn refresh_token(request: api::Request<RefreshToken>, state: State) -> Result<String, Error> {
-    request
-        .token
-        .ok_or(Error::MissingRequestToken)?
-        .decoded
+    let token = request.token.ok_or(Error::MissingRequestToken)?.decoded;
+
+    migrate_audience(token, state.role())
         // Bearer token is provided, so make sure
         // we return an access token
         .with_purpose(token::Purpose::Authentication)
-        .refresh()
+        .refresh(&state.config.load().token)
         .sign(&state.issuer.key_pair)
         .map_err(Error::SignToken)
 }
 
 // Middleware already validates this token is valid for this endpoint
 async fn refresh_issue_token(request: api::Request<RefreshIssueToken>, state: State) -> Result<String, Error> {
+    let token = request.token.ok_or(Error::MissingRequestToken)?.decoded;
+
+    migrate_audience(token, state.role())
+        .refresh(&state.config.load().token)
+        .sign(&state.issuer.key_pair)
+        .map_err(Error::SignToken)
+}
+
+/// Tokens issued w/ the legacy audience are refreshed w/ our own service as
+/// their audience, see [`token::Validation::legacy_aud_service`]
+fn migrate_audience(token: Token, role: Role) -> Token {
+    if token.payload.audience_service() != role.service_name() {
+        token.with_audience(token::Audience::Service(role))
+    } else {
+        token
+    }
+}
+
+/// Issue an access token only usable for the operation at the requested path,
+/// such as for handing to a collaborator which only needs to make one callback
+async fn issue_operation_token(request: api::Request<IssueOperationToken>, state: State) -> Result<String, Error> {
     request
         .token
         .ok_or(Error::MissingRequestToken)?
         .decoded
-        .refresh()
+        .with_purpose(token::Purpose::Authentication)
+        .with_audience(token::Audience::Operation(state.role(), &request.body.path))
+        .refresh(&state.config.load().token)
+        .sign(&state.issuer.key_pair)
+        .map_err(Error::SignToken)
+}
+
+async fn ping(_request: api::Request<Ping>, _state: State) -> Result<(), Error> {
+    Ok(())
+}
+
+async fn list_endpoints(_request: api::Request<ListEndpoints>, state: State) -> Result<Vec<EndpointSummary>, Error> {
+    let mut conn = state.db.acquire().await.map_err(Error::ListEndpoints)?;
+
+    let endpoints = Endpoint::list(conn.as_mut()).await.map_err(Error::ListEndpoints)?;
+
+    let mut summaries = Vec::with_capacity(endpoints.len());
+
+    for endpoint in endpoints {
+        let labels = endpoint.labels(conn.as_mut()).await.map_err(Error::ListEndpoints)?;
+        let account = Account::get(conn.as_mut(), endpoint.account)
+            .await
+            .map_err(Error::LoadEndpointAccount)?;
+
+        summaries.push(EndpointSummary {
+            id: endpoint.id.to_string(),
+            host_address: endpoint.host_address.to_string(),
+            role: endpoint.kind.role(),
+            status: endpoint.status.to_string(),
+            error: endpoint.error,
+            fingerprint: account.public_key.fingerprint(),
+            labels,
+        });
+    }
+
+    Ok(summaries)
+}
+
+async fn revoke_endpoint(request: api::Request<RevokeEndpoint>, state: State) -> Result<(), Error> {
+    let id = request
+        .body
+        .id
+        .parse::<endpoint::Id>()
+        .map_err(Error::InvalidEndpoint)?;
+
+    let mut tx = state.db.begin().await.map_err(Error::RevokeEndpoint)?;
+
+    let endpoint = match Endpoint::get(tx.as_mut(), id).await {
+        Ok(endpoint) => endpoint,
+        Err(database::Error::NotFound) => return Err(Error::EndpointNotFound(id)),
+        Err(e) => return Err(Error::RevokeEndpoint(e)),
+    };
+
+    endpoint.delete(&mut tx).await.map_err(Error::RevokeEndpoint)?;
+    // Keeps the service account for history, but removes the tokens we've issued it
+    Account::soft_delete(&mut tx, endpoint.account)
+        .await
+        .map_err(Error::RevokeAccount)?;
+
+    tx.commit().await.map_err(Error::RevokeEndpoint)?;
+
+    info!(
+        endpoint = %endpoint.id,
+        account = %endpoint.account,
+        url = %endpoint.host_address,
+        role = %endpoint.kind.role(),
+        "Endpoint revoked"
+    );
+
+    Ok(())
+}
+
+async fn set_endpoint_labels(request: api::Request<SetEndpointLabels>, state: State) -> Result<(), Error> {
+    let id = request
+        .body
+        .id
+        .parse::<endpoint::Id>()
+        .map_err(Error::InvalidEndpoint)?;
+
+    let mut tx = state.db.begin().await.map_err(Error::SetEndpointLabels)?;
+
+    let endpoint = match Endpoint::get(tx.as_mut(), id).await {
+        Ok(endpoint) => endpoint,
+        Err(database::Error::NotFound) => return Err(Error::EndpointNotFound(id)),
+        Err(e) => return Err(Error::SetEndpointLabels(e)),
+    };
+
+    endpoint
+        .set_labels(&mut tx, &request.body.labels)
+        .await
+        .map_err(Error::SetEndpointLabels)?;
+
+    tx.commit().await.map_err(Error::SetEndpointLabels)?;
+
+    info!(
+        endpoint = %endpoint.id,
+        labels = ?request.body.labels,
+        "Endpoint labels set"
+    );
+
+    Ok(())
+}
+
+async fn list_audit_log(_request: api::Request<ListAuditLog>, state: State) -> Result<Vec<AuditRecord>, Error> {
+    let mut conn = state.db.acquire().await.map_err(|e| Error::ListAuditLog(e.into()))?;
+
+    let entries = audit::list(conn.as_mut()).await.map_err(Error::ListAuditLog)?;
+
+    Ok(entries
+        .into_iter()
+        .map(|entry| AuditRecord {
+            id: entry.id,
+            timestamp: entry.timestamp.to_rfc3339(),
+            account: entry.record.account.map(i64::from),
+            operation: entry.record.operation,
+            decision: entry.record.decision,
+            reason: entry.record.reason,
+            source_ip: entry.source_ip.map(|ip| ip.to_string()),
+            request_id: entry.request_id,
+        })
+        .collect())
+}
+
+/// Issue a copy of the admin's token restricted to read-only operations,
+/// such as for a dashboard observing the service
+async fn issue_read_only_token(request: api::Request<IssueReadOnlyToken>, state: State) -> Result<String, Error> {
+    let mut token = request.token.ok_or(Error::MissingRequestToken)?.decoded;
+    token.payload.scope = Some(token::Scope::Read);
+
+    token
+        .refresh(&state.config.load().token)
         .sign(&state.issuer.key_pair)
         .map_err(Error::SignToken)
 }
 
+async fn set_maintenance(request: api::Request<SetMaintenance>, state: State) -> Result<(), Error> {
+    let enabled = request.body.enabled;
+
+    state.maintenance.store(enabled, Ordering::Relaxed);
+
+    info!(enabled, "Maintenance mode set");
+
+    Ok(())
+}
+
+async fn resolve_pending_enrollments(
+    request: api::Request<ResolvePendingEnrollments>,
+    state: State,
+) -> Result<Vec<ResolvedEnrollment>, Error> {
+    let action = request.body.action;
+
+    // Each enrollment is resolved independently so one failure doesn't abort the rest
+    let resolve = |id: String| {
+        let state = state.clone();
+
+        async move {
+            let result = resolve_pending(&id, action, &state).await;
+
+            if let Err(e) = &result {
+                error!(id, %action, error = %error::chain(e), "Resolving pending enrollment failed");
+            }
+
+            ResolvedEnrollment {
+                id,
+                error: result.err().map(error::chain),
+            }
+        }
+    };
+
+    Ok(future::join_all(request.body.ids.into_iter().map(resolve)).await)
+}
+
+async fn resolve_pending(id: &str, action: PendingAction, state: &State) -> Result<(), Error> {
+    let endpoint = id.parse::<endpoint::Id>().map_err(Error::InvalidEndpoint)?;
+
+    // Enrollments we've sent stay pending until the target responds
+    if let Some(sent) = state.pending_sent.get(&endpoint).await {
+        info!(
+            %endpoint,
+            public_key = %sent.target.public_key.fingerprint(),
+            url = %sent.target.host_address,
+            role = %sent.target.role,
+            %action,
+            "Resolving sent enrollment"
+        );
+
+        return match action {
+            PendingAction::Accept => Err(Error::AcceptSentEnrollment(endpoint)),
+            PendingAction::Decline => Ok(sent.cancel(&state.pending_sent).await?),
+        };
+    }
+
+    let received = state
+        .pending_received
+        .remove_where(|received| received.endpoint == endpoint)
+        .await
+        .ok_or(Error::MissingPendingEnrollment(endpoint))?;
+
+    info!(
+        %endpoint,
+        public_key = %received.remote.public_key.fingerprint(),
+        url = %received.remote.host_address,
+        role = %received.remote.role,
+        %action,
+        "Resolving received enrollment"
+    );
+
+    match action {
+        PendingAction::Accept => received.accept(&state.db, state.issuer()).await?,
+        PendingAction::Decline => received.decline().await?,
+    }
+
+    Ok(())
+}
+
 /// An error when handling an [`EndpointService`] request
-#[derive(Debug, Error)]
+#[derive(Debug, Error, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 #[allow(clippy::large_enum_variant)]
 enum Error {
     /// Required token is missing from the request
@@ -250,10 +624,10 @@ enum Error {
     /// Upstream request came from a different public key
     #[error("Upstream public key mismatch, expected: {expected} provided {provided}")]
     UpstreamMismatch {
-        /// The expected public key
-        expected: PublicKey,
-        /// The provided public key
-        provided: PublicKey,
+        /// Fingerprint of the expected public key
+        expected: String,
+        /// Fingerprint of the provided public key
+        provided: String,
     },
     /// Role on request doesn't match role of service
     #[error("Role mismatch, expected {expected:?} provided {provided:?}")]
@@ -266,6 +640,9 @@ enum Error {
     /// No pending enrollment is found for the provided endpoint ID
     #[error("Pending enrollment missing for endpoint {0}")]
     MissingPendingEnrollment(endpoint::Id),
+    /// Enrollment we sent can only be accepted by it's target
+    #[error("Enrollment sent to endpoint {0} can only be accepted by the target")]
+    AcceptSentEnrollment(endpoint::Id),
     /// Url cannot be parsed from string
     #[error("invalid uri")]
     InvalidUrl(#[from] http::uri::InvalidUri),
@@ -277,18 +654,61 @@ enum Error {
     VerifyToken(#[source] token::Error),
     #[error("sign token")]
     SignToken(#[source] token::Error),
+    /// Endpoint doesn't exist
+    #[error("endpoint {0} not found")]
+    EndpointNotFound(endpoint::Id),
+    /// Listing endpoints failed
+    #[error("list endpoints")]
+    ListEndpoints(#[source] database::Error),
+    /// Listing audit log failed
+    #[error("list audit log")]
+    ListAuditLog(#[source] audit::Error),
+    /// Loading the endpoint's service account failed
+    #[error("load endpoint account")]
+    LoadEndpointAccount(#[source] account::Error),
+    /// Revoking endpoint failed
+    #[error("revoke endpoint")]
+    RevokeEndpoint(#[source] database::Error),
+    /// Setting endpoint labels failed
+    #[error("set endpoint labels")]
+    SetEndpointLabels(#[source] database::Error),
+    /// Soft deleting the endpoint's service account failed
+    #[error("revoke endpoint account")]
+    RevokeAccount(#[source] account::Error),
     /// An enrollment error
     #[error("enrollment")]
     Enrollment(#[from] enrollment::Error),
 }
 
+impl api::ErrorCode for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Error::VerifyToken(error) | Error::SignToken(error) => api::ErrorCode::code(error),
+            Error::Enrollment(error) => api::ErrorCode::code(error),
+            _ => self.into(),
+        }
+    }
+}
+
 impl From<&Error> for http::StatusCode {
     fn from(error: &Error) -> Self {
         match error {
             Error::MissingRequestToken => http::StatusCode::UNAUTHORIZED,
-            Error::Enrollment(_) | Error::UpstreamNotSet | Error::SignToken(_) => {
-                http::StatusCode::INTERNAL_SERVER_ERROR
-            }
+            Error::Enrollment(
+                enrollment::Error::PublicKeyMismatch { .. }
+                | enrollment::Error::RoleMismatch { .. }
+                | enrollment::Error::NotPending(_),
+            ) => http::StatusCode::BAD_REQUEST,
+            Error::Enrollment(_)
+            | Error::UpstreamNotSet
+            | Error::SignToken(_)
+            | Error::ListEndpoints(_)
+            | Error::ListAuditLog(_)
+            | Error::LoadEndpointAccount(_)
+            | Error::RevokeEndpoint(_)
+            | Error::SetEndpointLabels(_)
+            | Error::RevokeAccount(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+            Error::EndpointNotFound(_) => http::StatusCode::NOT_FOUND,
             Error::InvalidPublicKey
             | Error::InvalidUrl(_)
             | Error::InvalidEndpoint(_)
@@ -296,7 +716,439 @@ impl From<&Error> for http::StatusCode {
             | Error::VerifyToken(_)
             | Error::RoleMismatch { .. }
             | Error::MissingPendingEnrollment(_)
+            | Error::AcceptSentEnrollment(_)
             | Error::UpstreamMismatch { .. } => http::StatusCode::BAD_REQUEST,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+    use axum::body::Body;
+    use chrono::Utc;
+    use http::{header, Method, Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{api::Operation, middleware, Config};
+
+    async fn setup() -> (std::path::PathBuf, crate::State, axum::Router) {
+        let root = std::env::temp_dir().join(format!("services-{}", uuid::Uuid::new_v4()));
+        let state = crate::State::load(&root).await.unwrap();
+
+        let config: Config = toml::from_str(&format!(
+            r#"
+            host_address = "http://127.0.0.1:5000"
+            description = "test"
+            # Own upstream, so it accepts enrollment requests it issues
+            upstream = "{0}"
+
+            [admin]
+            username = "admin"
+            name = "admin"
+            email = "admin@example.com"
+            public_key = "{0}"
+            "#,
+            state.key_pair.public_key().encode()
+        ))
+        .unwrap();
+
+        let router = services(
+            config.issuer(Role::Hub, state.key_pair.clone()),
+            Arc::new(ArcSwap::from_pointee(config)),
+            Arc::default(),
+            &state,
+        )
+        .into_parts()
+        .0
+        .layer(middleware::ExtractToken {
+            pub_key: state.key_pair.public_key(),
+            validation: token::Validation::new(),
+            leeway: token::Config::default().leeway(),
+        });
+
+        (root, state, router)
+    }
+
+    fn admin_token(state: &crate::State, read_only: bool) -> String {
+        let now = Utc::now();
+
+        Token::new(token::Payload {
+            aud: Role::Hub.service_name().to_string(),
+            exp: (now + chrono::Duration::hours(1)).timestamp(),
+            iat: now.timestamp(),
+            nbf: None,
+            iss: "test".to_string(),
+            sub: "admin".to_string(),
+            purpose: token::Purpose::Authentication,
+            account_id: account::Id::generate(),
+            account_type: account::Kind::Admin,
+            admin: true,
+            scope: read_only.then_some(token::Scope::Read),
+        })
+        .sign(&state.key_pair)
+        .unwrap()
+    }
+
+    fn hub(state: &crate::State) -> Issuer {
+        Issuer {
+            key_pair: state.key_pair.clone(),
+            host_address: "http://127.0.0.1:5000".parse().unwrap(),
+            role: Role::Hub,
+            description: "test".to_string(),
+            admin_name: "admin".to_string(),
+            admin_email: "admin@example.com".to_string(),
+            capabilities: None,
+            labels: endpoint::Labels::default(),
+            token: token::Config::default(),
+        }
+    }
+
+    /// Enrollment sent to a target which can't be reached
+    fn sent(hub: &Issuer, endpoint: endpoint::Id, account: account::Id) -> enrollment::Sent {
+        enrollment::Sent {
+            endpoint,
+            account,
+            target: enrollment::Target {
+                // Nothing listens here
+                host_address: "http://127.0.0.1:1".parse().unwrap(),
+                public_key: crate::crypto::KeyPair::generate().public_key(),
+                role: Role::Builder,
+            },
+            bearer_token: endpoint::create_token(
+                token::Purpose::Authorization,
+                endpoint,
+                account,
+                token::Audience::Service(Role::Hub),
+                hub,
+            )
+            .unwrap(),
+            reenroll: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn read_only_admin() {
+        let (root, state, router) = setup().await;
+        let token = |read_only| admin_token(&state, read_only);
+
+        let call = |method, path, body: &'static str, token: String| {
+            router.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(format!("/api/v1/{path}"))
+                    .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let revoke_body = r#"{"id":"8bbfd9a4-3e4b-4d1e-9c5f-1b2e8d1c1a55"}"#;
+
+        let issued = call(Method::GET, IssueReadOnlyToken::PATH, "", token(false))
+            .await
+            .unwrap();
+        assert_eq!(issued.status(), StatusCode::OK);
+        let issued = axum::body::to_bytes(issued.into_body(), usize::MAX).await.unwrap();
+        let issued: String = serde_json::from_slice(&issued).unwrap();
+        let verified = Token::verify(&issued, &state.key_pair.public_key(), &token::Validation::new()).unwrap();
+        assert!(verified.decoded.payload.is_read_only());
+
+        let list = call(Method::GET, ListEndpoints::PATH, "", issued.clone())
+            .await
+            .unwrap();
+        let revoke = call(Method::POST, RevokeEndpoint::PATH, revoke_body, issued)
+            .await
+            .unwrap();
+        // Full admin gets past auth to find the endpoint doesn't exist
+        let revoke_full = call(Method::POST, RevokeEndpoint::PATH, revoke_body, token(false))
+            .await
+            .unwrap();
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(list.status(), StatusCode::OK);
+        assert_eq!(revoke.status(), StatusCode::FORBIDDEN);
+        assert_eq!(revoke_full.status(), StatusCode::NOT_FOUND);
+
+        // Errors include a machine readable code
+        let body = axum::body::to_bytes(revoke_full.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "endpoint_not_found");
+        assert_eq!(body["status"], 404);
+
+        let body = axum::body::to_bytes(revoke.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "read_only");
+    }
+
+    #[tokio::test]
+    async fn dry_run() {
+        let (root, state, router) = setup().await;
+
+        let call = |path: String, body: &'static str, read_only| {
+            router.clone().oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("/api/v1/{path}"))
+                    .header(
+                        header::AUTHORIZATION,
+                        format!("Bearer {}", admin_token(&state, read_only)),
+                    )
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let revoke_body = r#"{"id":"8bbfd9a4-3e4b-4d1e-9c5f-1b2e8d1c1a55"}"#;
+
+        // Authorized & valid, but never reaches the handler
+        let dry_run = call(format!("{}?dry_run=true", RevokeEndpoint::PATH), revoke_body, false)
+            .await
+            .unwrap();
+        // Still subject to auth & body validation
+        let read_only = call(format!("{}?dry_run=true", RevokeEndpoint::PATH), revoke_body, true)
+            .await
+            .unwrap();
+        let invalid_body = call(format!("{}?dry_run=true", RevokeEndpoint::PATH), "{}", false)
+            .await
+            .unwrap();
+        let invalid_query = call(format!("{}?dry_run=yes", RevokeEndpoint::PATH), revoke_body, false)
+            .await
+            .unwrap();
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(dry_run.status(), StatusCode::NO_CONTENT);
+        assert_eq!(read_only.status(), StatusCode::FORBIDDEN);
+        assert_eq!(invalid_body.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(invalid_query.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn resolve_pending_batch() {
+        let (root, state, router) = setup().await;
+
+        let sent_id = endpoint::Id::generate();
+        state
+            .pending_sent
+            .insert(sent_id, sent(&hub(&state), sent_id, account::Id::generate()))
+            .await;
+
+        let resolve = |ids: &[&str], action: &str| {
+            router.clone().oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(format!("/api/v1/{}", ResolvePendingEnrollments::PATH))
+                    .header(header::AUTHORIZATION, format!("Bearer {}", admin_token(&state, false)))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::json!({ "ids": ids, "action": action }).to_string(),
+                    ))
+                    .unwrap(),
+            )
+        };
+        let results = |resp: axum::response::Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<Vec<ResolvedEnrollment>>(&body).unwrap()
+        };
+
+        let sent_id = sent_id.to_string();
+
+        let accept = resolve(&["invalid", "8bbfd9a4-3e4b-4d1e-9c5f-1b2e8d1c1a55", &sent_id], "accept")
+            .await
+            .unwrap();
+        let still_pending = state.pending_sent.get(&sent_id.parse().unwrap()).await.is_some();
+        let decline = resolve(&[&sent_id], "decline").await.unwrap();
+        let cancelled = state.pending_sent.get(&sent_id.parse().unwrap()).await.is_none();
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        // Failures are reported per id rather than failing the batch
+        assert_eq!(accept.status(), StatusCode::OK);
+
+        let results = results(accept).await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].id, "invalid");
+        assert!(results[0]
+            .error
+            .as_ref()
+            .is_some_and(|e| e.starts_with("invalid endpoint")));
+        assert!(results[1]
+            .error
+            .as_ref()
+            .is_some_and(|e| e.starts_with("Pending enrollment missing")));
+        // Only the target can accept an enrollment we sent
+        assert!(results[2]
+            .error
+            .as_ref()
+            .is_some_and(|e| e.contains("can only be accepted by the target")));
+        assert!(still_pending);
+
+        // Declining cancels it, even though the target can't be notified
+        assert_eq!(decline.status(), StatusCode::OK);
+        assert!(cancelled);
+    }
+
+    #[tokio::test]
+    async fn cancel_enrollment() {
+        let (root, state, router) = setup().await;
+
+        let hub = hub(&state);
+        let endpoint = endpoint::Id::generate();
+        let account = account::Id::generate();
+
+        let token = |purpose, audience| {
+            endpoint::create_token(purpose, endpoint, account, audience, &hub)
+                .unwrap()
+                .encoded
+        };
+        let call = |path: &str, body: String, token: Option<String>| {
+            let mut request = Request::builder()
+                .method(Method::POST)
+                .uri(format!("/api/v1/{path}"))
+                .header(header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            router.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+        let cancel = |issue_token: String| {
+            call(
+                CancelEnrollment::PATH,
+                serde_json::to_string(&CancelEnrollmentBody { issue_token }).unwrap(),
+                None,
+            )
+        };
+
+        let valid = cancel(token(
+            token::Purpose::Authorization,
+            token::Audience::Service(Role::Hub),
+        ))
+        .await
+        .unwrap();
+        let access_token = cancel(token(
+            token::Purpose::Authentication,
+            token::Audience::Service(Role::Hub),
+        ))
+        .await
+        .unwrap();
+        let other_audience = cancel(token(
+            token::Purpose::Authorization,
+            token::Audience::Service(Role::Builder),
+        ))
+        .await
+        .unwrap();
+
+        // Admin cancels an enrollment it sent, which is no longer pending
+        // even though the target can't be notified
+        state.pending_sent.insert(endpoint, sent(&hub, endpoint, account)).await;
+
+        let body = format!(r#"{{"id":"{endpoint}"}}"#);
+        let cancel_sent = call(
+            CancelSentEnrollment::PATH,
+            body.clone(),
+            Some(admin_token(&state, false)),
+        )
+        .await
+        .unwrap();
+        let cancelled = state.pending_sent.get(&endpoint).await.is_none();
+        let again = call(CancelSentEnrollment::PATH, body, Some(admin_token(&state, false)))
+            .await
+            .unwrap();
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        let code = |resp: axum::response::Response| async move {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["code"].clone()
+        };
+
+        assert!(valid.status().is_success());
+        assert_eq!(access_token.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(code(access_token).await, "require_bearer_token");
+        assert_eq!(other_audience.status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(cancel_sent.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(cancelled);
+        assert_eq!(again.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(code(again).await, "missing_pending_enrollment");
+    }
+
+    #[test]
+    fn innermost_error_code() {
+        let code = |error: Error| api::ErrorCode::code(&error);
+
+        assert_eq!(
+            code(Error::Enrollment(enrollment::Error::RoleMismatch {
+                expected: Role::Builder,
+                actual: Role::Hub,
+            })),
+            "role_mismatch"
+        );
+        assert_eq!(
+            code(Error::Enrollment(enrollment::Error::SignToken(
+                token::Error::NotYetValid
+            ))),
+            "not_yet_valid"
+        );
+        assert_eq!(
+            code(Error::VerifyToken(token::Error::InvalidSignature)),
+            "invalid_signature"
+        );
+        assert_eq!(code(Error::UpstreamNotSet), "upstream_not_set");
+    }
+
+    #[tokio::test]
+    async fn operation_token() {
+        let (root, state, router) = setup().await;
+
+        let bearer_token = endpoint::create_token(
+            token::Purpose::Authorization,
+            endpoint::Id::generate(),
+            account::Id::generate(),
+            token::Audience::Service(Role::Hub),
+            &hub(&state),
+        )
+        .unwrap()
+        .encoded;
+
+        let call = |method, path: &str, body: String, token: &str| {
+            router.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(format!("/api/v1/{path}"))
+                    .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let issue = |path: &str| {
+            let body = serde_json::to_string(&IssueOperationTokenBody { path: path.to_string() }).unwrap();
+            let call = call(Method::POST, IssueOperationToken::PATH, body, &bearer_token);
+
+            async move {
+                let resp = call.await.unwrap();
+                assert_eq!(resp.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<String>(&body).unwrap()
+            }
+        };
+
+        let scoped = issue(Ping::PATH).await;
+        let other = issue(ListAuditLog::PATH).await;
+
+        let ping = call(Method::GET, Ping::PATH, String::new(), &scoped).await.unwrap();
+        let ping_other = call(Method::GET, Ping::PATH, String::new(), &other).await.unwrap();
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(ping.status(), StatusCode::OK);
+        assert_eq!(ping_other.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,189 @@
+//! Write files atomically so concurrent readers never observe
+//! partially written or corrupt contents
+//!
+//! Contents are written to a temporary file alongside the destination
+//! which is synced to disk and then renamed over the destination.
+use std::{
+    fs::{self, File},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::collectable;
+
+/// File extension of the checksum sidecar written via [`AtomicFile::with_checksum`]
+pub const CHECKSUM_EXTENSION: &str = "sha256";
+
+/// Atomically write `contents` to `path`, replacing any existing file
+pub fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(contents.as_ref())?;
+    file.commit()
+}
+
+/// A file which is only visible at it's destination once [`AtomicFile::commit`]
+/// succeeds. If dropped before then, the destination is left untouched.
+#[derive(Debug)]
+pub struct AtomicFile {
+    file: File,
+    path: PathBuf,
+    temp_path: PathBuf,
+    checksum: bool,
+}
+
+impl AtomicFile {
+    /// Create a temporary file which will replace `path` once committed
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let temp_path = temp_path(&path)?;
+
+        let file = File::create(&temp_path)?;
+
+        Ok(Self {
+            file,
+            path,
+            temp_path,
+            checksum: false,
+        })
+    }
+
+    /// Also write a sidecar containing the hex encoded sha256sum of the
+    /// contents, at the destination path w/ [`CHECKSUM_EXTENSION`] appended
+    ///
+    /// The sidecar is replaced after the destination, so a reader can briefly
+    /// observe the prior checksum alongside new contents
+    pub fn with_checksum(mut self) -> Self {
+        self.checksum = true;
+        self
+    }
+
+    /// Sync the written contents to disk and move them to the destination
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()?;
+
+        let checksum = self
+            .checksum
+            .then(|| collectable::sha256sum(&self.temp_path))
+            .transpose()?;
+
+        fs::rename(&self.temp_path, &self.path)?;
+        sync_parent(&self.path)?;
+
+        if let Some(checksum) = checksum {
+            write(checksum_path(&self.path), checksum)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // No-op once committed since it's been renamed
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+/// Path of the checksum sidecar for the file at `path`
+pub fn checksum_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".");
+    path.push(CHECKSUM_EXTENSION);
+    path.into()
+}
+
+/// Temp file in the same directory as `path` so it can be atomically renamed
+fn temp_path(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", uuid::Uuid::new_v4()));
+
+    Ok(path.with_file_name(temp_name))
+}
+
+/// Persist the rename by syncing the parent directory
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("atomic-file-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn never_partial() {
+        let dir = temp_dir();
+        let path = dir.join("index");
+
+        write(&path, b"prior").unwrap();
+
+        let mut file = AtomicFile::create(&path).unwrap().with_checksum();
+        file.write_all(b"partial").unwrap();
+        file.flush().unwrap();
+
+        // Readers see the prior contents while writing
+        assert_eq!(fs::read(&path).unwrap(), b"prior");
+
+        file.write_all(b" and complete").unwrap();
+        file.commit().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"partial and complete");
+        assert_eq!(
+            fs::read_to_string(checksum_path(&path)).unwrap(),
+            collectable::sha256sum(&path).unwrap()
+        );
+        // Only the file & it's checksum remain
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted() {
+        let dir = temp_dir();
+        let path = dir.join("index");
+
+        write(&path, b"prior").unwrap();
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"interrupted").unwrap();
+        // Dropped before commit
+        drop(file);
+
+        assert_eq!(fs::read(&path).unwrap(), b"prior");
+        // Temp file is cleaned up
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+//! Durable trail of authentication, enrollment & authorization decisions
+//!
+//! Unlike tracing, [`Record`]s are persisted to the `audit_log` table so they
+//! can be reviewed later via [`ListAuditLog`]. Recording is best-effort: records
+//! are written in the background so they never block or fail the request
+//! they're made for.
+//!
+//! [`ListAuditLog`]: crate::api::v1::services::ListAuditLog
+use std::{convert::Infallible, future::Future, net::IpAddr, time::Duration};
+
+use chrono::{DateTime, Utc};
+use sqlx::FromRow;
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::{account, database, error, request_id::RequestId, Database};
+
+pub use service_core::api::v1::services::AuditDecision as Decision;
+
+/// Maximum number of entries returned by [`list`]
+pub const LIST_LIMIT: u32 = 1000;
+
+/// How long entries are kept before being deleted by [`sweep`]
+pub const RETENTION: chrono::TimeDelta = chrono::TimeDelta::days(90);
+
+/// How often entries older than [`RETENTION`] are deleted
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+/// Where records made while handling a request are written
+#[derive(Debug, Clone)]
+pub(crate) struct Context {
+    /// Database records are written to
+    pub db: Database,
+    /// IP address of the peer which sent the request, if known
+    pub source_ip: Option<IpAddr>,
+}
+
+impl Context {
+    /// Run `future` with records it makes written using this context
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CONTEXT.scope(self, future).await
+    }
+
+    /// Call `f` with records it makes written using this context, such as
+    /// those made synchronously while calling an inner service
+    pub fn sync_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        CONTEXT.sync_scope(self, f)
+    }
+}
+
+/// An auth decision to be recorded
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// Account the decision was made for, if known
+    pub account: Option<account::Id>,
+    /// Operation or path the decision was made for
+    pub operation: String,
+    /// Whether access was allowed or denied
+    pub decision: Decision,
+    /// Why the decision was made, such as the denial error code
+    pub reason: Option<String>,
+}
+
+impl Record {
+    /// Access to `operation` was allowed
+    pub fn allowed(operation: impl ToString) -> Self {
+        Self {
+            account: None,
+            operation: operation.to_string(),
+            decision: Decision::Allowed,
+            reason: None,
+        }
+    }
+
+    /// Access to `operation` was denied due to `reason`
+    pub fn denied(operation: impl ToString, reason: impl ToString) -> Self {
+        Self {
+            account: None,
+            operation: operation.to_string(),
+            decision: Decision::Denied,
+            reason: Some(reason.to_string()),
+        }
+    }
+
+    /// Set the account the decision was made for
+    pub fn with_account(self, account: account::Id) -> Self {
+        Self {
+            account: Some(account),
+            ..self
+        }
+    }
+
+    /// Set why the decision was made
+    pub fn with_reason(self, reason: impl ToString) -> Self {
+        Self {
+            reason: Some(reason.to_string()),
+            ..self
+        }
+    }
+}
+
+/// Record `record` for the request currently being handled.
+///
+/// This is a no-op outside of a request handled by the [`Server`](crate::Server).
+pub fn record(record: Record) {
+    let Ok(context) = CONTEXT.try_with(Clone::clone) else {
+        return;
+    };
+
+    let timestamp = Utc::now();
+    let request_id = RequestId::current();
+
+    tokio::spawn(async move {
+        if let Err(e) = insert(&context.db, &record, timestamp, context.source_ip, request_id).await {
+            warn!(
+                error = %error::chain(e),
+                operation = record.operation,
+                decision = %record.decision,
+                "Failed to write audit record"
+            );
+        }
+    });
+}
+
+async fn insert(
+    db: &Database,
+    record: &Record,
+    timestamp: DateTime<Utc>,
+    source_ip: Option<IpAddr>,
+    request_id: Option<RequestId>,
+) -> Result<(), Error> {
+    let mut conn = db.acquire().await?;
+
+    sqlx::query(
+        "
+        INSERT INTO audit_log
+        (
+          timestamp,
+          account_id,
+          operation,
+          decision,
+          reason,
+          source_ip,
+          request_id
+        )
+        VALUES (?,?,?,?,?,?,?);
+        ",
+    )
+    .bind(timestamp)
+    .bind(record.account.map(i64::from))
+    .bind(&record.operation)
+    .bind(record.decision.to_string())
+    .bind(&record.reason)
+    .bind(source_ip.map(|ip| ip.to_string()))
+    .bind(request_id.map(|id| id.to_string()))
+    .execute(conn.as_mut())
+    .await?;
+
+    Ok(())
+}
+
+/// Delete entries recorded before `cutoff`, returning how many were deleted
+pub async fn delete_before(tx: &mut database::Transaction, cutoff: DateTime<Utc>) -> Result<u64, Error> {
+    let result = sqlx::query(
+        "
+        DELETE FROM audit_log
+        WHERE timestamp < ?;
+        ",
+    )
+    .bind(cutoff)
+    .execute(tx.as_mut())
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Periodically delete entries older than [`RETENTION`] so the
+/// audit log doesn't grow without bound
+pub(crate) async fn sweep(db: Database) -> Result<(), Infallible> {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let result = async {
+            let mut tx = db.begin().await?;
+            let deleted = delete_before(&mut tx, Utc::now() - RETENTION).await?;
+            tx.commit().await?;
+
+            Ok::<_, Error>(deleted)
+        }
+        .await;
+
+        match result {
+            Ok(0) => {}
+            Ok(deleted) => info!(deleted, "Deleted expired audit log entries"),
+            Err(e) => error!(error = %error::chain(e), "Failed to delete expired audit log entries"),
+        }
+    }
+}
+
+/// A persisted [`Record`]
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Sequential id of the entry
+    pub id: i64,
+    /// When the decision was made
+    pub timestamp: DateTime<Utc>,
+    /// The recorded decision
+    pub record: Record,
+    /// IP address of the peer which sent the request, if known
+    pub source_ip: Option<IpAddr>,
+    /// Id of the request the decision was made for
+    pub request_id: Option<String>,
+}
+
+/// List the most recent entries, newest first, up to [`LIST_LIMIT`]
+pub async fn list<'a, T>(conn: &'a mut T) -> Result<Vec<Entry>, Error>
+where
+    &'a mut T: database::Executor<'a>,
+{
+    let rows: Vec<Row> = sqlx::query_as(
+        "
+        SELECT
+          audit_id,
+          timestamp,
+          account_id,
+          operation,
+          decision,
+          reason,
+          source_ip,
+          request_id
+        FROM audit_log
+        ORDER BY audit_id DESC
+        LIMIT ?;
+        ",
+    )
+    .bind(LIST_LIMIT)
+    .fetch_all(conn)
+    .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            Ok(Entry {
+                id: row.audit_id,
+                timestamp: row.timestamp,
+                record: Record {
+                    account: row.account_id.map(account::Id::from),
+                    decision: row.decision.parse().map_err(|_| Error::UnknownDecision(row.decision))?,
+                    operation: row.operation,
+                    reason: row.reason,
+                },
+                source_ip: row.source_ip.and_then(|ip| ip.parse().ok()),
+                request_id: row.request_id,
+            })
+        })
+        .collect()
+}
+
+#[derive(FromRow)]
+struct Row {
+    audit_id: i64,
+    timestamp: DateTime<Utc>,
+    account_id: Option<i64>,
+    operation: String,
+    decision: String,
+    reason: Option<String>,
+    source_ip: Option<String>,
+    request_id: Option<String>,
+}
+
+/// An audit error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Database error occurred
+    #[error("database")]
+    Database(#[from] database::Error),
+    /// Stored decision is unknown
+    #[error("unknown decision {0:?}")]
+    UnknownDecision(String),
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        Error::Database(error.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn record_in_context() {
+        let db = database::test::temp().await;
+
+        // Outside a request nothing is recorded
+        record(Record::allowed("services/ping"));
+
+        let context = Context {
+            db: Database::clone(&db),
+            source_ip: Some("192.0.2.1".parse().unwrap()),
+        };
+
+        context
+            .scope(async {
+                record(Record::denied("services/endpoints", "permission_denied").with_account(account::Id::from(7)));
+            })
+            .await;
+
+        // Written in the background
+        let mut entries = vec![];
+        for _ in 0..50 {
+            entries = list(db.acquire().await.unwrap().as_mut()).await.unwrap();
+            if !entries.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.record.operation, "services/endpoints");
+        assert_eq!(entry.record.decision, Decision::Denied);
+        assert_eq!(entry.record.reason.as_deref(), Some("permission_denied"));
+        assert_eq!(entry.record.account, Some(account::Id::from(7)));
+        assert_eq!(entry.source_ip, Some("192.0.2.1".parse().unwrap()));
+
+        let mut tx = db.begin().await.unwrap();
+        assert_eq!(delete_before(&mut tx, entry.timestamp).await.unwrap(), 0);
+        assert_eq!(
+            delete_before(&mut tx, entry.timestamp + chrono::TimeDelta::seconds(1))
+                .await
+                .unwrap(),
+            1
+        );
+        tx.commit().await.unwrap();
+    }
+}
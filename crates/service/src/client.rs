@@ -1,35 +1,221 @@
 //! Make requests to service APIs
-use std::{any, convert::Infallible, sync::LazyLock, time::Duration};
+use std::{
+    any,
+    collections::HashSet,
+    convert::Infallible,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, OnceLock},
+    time::Duration,
+};
 
+use axum::body::Bytes;
+use futures_util::{stream, Stream, StreamExt};
 use http::Uri;
+use serde::{de::DeserializeOwned, Deserialize};
 use service_core::auth;
 use thiserror::Error;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+pub use self::dns::ResolveError;
 
 use crate::{
     account, api,
     crypto::{self, PublicKey},
-    database, endpoint,
+    database,
+    deadline::{self, Deadline},
+    endpoint,
+    request_id::{self, RequestId},
     token::{self, VerifiedToken},
-    Account, Database, Endpoint, Token,
+    Account, Database, Endpoint, Role, Token,
 };
 
-static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
-    reqwest::ClientBuilder::new()
+mod dns;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Connections shared by all clients, w/ the configured settings
+fn shared() -> &'static reqwest::Client {
+    CLIENT.get_or_init(|| {
+        builder(CONFIG.get_or_init(Config::default))
+            .and_then(|builder| builder.build().map_err(MtlsError::Build))
+            .expect("build reqwest client")
+    })
+}
+
+/// Builder of reqwest clients w/ the provided connection settings
+fn builder(config: &Config) -> Result<reqwest::ClientBuilder, MtlsError> {
+    let builder = reqwest::ClientBuilder::new()
+        // Same TLS implementation as the server, even if dependencies enable others
+        .use_rustls_tls()
+        // Keeps the resolver's error code, so temporary failures are retried
+        .dns_resolver(Arc::new(dns::Resolver))
         .referer(false)
         // TODO: What should this be?
         .user_agent(concat!("serpentos-infra-client", "/", env!("CARGO_PKG_VERSION")))
-        .build()
-        .expect("build reqwest client")
-});
+        .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs))
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .tcp_keepalive(Duration::from_secs(config.tcp_keepalive_secs))
+        .http2_keep_alive_interval(Duration::from_secs(config.tcp_keepalive_secs))
+        .http2_keep_alive_while_idle(true);
+
+    let builder = if config.http2_prior_knowledge {
+        builder.http2_prior_knowledge()
+    } else {
+        builder
+    };
+
+    match &config.mtls {
+        Some(mtls) => mtls.apply(builder),
+        None => Ok(builder),
+    }
+}
+
+/// Connection settings shared by all clients
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Config {
+    /// How long an idle pooled connection is kept open, in seconds
+    #[serde(default = "default_pool_idle_timeout")]
+    pub pool_idle_timeout_secs: u64,
+    /// Maximum idle pooled connections kept open per host
+    #[serde(default = "default_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    /// Interval of TCP & HTTP/2 keep-alive probes on open connections, in seconds
+    #[serde(default = "default_tcp_keepalive")]
+    pub tcp_keepalive_secs: u64,
+    /// Speak HTTP/2 to plain HTTP services without first negotiating it
+    ///
+    /// HTTP/2 is always negotiated over TLS. Only enable if there's no proxy
+    /// in front of services which only speaks HTTP/1
+    #[serde(default)]
+    pub http2_prior_knowledge: bool,
+    /// Authenticate to services w/ a client certificate, required by those
+    /// which enable client auth via [`Server::with_client_auth`]
+    ///
+    /// [`Server::with_client_auth`]: crate::Server::with_client_auth
+    #[serde(default)]
+    pub mtls: Option<Mtls>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            pool_idle_timeout_secs: default_pool_idle_timeout(),
+            pool_max_idle_per_host: default_pool_max_idle_per_host(),
+            tcp_keepalive_secs: default_tcp_keepalive(),
+            http2_prior_knowledge: false,
+            mtls: None,
+        }
+    }
+}
+
+/// Client certificate presented to services (mutual TLS)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Mtls {
+    /// Path of the PEM encoded client certificate
+    pub cert_path: PathBuf,
+    /// Path of the PEM encoded PKCS #8 private key of the client certificate
+    pub key_path: PathBuf,
+    /// Path of the PEM encoded CA certificate(s) which issue service certificates,
+    /// the only ones trusted when set
+    pub ca_path: PathBuf,
+}
+
+impl Mtls {
+    fn apply(&self, builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder, MtlsError> {
+        let read = |path: &Path| std::fs::read(path).map_err(|e| MtlsError::Read(path.to_path_buf(), e));
+
+        // Identity is parsed from the private key followed by it's certificate
+        let mut identity = read(&self.key_path)?;
+        identity.push(b'\n');
+        identity.extend(read(&self.cert_path)?);
+
+        let identity = reqwest::Identity::from_pem(&identity).map_err(MtlsError::Identity)?;
+        let roots = reqwest::Certificate::from_pem_bundle(&read(&self.ca_path)?).map_err(MtlsError::Ca)?;
+
+        Ok(roots
+            .into_iter()
+            .fold(builder, |builder, root| builder.add_root_certificate(root))
+            .tls_built_in_root_certs(false)
+            .identity(identity))
+    }
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    90
+}
+
+fn default_pool_max_idle_per_host() -> usize {
+    32
+}
+
+fn default_tcp_keepalive() -> u64 {
+    60
+}
+
+/// Configure connection settings of all clients. Must be called before the first
+/// request is sent, otherwise it has no effect and a warning is logged.
+///
+/// Fails if the [`Config::mtls`] credentials can't be loaded.
+pub fn configure(config: Config) -> Result<(), MtlsError> {
+    let client = builder(&config)?.build().map_err(MtlsError::Build)?;
+
+    if CONFIG.get_or_init(|| config.clone()) != &config || CLIENT.set(client).is_err() {
+        warn!("Client already configured, connection settings can't be changed without a restart");
+    }
+
+    Ok(())
+}
 
 const TOKEN_VALIDITY: Duration = Duration::from_secs(15 * 60);
 
+/// Request bodies larger than this are gzip compressed, if the service accepts them
+const COMPRESSION_THRESHOLD: usize = 8 * 1024;
+
+/// Services which advertised they accept gzip compressed request bodies via the
+/// `Accept-Encoding` response header (RFC 7694), by authority. Updated from every
+/// response, so requests are only compressed once the service is known to accept them.
+static ACCEPTS_GZIP: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Mutex::default);
+
+/// Services which advertised they accept MessagePack operation bodies via the
+/// [`api::encoding::HEADER`] response header, by authority. Updated from every
+/// response, so bodies are JSON until the service is known to accept MessagePack.
+static ACCEPTS_MESSAGEPACK: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Mutex::default);
+
+/// Returns true if gzip is listed in the `Accept-Encoding` header, unless
+/// it's weight is `q=0` which marks it as not acceptable (RFC 9110)
+fn accepts_gzip(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+
+            // Malformed weights are ignored rather than rejecting the coding
+            let weight = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+                .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            name.eq_ignore_ascii_case("gzip") && weight > 0.0
+        })
+}
+
 /// A service client
 #[derive(Clone)]
 pub struct Client<A = NoAuth> {
     host_address: Uri,
     auth_storage: A,
+    /// Dedicated connections, otherwise connections are shared w/ all clients
+    http: Option<reqwest::Client>,
+    /// How long to wait for each response
+    timeout: Option<Duration>,
 }
 
 impl Client {
@@ -38,6 +224,8 @@ impl Client {
         Self {
             host_address,
             auth_storage: NoAuth,
+            http: None,
+            timeout: None,
         }
     }
 }
@@ -52,6 +240,8 @@ where
         Client {
             auth_storage: storage,
             host_address: self.host_address,
+            http: self.http,
+            timeout: self.timeout,
         }
     }
 
@@ -60,26 +250,134 @@ where
         Client {
             auth_storage: TokensAuth(tokens),
             host_address: self.host_address,
+            http: self.http,
+            timeout: self.timeout,
         }
     }
 
     /// Use [`EndpointAuth`] with this client
-    pub fn with_endpoint_auth(self, endpoint: endpoint::Id, db: Database) -> Client<EndpointAuth> {
+    pub fn with_endpoint_auth(self, endpoint: &Endpoint, db: Database) -> Client<EndpointAuth> {
         Client {
-            auth_storage: EndpointAuth { endpoint, db },
+            auth_storage: EndpointAuth::new(endpoint, db),
             host_address: self.host_address,
+            http: self.http,
+            timeout: self.timeout,
+        }
+    }
+
+    /// Give up waiting for each response after `timeout`, which is sent to
+    /// the service as a [`Deadline`] so it can stop handling the request
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
         }
     }
 
+    /// Authenticate to the service w/ the provided client certificate, rather
+    /// than any configured via [`Config::mtls`].
+    ///
+    /// Requests are made over dedicated connections, rather than those shared by
+    /// all other clients.
+    pub fn with_mtls(self, mtls: &Mtls) -> Result<Self, MtlsError> {
+        let config = Config {
+            mtls: Some(mtls.clone()),
+            ..CONFIG.get_or_init(Config::default).clone()
+        };
+
+        let http = builder(&config)?.build().map_err(MtlsError::Build)?;
+
+        Ok(Self {
+            http: Some(http),
+            ..self
+        })
+    }
+
     /// Send a request to an [`api::Operation`]
     #[tracing::instrument(
         skip_all,
         fields(
             url = %self.host_address,
             path = O::PATH,
+            endpoint_id = tracing::field::Empty,
+            role = tracing::field::Empty,
         )
     )]
     pub async fn send<O>(&self, body: &O::RequestBody) -> Result<O::ResponseBody, Error<A::Error>>
+    where
+        O: api::Operation + 'static,
+    {
+        self.record_endpoint();
+
+        let token = self.token::<O>().await?;
+
+        self.raw_send::<O, _>(body, token.as_deref()).await
+    }
+
+    /// Validate a request to an [`api::Operation`] would be accepted, without
+    /// the service mutating any state. Any response body is discarded.
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            url = %self.host_address,
+            path = O::PATH,
+            endpoint_id = tracing::field::Empty,
+            role = tracing::field::Empty,
+        )
+    )]
+    pub async fn dry_run<O>(&self, body: &O::RequestBody) -> Result<(), Error<A::Error>>
+    where
+        O: api::Operation + 'static,
+    {
+        self.record_endpoint();
+
+        let token = self.token::<O>().await?;
+
+        self.raw_request::<O, _>(body, token.as_deref(), None, true).await?;
+
+        Ok(())
+    }
+
+    /// Send a request to a [`api::Streaming`] operation, returning a stream of
+    /// response items which are decoded as they're received
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            url = %self.host_address,
+            path = O::PATH,
+            endpoint_id = tracing::field::Empty,
+            role = tracing::field::Empty,
+        )
+    )]
+    pub async fn stream<O>(
+        &self,
+        body: &O::RequestBody,
+    ) -> Result<impl Stream<Item = Result<O::Item, Error<A::Error>>> + Send + 'static, Error<A::Error>>
+    where
+        O: api::Streaming + 'static,
+    {
+        self.record_endpoint();
+
+        let token = self.token::<O>().await?;
+
+        let resp = self
+            .raw_request::<O, _>(body, token.as_deref(), Some(api::encoding::NDJSON), false)
+            .await?;
+
+        Ok(ndjson(resp.bytes_stream()))
+    }
+
+    /// Attribute the request being sent to the endpoint, if auth is for one
+    fn record_endpoint(&self) {
+        if let Some((id, role)) = self.auth_storage.endpoint() {
+            let span = tracing::Span::current();
+            span.record("endpoint_id", tracing::field::display(id));
+            span.record("role", tracing::field::display(role));
+        }
+    }
+
+    /// Token to authenticate a request to the operation with, if required
+    async fn token<O>(&self) -> Result<Option<String>, Error<A::Error>>
     where
         O: api::Operation + 'static,
     {
@@ -90,10 +388,11 @@ where
             let mut tokens = self.auth_storage.tokens().await.map_err(Error::AuthStorage)?;
 
             // If storage supports persisting refresh tokens, ensure they're refreshed
+            // No leeway so tokens are refreshed before the issuer considers them expired
             if A::REFRESH_ENABLED {
                 let bearer_token = tokens.bearer_token.clone().ok_or(Error::MissingBearerToken)?;
 
-                if bearer_token.decoded.is_expired_in(TOKEN_VALIDITY) {
+                if bearer_token.decoded.is_expired_in(TOKEN_VALIDITY, Duration::ZERO) {
                     tokens = self
                         .refresh_token(token::Purpose::Authorization, &bearer_token.encoded)
                         .await?;
@@ -102,7 +401,7 @@ where
                     || tokens
                         .access_token
                         .as_ref()
-                        .is_some_and(|token| token.decoded.is_expired_in(TOKEN_VALIDITY))
+                        .is_some_and(|token| token.decoded.is_expired_in(TOKEN_VALIDITY, Duration::ZERO))
                 {
                     tokens = self
                         .refresh_token(token::Purpose::Authentication, &bearer_token.encoded)
@@ -128,42 +427,150 @@ where
             });
         }
 
-        Ok(self.raw_send::<O>(body, token.as_deref()).await?)
+        Ok(token)
+    }
+
+    fn authority(&self) -> String {
+        self.host_address
+            .authority()
+            .map(ToString::to_string)
+            .unwrap_or_default()
+    }
+
+    /// Encoding of bodies sent to this service, MessagePack once it's advertised
+    /// accepting it, see [`ACCEPTS_MESSAGEPACK`]
+    fn encoding(&self) -> api::Encoding {
+        if ACCEPTS_MESSAGEPACK
+            .lock()
+            .expect("not poisoned")
+            .contains(&self.authority())
+        {
+            api::Encoding::MessagePack
+        } else {
+            api::Encoding::Json
+        }
+    }
+
+    async fn raw_send<O, E>(&self, body: &O::RequestBody, token: Option<&str>) -> Result<O::ResponseBody, Error<E>>
+    where
+        O: api::Operation + 'static,
+        E: std::error::Error,
+    {
+        let encoding = self.encoding();
+        let accept = (encoding != api::Encoding::Json).then(|| encoding.content_type());
+
+        let resp = self.raw_request::<O, E>(body, token, accept, false).await?;
+
+        // Support empty body into ()
+        if any::TypeId::of::<O::ResponseBody>() == any::TypeId::of::<()>() {
+            Ok(serde_json::from_slice(b"null").expect("null is ()"))
+        } else {
+            // Services fallback to JSON if they don't support the requested encoding
+            let encoding = api::Encoding::from_content_type(resp.headers());
+            let bytes = resp.bytes().await?;
+
+            Ok(encoding.decode(&bytes)?)
+        }
     }
 
-    async fn raw_send<O>(&self, body: &O::RequestBody, token: Option<&str>) -> Result<O::ResponseBody, reqwest::Error>
+    /// Send the request, returning the response if successful
+    async fn raw_request<O, E>(
+        &self,
+        body: &O::RequestBody,
+        token: Option<&str>,
+        accept: Option<&str>,
+        dry_run: bool,
+    ) -> Result<reqwest::Response, Error<E>>
     where
         O: api::Operation + 'static,
+        E: std::error::Error,
     {
-        let mut request = CLIENT.request(
+        let http = self.http.as_ref().unwrap_or_else(|| shared());
+
+        let mut request = http.request(
             O::METHOD,
             format!("{}api/{}/{}", self.host_address, O::VERSION, O::PATH),
         );
 
+        if dry_run {
+            request = request.query(&[("dry_run", "true")]);
+        }
+
         if let Some(token) = token {
             request = request.bearer_auth(token);
         }
 
+        if let Some(accept) = accept {
+            request = request.header(http::header::ACCEPT, accept);
+        }
+
+        // Correlate w/ the request being handled, otherwise this starts a new one
+        let request_id = RequestId::current().unwrap_or_else(RequestId::generate);
+        request = request.header(request_id::HEADER, request_id.to_header_value());
+
+        // Never outlive the deadline of the request being handled
+        let deadline = self
+            .timeout
+            .map(Deadline::after)
+            .into_iter()
+            .chain(Deadline::current())
+            .min();
+
+        if let Some(deadline) = deadline {
+            request = request
+                .timeout(deadline.remaining())
+                .header(deadline::HEADER, deadline.to_header_value());
+        }
+
+        let authority = self.authority();
+        let compression = ACCEPTS_GZIP.lock().expect("not poisoned").contains(&authority);
+
         // Send () as empty body
         if any::TypeId::of::<O::RequestBody>() == any::TypeId::of::<()>() {
             request = request.body(reqwest::Body::default());
         } else {
-            request = request.json(body);
+            let encoding = self.encoding();
+            let bytes = encoding.encode(body)?;
+
+            request = request.header(http::header::CONTENT_TYPE, encoding.content_type());
+
+            if compression && bytes.len() > COMPRESSION_THRESHOLD {
+                request = request
+                    .header(http::header::CONTENT_ENCODING, "gzip")
+                    .body(gzip(&bytes).map_err(Error::Compress)?);
+            } else {
+                request = request.body(bytes);
+            }
+        }
+
+        let resp = http.execute(request.build()?).await?;
+
+        {
+            let mut accepts = ACCEPTS_GZIP.lock().expect("not poisoned");
+            if accepts_gzip(resp.headers()) {
+                accepts.insert(authority.clone());
+            } else {
+                accepts.remove(&authority);
+            }
+        }
+
+        {
+            let mut accepts = ACCEPTS_MESSAGEPACK.lock().expect("not poisoned");
+            if api::Encoding::from_advertised(resp.headers()) == api::Encoding::MessagePack {
+                accepts.insert(authority);
+            } else {
+                accepts.remove(&authority);
+            }
         }
 
-        let resp = CLIENT.execute(request.build()?).await?;
+        let status = resp.status();
 
-        if let Err(e) = resp.error_for_status_ref() {
-            let status = resp.status();
+        if status.is_client_error() || status.is_server_error() {
             let body = resp.text().await?;
             error!(response = body, %status, "Request error");
-            Err(e)
-        }
-        // Support empty body into ()
-        else if any::TypeId::of::<O::ResponseBody>() == any::TypeId::of::<()>() {
-            Ok(serde_json::from_slice(b"null").expect("null is ()"))
+            Err(Error::Response(ResponseError::new(status, &body)))
         } else {
-            resp.json::<O::ResponseBody>().await
+            Ok(resp)
         }
     }
 
@@ -177,11 +584,11 @@ where
     async fn refresh_token(&self, purpose: token::Purpose, bearer: &str) -> Result<Tokens, Error<A::Error>> {
         let resp = match purpose {
             token::Purpose::Authorization => {
-                self.raw_send::<api::v1::services::RefreshIssueToken>(&(), Some(bearer))
+                self.raw_send::<api::v1::services::RefreshIssueToken, _>(&(), Some(bearer))
                     .await
             }
             token::Purpose::Authentication => {
-                self.raw_send::<api::v1::services::RefreshToken>(&(), Some(bearer))
+                self.raw_send::<api::v1::services::RefreshToken, _>(&(), Some(bearer))
                     .await
             }
         };
@@ -192,7 +599,7 @@ where
                 .token_refreshed(purpose, &token)
                 .await
                 .map_err(Error::AuthStorage),
-            Err(e) => {
+            Err(Error::Reqwest(e)) => {
                 self.auth_storage
                     .token_refresh_failed(purpose, &e)
                     .await
@@ -200,6 +607,23 @@ where
 
                 Err(Error::Reqwest(e))
             }
+            Err(Error::Response(e)) => {
+                self.auth_storage
+                    .token_refresh_failed(purpose, &e)
+                    .await
+                    .map_err(Error::AuthStorage)?;
+
+                Err(Error::Response(e))
+            }
+            Err(Error::Resolve(e)) => {
+                self.auth_storage
+                    .token_refresh_failed(purpose, e.as_ref())
+                    .await
+                    .map_err(Error::AuthStorage)?;
+
+                Err(Error::Resolve(e))
+            }
+            Err(e) => Err(e),
         }
     }
 }
@@ -225,9 +649,211 @@ where
     /// Auth storage error
     #[error("auth storage")]
     AuthStorage(#[source] E),
+    /// Host address of the service can't be resolved
+    #[error("resolve host")]
+    Resolve(#[source] Box<dyn std::error::Error + Send + Sync>),
     /// Reqwest error
     #[error("reqwest")]
-    Reqwest(#[from] reqwest::Error),
+    Reqwest(#[source] reqwest::Error),
+    /// Service responded w/ an error status
+    #[error("response")]
+    Response(#[source] ResponseError),
+    /// Encoding or decoding a body failed
+    #[error("encoding")]
+    Encoding(#[from] api::encoding::Error),
+    /// Compressing the request body failed
+    #[error("compress body")]
+    Compress(#[source] io::Error),
+}
+
+/// Error status & details returned by a service
+#[derive(Debug, Clone, Error)]
+#[error("{status}{}", .message.as_ref().map(|message| format!(": {message}")).unwrap_or_default())]
+pub struct ResponseError {
+    /// Status code of the response
+    pub status: http::StatusCode,
+    /// Error message, if the body contained one
+    pub message: Option<String>,
+    /// Machine readable code of the error, if the body contained one
+    pub code: Option<String>,
+}
+
+impl ResponseError {
+    /// Parse the JSON error body services respond with, falling back
+    /// to only the `status` if it isn't one
+    fn new(status: http::StatusCode, body: &str) -> Self {
+        #[derive(Deserialize)]
+        struct Body {
+            error: String,
+            #[serde(default)]
+            code: Option<String>,
+        }
+
+        let body = serde_json::from_str::<Body>(body).ok();
+
+        Self {
+            status,
+            message: body.as_ref().map(|body| body.error.clone()),
+            code: body.and_then(|body| body.code),
+        }
+    }
+}
+
+/// Loading mutual TLS credentials for a [`Client`] failed
+#[derive(Debug, Error)]
+pub enum MtlsError {
+    /// Reading a certificate or key failed
+    #[error("read {}", .0.display())]
+    Read(PathBuf, #[source] io::Error),
+    /// Client certificate or key is invalid
+    #[error("invalid client identity")]
+    Identity(#[source] reqwest::Error),
+    /// CA certificate is invalid
+    #[error("invalid ca certificate")]
+    Ca(#[source] reqwest::Error),
+    /// Building the underlying client failed
+    #[error("build client")]
+    Build(#[source] reqwest::Error),
+}
+
+impl<E> Error<E>
+where
+    E: std::error::Error,
+{
+    /// Returns true if the request failed due to a transient condition
+    /// (remote unreachable, timed out, overloaded or erroring) and can be retried.
+    ///
+    /// Otherwise the request was rejected by the remote and retrying it won't help.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Reqwest(e) => match e.status() {
+                Some(status) => status.is_server_error() || status == http::StatusCode::TOO_MANY_REQUESTS,
+                None => e.is_connect() || e.is_timeout(),
+            },
+            Error::Response(e) => e.status.is_server_error() || e.status == http::StatusCode::TOO_MANY_REQUESTS,
+            Error::RefreshBearerTokenFailed | Error::RefreshAccessTokenFailed => true,
+            // Unless the name server couldn't be reached it's typically a
+            // misconfigured host, retrying just delays the inevitable
+            Error::Resolve(e) => resolve_error(e.as_ref()).is_some_and(ResolveError::is_temporary),
+            Error::MissingBearerToken
+            | Error::MissingAccessToken
+            | Error::AuthStorage(_)
+            | Error::Encoding(_)
+            | Error::Compress(_) => false,
+        }
+    }
+}
+
+impl<E> From<reqwest::Error> for Error<E>
+where
+    E: std::error::Error,
+{
+    fn from(error: reqwest::Error) -> Self {
+        if is_resolve_error(&error) {
+            Error::Resolve(Box::new(error))
+        } else {
+            Error::Reqwest(error)
+        }
+    }
+}
+
+/// Returns true if the request failed due to DNS resolution of the host
+fn is_resolve_error(error: &reqwest::Error) -> bool {
+    error.is_connect() && resolve_error(error).is_some()
+}
+
+/// The [`ResolveError`] `error` or any of it's sources are
+fn resolve_error<'a>(error: &'a (dyn std::error::Error + 'static)) -> Option<&'a ResolveError> {
+    let mut source = Some(error);
+
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<ResolveError>() {
+            return Some(error);
+        }
+
+        source = error.source();
+    }
+
+    None
+}
+
+/// Decode a newline delimited JSON body as it's received
+fn ndjson<T, E>(
+    body: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+) -> impl Stream<Item = Result<T, Error<E>>> + Send + 'static
+where
+    T: DeserializeOwned,
+    E: std::error::Error,
+{
+    let state = (body.boxed(), Vec::new(), false);
+
+    stream::unfold(state, |(mut body, mut buffer, mut done)| async move {
+        loop {
+            if let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                let line = buffer.drain(..=end).collect::<Vec<_>>();
+
+                if line.trim_ascii().is_empty() {
+                    continue;
+                }
+
+                let item = serde_json::from_slice(&line).map_err(|e| Error::Encoding(e.into()));
+                return Some((item, (body, buffer, done)));
+            }
+
+            if done {
+                // Final line may not be newline terminated
+                if buffer.trim_ascii().is_empty() {
+                    return None;
+                }
+
+                let item = serde_json::from_slice(&std::mem::take(&mut buffer)).map_err(|e| Error::Encoding(e.into()));
+                return Some((item, (body, buffer, done)));
+            }
+
+            match body.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    // Body is incomplete, any partial line is discarded
+                    buffer.clear();
+                    done = true;
+                    return Some((Err(Error::Reqwest(e)), (body, buffer, done)));
+                }
+                None => done = true,
+            }
+        }
+    })
+}
+
+/// Gzip compress `bytes`
+fn gzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+/// Resolve the host of the provided address ahead of making any requests to it,
+/// to catch misconfigured hosts early
+pub async fn resolve(host_address: &Uri) -> Result<(), Error> {
+    let host = host_address
+        .host()
+        .ok_or_else(|| Error::Resolve(format!("missing host in {host_address}").into()))?;
+    let port = host_address
+        .port_u16()
+        .unwrap_or(if host_address.scheme() == Some(&http::uri::Scheme::HTTPS) {
+            443
+        } else {
+            80
+        });
+
+    let addrs = dns::lookup(host.to_string(), port)
+        .await
+        .map_err(|e| Error::Resolve(Box::new(e)))?;
+
+    if addrs.is_empty() {
+        return Err(Error::Resolve(format!("no addresses found for {host}").into()));
+    }
+
+    Ok(())
 }
 
 /// Tokens needed to make authenticated requests
@@ -252,6 +878,11 @@ pub trait AuthStorage {
     /// after an expired token is refreshed.
     const REFRESH_ENABLED: bool = false;
 
+    /// Endpoint the tokens of this storage authenticate with, recorded as the
+    /// `endpoint_id` & `role` of each request sent
+    fn endpoint(&self) -> Option<(endpoint::Id, Role)> {
+        None
+    }
     /// Returns current tokens from this storage
     async fn tokens(&self) -> Result<Tokens, Self::Error>;
     /// Called when [`Client`] fetches a refresh token, allowing storage to persist
@@ -262,7 +893,11 @@ pub trait AuthStorage {
         Ok(Tokens::default())
     }
     /// Called when [`Client`] fails to refresh a token
-    async fn token_refresh_failed(&self, _purpose: token::Purpose, _error: &reqwest::Error) -> Result<(), Self::Error> {
+    async fn token_refresh_failed(
+        &self,
+        _purpose: token::Purpose,
+        _error: &(dyn std::error::Error + Send + Sync),
+    ) -> Result<(), Self::Error> {
         Ok(())
     }
 }
@@ -291,14 +926,107 @@ impl AuthStorage for TokensAuth {
     }
 }
 
+/// Caches the [`Tokens`] of the wrapped [`AuthStorage`] in memory until they're near
+/// expiry, avoiding a storage lookup & token verification on every request. The
+/// cache is invalidated whenever a token is refreshed.
+///
+/// Clones share the same cache, so a single instance can be reused across clients.
+#[derive(Debug, Clone)]
+pub struct CachingAuth<A> {
+    inner: A,
+    cache: Arc<Mutex<Option<Tokens>>>,
+}
+
+impl<A> CachingAuth<A> {
+    /// Cache the tokens of the `inner` [`AuthStorage`]
+    pub fn new(inner: A) -> Self {
+        Self {
+            inner,
+            cache: Arc::default(),
+        }
+    }
+
+    fn cached(&self) -> Option<Tokens> {
+        self.cache
+            .lock()
+            .expect("mutex poisoned")
+            .clone()
+            // Defer to inner storage once the client would refresh them
+            .filter(|tokens| {
+                [&tokens.bearer_token, &tokens.access_token]
+                    .into_iter()
+                    .flatten()
+                    .all(|token| !token.decoded.is_expired_in(TOKEN_VALIDITY, Duration::ZERO))
+            })
+    }
+
+    fn store(&self, tokens: Option<Tokens>) {
+        *self.cache.lock().expect("mutex poisoned") = tokens;
+    }
+}
+
+impl<A> AuthStorage for CachingAuth<A>
+where
+    A: AuthStorage,
+{
+    type Error = A::Error;
+
+    const REFRESH_ENABLED: bool = A::REFRESH_ENABLED;
+
+    fn endpoint(&self) -> Option<(endpoint::Id, Role)> {
+        self.inner.endpoint()
+    }
+
+    async fn tokens(&self) -> Result<Tokens, Self::Error> {
+        if let Some(tokens) = self.cached() {
+            return Ok(tokens);
+        }
+
+        let tokens = self.inner.tokens().await?;
+        self.store(Some(tokens.clone()));
+
+        Ok(tokens)
+    }
+
+    async fn token_refreshed(&self, purpose: token::Purpose, token: &str) -> Result<Tokens, Self::Error> {
+        self.store(None);
+
+        let tokens = self.inner.token_refreshed(purpose, token).await?;
+        self.store(Some(tokens.clone()));
+
+        Ok(tokens)
+    }
+
+    async fn token_refresh_failed(
+        &self,
+        purpose: token::Purpose,
+        error: &(dyn std::error::Error + Send + Sync),
+    ) -> Result<(), Self::Error> {
+        self.store(None);
+
+        self.inner.token_refresh_failed(purpose, error).await
+    }
+}
+
 /// Auth credentials are stored in [`Database`] for a configured endpoint
 /// and updated when refresh tokens are fetched
+#[derive(Debug, Clone)]
 pub struct EndpointAuth {
     endpoint: endpoint::Id,
+    role: Role,
     db: Database,
 }
 
 impl EndpointAuth {
+    /// Auth using the credentials stored in [`Database`] for `endpoint`
+    pub fn new(endpoint: &Endpoint, db: Database) -> Self {
+        Self {
+            endpoint: endpoint.id,
+            role: endpoint.kind.role(),
+            db,
+        }
+    }
+
     async fn verified_tokens(&self, public_key: &PublicKey) -> Result<Tokens, EndpointAuthError> {
         let tokens = endpoint::Tokens::get(self.db.acquire().await?.as_mut(), self.endpoint).await?;
 
@@ -322,6 +1050,10 @@ impl AuthStorage for EndpointAuth {
 
     const REFRESH_ENABLED: bool = true;
 
+    fn endpoint(&self) -> Option<(endpoint::Id, Role)> {
+        Some((self.endpoint, self.role))
+    }
+
     async fn tokens(&self) -> Result<Tokens, EndpointAuthError> {
         let mut conn = self.db.acquire().await?;
 
@@ -352,9 +1084,6 @@ impl AuthStorage for EndpointAuth {
             Ok(token) => {
                 let mut tokens = self.verified_tokens(&public_key).await?;
 
-                endpoint.status = endpoint::Status::Operational;
-                endpoint.error = None;
-
                 match purpose {
                     token::Purpose::Authorization => tokens.bearer_token = Some(token),
                     token::Purpose::Authentication => tokens.access_token = Some(token),
@@ -366,7 +1095,8 @@ impl AuthStorage for EndpointAuth {
                 }
                 .save(&mut tx, self.endpoint)
                 .await?;
-                endpoint.save(&mut tx).await?;
+                endpoint.set_status(&mut tx, endpoint::Status::Operational).await?;
+                endpoint.set_error(&mut tx, None).await?;
 
                 tx.commit().await?;
 
@@ -375,24 +1105,22 @@ impl AuthStorage for EndpointAuth {
                 Ok(tokens)
             }
             Err(token::Error::InvalidSignature) => {
-                endpoint.status = endpoint::Status::Forbidden;
-                endpoint.error = Some("Invalid signature".to_string());
-
                 error!("Invalid signature");
 
-                endpoint.save(&mut tx).await?;
+                endpoint.set_status(&mut tx, endpoint::Status::Forbidden).await?;
+                endpoint
+                    .set_error(&mut tx, Some("Invalid signature".to_string()))
+                    .await?;
 
                 tx.commit().await?;
 
                 Err(EndpointAuthError::InvalidRefreshToken)
             }
             Err(_) => {
-                endpoint.status = endpoint::Status::Forbidden;
-                endpoint.error = Some("Invalid token".to_string());
-
                 error!("Invalid token");
 
-                endpoint.save(&mut tx).await?;
+                endpoint.set_status(&mut tx, endpoint::Status::Forbidden).await?;
+                endpoint.set_error(&mut tx, Some("Invalid token".to_string())).await?;
 
                 tx.commit().await?;
 
@@ -408,25 +1136,30 @@ impl AuthStorage for EndpointAuth {
             purpose = %purpose,
         )
     )]
-    async fn token_refresh_failed(&self, purpose: token::Purpose, error: &reqwest::Error) -> Result<(), Self::Error> {
+    async fn token_refresh_failed(
+        &self,
+        purpose: token::Purpose,
+        error: &(dyn std::error::Error + Send + Sync),
+    ) -> Result<(), Self::Error> {
         let mut tx = self.db.begin().await?;
 
         let mut endpoint = Endpoint::get(tx.as_mut(), self.endpoint).await?;
 
-        endpoint.status = endpoint::Status::Unreachable;
+        let error = crate::error::chain(error);
 
-        match purpose {
+        let message = match purpose {
             token::Purpose::Authorization => {
-                endpoint.error = Some("Failed to refresh bearer token".to_string());
                 error!(%error, "Failed to refresh bearer token");
+                "Failed to refresh bearer token"
             }
             token::Purpose::Authentication => {
-                endpoint.error = Some("Failed to refresh access token".to_string());
                 error!(%error, "Failed to refresh access token");
+                "Failed to refresh access token"
             }
-        }
+        };
 
-        endpoint.save(&mut tx).await?;
+        endpoint.set_status(&mut tx, endpoint::Status::Unreachable).await?;
+        endpoint.set_error(&mut tx, Some(message.to_string())).await?;
 
         tx.commit().await?;
 
@@ -453,3 +1186,342 @@ pub enum EndpointAuthError {
     #[error("decode token")]
     DecodeToken(#[from] token::Error),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn status_error(status: u16) -> Error {
+        Error::Response(ResponseError::new(http::StatusCode::from_u16(status).unwrap(), ""))
+    }
+
+    #[test]
+    fn transient_vs_rejected() {
+        assert!(status_error(503).is_transient());
+        assert!(status_error(502).is_transient());
+        assert!(status_error(429).is_transient());
+
+        assert!(!status_error(400).is_transient());
+        assert!(!status_error(403).is_transient());
+        assert!(!Error::<Infallible>::MissingAccessToken.is_transient());
+    }
+
+    #[test]
+    fn response_error_body() {
+        let error = ResponseError::new(
+            http::StatusCode::FORBIDDEN,
+            r#"{"error":"permission denied","code":"permission_denied","status":403}"#,
+        );
+        assert_eq!(error.message.as_deref(), Some("permission denied"));
+        assert_eq!(error.code.as_deref(), Some("permission_denied"));
+        assert_eq!(error.to_string(), "403 Forbidden: permission denied");
+
+        // Not every error is from a service, such as those of a proxy
+        let error = ResponseError::new(http::StatusCode::BAD_GATEWAY, "<html>Bad Gateway</html>");
+        assert_eq!(error.message, None);
+        assert_eq!(error.to_string(), "502 Bad Gateway");
+    }
+
+    #[test]
+    fn mtls_missing_credentials() {
+        let missing = std::env::temp_dir().join(format!("client-{}.pem", uuid::Uuid::new_v4()));
+
+        let mtls = Mtls {
+            cert_path: missing.clone(),
+            key_path: missing.clone(),
+            ca_path: missing.clone(),
+        };
+
+        let result = Client::new("http://127.0.0.1:5000".parse().unwrap()).with_mtls(&mtls);
+        assert!(matches!(result, Err(MtlsError::Read(ref path, _)) if *path == missing));
+
+        let result = configure(Config {
+            mtls: Some(mtls),
+            ..Default::default()
+        });
+
+        assert!(matches!(result, Err(MtlsError::Read(path, _)) if path == missing));
+    }
+
+    #[tokio::test]
+    async fn decode_ndjson() {
+        // Lines split across chunks & the final line isn't terminated
+        let chunks = ["{\"a\":", "1}\n\n{\"a\"", ":2}\n{\"a\":3}"].map(|chunk| Ok(Bytes::from(chunk)));
+
+        let items = ndjson::<serde_json::Value, Infallible>(stream::iter(chunks))
+            .map(|item| item.unwrap()["a"].as_u64().unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(items, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn unresolvable_host() {
+        /// Fails w/ the provided `getaddrinfo` error code, without querying a name server
+        struct Unresolvable(libc::c_int);
+
+        impl reqwest::dns::Resolve for Unresolvable {
+            fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+                let error = ResolveError::new(name.as_str(), self.0);
+                Box::pin(async move { Err(error.into()) })
+            }
+        }
+
+        let send = |code| async move {
+            let client = Client {
+                http: Some(
+                    reqwest::Client::builder()
+                        .dns_resolver(Arc::new(Unresolvable(code)))
+                        .build()
+                        .unwrap(),
+                ),
+                ..Client::new("http://unresolvable.invalid".parse().unwrap())
+            };
+
+            client
+                .raw_send::<api::v1::services::Ping, Infallible>(&(), None)
+                .await
+                .unwrap_err()
+        };
+
+        // Host doesn't exist
+        let error = send(libc::EAI_NONAME).await;
+        assert!(matches!(error, Error::Resolve(_)));
+        assert!(!error.is_transient());
+
+        // Name server couldn't be reached
+        let error = send(libc::EAI_AGAIN).await;
+        assert!(matches!(error, Error::Resolve(_)));
+        assert!(error.is_transient());
+
+        let missing_host = Uri::from_static("/path");
+        assert!(matches!(resolve(&missing_host).await, Err(Error::Resolve(_))));
+    }
+
+    #[test]
+    fn accept_encoding_weight() {
+        let accepts = |value: &'static str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(http::header::ACCEPT_ENCODING, http::HeaderValue::from_static(value));
+            accepts_gzip(&headers)
+        };
+
+        assert!(accepts("gzip"));
+        assert!(accepts("deflate, GZIP;q=0.5"));
+        assert!(accepts("gzip; q=1.0"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("deflate, gzip; Q=0.000"));
+        assert!(!accepts("deflate"));
+        assert!(!accepts(""));
+    }
+
+    #[tokio::test]
+    async fn negotiate_compression() {
+        use api::Operation;
+        use axum::routing::post;
+
+        let encodings = Arc::new(Mutex::new(vec![]));
+
+        let router = axum::Router::new().route(
+            &format!(
+                "/api/{}/{}",
+                api::v1::vessel::Build::VERSION,
+                api::v1::vessel::Build::PATH
+            ),
+            post({
+                let encodings = encodings.clone();
+
+                |headers: http::HeaderMap| async move {
+                    encodings
+                        .lock()
+                        .unwrap()
+                        .push(headers.contains_key(http::header::CONTENT_ENCODING));
+
+                    [(http::header::ACCEPT_ENCODING, "deflate, GZIP;q=0.5")]
+                }
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = Client::new(format!("http://{addr}").parse().unwrap());
+        let collectable = crate::Collectable {
+            kind: crate::collectable::Kind::Package,
+            uri: format!("http://{addr}/assets/0/package.stone"),
+            sha256sum: "0".repeat(64),
+            signature: None,
+        };
+        let body = api::v1::vessel::BuildRequestBody {
+            task_id: 0,
+            collectables: vec![collectable; 100],
+        };
+
+        // Only compressed once the service advertised accepting it
+        for _ in 0..2 {
+            client
+                .raw_send::<api::v1::vessel::Build, Infallible>(&body, None)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(*encodings.lock().unwrap(), [false, true]);
+    }
+
+    #[tokio::test]
+    async fn negotiate_encoding() {
+        use api::Operation;
+        use axum::routing::post;
+
+        let content_types = Arc::new(Mutex::new(vec![]));
+        let advertise = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let router = axum::Router::new().route(
+            &format!(
+                "/api/{}/{}",
+                api::v1::summit::BuildSucceeded::VERSION,
+                api::v1::summit::BuildSucceeded::PATH
+            ),
+            post({
+                let content_types = content_types.clone();
+                let advertise = advertise.clone();
+
+                |headers: http::HeaderMap| async move {
+                    content_types
+                        .lock()
+                        .unwrap()
+                        .push(api::Encoding::from_content_type(&headers));
+
+                    let mut resp = http::HeaderMap::new();
+                    if advertise.load(std::sync::atomic::Ordering::Relaxed) {
+                        resp.insert(
+                            api::encoding::HEADER,
+                            http::HeaderValue::from_static(api::encoding::MESSAGEPACK),
+                        );
+                    }
+                    resp
+                }
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = Client::new(format!("http://{addr}").parse().unwrap());
+        let body = api::v1::summit::BuildBody {
+            task_id: 0,
+            collectables: vec![],
+        };
+        let send = || async {
+            client
+                .raw_send::<api::v1::summit::BuildSucceeded, Infallible>(&body, None)
+                .await
+                .unwrap();
+        };
+
+        // Falls back to JSON as the service never advertised MessagePack
+        send().await;
+        send().await;
+
+        // Then only MessagePack once it's advertised
+        advertise.store(true, std::sync::atomic::Ordering::Relaxed);
+        send().await;
+        send().await;
+
+        assert_eq!(
+            *content_types.lock().unwrap(),
+            [
+                api::Encoding::Json,
+                api::Encoding::Json,
+                api::Encoding::Json,
+                api::Encoding::MessagePack
+            ]
+        );
+    }
+
+    #[derive(Clone)]
+    struct CountingAuth {
+        calls: Arc<std::sync::atomic::AtomicUsize>,
+        tokens: Tokens,
+    }
+
+    impl CountingAuth {
+        fn new(expires_in: chrono::Duration) -> Self {
+            let key_pair = crypto::KeyPair::generate();
+            let now = chrono::Utc::now();
+
+            let token = |purpose| {
+                let encoded = Token::new(token::Payload {
+                    aud: "test".into(),
+                    exp: (now + expires_in).timestamp(),
+                    iat: now.timestamp(),
+                    nbf: None,
+                    iss: "test".into(),
+                    sub: "test".into(),
+                    purpose,
+                    account_id: 0.into(),
+                    account_type: account::Kind::Service,
+                    admin: false,
+                    scope: None,
+                })
+                .sign(&key_pair)
+                .unwrap();
+
+                Token::verify(&encoded, &key_pair.public_key(), &token::Validation::new()).unwrap()
+            };
+
+            Self {
+                calls: Arc::default(),
+                tokens: Tokens {
+                    bearer_token: Some(token(token::Purpose::Authorization)),
+                    access_token: Some(token(token::Purpose::Authentication)),
+                },
+            }
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    impl AuthStorage for CountingAuth {
+        type Error = Infallible;
+
+        const REFRESH_ENABLED: bool = true;
+
+        async fn tokens(&self) -> Result<Tokens, Self::Error> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(self.tokens.clone())
+        }
+
+        async fn token_refreshed(&self, _purpose: token::Purpose, _token: &str) -> Result<Tokens, Self::Error> {
+            Ok(self.tokens.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn caching_auth() {
+        let inner = CountingAuth::new(chrono::Duration::hours(1));
+        let auth = CachingAuth::new(inner.clone());
+
+        auth.tokens().await.unwrap();
+        auth.clone().tokens().await.unwrap();
+        assert_eq!(inner.calls(), 1);
+
+        // Refreshed tokens replace the cache
+        auth.token_refreshed(token::Purpose::Authentication, "").await.unwrap();
+        auth.tokens().await.unwrap();
+        assert_eq!(inner.calls(), 1);
+
+        // Tokens near expiry aren't served from cache
+        let inner = CountingAuth::new(chrono::Duration::minutes(5));
+        let auth = CachingAuth::new(inner.clone());
+
+        auth.tokens().await.unwrap();
+        auth.tokens().await.unwrap();
+        assert_eq!(inner.calls(), 2);
+    }
+}
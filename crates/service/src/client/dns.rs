@@ -0,0 +1,160 @@
+//! Resolve hosts w/ `getaddrinfo`, keeping it's error code so temporary failures
+//! can be told apart from hosts which don't exist
+
+use std::{
+    ffi::{CStr, CString},
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+};
+
+use thiserror::Error;
+
+/// Resolver used by all clients
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct Resolver;
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+
+        Box::pin(async move {
+            // Port is set from the request URI
+            let addrs = lookup(host, 0).await?;
+
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Resolve `host` to the addresses it can be reached at on `port`
+pub(super) async fn lookup(host: String, port: u16) -> Result<Vec<SocketAddr>, ResolveError> {
+    tokio::task::spawn_blocking({
+        let host = host.clone();
+        move || getaddrinfo(&host, port)
+    })
+    .await
+    .unwrap_or_else(|e| {
+        Err(ResolveError {
+            host,
+            code: libc::EAI_SYSTEM,
+            message: e.to_string(),
+        })
+    })
+}
+
+/// Resolving a host failed
+#[derive(Debug, Error)]
+#[error("resolve {host}: {message}")]
+pub struct ResolveError {
+    host: String,
+    code: libc::c_int,
+    message: String,
+}
+
+impl ResolveError {
+    /// Returns true if resolving may succeed when retried, such as when the
+    /// name server couldn't be reached, rather than the host not existing
+    pub fn is_temporary(&self) -> bool {
+        self.code == libc::EAI_AGAIN
+    }
+
+    #[cfg(test)]
+    pub(crate) fn new(host: &str, code: libc::c_int) -> Self {
+        Self {
+            host: host.to_string(),
+            code,
+            message: String::new(),
+        }
+    }
+}
+
+fn getaddrinfo(host: &str, port: u16) -> Result<Vec<SocketAddr>, ResolveError> {
+    let error = |code, message| ResolveError {
+        host: host.to_string(),
+        code,
+        message,
+    };
+
+    let node = CString::new(host).map_err(|_| error(libc::EAI_NONAME, "host contains a nul byte".to_string()))?;
+
+    // SAFETY: All zeroes is a valid, empty addrinfo
+    let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+    hints.ai_socktype = libc::SOCK_STREAM;
+
+    let mut list = std::ptr::null_mut();
+
+    // SAFETY: node & hints outlive the call and the returned list is freed below
+    let code = unsafe { libc::getaddrinfo(node.as_ptr(), std::ptr::null(), &hints, &mut list) };
+
+    if code != 0 {
+        let message = if code == libc::EAI_SYSTEM {
+            io::Error::last_os_error().to_string()
+        } else {
+            // SAFETY: gai_strerror returns a static nul terminated string
+            unsafe { CStr::from_ptr(libc::gai_strerror(code)) }
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        return Err(error(code, message));
+    }
+
+    let mut addrs = vec![];
+    let mut next = list;
+
+    while !next.is_null() {
+        // SAFETY: Non-null entries of the list are valid until it's freed
+        let info = unsafe { &*next };
+
+        match info.ai_family {
+            libc::AF_INET => {
+                // SAFETY: Address of AF_INET entries is a sockaddr_in
+                let addr = unsafe { &*(info.ai_addr as *const libc::sockaddr_in) };
+
+                addrs.push(SocketAddr::from((
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    port,
+                )));
+            }
+            libc::AF_INET6 => {
+                // SAFETY: Address of AF_INET6 entries is a sockaddr_in6
+                let addr = unsafe { &*(info.ai_addr as *const libc::sockaddr_in6) };
+
+                addrs.push(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    port,
+                    0,
+                    addr.sin6_scope_id,
+                )));
+            }
+            _ => {}
+        }
+
+        next = info.ai_next;
+    }
+
+    // SAFETY: List was returned by getaddrinfo and isn't used after
+    unsafe { libc::freeaddrinfo(list) };
+
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn numeric_hosts() {
+        // Parsed without querying any name server
+        assert_eq!(
+            lookup("127.0.0.1".to_string(), 80).await.unwrap(),
+            [SocketAddr::from((Ipv4Addr::LOCALHOST, 80))]
+        );
+        assert_eq!(
+            lookup("::1".to_string(), 443).await.unwrap(),
+            [SocketAddr::from((Ipv6Addr::LOCALHOST, 443))]
+        );
+
+        assert!(lookup("nul\0host".to_string(), 80).await.is_err());
+    }
+}
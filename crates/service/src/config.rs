@@ -1,18 +1,27 @@
 //! Shared service configuration
 
-use std::{io, path::Path};
+use std::{io, path::Path, sync::Arc};
 
+use arc_swap::ArcSwap;
 use http::Uri;
 use serde::Deserialize;
 use tokio::fs;
 
 use crate::{
     account::Admin,
+    client,
     crypto::{KeyPair, PublicKey},
-    endpoint::enrollment::{self, Issuer},
-    tracing, Role,
+    endpoint::{
+        self,
+        enrollment::{self, Issuer},
+        keepalive,
+    },
+    server, token, tracing, Role,
 };
 
+/// A [`Config`] which is swapped in place when reloaded
+pub type Live = Arc<ArcSwap<Config>>;
+
 /// Service configuration
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
@@ -26,6 +35,9 @@ pub struct Config {
     /// Tracing configuration
     #[serde(default)]
     pub tracing: tracing::Config,
+    /// Token lifetime configuration
+    #[serde(default)]
+    pub token: token::Config,
     /// Upstream hub to auto-accept enrollment with
     ///
     /// Only applicable for non-hub services
@@ -35,6 +47,46 @@ pub struct Config {
     /// Only applicable for hub service
     #[serde(default)]
     pub downstream: Vec<enrollment::Target>,
+    /// Downstream services we're allowed to send enrollment to. If set,
+    /// [`Config::downstream`] targets not on this list are skipped.
+    ///
+    /// Only applicable for hub service
+    pub downstream_allowlist: Option<Vec<enrollment::Allowed>>,
+    /// Retry policy when sending enrollment to downstream services
+    ///
+    /// Only applicable for hub service
+    #[serde(default)]
+    pub enrollment_retry: enrollment::Retry,
+    /// Resolve [`Config::downstream`] hosts before sending enrollment, skipping
+    /// any which can't be resolved instead of retrying them
+    ///
+    /// Only applicable for hub service
+    #[serde(default)]
+    pub resolve_downstream: bool,
+    /// Endpoint keepalive configuration
+    ///
+    /// Only applicable for hub service
+    #[serde(default)]
+    pub keepalive: keepalive::Config,
+    /// Server configuration
+    #[serde(default)]
+    pub server: server::Config,
+    /// Connection settings used when making requests to other services
+    #[serde(default)]
+    pub client: client::Config,
+    /// Labels to request when enrolling with the hub, such as `memory = "high"`
+    ///
+    /// The hub's admin can change them via the admin API, after which these
+    /// are no longer applied when re-enrolling
+    ///
+    /// Only applicable for non-hub services
+    #[serde(default)]
+    pub labels: endpoint::Labels,
+    /// Disable gzip compression of request & response bodies handled by this service,
+    /// in case a proxy in front of it mishandles content encoding. Other services then
+    /// stop compressing requests to it, as it no longer advertises accepting them
+    #[serde(default)]
+    pub disable_compression: bool,
 }
 
 impl Config {
@@ -44,6 +96,78 @@ impl Config {
         let config = toml::from_str(&content)?;
         Ok(config)
     }
+
+    /// Reload configuration from the provided `path`
+    ///
+    /// Fields which can't be safely changed while running, such as [`Config::host_address`],
+    /// keep their current value and a warning is logged if they differ
+    pub async fn reload(&self, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let mut config = Self::load(path).await?;
+
+        if config.host_address != self.host_address {
+            ::tracing::warn!(
+                current = %self.host_address,
+                reloaded = %config.host_address,
+                "host_address can't be changed without a restart, ignoring"
+            );
+            config.host_address = self.host_address.clone();
+        }
+
+        if config.server != self.server {
+            ::tracing::warn!("server settings can't be changed without a restart, ignoring");
+            config.server = self.server.clone();
+        }
+
+        if config.client != self.client {
+            ::tracing::warn!("client connection settings can't be changed without a restart, ignoring");
+            config.client = self.client.clone();
+        }
+
+        // Token extraction is configured once when the server starts
+        if config.token.leeway != self.token.leeway || config.token.legacy_audience != self.token.legacy_audience {
+            ::tracing::warn!("token leeway & legacy_audience can't be changed without a restart, ignoring");
+            config.token.leeway = self.token.leeway;
+            config.token.legacy_audience = self.token.legacy_audience;
+        }
+
+        Ok(config)
+    }
+
+    /// Ensure this configuration is consistent with the provided `role`, returning
+    /// a descriptive error otherwise so the service can fail fast on startup
+    pub fn validate(&self, role: Role) -> Result<(), Error> {
+        if self.admin.username.trim().is_empty() {
+            return Err(Error::MissingAdmin);
+        }
+
+        self.admin
+            .public_key
+            .decoded()
+            .map_err(|e| Error::InvalidAdminKey(e.to_string()))?;
+
+        match role {
+            Role::Hub => {
+                if self.upstream.is_some() {
+                    return Err(Error::NotApplicable("upstream", role));
+                }
+            }
+            Role::Builder | Role::RepositoryManager => {
+                if self.upstream.is_none() {
+                    return Err(Error::MissingUpstream(role));
+                }
+
+                if !self.downstream.is_empty() {
+                    return Err(Error::NotApplicable("downstream", role));
+                }
+
+                if self.downstream_allowlist.is_some() {
+                    return Err(Error::NotApplicable("downstream_allowlist", role));
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Config {
@@ -57,6 +181,9 @@ impl Config {
             admin_name: self.admin.name.clone(),
             admin_email: self.admin.email.clone(),
             description: self.description.clone(),
+            capabilities: None,
+            labels: self.labels.clone(),
+            token: self.token,
         }
     }
 }
@@ -70,4 +197,96 @@ pub enum Error {
     /// Decoding the config failed
     #[error("decode config")]
     Decode(#[from] toml::de::Error),
+    /// No admin username is configured
+    #[error("admin username must be set")]
+    MissingAdmin,
+    /// Admin public key doesn't decode
+    #[error("invalid admin public key: {0}")]
+    InvalidAdminKey(String),
+    /// Non-hub service has no upstream hub to enroll with
+    #[error("{0} requires an upstream hub public key, otherwise it never enrolls")]
+    MissingUpstream(Role),
+    /// Field is set which doesn't apply to the role
+    #[error("{0} is not applicable to {1}")]
+    NotApplicable(&'static str, Role),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(host_address: &str, level_filter: &str) -> String {
+        format!(
+            r#"
+            host_address = "{host_address}"
+            description = "test"
+
+            [admin]
+            username = "admin"
+            name = "admin"
+            email = "admin@example.com"
+            public_key = "{}"
+
+            [tracing]
+            level_filter = "{level_filter}"
+            "#,
+            KeyPair::generate().public_key().encode()
+        )
+    }
+
+    #[test]
+    fn validate_role() {
+        let config = |extra: &str| {
+            let content = format!("{extra}\n{}", config("http://127.0.0.1:5000", "info"));
+            toml::from_str::<Config>(&content).unwrap()
+        };
+        let upstream = format!("upstream = \"{}\"", KeyPair::generate().public_key().encode());
+
+        assert!(config("").validate(Role::Hub).is_ok());
+        assert!(matches!(
+            config("").validate(Role::Builder),
+            Err(Error::MissingUpstream(Role::Builder))
+        ));
+
+        let with_upstream = config(&upstream);
+        assert!(with_upstream.validate(Role::RepositoryManager).is_ok());
+        assert!(matches!(
+            with_upstream.validate(Role::Hub),
+            Err(Error::NotApplicable("upstream", Role::Hub))
+        ));
+
+        let mut missing_admin = with_upstream.clone();
+        missing_admin.admin.username = String::new();
+        assert!(matches!(
+            missing_admin.validate(Role::Builder),
+            Err(Error::MissingAdmin)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reload_ignores_restart_only() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", uuid::Uuid::new_v4()));
+
+        fs::write(&path, config("http://127.0.0.1:5000", "info")).await.unwrap();
+        let current = Config::load(&path).await.unwrap();
+
+        fs::write(
+            &path,
+            format!(
+                "{}\n[token]\nauthentication = \"2h\"\nleeway = \"0s\"",
+                config("http://127.0.0.1:6000", "debug")
+            ),
+        )
+        .await
+        .unwrap();
+        let reloaded = current.reload(&path).await;
+
+        let _ = fs::remove_file(&path).await;
+        let reloaded = reloaded.unwrap();
+
+        assert_eq!(reloaded.host_address, current.host_address);
+        assert_eq!(reloaded.tracing.level_filter, "debug");
+        assert_eq!(reloaded.token.leeway, current.token.leeway);
+        assert_eq!(reloaded.token.authentication, chrono::Duration::hours(2));
+    }
 }
@@ -1,12 +1,25 @@
 //! Service database
 
-use std::path::Path;
+use std::{collections::BTreeSet, fmt, path::Path, time::Duration};
 
+use futures_util::future::BoxFuture;
+use itertools::Itertools;
 use sqlx::{pool::PoolConnection, Pool, Sqlite, SqliteConnection};
 use thiserror::Error;
+use tracing::debug;
 
 pub use sqlx::migrate::Migrator;
 
+/// Maximum attempts of [`Database::with_retry`] before giving up
+pub const RETRY_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of [`Database::with_retry`], doubled each attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(25);
+
+/// Primary result codes of SQLite errors caused by lock contention
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
 /// Service database
 #[derive(Debug, Clone)]
 pub struct Database {
@@ -26,14 +39,63 @@ impl Database {
         )
         .await?;
 
-        sqlx::migrate!("./migrations")
-            .set_ignore_missing(true)
-            .run(&pool)
-            .await?;
+        service_migrator().set_ignore_missing(true).run(&pool).await?;
+
+        Ok(Self { pool })
+    }
+
+    /// Opens a read-only connection to an existing database at the provided path
+    /// without running any migrations
+    pub async fn open_read_only(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let pool = sqlx::SqlitePool::connect_with(
+            sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(false)
+                .read_only(true),
+        )
+        .await?;
 
         Ok(Self { pool })
     }
 
+    /// Compare the migrations applied to this database against the shared service
+    /// migrations and the provided `migrators`
+    pub async fn migration_status(&self, migrators: &[Migrator]) -> Result<MigrationStatus, Error> {
+        Ok(MigrationStatus::new(self.applied_migrations().await?, migrators))
+    }
+
+    /// Versions of the migrations successfully applied to this database
+    pub(crate) async fn applied_migrations(&self) -> Result<Vec<i64>, Error> {
+        let mut conn = self.acquire().await?;
+
+        let table: Option<(String,)> = sqlx::query_as(
+            "
+            SELECT name
+            FROM sqlite_master
+            WHERE type = 'table' AND name = '_sqlx_migrations';
+            ",
+        )
+        .fetch_optional(conn.as_mut())
+        .await?;
+
+        let applied: Vec<(i64,)> = if table.is_some() {
+            sqlx::query_as(
+                "
+                SELECT version
+                FROM _sqlx_migrations
+                WHERE success = TRUE
+                ORDER BY version;
+                ",
+            )
+            .fetch_all(conn.as_mut())
+            .await?
+        } else {
+            vec![]
+        };
+
+        Ok(applied.into_iter().map(|(version,)| version).collect())
+    }
+
     /// Runs the provided migrations on the database
     pub async fn with_migrations(self, mut migrator: Migrator) -> Result<Self, Error> {
         migrator.set_ignore_missing(true).run(&self.pool).await?;
@@ -49,6 +111,129 @@ impl Database {
     pub async fn begin(&self) -> Result<Transaction, Error> {
         Ok(Transaction(self.pool.begin().await?))
     }
+
+    /// Run `f` in a transaction which is committed if it succeeds
+    ///
+    /// SQLite only allows a single writer, so transactions can conflict under
+    /// concurrency. If `f` or the commit fails because the database is busy, the
+    /// transaction is rolled back and `f` is retried w/ backoff, up to
+    /// [`RETRY_ATTEMPTS`]. Any other error is returned immediately.
+    pub async fn with_retry<T, E, F>(&self, mut f: F) -> Result<T, E>
+    where
+        F: for<'t> FnMut(&'t mut Transaction) -> BoxFuture<'t, Result<T, E>>,
+        E: std::error::Error + From<Error> + 'static,
+    {
+        let mut attempt = 1;
+
+        loop {
+            let result: Result<T, E> = async {
+                let mut tx = self.begin().await?;
+                let value = f(&mut tx).await?;
+                tx.commit().await?;
+                Ok(value)
+            }
+            .await;
+
+            match result {
+                Err(error) if attempt < RETRY_ATTEMPTS && is_busy(&error) => {
+                    let delay = RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+                    debug!(attempt, ?delay, "Database busy, retrying transaction");
+
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Returns true if `error` was caused by SQLite lock contention
+fn is_busy(error: &(dyn std::error::Error + 'static)) -> bool {
+    std::iter::successors(Some(error), |error| error.source()).any(|error| {
+        let Some(sqlx::Error::Database(error)) = error.downcast_ref::<sqlx::Error>() else {
+            return false;
+        };
+
+        // Extended result codes carry the primary code in the lower byte
+        error
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED))
+    })
+}
+
+fn service_migrator() -> Migrator {
+    sqlx::migrate!("./migrations")
+}
+
+/// Applied vs. pending migration versions of a [`Database`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationStatus {
+    /// Versions applied to the database
+    pub applied: Vec<i64>,
+    /// Versions known to this binary which haven't been applied yet,
+    /// these will be applied on startup
+    pub pending: Vec<i64>,
+    /// Versions applied to the database which aren't known to this binary
+    pub unknown: Vec<i64>,
+}
+
+impl MigrationStatus {
+    /// Status of the `applied` versions against the shared service migrations
+    /// and the provided `migrators`
+    pub(crate) fn new(applied: impl IntoIterator<Item = i64>, migrators: &[Migrator]) -> Self {
+        Self::with_versions(
+            applied,
+            migrators
+                .iter()
+                .flat_map(|migrator| migrator.iter().map(|migration| migration.version)),
+        )
+    }
+
+    /// Status of the `applied` versions against the shared service migrations
+    /// and the provided migration `versions`
+    pub(crate) fn with_versions(
+        applied: impl IntoIterator<Item = i64>,
+        versions: impl IntoIterator<Item = i64>,
+    ) -> Self {
+        let applied = applied.into_iter().collect::<BTreeSet<_>>();
+        let known = service_migrator()
+            .iter()
+            .map(|migration| migration.version)
+            .chain(versions)
+            .collect::<BTreeSet<_>>();
+
+        Self {
+            pending: known.difference(&applied).copied().collect(),
+            unknown: applied.difference(&known).copied().collect(),
+            applied: applied.into_iter().collect(),
+        }
+    }
+
+    /// Returns true if the database has migrations applied which this binary doesn't
+    /// know about, meaning it was migrated by a newer version
+    pub fn is_newer(&self) -> bool {
+        !self.unknown.is_empty()
+    }
+
+    /// Returns [`Error::NewerMigrations`] if [`MigrationStatus::is_newer`], as running
+    /// against a database migrated by a newer version could corrupt it
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_newer() {
+            return Err(Error::NewerMigrations(self.unknown.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for MigrationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "applied: {}", self.applied.iter().join(", "))?;
+        writeln!(f, "pending: {}", self.pending.iter().join(", "))?;
+        write!(f, "unknown: {}", self.unknown.iter().join(", "))
+    }
 }
 
 /// A database transaction
@@ -75,10 +260,263 @@ impl<'a, T> Executor<'a> for &'a mut T where &'a mut T: sqlx::Executor<'a, Datab
 /// A database error
 #[derive(Debug, Error)]
 pub enum Error {
+    /// Requested row doesn't exist
+    #[error("not found")]
+    NotFound,
     /// Sqlx error
     #[error("sqlx")]
-    Sqlx(#[from] sqlx::Error),
+    Sqlx(#[source] sqlx::Error),
     /// Migration error
     #[error("sqlx migrate")]
     Migrate(#[from] sqlx::migrate::MigrateError),
+    /// Database has migrations applied by a newer version, see [`MigrationStatus::is_newer`]
+    #[error("database has migrations applied which are unknown to this version: {}", .0.iter().join(", "))]
+    NewerMigrations(Vec<i64>),
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::RowNotFound => Error::NotFound,
+            error => Error::Sqlx(error),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{ops::Deref, path::PathBuf};
+
+    use super::*;
+    use crate::{account, endpoint, Account, Endpoint};
+
+    /// A migrated [`Database`] in the temp directory, removed on drop
+    pub(crate) struct Temp {
+        db: Option<Database>,
+        path: PathBuf,
+    }
+
+    impl Deref for Temp {
+        type Target = Database;
+
+        fn deref(&self) -> &Database {
+            self.db.as_ref().expect("database is open")
+        }
+    }
+
+    impl Drop for Temp {
+        fn drop(&mut self) {
+            self.db.take();
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    pub(crate) async fn temp() -> Temp {
+        let path = std::env::temp_dir().join(format!("service-db-{}.sqlite", uuid::Uuid::new_v4()));
+        let db = Database::new(&path).await.unwrap();
+
+        Temp { db: Some(db), path }
+    }
+
+    #[tokio::test]
+    async fn migration_status() {
+        let db = temp().await;
+
+        let status = db.migration_status(&[]).await.unwrap();
+        assert!(!status.applied.is_empty());
+        assert!(status.pending.is_empty());
+        assert!(!status.is_newer());
+
+        // Applied by a newer binary
+        sqlx::query(
+            "
+            INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES (99990101000000, 'future', TRUE, x'00', 0);
+            ",
+        )
+        .execute(db.acquire().await.unwrap().as_mut())
+        .await
+        .unwrap();
+
+        let status = db.migration_status(&[]).await.unwrap();
+        assert_eq!(status.unknown, vec![99990101000000]);
+        assert!(status.is_newer());
+        assert!(matches!(status.check(), Err(Error::NewerMigrations(versions)) if versions == [99990101000000]));
+
+        // Nothing applied to a new database
+        let status = MigrationStatus::new([], &[]);
+        assert!(status.applied.is_empty());
+        assert_eq!(status.pending.len(), service_migrator().iter().count());
+    }
+
+    #[tokio::test]
+    async fn retry_busy_transaction() {
+        use futures_util::FutureExt;
+
+        let db = temp().await;
+
+        // Conflicts w/ a newer snapshot are reported immediately in WAL mode,
+        // rather than after waiting on the busy timeout
+        sqlx::query("PRAGMA journal_mode = WAL; CREATE TABLE retry_test (value INTEGER NOT NULL);")
+            .execute(db.acquire().await.unwrap().as_mut())
+            .await
+            .unwrap();
+
+        let mut attempts = 0;
+
+        let count = db
+            .with_retry(|tx| {
+                attempts += 1;
+                let first = attempts == 1;
+                let db = Database::clone(&db);
+
+                async move {
+                    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM retry_test;")
+                        .fetch_one(tx.as_mut())
+                        .await?;
+
+                    // Another writer commits after our read snapshot, so
+                    // upgrading to a write transaction is busy
+                    if first {
+                        sqlx::query("INSERT INTO retry_test (value) VALUES (1);")
+                            .execute(db.acquire().await?.as_mut())
+                            .await?;
+                    }
+
+                    sqlx::query("INSERT INTO retry_test (value) VALUES (2);")
+                        .execute(tx.as_mut())
+                        .await?;
+
+                    Ok::<_, Error>(count)
+                }
+                .boxed()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(attempts, 2);
+        assert_eq!(count, 1);
+
+        // Other errors aren't retried & are rolled back
+        let mut attempts = 0;
+
+        let result = db
+            .with_retry(|tx| {
+                attempts += 1;
+
+                async move {
+                    sqlx::query("INSERT INTO retry_test (value) VALUES (3);")
+                        .execute(tx.as_mut())
+                        .await?;
+
+                    Err::<(), _>(Error::NotFound)
+                }
+                .boxed()
+            })
+            .await;
+
+        assert!(matches!(result, Err(Error::NotFound)));
+        assert_eq!(attempts, 1);
+
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM retry_test;")
+            .fetch_one(db.acquire().await.unwrap().as_mut())
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn loaders_not_found() {
+        let db = temp().await;
+        let mut conn = db.acquire().await.unwrap();
+
+        let endpoint = endpoint::Id::generate();
+        let account = account::Id::generate();
+
+        assert!(matches!(
+            Endpoint::get(conn.as_mut(), endpoint).await,
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(
+            endpoint::Tokens::get(conn.as_mut(), endpoint).await,
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(
+            Account::get(conn.as_mut(), account).await,
+            Err(account::Error::Database(Error::NotFound))
+        ));
+        assert!(matches!(
+            account::Token::get(conn.as_mut(), account).await,
+            Err(account::Error::Database(Error::NotFound))
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_account_tokens() {
+        let db = temp().await;
+
+        let expired = account::Id::generate();
+        let active = account::Id::generate();
+        let now = chrono::Utc::now();
+
+        let mut tx = db.begin().await.unwrap();
+        for (id, expiration) in [
+            (expired, now - chrono::Duration::minutes(1)),
+            (active, now + chrono::Duration::hours(1)),
+        ] {
+            Account::service(id, crate::crypto::KeyPair::generate().public_key().encode())
+                .save(&mut tx)
+                .await
+                .unwrap();
+            account::Token::set(&mut tx, id, "token", expiration).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        let mut conn = db.acquire().await.unwrap();
+        assert!(account::Token::list(conn.as_mut(), expired).await.unwrap().is_empty());
+        assert_eq!(account::Token::list(conn.as_mut(), active).await.unwrap().len(), 1);
+        drop(conn);
+
+        let mut tx = db.begin().await.unwrap();
+        assert_eq!(account::Token::delete_expired(&mut tx).await.unwrap(), 1);
+        tx.commit().await.unwrap();
+
+        let mut conn = db.acquire().await.unwrap();
+        assert!(matches!(
+            account::Token::get(conn.as_mut(), expired).await,
+            Err(account::Error::Database(Error::NotFound))
+        ));
+        assert!(account::Token::get(conn.as_mut(), active).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn soft_deleted_accounts() {
+        let db = temp().await;
+
+        let id = account::Id::generate();
+
+        let mut tx = db.begin().await.unwrap();
+        Account::service(id, crate::crypto::KeyPair::generate().public_key().encode())
+            .save(&mut tx)
+            .await
+            .unwrap();
+        account::Token::set(&mut tx, id, "token", chrono::Utc::now() + chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        Account::soft_delete(&mut tx, id).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let mut conn = db.acquire().await.unwrap();
+        assert!(matches!(
+            Account::get(conn.as_mut(), id).await,
+            Err(account::Error::Database(Error::NotFound))
+        ));
+        assert!(matches!(
+            account::Token::get(conn.as_mut(), id).await,
+            Err(account::Error::Database(Error::NotFound))
+        ));
+
+        let account = Account::get_with(conn.as_mut(), id, true).await.unwrap();
+        assert!(account.deleted_at.is_some());
+    }
 }
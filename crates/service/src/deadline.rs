@@ -0,0 +1,97 @@
+//! Propagate how long callers will wait for a response via the [`HEADER`] header
+//!
+//! A [`Client`] w/ a timeout sends how long it'll wait, relative so it's
+//! unaffected by clock skew between hosts. The server rejects requests which
+//! arrive after the resulting deadline, but never cancels one being handled as
+//! that could leave it's changes half applied. Handlers which do long running
+//! work can instead check [`Deadline::current`] to abort early. Any [`Client`]
+//! requests made while handling it propagate the same deadline.
+//!
+//! [`Client`]: crate::Client
+use std::{future::Future, time::Duration};
+
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, HeaderValue};
+
+/// Header carrying the time remaining until the deadline, in milliseconds
+pub const HEADER: &str = "x-request-timeout";
+
+tokio::task_local! {
+    static CURRENT: Deadline;
+}
+
+/// Time by which the caller will have given up on a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(DateTime<Utc>);
+
+impl Deadline {
+    /// Deadline `timeout` from now
+    pub fn after(timeout: Duration) -> Self {
+        Self(Utc::now() + timeout)
+    }
+
+    /// Deadline of the request currently being handled, if any
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Deadline sent by the caller in the [`HEADER`] header, if present & valid
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get(HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(|millis| Self::after(Duration::from_millis(millis)))
+    }
+
+    /// Run `future` with this as the [`Deadline::current`] deadline
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    /// Header value of the time remaining until this deadline
+    pub fn to_header_value(&self) -> HeaderValue {
+        HeaderValue::from(self.remaining().as_millis() as u64)
+    }
+
+    /// Time remaining until the deadline, zero if it's been exceeded
+    pub fn remaining(&self) -> Duration {
+        (self.0 - Utc::now()).to_std().unwrap_or_default()
+    }
+
+    /// Returns true if the deadline has passed
+    pub fn is_exceeded(&self) -> bool {
+        self.0 <= Utc::now()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn current() {
+        let deadline = Deadline::after(Duration::from_secs(60));
+
+        assert_eq!(Deadline::current(), None);
+        assert_eq!(deadline.scope(async { Deadline::current() }).await, Some(deadline));
+        assert!(!deadline.is_exceeded());
+        assert!(deadline.remaining() > Duration::from_secs(59));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, HeaderValue::from_static("60000"));
+        let received = Deadline::from_headers(&headers).unwrap();
+        assert!(received.remaining() > Duration::from_secs(59));
+        assert!(received.remaining() <= Duration::from_secs(60));
+
+        headers.insert(HEADER, deadline.to_header_value());
+        assert!(Deadline::from_headers(&headers).unwrap() <= deadline);
+
+        headers.insert(HEADER, HeaderValue::from_static("soon"));
+        assert_eq!(Deadline::from_headers(&headers), None);
+
+        let exceeded = Deadline::after(Duration::ZERO);
+        assert!(exceeded.is_exceeded());
+        assert_eq!(exceeded.remaining(), Duration::ZERO);
+    }
+}
@@ -16,7 +16,10 @@ use crate::{
     Role, Token,
 };
 
+pub use service_core::endpoint::Labels;
+
 pub mod enrollment;
+pub mod keepalive;
 
 /// Unique identifier of an [`Endpoint`]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, From)]
@@ -98,7 +101,8 @@ impl Endpoint {
               error,
               account_id,
               role,
-              work_status
+              work_status,
+              capabilities
             FROM endpoint
             WHERE endpoint_id = ?;
             ",
@@ -122,16 +126,18 @@ impl Endpoint {
               error,
               account_id,
               role,
-              work_status
+              work_status,
+              capabilities
             )
-            VALUES (?,?,?,?,?,?,?)
+            VALUES (?,?,?,?,?,?,?,?)
             ON CONFLICT(account_id) DO UPDATE SET 
               host_address=excluded.host_address,
               status=excluded.status,
               error=excluded.error,
               account_id=excluded.account_id,
               role=excluded.role,
-              work_status=excluded.work_status;
+              work_status=excluded.work_status,
+              capabilities=excluded.capabilities;
             ",
         )
         .bind(self.id.0)
@@ -141,9 +147,157 @@ impl Endpoint {
         .bind(i64::from(self.account))
         .bind(self.kind.role().to_string())
         .bind(self.kind.work_status().map(ToString::to_string))
+        .bind(
+            self.kind
+                .capabilities()
+                .map(serde_json::to_string)
+                .transpose()
+                .map_err(|e| sqlx::Error::Encode(Box::new(e)))?,
+        )
+        .execute(tx.as_mut())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Update only the status of this endpoint in the provided [`Database`]
+    ///
+    /// Unlike [`Endpoint::save`], other columns are left untouched so concurrent
+    /// updates to them aren't lost
+    pub async fn set_status(&mut self, tx: &mut database::Transaction, status: Status) -> Result<(), database::Error> {
+        sqlx::query(
+            "
+            UPDATE endpoint
+            SET status = ?
+            WHERE endpoint_id = ?;
+            ",
+        )
+        .bind(status.to_string())
+        .bind(self.id.0)
+        .execute(tx.as_mut())
+        .await?;
+
+        self.status = status;
+
+        Ok(())
+    }
+
+    /// Update only the error of this endpoint in the provided [`Database`]
+    ///
+    /// Unlike [`Endpoint::save`], other columns are left untouched so concurrent
+    /// updates to them aren't lost
+    pub async fn set_error(
+        &mut self,
+        tx: &mut database::Transaction,
+        error: Option<String>,
+    ) -> Result<(), database::Error> {
+        sqlx::query(
+            "
+            UPDATE endpoint
+            SET error = ?
+            WHERE endpoint_id = ?;
+            ",
+        )
+        .bind(&error)
+        .bind(self.id.0)
+        .execute(tx.as_mut())
+        .await?;
+
+        self.error = error;
+
+        Ok(())
+    }
+
+    /// Get the [`Labels`] of this endpoint from the provided [`Database`]
+    pub async fn labels<'a, T>(&self, conn: &'a mut T) -> Result<Labels, database::Error>
+    where
+        &'a mut T: database::Executor<'a>,
+    {
+        let labels: Vec<(String, String)> = sqlx::query_as(
+            "
+            SELECT
+              key,
+              value
+            FROM endpoint_label
+            WHERE endpoint_id = ?;
+            ",
+        )
+        .bind(self.id.0)
+        .fetch_all(conn)
+        .await?;
+
+        Ok(labels.into_iter().collect())
+    }
+
+    /// Replace the [`Labels`] of this endpoint in the provided [`Database`], as set by an admin
+    ///
+    /// These are kept when the endpoint re-enrolls, see [`Endpoint::request_labels`]
+    pub async fn set_labels(&self, tx: &mut database::Transaction, labels: &Labels) -> Result<(), database::Error> {
+        sqlx::query(
+            "
+            UPDATE endpoint
+            SET labels_set_by_admin = TRUE
+            WHERE endpoint_id = ?;
+            ",
+        )
+        .bind(self.id.0)
+        .execute(tx.as_mut())
+        .await?;
+
+        self.replace_labels(tx, labels).await
+    }
+
+    /// Replace the [`Labels`] of this endpoint in the provided [`Database`] w/ those it
+    /// requested when enrolling, unless an admin has since set them
+    pub async fn request_labels(&self, tx: &mut database::Transaction, labels: &Labels) -> Result<(), database::Error> {
+        let (set_by_admin,): (bool,) = sqlx::query_as(
+            "
+            SELECT labels_set_by_admin
+            FROM endpoint
+            WHERE endpoint_id = ?;
+            ",
+        )
+        .bind(self.id.0)
+        .fetch_one(tx.as_mut())
+        .await?;
+
+        if set_by_admin {
+            return Ok(());
+        }
+
+        self.replace_labels(tx, labels).await
+    }
+
+    async fn replace_labels(&self, tx: &mut database::Transaction, labels: &Labels) -> Result<(), database::Error> {
+        sqlx::query(
+            "
+            DELETE FROM endpoint_label
+            WHERE endpoint_id = ?;
+            ",
+        )
+        .bind(self.id.0)
         .execute(tx.as_mut())
         .await?;
 
+        for (key, value) in labels.iter() {
+            sqlx::query(
+                "
+                INSERT INTO endpoint_label
+                (
+                  endpoint_id,
+                  key,
+                  value
+                )
+                VALUES (?,?,?);
+                ",
+            )
+            .bind(self.id.0)
+            .bind(key)
+            .bind(value)
+            .execute(tx.as_mut())
+            .await?;
+        }
+
         Ok(())
     }
 
@@ -161,7 +315,8 @@ impl Endpoint {
               error,
               account_id,
               role,
-              work_status
+              work_status,
+              capabilities
             FROM endpoint;
             ",
         )
@@ -249,7 +404,7 @@ impl Tokens {
 }
 
 /// Status of the [`Endpoint`]
-#[derive(Debug, Clone, Copy, strum::Display, strum::EnumString, Serialize)]
+#[derive(Debug, Clone, Copy, strum::Display, strum::EnumString, strum::EnumIter, Serialize)]
 #[serde(rename_all = "kebab-case")]
 #[strum(serialize_all = "kebab-case")]
 pub enum Status {
@@ -295,6 +450,15 @@ impl Kind {
             None
         }
     }
+
+    /// Capabilities advertised by a [`Role::Builder`] endpoint
+    pub fn capabilities(&self) -> Option<&builder::Capabilities> {
+        if let Self::Builder(ext) = self {
+            ext.capabilities.as_ref()
+        } else {
+            None
+        }
+    }
 }
 
 impl<'a> FromRow<'a, sqlx::sqlite::SqliteRow> for Kind {
@@ -306,6 +470,7 @@ impl<'a> FromRow<'a, sqlx::sqlite::SqliteRow> for Kind {
 
             // Builder fields
             work_status: Option<String>,
+            capabilities: Option<String>,
         }
 
         let row = Row::from_row(row)?;
@@ -313,7 +478,16 @@ impl<'a> FromRow<'a, sqlx::sqlite::SqliteRow> for Kind {
         match (row.role, row.work_status) {
             (Role::Builder, Some(value)) => {
                 let work_status = value.parse().map_err(|e| sqlx::Error::Decode(Box::from(e)))?;
-                Ok(Kind::Builder(builder::Extension { work_status }))
+                let capabilities = row
+                    .capabilities
+                    .map(|value| serde_json::from_str(&value))
+                    .transpose()
+                    .map_err(|e| sqlx::Error::Decode(Box::from(e)))?;
+
+                Ok(Kind::Builder(builder::Extension {
+                    work_status,
+                    capabilities,
+                }))
             }
             (Role::Builder, _) => Err(sqlx::Error::Decode(Box::from(
                 "extension can't be null for builder endpoint",
@@ -328,11 +502,37 @@ pub mod builder {
     //! Builder specific endpoint details
     use serde::{Deserialize, Serialize};
 
+    pub use service_core::endpoint::builder::{Capabilities, DEFAULT_ARCH};
+
     /// Builder extension details
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Extension {
         /// Work status of the endpoint
         pub work_status: WorkStatus,
+        /// Capabilities advertised by the builder during enrollment
+        ///
+        /// Builders enrolled before capabilities were introduced won't report any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub capabilities: Option<Capabilities>,
+    }
+
+    impl Extension {
+        /// Returns true if this builder advertised capabilities which satisfy `required`
+        pub fn supports(&self, required: &Capabilities) -> bool {
+            match &self.capabilities {
+                Some(capabilities) => capabilities.satisfies(required),
+                None => *required == Capabilities::default(),
+            }
+        }
+
+        /// Returns true if this builder can build for `arch`, which should be checked
+        /// before assigning it a task to avoid cross-arch misassignment
+        pub fn can_build(&self, arch: &str) -> bool {
+            match &self.capabilities {
+                Some(capabilities) => capabilities.supports_arch(arch),
+                None => arch == DEFAULT_ARCH,
+            }
+        }
     }
 
     /// Work status of the builder
@@ -351,22 +551,24 @@ pub(crate) fn create_token(
     purpose: token::Purpose,
     endpoint: Id,
     account: account::Id,
-    role: Role,
+    audience: token::Audience<'_>,
     ourself: &enrollment::Issuer,
 ) -> Result<VerifiedToken, token::Error> {
     let now = Utc::now();
-    let expires_on = now + purpose.duration();
+    let expires_on = now + ourself.token.duration(purpose);
 
     let token = Token::new(token::Payload {
-        aud: role.service_name().to_string(),
+        aud: audience.encode(),
         exp: expires_on.timestamp(),
         iat: now.timestamp(),
+        nbf: Some(now.timestamp()),
         iss: ourself.role.service_name().to_string(),
         sub: endpoint.to_string(),
         purpose,
         account_id: account,
         account_type: account::Kind::Service,
         admin: false,
+        scope: None,
     });
     let account_token = token.sign(&ourself.key_pair)?;
 
@@ -375,3 +577,136 @@ pub(crate) fn create_token(
         decoded: token,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{crypto::KeyPair, database, Account};
+
+    #[tokio::test]
+    async fn partial_updates_dont_clobber() {
+        let db = database::test::temp().await;
+
+        let account = account::Id::generate();
+        let id = Id::generate();
+
+        let mut tx = db.begin().await.unwrap();
+        Account::service(account, KeyPair::generate().public_key().encode())
+            .save(&mut tx)
+            .await
+            .unwrap();
+        Endpoint {
+            id,
+            host_address: "http://127.0.0.1:5000".parse().unwrap(),
+            status: Status::AwaitingAcceptance,
+            error: None,
+            account,
+            kind: Kind::RepositoryManager,
+        }
+        .save(&mut tx)
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let update_status = async {
+            let mut tx = db.begin().await.unwrap();
+            let mut endpoint = Endpoint::get(tx.as_mut(), id).await.unwrap();
+            endpoint.set_status(&mut tx, Status::Operational).await.unwrap();
+            tx.commit().await.unwrap();
+        };
+        let update_tokens = async {
+            let mut tx = db.begin().await.unwrap();
+            Tokens {
+                bearer_token: Some("bearer".to_string()),
+                access_token: Some("access".to_string()),
+            }
+            .save(&mut tx, id)
+            .await
+            .unwrap();
+            tx.commit().await.unwrap();
+        };
+        tokio::join!(update_status, update_tokens);
+
+        // A stale copy only writes the column it updates
+        let mut conn = db.acquire().await.unwrap();
+        let mut stale = Endpoint::get(conn.as_mut(), id).await.unwrap();
+        drop(conn);
+        stale.status = Status::Failed;
+
+        let mut tx = db.begin().await.unwrap();
+        stale.set_error(&mut tx, Some("error".to_string())).await.unwrap();
+        tx.commit().await.unwrap();
+
+        let mut conn = db.acquire().await.unwrap();
+        let endpoint = Endpoint::get(conn.as_mut(), id).await.unwrap();
+        let tokens = Tokens::get(conn.as_mut(), id).await.unwrap();
+
+        assert!(matches!(endpoint.status, Status::Operational));
+        assert_eq!(endpoint.error.as_deref(), Some("error"));
+        assert_eq!(tokens.bearer_token.as_deref(), Some("bearer"));
+        assert_eq!(tokens.access_token.as_deref(), Some("access"));
+    }
+
+    #[tokio::test]
+    async fn labels() {
+        let db = database::test::temp().await;
+
+        let account = account::Id::generate();
+        let endpoint = Endpoint {
+            id: Id::generate(),
+            host_address: "http://127.0.0.1:5002".parse().unwrap(),
+            status: Status::Operational,
+            error: None,
+            account,
+            kind: Kind::RepositoryManager,
+        };
+
+        let mut tx = db.begin().await.unwrap();
+        Account::service(account, KeyPair::generate().public_key().encode())
+            .save(&mut tx)
+            .await
+            .unwrap();
+        endpoint.save(&mut tx).await.unwrap();
+        endpoint
+            .set_labels(&mut tx, &Labels::from_iter([("memory", "high"), ("region", "eu")]))
+            .await
+            .unwrap();
+        // Replaces rather than merges
+        endpoint
+            .set_labels(&mut tx, &Labels::from_iter([("memory", "low")]))
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let mut conn = db.acquire().await.unwrap();
+        assert_eq!(
+            endpoint.labels(conn.as_mut()).await.unwrap(),
+            Labels::from_iter([("memory", "low")])
+        );
+        drop(conn);
+
+        // Admin set labels are kept when the endpoint re-enrolls
+        let mut tx = db.begin().await.unwrap();
+        endpoint.save(&mut tx).await.unwrap();
+        endpoint
+            .request_labels(&mut tx, &Labels::from_iter([("memory", "high")]))
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(
+            endpoint.labels(db.acquire().await.unwrap().as_mut()).await.unwrap(),
+            Labels::from_iter([("memory", "low")])
+        );
+
+        let mut tx = db.begin().await.unwrap();
+        endpoint.delete(&mut tx).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert!(endpoint
+            .labels(db.acquire().await.unwrap().as_mut())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,14 +1,18 @@
 //! Enroll with remote services to provision authorization
 
+use std::time::Duration;
+
+use futures_util::future;
 use http::Uri;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::{debug, error, info, info_span};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use crate::{
     account, api, client,
     crypto::{EncodedPublicKey, KeyPair, PublicKey},
     database, endpoint, error,
+    sync::SharedMap,
     token::{self, VerifiedToken},
     Account, Client, Database, Endpoint, Role, State,
 };
@@ -30,6 +34,12 @@ pub struct Issuer {
     pub admin_name: String,
     /// Admin email
     pub admin_email: String,
+    /// Capabilities advertised to the remote endpoint, only applicable for builders
+    pub capabilities: Option<endpoint::builder::Capabilities>,
+    /// Labels the remote endpoint should assign us
+    pub labels: endpoint::Labels,
+    /// Lifetimes of tokens issued to remote endpoints
+    pub token: token::Config,
 }
 
 impl From<Issuer> for service_core::endpoint::enrollment::Issuer {
@@ -38,6 +48,8 @@ impl From<Issuer> for service_core::endpoint::enrollment::Issuer {
             key_pair,
             host_address,
             role,
+            capabilities,
+            labels,
             ..
         } = issuer;
 
@@ -45,6 +57,8 @@ impl From<Issuer> for service_core::endpoint::enrollment::Issuer {
             public_key: key_pair.public_key().encode().to_string(),
             url: host_address.to_string(),
             role,
+            capabilities,
+            labels,
         }
     }
 }
@@ -60,6 +74,10 @@ pub struct Remote {
     pub role: Role,
     /// Bearer token assigned to us by the remote endpoint
     pub bearer_token: VerifiedToken,
+    /// Capabilities advertised by the remote endpoint
+    pub capabilities: Option<endpoint::builder::Capabilities>,
+    /// Labels requested by the remote endpoint
+    pub labels: endpoint::Labels,
 }
 
 /// A received enrollment request
@@ -84,10 +102,13 @@ pub struct Sent {
     pub target: Target,
     /// Bearer token we've issued and sent along w/ the request
     pub bearer_token: VerifiedToken,
+    /// Re-enrolls an existing endpoint whose public key changed, such as
+    /// after it was reimaged, updating it's endpoint & account when accepted
+    pub reenroll: bool,
 }
 
 /// The target of a [`Sent`] enrollment
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Target {
     /// [`Uri`] the target endpoint can be reached at
     #[serde(with = "http_serde::uri")]
@@ -98,90 +119,251 @@ pub struct Target {
     pub role: Role,
 }
 
-/// Send auto-enrollment to the list of targets if the endpoint isn't already configured
-pub(crate) async fn auto_enrollment(targets: &[Target], ourself: Issuer, state: &State) -> Result<(), Error> {
-    let mut conn = state.service_db.acquire().await?;
+/// A downstream service which is allowed to be enrolled with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Allowed {
+    /// [`PublicKey`] of the allowed endpoint
+    pub public_key: PublicKey,
+    /// Allowed endpoint role
+    pub role: Role,
+}
+
+impl Allowed {
+    /// Returns true if the [`Target`] matches this allowed endpoint
+    pub fn matches(&self, target: &Target) -> bool {
+        self.public_key == target.public_key && self.role == target.role
+    }
+}
+
+/// Retry policy used when sending enrollment requests
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Retry {
+    /// Maximum number of attempts before giving up
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    /// Delay before the first retry in seconds, doubled for each subsequent retry
+    #[serde(default = "default_retry_base_delay")]
+    pub base_delay_secs: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: default_retry_attempts(),
+            base_delay_secs: default_retry_base_delay(),
+        }
+    }
+}
 
-    let endpoints = Endpoint::list(conn.as_mut()).await.map_err(Error::ListEndpoints)?;
+impl Retry {
+    /// Delay before retrying after the provided (1-based) failed attempt
+    fn delay(&self, attempt: u32) -> Duration {
+        Duration::from_secs(self.base_delay_secs.saturating_mul(2u64.saturating_pow(attempt - 1)))
+    }
+}
 
-    for target in targets {
-        let mut enrolled = false;
+fn default_retry_attempts() -> u32 {
+    5
+}
 
+fn default_retry_base_delay() -> u64 {
+    2
+}
+
+/// Send auto-enrollment to the list of targets if the endpoint isn't already configured
+///
+/// If an `allowlist` is provided, targets not matching any entry are skipped. If
+/// `resolve` is set, targets whose host can't be resolved are skipped.
+///
+/// A target w/ the same host address & role as an existing endpoint but a different
+/// public key is re-enrolled, see [`reenroll`]. Since targets are only defined by
+/// the admin, a host can't take over an existing endpoint by changing it's key.
+pub(crate) async fn auto_enrollment(
+    targets: &[Target],
+    allowlist: Option<&[Allowed]>,
+    retry: Retry,
+    resolve: bool,
+    ourself: Issuer,
+    state: &State,
+) -> Result<(), Error> {
+    let endpoints = Endpoint::list(state.service_db.acquire().await?.as_mut())
+        .await
+        .map_err(Error::ListEndpoints)?;
+
+    // Concurrently, so retrying an unreachable target doesn't delay the others
+    let enrollments = targets.iter().map(|target| {
         let span = info_span!(
             "auto_enrollment",
             url = %target.host_address,
-            public_key = %target.public_key,
+            public_key = %target.public_key.fingerprint(),
             role = %target.role,
         );
-        let _guard = span.enter();
 
-        if let Some(endpoint) = endpoints.iter().find(|e| e.host_address == target.host_address) {
-            let account = Account::get(conn.as_mut(), endpoint.account)
-                .await
-                .map_err(Error::ReadAccount)?;
+        auto_enroll_target(target, &endpoints, allowlist, retry, resolve, ourself.clone(), state).instrument(span)
+    });
 
-            if account.public_key == target.public_key.encode() {
-                enrolled = true;
+    future::join_all(enrollments).await.into_iter().collect()
+}
 
-                debug!("Endpoint already enrolled");
-            }
+async fn auto_enroll_target(
+    target: &Target,
+    endpoints: &[Endpoint],
+    allowlist: Option<&[Allowed]>,
+    retry: Retry,
+    resolve: bool,
+    ourself: Issuer,
+    state: &State,
+) -> Result<(), Error> {
+    let mut existing = None;
+
+    if let Some(allowlist) = allowlist {
+        if !allowlist.iter().any(|allowed| allowed.matches(target)) {
+            warn!("Downstream target not on allowlist, skipping enrollment");
+            return Ok(());
         }
+    }
 
-        if !enrolled {
-            debug!("Sending enrollment request");
+    if state.pending_sent.any(|sent| sent.target == *target).await {
+        debug!("Enrollment already pending");
+        return Ok(());
+    }
 
-            let Ok(enrollment) = send(target.clone(), ourself.clone())
-                .await
-                .inspect_err(|e| error!(error=%error::chain(e), "Enrollment request failed"))
-            else {
-                continue;
-            };
+    if let Some(endpoint) = endpoints.iter().find(|e| e.host_address == target.host_address) {
+        let account = Account::get(state.service_db.acquire().await?.as_mut(), endpoint.account)
+            .await
+            .map_err(Error::ReadAccount)?;
+
+        if account.public_key == target.public_key.encode() {
+            debug!("Endpoint already enrolled");
+            return Ok(());
+        } else if endpoint.kind.role() == target.role {
+            warn!(endpoint = %endpoint.id, "Endpoint public key changed, re-enrolling existing endpoint");
+
+            existing = Some(endpoint);
+        }
+    }
+
+    if resolve {
+        if let Err(e) = client::resolve(&target.host_address).await {
+            error!(error = %error::chain(e), "Downstream host can't be resolved, skipping enrollment");
+            return Ok(());
+        }
+    }
+
+    debug!("Sending enrollment request");
+
+    let sent = match existing {
+        Some(endpoint) => reenroll(endpoint, target.clone(), ourself, retry).await,
+        None => send(target.clone(), ourself, retry).await,
+    };
 
+    match sent {
+        Ok(enrollment) => {
             state.pending_sent.insert(enrollment.endpoint, enrollment).await;
 
             info!("Enrollment sent");
         }
+        Err(e) => error!(error = %error::chain(e), "Enrollment request failed"),
     }
 
     Ok(())
 }
 
+/// Targets of `reloaded` which aren't in `current`, such as those added to
+/// [`Config::downstream`](crate::Config::downstream) when it's reloaded
+pub(crate) fn added_targets(current: &[Target], reloaded: &[Target]) -> Vec<Target> {
+    reloaded
+        .iter()
+        .filter(|target| !current.contains(target))
+        .cloned()
+        .collect()
+}
+
+/// Create and send an enrollment request to [`Target`]
+///
+/// Transient failures, such as the target being unreachable, are retried with
+/// exponential backoff according to [`Retry`]. Rejections fail immediately.
+pub async fn send(target: Target, ourself: Issuer, retry: Retry) -> Result<Sent, Error> {
+    let endpoint = endpoint::Id::generate();
+    let account = account::Id::generate();
+
+    debug!(%endpoint, %account, "Generated endpoint & account IDs for enrollment request");
+
+    send_as(endpoint, account, false, target, ourself, retry).await
+}
+
+/// Send an enrollment request to [`Target`] which re-enrolls the `existing` endpoint
+/// at it's host address, such as after it was reimaged w/ a new key pair
+///
+/// Once accepted, the existing endpoint & it's service account are updated w/ the new
+/// public key & tokens rather than creating duplicates for the same host.
+pub async fn reenroll(existing: &Endpoint, target: Target, ourself: Issuer, retry: Retry) -> Result<Sent, Error> {
+    send_as(existing.id, existing.account, true, target, ourself, retry).await
+}
+
 #[tracing::instrument(
-    name = "send_enrollment", 
+    name = "send_enrollment",
     skip_all,
     fields(
-        public_key = %target.public_key,
+        public_key = %target.public_key.fingerprint(),
         url = %target.host_address,
         role = %target.role,
+        reenroll,
     )
 )]
-/// Create and send an enrollment request to [`Target`]
-pub async fn send(target: Target, ourself: Issuer) -> Result<Sent, Error> {
-    let endpoint = endpoint::Id::generate();
-    let account = account::Id::generate();
-
-    debug!(%endpoint, %account, "Generated endpoint & account IDs for enrollment request");
-
-    let bearer_token = endpoint::create_token(token::Purpose::Authorization, endpoint, account, target.role, &ourself)?;
+async fn send_as(
+    endpoint: endpoint::Id,
+    account: account::Id,
+    reenroll: bool,
+    target: Target,
+    ourself: Issuer,
+    retry: Retry,
+) -> Result<Sent, Error> {
+    let bearer_token = endpoint::create_token(
+        token::Purpose::Authorization,
+        endpoint,
+        account,
+        token::Audience::Service(ourself.role),
+        &ourself,
+    )?;
 
     let client = Client::new(target.host_address.clone());
-
-    let resp = client
-        .send::<api::v1::services::Enroll>(&api::v1::services::EnrollRequestBody {
-            request: Request {
-                issuer: ourself.into(),
-                issue_token: bearer_token.encoded.clone(),
-                role: target.role,
-            },
-        })
-        .await;
+    let body = api::v1::services::EnrollRequestBody {
+        request: Request {
+            issuer: ourself.into(),
+            issue_token: bearer_token.encoded.clone(),
+            role: target.role,
+        },
+    };
+
+    let mut attempt = 1;
+
+    let resp = loop {
+        match client.send::<api::v1::services::Enroll>(&body).await {
+            Err(e) if e.is_transient() && attempt < retry.attempts => {
+                let delay = retry.delay(attempt);
+
+                warn!(
+                    attempt,
+                    attempts = retry.attempts,
+                    error = %error::chain(&e),
+                    "Enrollment request failed, retrying in {}s",
+                    delay.as_secs(),
+                );
+
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            resp => break resp,
+        }
+    };
 
     match resp {
         Ok(_) => {
             info!(
                 %endpoint,
                 %account,
-                public_key = %target.public_key,
+                public_key = %target.public_key.fingerprint(),
                 url = %target.host_address,
                 role = %target.role,
                 "Enrollment request sent"
@@ -192,6 +374,7 @@ pub async fn send(target: Target, ourself: Issuer) -> Result<Sent, Error> {
                 account,
                 target,
                 bearer_token,
+                reenroll,
             })
         }
         Err(error) => Err(Error::Client(error)),
@@ -206,7 +389,7 @@ impl Received {
         fields(
             endpoint = %self.endpoint,
             account = %self.account,
-            public_key = %self.remote.public_key,
+            public_key = %self.remote.public_key.fingerprint(),
             url = %self.remote.host_address,
             role = %self.remote.role,
         )
@@ -225,13 +408,7 @@ impl Received {
         info!(username, "Created a new service account");
 
         let endpoint_id = self.endpoint;
-        let kind = match self.remote.role {
-            Role::Builder => endpoint::Kind::Builder(endpoint::builder::Extension {
-                work_status: endpoint::builder::WorkStatus::Idle,
-            }),
-            Role::RepositoryManager => endpoint::Kind::RepositoryManager,
-            Role::Hub => endpoint::Kind::Hub,
-        };
+        let kind = endpoint_kind(self.remote.role, self.remote.capabilities.clone());
 
         let mut endpoint = Endpoint {
             id: endpoint_id,
@@ -242,6 +419,10 @@ impl Received {
             kind,
         };
         endpoint.save(&mut tx).await.map_err(Error::CreateEndpoint)?;
+        endpoint
+            .request_labels(&mut tx, &self.remote.labels)
+            .await
+            .map_err(Error::CreateEndpoint)?;
 
         endpoint::Tokens {
             bearer_token: Some(self.remote.bearer_token.encoded.clone()),
@@ -257,7 +438,7 @@ impl Received {
             token::Purpose::Authorization,
             endpoint_id,
             account_id,
-            self.remote.role,
+            token::Audience::Service(ourself.role),
             &ourself,
         )?;
 
@@ -286,8 +467,10 @@ impl Received {
 
         match resp {
             Ok(_) => {
-                endpoint.status = endpoint::Status::Operational;
-                endpoint.save(&mut tx).await.map_err(Error::UpdateEndpointStatus)?;
+                endpoint
+                    .set_status(&mut tx, endpoint::Status::Operational)
+                    .await
+                    .map_err(Error::UpdateEndpointStatus)?;
 
                 tx.commit().await?;
 
@@ -296,9 +479,14 @@ impl Received {
                 Ok(())
             }
             Err(error) => {
-                endpoint.status = endpoint::Status::Failed;
-                endpoint.error = Some(error.to_string());
-                endpoint.save(&mut tx).await.map_err(Error::UpdateEndpointStatus)?;
+                endpoint
+                    .set_status(&mut tx, endpoint::Status::Failed)
+                    .await
+                    .map_err(Error::UpdateEndpointStatus)?;
+                endpoint
+                    .set_error(&mut tx, Some(error.to_string()))
+                    .await
+                    .map_err(Error::UpdateEndpointStatus)?;
 
                 tx.commit().await?;
 
@@ -329,7 +517,7 @@ impl Sent {
         fields(
             endpoint = %self.endpoint,
             account = %self.account,
-            public_key = %self.target.public_key,
+            public_key = %self.target.public_key.fingerprint(),
             url = %self.target.host_address,
             role = %self.target.role,
         )
@@ -341,6 +529,12 @@ impl Sent {
                 actual: remote.public_key.encode(),
             });
         }
+        if remote.role != self.target.role {
+            return Err(Error::RoleMismatch {
+                expected: self.target.role,
+                actual: remote.role,
+            });
+        }
 
         let account = self.account;
         let username = format!("@{account}");
@@ -354,26 +548,33 @@ impl Sent {
             email: None,
             name: None,
             public_key: self.target.public_key.encode(),
+            deleted_at: None,
         }
         .save(&mut tx)
         .await
         .map_err(Error::CreateServiceAccount)?;
 
-        info!(username, "Created a new service account");
+        if self.reenroll {
+            info!(username, "Updated public key of existing service account");
+        } else {
+            info!(username, "Created a new service account");
+        }
 
         let endpoint = self.endpoint;
 
-        Endpoint {
+        let created = Endpoint {
             id: endpoint,
             host_address: self.target.host_address.clone(),
             status: endpoint::Status::Operational,
             error: None,
             account,
-            kind: endpoint::Kind::Hub,
-        }
-        .save(&mut tx)
-        .await
-        .map_err(Error::CreateEndpoint)?;
+            kind: endpoint_kind(self.target.role, remote.capabilities),
+        };
+        created.save(&mut tx).await.map_err(Error::CreateEndpoint)?;
+        created
+            .request_labels(&mut tx, &remote.labels)
+            .await
+            .map_err(Error::CreateEndpoint)?;
 
         endpoint::Tokens {
             bearer_token: Some(remote.bearer_token.encoded),
@@ -383,7 +584,11 @@ impl Sent {
         .await
         .map_err(Error::SetEndpointAccountToken)?;
 
-        info!("Created a new endpoint for the service account");
+        if self.reenroll {
+            info!("Updated existing endpoint for the service account");
+        } else {
+            info!("Created a new endpoint for the service account");
+        }
 
         account::Token::set(
             &mut tx,
@@ -405,10 +610,53 @@ impl Sent {
 
         Ok(())
     }
+
+    /// Cancel the sent enrollment before it's accepted, removing it from pending
+    /// enrollments and notifying the target so it can drop the received request
+    ///
+    /// The enrollment is no longer pending even if notifying the target fails, so
+    /// any later acceptance from the target will be rejected
+    #[tracing::instrument(
+        name = "cancel_enrollment",
+        skip_all,
+        fields(
+            endpoint = %self.endpoint,
+            public_key = %self.target.public_key.fingerprint(),
+            url = %self.target.host_address,
+            role = %self.target.role,
+        )
+    )]
+    pub async fn cancel(self, pending_sent: &SharedMap<endpoint::Id, Sent>) -> Result<(), Error> {
+        if pending_sent.remove(&self.endpoint).await.is_none() {
+            return Err(Error::NotPending(self.endpoint));
+        }
+
+        info!("Enrollment cancelled");
+
+        Client::new(self.target.host_address)
+            .send::<api::v1::services::CancelEnrollment>(&api::v1::services::CancelEnrollmentBody {
+                issue_token: self.bearer_token.encoded,
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn endpoint_kind(role: Role, capabilities: Option<endpoint::builder::Capabilities>) -> endpoint::Kind {
+    match role {
+        Role::Builder => endpoint::Kind::Builder(endpoint::builder::Extension {
+            work_status: endpoint::builder::WorkStatus::Idle,
+            capabilities,
+        }),
+        Role::RepositoryManager => endpoint::Kind::RepositoryManager,
+        Role::Hub => endpoint::Kind::Hub,
+    }
 }
 
 /// An enrollment error
-#[derive(Debug, Error)]
+#[derive(Debug, Error, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum Error {
     /// Reading an [`Account`] failed
     #[error("read account")]
@@ -439,6 +687,17 @@ pub enum Error {
         /// The actual key
         actual: EncodedPublicKey,
     },
+    /// Enrollment isn't pending, it was already accepted, declined or cancelled
+    #[error("enrollment for endpoint {0} isn't pending")]
+    NotPending(endpoint::Id),
+    /// Remote's reported role doesn't match the role it was enrolled as
+    #[error("role mismatch, expected {expected} got {actual}")]
+    RoleMismatch {
+        /// The expected role
+        expected: Role,
+        /// The actual role
+        actual: Role,
+    },
     /// Token signing failed
     #[error("sign token")]
     SignToken(#[from] token::Error),
@@ -449,3 +708,383 @@ pub enum Error {
     #[error("database")]
     Database(#[from] database::Error),
 }
+
+impl api::ErrorCode for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Error::SignToken(error) => api::ErrorCode::code(error),
+            _ => self.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hub_issuer(key_pair: KeyPair) -> Issuer {
+        Issuer {
+            key_pair,
+            host_address: "http://127.0.0.1:5000".parse().unwrap(),
+            role: Role::Hub,
+            description: "hub".to_string(),
+            admin_name: "admin".to_string(),
+            admin_email: "admin@example.com".to_string(),
+            capabilities: None,
+            labels: endpoint::Labels::default(),
+            token: token::Config::default(),
+        }
+    }
+
+    /// Serve `handler` as the enroll operation of a target, returning it's address
+    async fn mock_target<H, T>(handler: H) -> std::net::SocketAddr
+    where
+        H: axum::handler::Handler<T, ()>,
+        T: 'static,
+    {
+        use api::Operation;
+
+        let router = axum::Router::new().route(
+            &format!(
+                "/api/{}/{}",
+                api::v1::services::Enroll::VERSION,
+                api::v1::services::Enroll::PATH
+            ),
+            axum::routing::post(handler),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn accepted_role_mismatch() {
+        let db = database::test::temp().await;
+
+        let hub = hub_issuer(KeyPair::generate());
+        let remote = Issuer {
+            key_pair: KeyPair::generate(),
+            host_address: "http://127.0.0.1:5001".parse().unwrap(),
+            role: Role::Builder,
+            ..hub.clone()
+        };
+
+        let endpoint = endpoint::Id::generate();
+        let account = account::Id::generate();
+
+        let sent = Sent {
+            endpoint,
+            account,
+            target: Target {
+                host_address: remote.host_address.clone(),
+                public_key: remote.key_pair.public_key(),
+                role: Role::RepositoryManager,
+            },
+            bearer_token: endpoint::create_token(
+                token::Purpose::Authorization,
+                endpoint,
+                account,
+                token::Audience::Service(Role::RepositoryManager),
+                &hub,
+            )
+            .unwrap(),
+            reenroll: false,
+        };
+
+        // Remote claims to be a builder when we enrolled it as a repository manager
+        let result = sent
+            .accepted(
+                &db,
+                Remote {
+                    public_key: remote.key_pair.public_key(),
+                    host_address: remote.host_address.clone(),
+                    role: remote.role,
+                    bearer_token: endpoint::create_token(
+                        token::Purpose::Authorization,
+                        endpoint,
+                        account,
+                        token::Audience::Service(Role::Hub),
+                        &remote,
+                    )
+                    .unwrap(),
+                    capabilities: None,
+                    labels: endpoint::Labels::default(),
+                },
+            )
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(Error::RoleMismatch {
+                expected: Role::RepositoryManager,
+                actual: Role::Builder
+            })
+        ));
+        assert!(Endpoint::list(db.acquire().await.unwrap().as_mut())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn reenroll_existing_endpoint() {
+        let db = database::test::temp().await;
+
+        let hub = hub_issuer(KeyPair::generate());
+        let host_address: Uri = "http://127.0.0.1:5001".parse().unwrap();
+
+        let endpoint = endpoint::Id::generate();
+        let account = account::Id::generate();
+
+        let accept = |key_pair: &KeyPair, reenroll| {
+            let remote = Issuer {
+                key_pair: key_pair.clone(),
+                host_address: host_address.clone(),
+                role: Role::Builder,
+                ..hub.clone()
+            };
+            let sent = Sent {
+                endpoint,
+                account,
+                target: Target {
+                    host_address: host_address.clone(),
+                    public_key: key_pair.public_key(),
+                    role: Role::Builder,
+                },
+                bearer_token: endpoint::create_token(
+                    token::Purpose::Authorization,
+                    endpoint,
+                    account,
+                    token::Audience::Service(Role::Builder),
+                    &hub,
+                )
+                .unwrap(),
+                reenroll,
+            };
+            let remote = Remote {
+                public_key: key_pair.public_key(),
+                host_address: host_address.clone(),
+                role: Role::Builder,
+                bearer_token: endpoint::create_token(
+                    token::Purpose::Authorization,
+                    endpoint,
+                    account,
+                    token::Audience::Service(Role::Hub),
+                    &remote,
+                )
+                .unwrap(),
+                capabilities: None,
+                labels: endpoint::Labels::default(),
+            };
+            let db = Database::clone(&db);
+
+            async move { sent.accepted(&db, remote).await }
+        };
+
+        accept(&KeyPair::generate(), false).await.unwrap();
+
+        // Reimaged w/ a new key pair
+        let reimaged = KeyPair::generate();
+        accept(&reimaged, true).await.unwrap();
+
+        let mut conn = db.acquire().await.unwrap();
+        let endpoints = Endpoint::list(conn.as_mut()).await.unwrap();
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints[0].id, endpoint);
+
+        let account = Account::get(conn.as_mut(), account).await.unwrap();
+        assert_eq!(account.public_key, reimaged.public_key().encode());
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let root = std::env::temp_dir().join(format!("enrollment-{}", uuid::Uuid::new_v4()));
+        let state = State::load(&root).await.unwrap();
+
+        let hub = hub_issuer(state.key_pair.clone());
+
+        let endpoint = endpoint::Id::generate();
+        let account = account::Id::generate();
+
+        let sent = Sent {
+            endpoint,
+            account,
+            target: Target {
+                // Nothing listens here
+                host_address: "http://127.0.0.1:1".parse().unwrap(),
+                public_key: KeyPair::generate().public_key(),
+                role: Role::Builder,
+            },
+            bearer_token: endpoint::create_token(
+                token::Purpose::Authorization,
+                endpoint,
+                account,
+                token::Audience::Service(Role::Hub),
+                &hub,
+            )
+            .unwrap(),
+            reenroll: false,
+        };
+
+        state.pending_sent.insert(endpoint, sent.clone()).await;
+
+        // Target can't be notified but the enrollment is no longer pending
+        let notify = sent.clone().cancel(&state.pending_sent).await;
+        let again = sent.cancel(&state.pending_sent).await;
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert!(matches!(notify, Err(Error::Client(_))));
+        assert!(matches!(again, Err(Error::NotPending(id)) if id == endpoint));
+    }
+
+    #[tokio::test]
+    async fn auto_enrollment_retry() {
+        use std::sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        };
+
+        use http::StatusCode;
+
+        let root = std::env::temp_dir().join(format!("enrollment-{}", uuid::Uuid::new_v4()));
+        let state = State::load(&root).await.unwrap();
+
+        // Unavailable for the first 2 attempts
+        let attempts = Arc::new(AtomicU32::new(0));
+        let addr = mock_target({
+            let attempts = attempts.clone();
+
+            || async move {
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    StatusCode::OK
+                }
+            }
+        })
+        .await;
+
+        let hub = hub_issuer(state.key_pair.clone());
+        let target = Target {
+            host_address: format!("http://{addr}").parse().unwrap(),
+            public_key: KeyPair::generate().public_key(),
+            role: Role::Builder,
+        };
+        let retry = |attempts| Retry {
+            attempts,
+            base_delay_secs: 0,
+        };
+
+        // Gives up before it's available
+        auto_enrollment(
+            std::slice::from_ref(&target),
+            None,
+            retry(2),
+            false,
+            hub.clone(),
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(state.pending_sent.remove_where(|_| true).await.is_none());
+
+        attempts.store(0, Ordering::SeqCst);
+
+        auto_enrollment(std::slice::from_ref(&target), None, retry(3), false, hub, &state)
+            .await
+            .unwrap();
+        let pending = state.pending_sent.remove_where(|_| true).await;
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(matches!(pending, Some(sent) if sent.target.host_address == target.host_address));
+    }
+
+    #[tokio::test]
+    async fn auto_enrollment_allowlist() {
+        use std::sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        };
+
+        let root = std::env::temp_dir().join(format!("enrollment-{}", uuid::Uuid::new_v4()));
+        let state = State::load(&root).await.unwrap();
+
+        let requests = Arc::new(AtomicU32::new(0));
+        let addr = mock_target({
+            let requests = requests.clone();
+
+            || async move {
+                requests.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .await;
+
+        let hub = hub_issuer(state.key_pair.clone());
+        let target = |public_key: PublicKey, role| Target {
+            host_address: format!("http://{addr}").parse().unwrap(),
+            public_key,
+            role,
+        };
+
+        let allowed = KeyPair::generate().public_key();
+        let allowlist = [Allowed {
+            public_key: allowed,
+            role: Role::Builder,
+        }];
+        let targets = [
+            target(allowed, Role::Builder),
+            // Key isn't allowed
+            target(KeyPair::generate().public_key(), Role::Builder),
+            // Key is only allowed for another role
+            target(allowed, Role::RepositoryManager),
+        ];
+
+        auto_enrollment(&targets, Some(&allowlist), Retry::default(), false, hub.clone(), &state)
+            .await
+            .unwrap();
+
+        // Not sent again while pending
+        auto_enrollment(&targets, Some(&allowlist), Retry::default(), false, hub, &state)
+            .await
+            .unwrap();
+
+        let pending = state.pending_sent.remove_where(|_| true).await;
+        let more = state.pending_sent.remove_where(|_| true).await;
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(
+            matches!(pending, Some(sent) if sent.target.public_key == allowed && sent.target.role == Role::Builder)
+        );
+        assert!(more.is_none());
+    }
+
+    #[test]
+    fn added_downstream_targets() {
+        let target = |host: &str| Target {
+            host_address: host.parse().unwrap(),
+            public_key: KeyPair::generate().public_key(),
+            role: Role::Builder,
+        };
+
+        let kept = target("http://kept");
+        let removed = target("http://removed");
+        let added = target("http://added");
+        let rekeyed = Target {
+            public_key: KeyPair::generate().public_key(),
+            ..kept.clone()
+        };
+
+        let current = [kept.clone(), removed];
+        assert_eq!(added_targets(&current, &[kept.clone(), added.clone()]), [added]);
+        assert_eq!(added_targets(&current, std::slice::from_ref(&rekeyed)), [rekeyed]);
+        assert!(added_targets(&current, &[kept]).is_empty());
+    }
+}
@@ -0,0 +1,525 @@
+//! Periodically ping endpoints to detect ones which have silently
+//! gone away (half-open connections, firewall drops, etc) and ones
+//! which have since recovered
+//!
+//! Pings are authenticated w/ the endpoint's tokens, so an endpoint which
+//! became unreachable because it's tokens couldn't be refreshed only recovers
+//! once they can be.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::{api, client, config, database, endpoint, error, Client, Database, Endpoint};
+
+/// How often endpoints are checked for a due ping
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait for a ping response
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Consecutive failed pings before an endpoint is marked [`endpoint::Status::Unreachable`]
+const MAX_FAILURES: u32 = 3;
+
+/// Keepalive configuration
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Config {
+    /// How often a healthy or unreachable endpoint is pinged, in seconds
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_interval(),
+        }
+    }
+}
+
+impl Config {
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+/// Ping all operational & unreachable endpoints until the task is cancelled
+pub(crate) async fn run(db: Database, config: config::Live) -> Result<(), Infallible> {
+    let mut tracker = Tracker::new(config.load().keepalive.interval());
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // Pick up any reloaded interval
+        tracker.interval = config.load().keepalive.interval();
+
+        if let Err(e) = check(&db, &mut tracker).await {
+            error!(error = %error::chain(e), "Endpoint keepalive failed");
+        }
+    }
+}
+
+async fn check(db: &Database, tracker: &mut Tracker) -> Result<(), database::Error> {
+    let now = Instant::now();
+
+    let endpoints = Endpoint::list(db.acquire().await?.as_mut())
+        .await?
+        .into_iter()
+        .filter(|endpoint| {
+            matches!(
+                endpoint.status,
+                endpoint::Status::Operational | endpoint::Status::Unreachable
+            )
+        })
+        .collect::<Vec<_>>();
+
+    tracker.retain(|id| endpoints.iter().any(|endpoint| endpoint.id == *id));
+
+    let mut pings = JoinSet::new();
+
+    for endpoint in endpoints
+        .into_iter()
+        .filter(|endpoint| tracker.is_due(endpoint.id, now))
+    {
+        let span = info_span!("ping", endpoint = %endpoint.id, url = %endpoint.host_address);
+
+        let db = db.clone();
+
+        pings.spawn(
+            async move {
+                let result = ping(&endpoint, db).await;
+                (endpoint.id, endpoint.status, result)
+            }
+            .instrument(span),
+        );
+    }
+
+    while let Some(joined) = pings.join_next().await {
+        let Ok((id, status, result)) = joined else {
+            continue;
+        };
+
+        handle(db, tracker, id, status, result).await?;
+    }
+
+    Ok(())
+}
+
+/// Update the endpoint & it's tracked health from the `result` of pinging it
+async fn handle(
+    db: &Database,
+    tracker: &mut Tracker,
+    id: endpoint::Id,
+    status: endpoint::Status,
+    result: Result<(), Error>,
+) -> Result<(), database::Error> {
+    let unreachable = matches!(status, endpoint::Status::Unreachable);
+
+    match result {
+        Ok(()) => {
+            tracker.reset(id, Instant::now());
+
+            if unreachable {
+                mark_operational(db, id).await?;
+            }
+        }
+        // Already unreachable, keep probing at the normal interval
+        Err(e) if unreachable => {
+            debug!(endpoint = %id, error = %error::chain(e), "Unreachable endpoint ping failed");
+
+            tracker.reset(id, Instant::now());
+        }
+        // Host no longer resolves, no point backing off
+        Err(Error::Client(e @ client::Error::Resolve(_))) if !e.is_transient() => {
+            let error = error::chain(Error::Client(e));
+
+            warn!(endpoint = %id, %error, "Endpoint host can't be resolved");
+
+            tracker.reset(id, Instant::now());
+            mark_unreachable(db, id, error).await?;
+        }
+        Err(e) => {
+            let error = error::chain(e);
+
+            warn!(endpoint = %id, %error, "Endpoint ping failed");
+
+            if tracker.failed(id, Instant::now()) {
+                warn!(endpoint = %id, failures = MAX_FAILURES, "Endpoint failed consecutive pings");
+
+                mark_unreachable(db, id, error).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Ping w/ the endpoint's tokens, so it's only considered healthy if we can
+/// still authenticate with it
+async fn ping(endpoint: &Endpoint, db: Database) -> Result<(), Error> {
+    tokio::time::timeout(
+        PING_TIMEOUT,
+        Client::new(endpoint.host_address.clone())
+            .with_endpoint_auth(endpoint, db)
+            .send::<api::v1::services::Ping>(&()),
+    )
+    .await
+    .map_err(|_| Error::Timeout)??;
+
+    debug!("Endpoint responded to ping");
+
+    Ok(())
+}
+
+async fn mark_unreachable(db: &Database, id: endpoint::Id, error: String) -> Result<(), database::Error> {
+    let mut tx = db.begin().await?;
+
+    let mut endpoint = Endpoint::get(tx.as_mut(), id).await?;
+
+    // Status could have changed while we were pinging
+    if !matches!(endpoint.status, endpoint::Status::Operational) {
+        return Ok(());
+    }
+
+    endpoint.set_status(&mut tx, endpoint::Status::Unreachable).await?;
+    endpoint.set_error(&mut tx, Some(error)).await?;
+
+    tx.commit().await?;
+
+    warn!(endpoint = %id, "Endpoint marked unreachable");
+
+    Ok(())
+}
+
+async fn mark_operational(db: &Database, id: endpoint::Id) -> Result<(), database::Error> {
+    let mut tx = db.begin().await?;
+
+    let mut endpoint = Endpoint::get(tx.as_mut(), id).await?;
+
+    // Status could have changed while we were pinging
+    if !matches!(endpoint.status, endpoint::Status::Unreachable) {
+        return Ok(());
+    }
+
+    endpoint.set_status(&mut tx, endpoint::Status::Operational).await?;
+    endpoint.set_error(&mut tx, None).await?;
+
+    tx.commit().await?;
+
+    info!(endpoint = %id, "Endpoint recovered, marked operational");
+
+    Ok(())
+}
+
+/// Tracks consecutive ping failures of each endpoint, backing off
+/// exponentially between retries of a failing endpoint
+#[derive(Debug)]
+struct Tracker {
+    /// How long to wait between pings of a healthy endpoint
+    interval: Duration,
+    endpoints: HashMap<endpoint::Id, Health>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Health {
+    failures: u32,
+    next_ping: Instant,
+}
+
+impl Tracker {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            endpoints: HashMap::new(),
+        }
+    }
+
+    fn is_due(&self, id: endpoint::Id, now: Instant) -> bool {
+        self.endpoints.get(&id).is_none_or(|health| health.next_ping <= now)
+    }
+
+    /// Clear any failures and wait the full interval before the next ping
+    fn reset(&mut self, id: endpoint::Id, now: Instant) {
+        self.endpoints.insert(
+            id,
+            Health {
+                failures: 0,
+                next_ping: now + self.interval,
+            },
+        );
+    }
+
+    /// Returns true once the endpoint has failed [`MAX_FAILURES`] in a row
+    fn failed(&mut self, id: endpoint::Id, now: Instant) -> bool {
+        let failures = self.endpoints.get(&id).map_or(0, |health| health.failures) + 1;
+
+        if failures >= MAX_FAILURES {
+            self.reset(id, now);
+            return true;
+        }
+
+        let backoff = (CHECK_INTERVAL * 2u32.pow(failures - 1)).min(self.interval);
+
+        self.endpoints.insert(
+            id,
+            Health {
+                failures,
+                next_ping: now + backoff,
+            },
+        );
+
+        false
+    }
+
+    fn retain(&mut self, f: impl Fn(&endpoint::Id) -> bool) {
+        self.endpoints.retain(|id, _| f(id));
+    }
+}
+
+/// A keepalive ping error
+#[derive(Debug, thiserror::Error)]
+enum Error {
+    /// Endpoint didn't respond in time
+    #[error("timed out")]
+    Timeout,
+    /// Client error
+    #[error("client")]
+    Client(#[from] client::Error<client::EndpointAuthError>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unreachable_after_failed_pings() {
+        const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+        let mut tracker = Tracker::new(PING_INTERVAL);
+        let id = endpoint::Id::generate();
+        let now = Instant::now();
+
+        assert!(tracker.is_due(id, now));
+
+        // Failures back off before being retried
+        assert!(!tracker.failed(id, now));
+        assert!(!tracker.is_due(id, now));
+        assert!(tracker.is_due(id, now + CHECK_INTERVAL));
+
+        assert!(!tracker.failed(id, now));
+        assert!(!tracker.is_due(id, now + CHECK_INTERVAL));
+        assert!(tracker.is_due(id, now + CHECK_INTERVAL * 2));
+
+        // Final failure marks it unreachable, then it's probed
+        // at the normal interval for recovery
+        assert!(tracker.failed(id, now));
+        assert!(!tracker.is_due(id, now + CHECK_INTERVAL * 4));
+        assert!(tracker.is_due(id, now + PING_INTERVAL));
+
+        // A successful ping resets the failure count
+        assert!(!tracker.failed(id, now));
+        tracker.reset(id, now);
+        assert!(!tracker.is_due(id, now));
+        assert!(!tracker.failed(id, now + PING_INTERVAL));
+        assert!(!tracker.failed(id, now + PING_INTERVAL));
+        assert!(tracker.failed(id, now + PING_INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn unresolvable_host_marked_unreachable() {
+        use crate::{crypto::KeyPair, database, Account};
+
+        let db = database::test::temp().await;
+
+        let create = |host: &str| {
+            let db = db.clone();
+            let host_address = format!("http://{host}").parse().unwrap();
+
+            async move {
+                let id = endpoint::Id::generate();
+                let account = crate::account::Id::generate();
+
+                let mut tx = db.begin().await.unwrap();
+                Account::service(account, KeyPair::generate().public_key().encode())
+                    .save(&mut tx)
+                    .await
+                    .unwrap();
+                Endpoint {
+                    id,
+                    host_address,
+                    status: endpoint::Status::Operational,
+                    error: None,
+                    account,
+                    kind: endpoint::Kind::RepositoryManager,
+                }
+                .save(&mut tx)
+                .await
+                .unwrap();
+                tx.commit().await.unwrap();
+
+                id
+            }
+        };
+        let unresolvable = |host: &str, code| {
+            Err(Error::Client(client::Error::Resolve(Box::new(
+                client::ResolveError::new(host, code),
+            ))))
+        };
+        let get = |id| {
+            let db = db.clone();
+            async move { Endpoint::get(db.acquire().await.unwrap().as_mut(), id).await.unwrap() }
+        };
+
+        let mut tracker = Tracker::new(Duration::from_secs(60));
+
+        // A single failed resolution is enough if the host doesn't exist
+        let missing = create("missing.invalid").await;
+        handle(
+            &db,
+            &mut tracker,
+            missing,
+            endpoint::Status::Operational,
+            unresolvable("missing.invalid", libc::EAI_NONAME),
+        )
+        .await
+        .unwrap();
+
+        let endpoint = get(missing).await;
+        assert!(matches!(endpoint.status, endpoint::Status::Unreachable));
+        assert!(endpoint
+            .error
+            .is_some_and(|error| error.contains("resolve missing.invalid")));
+
+        // Temporary failures back off like any other failed ping
+        let flaky = create("flaky.invalid").await;
+        handle(
+            &db,
+            &mut tracker,
+            flaky,
+            endpoint::Status::Operational,
+            unresolvable("flaky.invalid", libc::EAI_AGAIN),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(get(flaky).await.status, endpoint::Status::Operational));
+        assert!(!tracker.is_due(flaky, Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn recovery_requires_auth() {
+        use api::Operation;
+
+        use crate::{crypto::KeyPair, database, token, Account, Token};
+
+        let db = database::test::temp().await;
+
+        let router = axum::Router::new().route(
+            &format!(
+                "/api/{}/{}",
+                api::v1::services::Ping::VERSION,
+                api::v1::services::Ping::PATH
+            ),
+            axum::routing::get(|headers: http::HeaderMap| async move {
+                if headers.contains_key(http::header::AUTHORIZATION) {
+                    http::StatusCode::OK
+                } else {
+                    http::StatusCode::UNAUTHORIZED
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host_address = format!("http://{}", listener.local_addr().unwrap())
+            .parse::<http::Uri>()
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let create = |authenticated: bool| {
+            let db = db.clone();
+            let host_address = host_address.clone();
+
+            async move {
+                let id = endpoint::Id::generate();
+                let account = crate::account::Id::generate();
+                let key_pair = KeyPair::generate();
+
+                let mut tx = db.begin().await.unwrap();
+                Account::service(account, key_pair.public_key().encode())
+                    .save(&mut tx)
+                    .await
+                    .unwrap();
+                Endpoint {
+                    id,
+                    host_address,
+                    status: endpoint::Status::Unreachable,
+                    error: Some("Failed to refresh access token".to_string()),
+                    account,
+                    kind: endpoint::Kind::RepositoryManager,
+                }
+                .save(&mut tx)
+                .await
+                .unwrap();
+
+                if authenticated {
+                    let now = chrono::Utc::now();
+                    let token = |purpose| {
+                        Token::new(token::Payload {
+                            aud: "test".into(),
+                            exp: (now + chrono::Duration::hours(1)).timestamp(),
+                            iat: now.timestamp(),
+                            nbf: None,
+                            iss: "test".into(),
+                            sub: "test".into(),
+                            purpose,
+                            account_id: account,
+                            account_type: crate::account::Kind::Service,
+                            admin: false,
+                            scope: None,
+                        })
+                        .sign(&key_pair)
+                        .unwrap()
+                    };
+
+                    endpoint::Tokens {
+                        bearer_token: Some(token(token::Purpose::Authorization)),
+                        access_token: Some(token(token::Purpose::Authentication)),
+                    }
+                    .save(&mut tx, id)
+                    .await
+                    .unwrap();
+                }
+
+                tx.commit().await.unwrap();
+
+                id
+            }
+        };
+
+        let authenticated = create(true).await;
+        let unauthenticated = create(false).await;
+
+        check(&db, &mut Tracker::new(Duration::from_secs(60))).await.unwrap();
+
+        let get = |id| {
+            let db = db.clone();
+            async move { Endpoint::get(db.acquire().await.unwrap().as_mut(), id).await.unwrap() }
+        };
+
+        let endpoint = get(authenticated).await;
+        assert!(matches!(endpoint.status, endpoint::Status::Operational));
+        assert_eq!(endpoint.error, None);
+
+        // Host responds, but we can't authenticate with it
+        let endpoint = get(unauthenticated).await;
+        assert!(matches!(endpoint.status, endpoint::Status::Unreachable));
+    }
+}
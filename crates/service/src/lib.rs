@@ -18,13 +18,18 @@ mod task;
 
 pub mod account;
 pub mod api;
+pub mod atomic_file;
+pub mod audit;
 pub mod client;
 pub mod config;
 pub mod crypto;
 pub mod database;
+pub mod deadline;
 pub mod endpoint;
 pub mod error;
+pub mod metrics;
 pub mod request;
+pub mod request_id;
 pub mod server;
 pub mod signal;
 pub mod state;
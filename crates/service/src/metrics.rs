@@ -0,0 +1,96 @@
+//! Prometheus metrics exposed by the built-in [`Server`]
+//!
+//! The recorder is installed globally, so consumers can record additional
+//! metrics via the [`::metrics`] macros, but it's only rendered at `/metrics`
+//! for services which opt in via [`Server::with_metrics`].
+//!
+//! [`Server`]: crate::Server
+//! [`Server::with_metrics`]: crate::Server::with_metrics
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use axum::{extract::State, routing::get};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use strum::IntoEnumIterator;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{database::Database, endpoint, error, Endpoint, Role};
+
+/// Total number of handled requests
+pub const REQUESTS_TOTAL: &str = "http_requests_total";
+/// Duration of handled requests
+pub const REQUEST_DURATION_SECONDS: &str = "http_request_duration_seconds";
+/// Number of requests currently being handled
+pub const REQUESTS_IN_FLIGHT: &str = "http_requests_in_flight";
+/// Number of endpoints by role & status
+pub const ENDPOINTS: &str = "endpoints";
+
+const DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Install the global prometheus recorder, if not already installed
+pub fn install() -> Result<PrometheusHandle, Error> {
+    if let Some(handle) = HANDLE.get() {
+        return Ok(handle.clone());
+    }
+
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(REQUEST_DURATION_SECONDS.to_string()), DURATION_BUCKETS)?
+        .install_recorder()?;
+
+    Ok(HANDLE.get_or_init(|| handle).clone())
+}
+
+/// Router which renders all metrics at `/metrics` in prometheus text format
+pub(crate) fn router(handle: PrometheusHandle, db: Database) -> axum::Router {
+    axum::Router::new()
+        .route("/metrics", get(render))
+        .with_state((handle, db))
+}
+
+async fn render(State((handle, db)): State<(PrometheusHandle, Database)>) -> String {
+    if let Err(e) = record_endpoints(&db).await {
+        warn!(error = %error::chain(e), "Failed to record endpoint metrics");
+    }
+
+    handle.render()
+}
+
+/// Endpoints are gauged at scrape time so counts are always in sync with the database
+async fn record_endpoints(db: &Database) -> Result<(), Error> {
+    let mut conn = db.acquire().await?;
+    let endpoints = Endpoint::list(conn.as_mut()).await?;
+
+    let mut counts = HashMap::<_, usize>::new();
+    for endpoint in &endpoints {
+        *counts
+            .entry((endpoint.kind.role().to_string(), endpoint.status.to_string()))
+            .or_default() += 1;
+    }
+
+    for role in [Role::Hub, Role::RepositoryManager, Role::Builder] {
+        let role = role.to_string();
+
+        for status in endpoint::Status::iter() {
+            let status = status.to_string();
+            let count = counts.get(&(role.clone(), status.clone())).copied().unwrap_or_default();
+
+            ::metrics::gauge!(ENDPOINTS, "role" => role.clone(), "status" => status).set(count as f64);
+        }
+    }
+
+    Ok(())
+}
+
+/// A metrics error
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Installing the prometheus recorder failed
+    #[error("install prometheus recorder")]
+    Install(#[from] BuildError),
+    /// Database error
+    #[error("database")]
+    Database(#[from] crate::database::Error),
+}
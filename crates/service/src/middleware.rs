@@ -2,8 +2,18 @@
 //!
 //! [`Server`]: crate::Server
 
+pub use self::audit::Audit;
+pub use self::concurrency_limit::ConcurrencyLimit;
+pub use self::deadline::Deadline;
 pub use self::extract_token::ExtractToken;
 pub use self::log::Log;
+pub use self::maintenance::Maintenance;
+pub use self::metrics::Metrics;
 
+pub mod audit;
+pub mod concurrency_limit;
+pub mod deadline;
 pub mod extract_token;
 pub mod log;
+pub mod maintenance;
+pub mod metrics;
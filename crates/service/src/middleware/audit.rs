@@ -0,0 +1,121 @@
+//! Make [`audit::record`] available to downstream middleware / handlers
+
+use std::net::SocketAddr;
+
+use axum::{body::Body, extract::ConnectInfo};
+use futures_util::{future::BoxFuture, FutureExt};
+
+use crate::{audit, Database};
+
+/// Middleware which scopes each request so auth decisions recorded while
+/// handling it are written to the audit log along w/ the peer's IP address
+#[derive(Debug, Clone)]
+pub struct Audit {
+    db: Database,
+}
+
+impl Audit {
+    /// Write audit records to `db`
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+}
+
+impl<S> tower::Layer<S> for Audit {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service {
+            inner,
+            db: self.db.clone(),
+        }
+    }
+}
+
+/// Tower service of the [`Audit`] layer
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    db: Database,
+}
+
+impl<S> tower::Service<http::Request<Body>> for Service<S>
+where
+    S: tower::Service<http::Request<Body>, Response = http::Response<Body>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        tower::Service::poll_ready(&mut self.inner, cx)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        // Only available when served over TCP
+        let source_ip = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        let context = audit::Context {
+            db: self.db.clone(),
+            source_ip,
+        };
+
+        // Inner middleware such as `ExtractToken` record decisions synchronously
+        // when called, before the returned future is first polled
+        let future = context.clone().sync_scope(|| inner.call(req));
+
+        context.scope(future).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{audit::Decision, crypto::KeyPair, database, middleware::ExtractToken, token::Validation};
+
+    #[tokio::test]
+    async fn records_invalid_token() {
+        let db = database::test::temp().await;
+
+        let router = axum::Router::new()
+            .route("/api/v1/services/endpoints", get(|| async {}))
+            .layer(ExtractToken {
+                pub_key: KeyPair::generate().public_key(),
+                validation: Validation::new(),
+                leeway: std::time::Duration::ZERO,
+            })
+            .layer(Audit::new(Database::clone(&db)));
+
+        let request = http::Request::builder()
+            .uri("/api/v1/services/endpoints")
+            .header(http::header::AUTHORIZATION, "Bearer invalid")
+            .body(Body::empty())
+            .unwrap();
+
+        router.oneshot(request).await.unwrap();
+
+        // Written in the background
+        let mut entries = vec![];
+        for _ in 0..50 {
+            entries = audit::list(db.acquire().await.unwrap().as_mut()).await.unwrap();
+            if !entries.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].record.operation, "/api/v1/services/endpoints");
+        assert_eq!(entries[0].record.decision, Decision::Denied);
+    }
+}
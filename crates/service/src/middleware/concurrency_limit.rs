@@ -0,0 +1,223 @@
+//! Shed load once too many requests are being handled concurrently
+
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, HttpBody},
+    response::IntoResponse,
+    Json,
+};
+use futures_util::{future::BoxFuture, FutureExt, StreamExt};
+use http::{header, StatusCode};
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::api::{self, Operation};
+
+/// Middleware which rejects requests w/ `503 Service Unavailable` while
+/// `max` requests are already being handled, rather than queuing them
+///
+/// Ping & metrics requests bypass the limit so an overloaded service
+/// is still reported as reachable. Streamed responses count towards the
+/// limit until their body has been sent.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    permits: Arc<Semaphore>,
+    bypass: Arc<[String]>,
+}
+
+impl ConcurrencyLimit {
+    /// Limit the server to handling `max` concurrent requests
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+            bypass: Arc::new([
+                "/metrics".to_string(),
+                format!(
+                    "/api/{}/{}",
+                    api::v1::services::Ping::VERSION,
+                    api::v1::services::Ping::PATH
+                ),
+            ]),
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for ConcurrencyLimit {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service {
+            inner,
+            limit: self.clone(),
+        }
+    }
+}
+
+/// Tower service of the [`ConcurrencyLimit`] layer
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    limit: ConcurrencyLimit,
+}
+
+impl<S> tower::Service<http::Request<Body>> for Service<S>
+where
+    S: tower::Service<http::Request<Body>, Response = http::Response<Body>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        tower::Service::poll_ready(&mut self.inner, cx)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        // See `Log` middleware for why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        if self.limit.bypass.iter().any(|path| path == req.uri().path()) {
+            return inner.call(req).boxed();
+        }
+
+        let Ok(permit) = self.limit.permits.clone().try_acquire_owned() else {
+            warn!(path = req.uri().path(), "Too many concurrent requests, rejecting");
+            return async { Ok(overloaded()) }.boxed();
+        };
+
+        async move {
+            let response = inner.call(req).await?;
+            Ok(hold_until_sent(response, permit))
+        }
+        .boxed()
+    }
+}
+
+/// Bodies which are streamed rather than already buffered are still being
+/// produced once the handler completes, so they hold the `permit` until sent
+fn hold_until_sent(response: http::Response<Body>, permit: OwnedSemaphorePermit) -> http::Response<Body> {
+    if response.body().size_hint().exact().is_some() {
+        return response;
+    }
+
+    response.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _held = &permit;
+            chunk
+        }))
+    })
+}
+
+fn overloaded() -> http::Response<Body> {
+    #[derive(Serialize)]
+    struct Error {
+        error: &'static str,
+        code: &'static str,
+        status: u16,
+    }
+
+    let status = StatusCode::SERVICE_UNAVAILABLE;
+
+    (
+        status,
+        [(header::RETRY_AFTER, "1")],
+        Json(Error {
+            error: "too many concurrent requests",
+            code: "overloaded",
+            status: status.as_u16(),
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use axum::routing::get;
+    use tokio::sync::oneshot;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sheds_when_saturated() {
+        let (release, released) = oneshot::channel::<()>();
+        let released = Arc::new(tokio::sync::Mutex::new(Some(released)));
+
+        let router = axum::Router::new()
+            .route(
+                "/slow",
+                get(move || {
+                    let released = released.clone();
+                    async move {
+                        if let Some(released) = released.lock().await.take() {
+                            let _ = released.await;
+                        }
+                    }
+                }),
+            )
+            .route("/api/v1/services/ping", get(|| async {}))
+            .layer(ConcurrencyLimit::new(1));
+
+        let request = |path: &str| http::Request::get(path).body(Body::empty()).unwrap();
+
+        let slow = tokio::spawn(router.clone().oneshot(request("/slow")));
+        tokio::task::yield_now().await;
+
+        let resp = router.clone().oneshot(request("/slow")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Ping bypasses the limit
+        let resp = router.clone().oneshot(request("/api/v1/services/ping")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        release.send(()).unwrap();
+        assert_eq!(slow.await.unwrap().unwrap().status(), StatusCode::OK);
+
+        // Permit is released once the request completes
+        let resp = router.oneshot(request("/slow")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn streamed_body_holds_permit() {
+        let (send, recv) = oneshot::channel::<()>();
+        let recv = Arc::new(tokio::sync::Mutex::new(Some(recv)));
+
+        let router = axum::Router::new()
+            .route(
+                "/stream",
+                get(move || {
+                    let recv = recv.clone();
+                    async move {
+                        let recv = recv.lock().await.take().unwrap();
+                        Body::from_stream(futures_util::stream::once(async move {
+                            let _ = recv.await;
+                            Ok::<_, std::io::Error>("done")
+                        }))
+                    }
+                }),
+            )
+            .route("/fast", get(|| async {}))
+            .layer(ConcurrencyLimit::new(1));
+
+        let request = |path: &str| http::Request::get(path).body(Body::empty()).unwrap();
+
+        // Handler completed, but the body is still being streamed
+        let stream = router.clone().oneshot(request("/stream")).await.unwrap();
+        assert_eq!(stream.status(), StatusCode::OK);
+
+        let resp = router.clone().oneshot(request("/fast")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        send.send(()).unwrap();
+        let body = axum::body::to_bytes(stream.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "done");
+
+        let resp = router.oneshot(request("/fast")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
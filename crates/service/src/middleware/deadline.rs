@@ -0,0 +1,165 @@
+//! Reject requests which arrive after the caller's [`Deadline`](deadline::Deadline) passes
+
+use axum::{body::Body, response::IntoResponse, Json};
+use futures_util::{future::BoxFuture, FutureExt};
+use http::StatusCode;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::deadline;
+
+/// Middleware which makes the deadline sent by the caller available to handlers
+/// and responds w/ `504 Gateway Timeout` if it's already exceeded, as the caller
+/// has given up.
+///
+/// Handlers are never cancelled once called, as that could interrupt a mutation
+/// part way through. Those doing long running work can check
+/// [`Deadline::current`](deadline::Deadline::current) instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline;
+
+impl<S> tower::Layer<S> for Deadline {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service { inner }
+    }
+}
+
+/// Tower service of the [`Deadline`] layer
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+}
+
+impl<S> tower::Service<http::Request<Body>> for Service<S>
+where
+    S: tower::Service<http::Request<Body>, Response = http::Response<Body>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        tower::Service::poll_ready(&mut self.inner, cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<Body>) -> Self::Future {
+        // See `Log` middleware for why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let Some(deadline) = deadline::Deadline::from_headers(req.headers()) else {
+            return inner.call(req).boxed();
+        };
+
+        if deadline.is_exceeded() {
+            warn!("Deadline exceeded, request rejected");
+            return async { Ok(exceeded()) }.boxed();
+        }
+
+        req.extensions_mut().insert(deadline);
+
+        deadline.scope(inner.call(req)).boxed()
+    }
+}
+
+fn exceeded() -> http::Response<Body> {
+    #[derive(Serialize)]
+    struct Error {
+        error: &'static str,
+        code: &'static str,
+        status: u16,
+    }
+
+    let status = StatusCode::GATEWAY_TIMEOUT;
+
+    (
+        status,
+        Json(Error {
+            error: "deadline exceeded",
+            code: "deadline_exceeded",
+            status: status.as_u16(),
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_exceeded_requests() {
+        let handled = Arc::new(AtomicBool::new(false));
+
+        let router = axum::Router::new()
+            .route(
+                "/slow",
+                get({
+                    let handled = handled.clone();
+
+                    || async move {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        handled.store(true, Ordering::Relaxed);
+                    }
+                }),
+            )
+            .route(
+                "/deadline",
+                get(|| async { deadline::Deadline::current().is_some().to_string() }),
+            )
+            .layer(Deadline);
+
+        let request = |path: &str, timeout: Option<Duration>| {
+            let mut builder = http::Request::builder().uri(path);
+            if let Some(timeout) = timeout {
+                builder = builder.header(deadline::HEADER, deadline::Deadline::after(timeout).to_header_value());
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        // Already exceeded on arrival
+        let resp = router
+            .clone()
+            .oneshot(request("/slow", Some(Duration::ZERO)))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(!handled.load(Ordering::Relaxed));
+
+        // Exceeded while handling, which always runs to completion
+        let resp = router
+            .clone()
+            .oneshot(request("/slow", Some(Duration::from_millis(10))))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(handled.load(Ordering::Relaxed));
+
+        let body =
+            |resp: http::Response<Body>| async { axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap() };
+
+        let resp = router
+            .clone()
+            .oneshot(request("/deadline", Some(Duration::from_secs(60))))
+            .await
+            .unwrap();
+        assert_eq!(body(resp).await, "true");
+
+        let resp = router.oneshot(request("/deadline", None)).await.unwrap();
+        assert_eq!(body(resp).await, "false");
+    }
+}
@@ -1,11 +1,13 @@
 //! Parse the authorization token from incoming requests, validate it and provide
 //! the verified token & flags as extensions to downstream middleware / handlers
 
+use std::time::Duration;
+
 use axum::body::Body;
 use tracing::{debug, warn};
 
 use crate::{
-    account,
+    account, audit,
     auth::{flag_names, Flags},
     crypto::PublicKey,
     token::{self, Validation, VerifiedToken},
@@ -23,6 +25,8 @@ pub struct ExtractToken {
     pub pub_key: PublicKey,
     /// Validation rules used when calling [`Token::verify`]
     pub validation: Validation,
+    /// Clock skew tolerated past a token's expiration, see [`Token::is_expired`]
+    pub leeway: Duration,
 }
 
 impl<S> tower::Layer<S> for ExtractToken {
@@ -33,6 +37,7 @@ impl<S> tower::Layer<S> for ExtractToken {
             inner,
             pub_key: self.pub_key,
             validation: self.validation.clone(),
+            leeway: self.leeway,
         }
     }
 }
@@ -43,6 +48,7 @@ pub struct Service<S> {
     inner: S,
     pub_key: PublicKey,
     validation: Validation,
+    leeway: Duration,
 }
 
 impl<S> tower::Service<http::Request<Body>> for Service<S>
@@ -81,7 +87,11 @@ where
                 account::Kind::Service => flags |= Flags::SERVICE_ACCOUNT,
             }
 
-            if token.decoded.is_expired() {
+            if token.decoded.payload.is_read_only() {
+                flags |= Flags::READ_ONLY
+            }
+
+            if token.decoded.is_expired(self.leeway) {
                 flags |= Flags::EXPIRED
             } else {
                 flags |= Flags::NOT_EXPIRED
@@ -109,6 +119,7 @@ fn extract_token(req: &http::Request<Body>, pub_key: &PublicKey, validation: &Va
         Ok(token) => Some(token),
         Err(error) => {
             warn!(%error, "Invalid authorization token");
+            audit::record(audit::Record::denied(req.uri().path(), crate::error::chain(&error)));
             None
         }
     }
@@ -1,4 +1,4 @@
-//! Log the request and if applicable, error
+//! Log the request and if applicable, error, under it's [`RequestId`]
 
 use std::sync::Arc;
 
@@ -6,7 +6,10 @@ use axum::body::Body;
 use futures_util::{future::BoxFuture, FutureExt};
 use tracing::{debug, error, info_span, Instrument};
 
-use crate::error;
+use crate::{
+    error,
+    request_id::{self, RequestId},
+};
 
 /// Logging middleware which logs the request and if applicable, error
 #[derive(Debug, Clone, Copy)]
@@ -39,7 +42,7 @@ where
         tower::Service::poll_ready(&mut self.inner, cx)
     }
 
-    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+    fn call(&mut self, mut req: http::Request<Body>) -> Self::Future {
         // This is necessary because tonic internally uses `tower::buffer::Buffer`.
         // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
         // for details on why this is necessary
@@ -47,8 +50,14 @@ where
         let mut inner = std::mem::replace(&mut self.inner, clone);
 
         let path = req.uri().path().to_string();
+        let request_id = RequestId::from_headers(req.headers()).unwrap_or_else(RequestId::generate);
 
-        async move {
+        req.extensions_mut().insert(request_id.clone());
+
+        let span = info_span!("request", path, request_id = %request_id);
+        let current = request_id.clone();
+
+        let future = async move {
             debug!("Request received");
 
             match inner.call(req).await {
@@ -60,7 +69,10 @@ where
                         error!(%error, "Handler error");
                     }
 
-                    let resp = http::Response::from_parts(parts, body);
+                    let mut resp = http::Response::from_parts(parts, body);
+
+                    resp.headers_mut()
+                        .insert(request_id::HEADER, request_id.to_header_value());
 
                     debug!(status = %resp.status(), "Sending response");
 
@@ -68,9 +80,10 @@ where
                 }
                 Err(e) => Err(e),
             }
-        }
-        .instrument(info_span!("request", path))
-        .boxed()
+        };
+
+        // Propagated by clients used while handling the request
+        current.scope(future.instrument(span)).boxed()
     }
 }
 
@@ -0,0 +1,178 @@
+//! Reject mutating requests while the service is in maintenance mode
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use axum::{body::Body, response::IntoResponse, Json};
+use futures_util::{future::BoxFuture, FutureExt};
+use http::{header, Method, StatusCode};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::api::{self, Operation};
+
+/// Seconds clients are asked to wait before retrying a rejected request
+const RETRY_AFTER: &str = "60";
+
+/// Middleware which rejects mutating operations w/ `503 Service Unavailable`
+/// while maintenance mode is enabled, such as to drain writes before a
+/// schema migration
+///
+/// All operations using `POST` are mutating, so reads & health checks are
+/// still handled. Toggling maintenance mode itself bypasses the check.
+#[derive(Debug, Clone)]
+pub struct Maintenance {
+    enabled: Arc<AtomicBool>,
+    bypass: Arc<[String]>,
+}
+
+impl Maintenance {
+    /// Reject mutating operations while `enabled` is set
+    pub fn new(enabled: Arc<AtomicBool>) -> Self {
+        Self {
+            enabled,
+            bypass: Arc::new([format!(
+                "/api/{}/{}",
+                api::v1::services::SetMaintenance::VERSION,
+                api::v1::services::SetMaintenance::PATH
+            )]),
+        }
+    }
+
+    fn rejects(&self, req: &http::Request<Body>) -> bool {
+        let path = req.uri().path();
+
+        self.enabled.load(Ordering::Relaxed)
+            && req.method() == Method::POST
+            && path.starts_with("/api/")
+            && !self.bypass.iter().any(|bypass| bypass == path)
+    }
+}
+
+impl<S> tower::Layer<S> for Maintenance {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service {
+            inner,
+            maintenance: self.clone(),
+        }
+    }
+}
+
+/// Tower service of the [`Maintenance`] layer
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    maintenance: Maintenance,
+}
+
+impl<S> tower::Service<http::Request<Body>> for Service<S>
+where
+    S: tower::Service<http::Request<Body>, Response = http::Response<Body>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        tower::Service::poll_ready(&mut self.inner, cx)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        // See `Log` middleware for why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        if self.maintenance.rejects(&req) {
+            warn!(path = req.uri().path(), "Maintenance mode enabled, rejecting");
+            return async { Ok(unavailable()) }.boxed();
+        }
+
+        inner.call(req).boxed()
+    }
+}
+
+fn unavailable() -> http::Response<Body> {
+    #[derive(Serialize)]
+    struct Error {
+        error: &'static str,
+        code: &'static str,
+        status: u16,
+    }
+
+    let status = StatusCode::SERVICE_UNAVAILABLE;
+
+    (
+        status,
+        [(header::RETRY_AFTER, RETRY_AFTER)],
+        Json(Error {
+            error: "service is in maintenance mode",
+            code: "maintenance",
+            status: status.as_u16(),
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use axum::routing::{get, post};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_mutating_when_enabled() {
+        let enabled = Arc::new(AtomicBool::new(false));
+
+        let router = axum::Router::new()
+            .route("/api/v1/services/endpoints", get(|| async {}))
+            .route("/api/v1/services/revoke_endpoint", post(|| async {}))
+            .route("/api/v1/services/maintenance", post(|| async {}))
+            .layer(Maintenance::new(enabled.clone()));
+
+        let request = |method: Method, path: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let resp = router
+            .clone()
+            .oneshot(request(Method::POST, "/api/v1/services/revoke_endpoint"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        enabled.store(true, Ordering::Relaxed);
+
+        let resp = router
+            .clone()
+            .oneshot(request(Method::POST, "/api/v1/services/revoke_endpoint"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], RETRY_AFTER);
+
+        // Reads are still handled
+        let resp = router
+            .clone()
+            .oneshot(request(Method::GET, "/api/v1/services/endpoints"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Maintenance mode can still be disabled
+        let resp = router
+            .oneshot(request(Method::POST, "/api/v1/services/maintenance"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,99 @@
+//! Record request counts, latencies & in-flight requests
+
+use std::time::Instant;
+
+use axum::{body::Body, extract::MatchedPath};
+use futures_util::{future::BoxFuture, FutureExt};
+
+use crate::metrics::{REQUESTS_IN_FLIGHT, REQUESTS_TOTAL, REQUEST_DURATION_SECONDS};
+
+/// Metrics middleware which records the method, matched path & status of each request
+#[derive(Debug, Clone, Copy)]
+pub struct Metrics;
+
+impl<S> tower::Layer<S> for Metrics {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service { inner }
+    }
+}
+
+/// Tower service of the [`Metrics`] layer
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+}
+
+impl<S> tower::Service<http::Request<Body>> for Service<S>
+where
+    S: tower::Service<http::Request<Body>, Response = http::Response<Body>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        tower::Service::poll_ready(&mut self.inner, cx)
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        // See `Log` middleware for why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let method = req.method().to_string();
+        // Use the matched route so path params don't explode label cardinality
+        let path = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_else(|| "unmatched".to_string());
+
+        async move {
+            let in_flight = InFlight::new(::metrics::gauge!(
+                REQUESTS_IN_FLIGHT,
+                "method" => method.clone(),
+                "path" => path.clone()
+            ));
+
+            let start = Instant::now();
+            let result = inner.call(req).await;
+            let elapsed = start.elapsed();
+
+            drop(in_flight);
+
+            if let Ok(resp) = &result {
+                let labels = [
+                    ("method", method),
+                    ("path", path),
+                    ("status", resp.status().as_u16().to_string()),
+                ];
+
+                ::metrics::counter!(REQUESTS_TOTAL, &labels).increment(1);
+                ::metrics::histogram!(REQUEST_DURATION_SECONDS, &labels).record(elapsed);
+            }
+
+            result
+        }
+        .boxed()
+    }
+}
+
+/// Counts a request as in flight until dropped, including when the request's
+/// future is dropped before completing
+struct InFlight(::metrics::Gauge);
+
+impl InFlight {
+    fn new(gauge: ::metrics::Gauge) -> Self {
+        gauge.increment(1);
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.decrement(1);
+    }
+}
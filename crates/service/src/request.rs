@@ -2,37 +2,33 @@
 use std::{io, path::Path};
 
 use futures_util::StreamExt;
-use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tokio::{fs::File, io::AsyncWriteExt};
 use url::Url;
 
+use crate::collectable::{self, VerifyError};
+
 /// Downloads the file at [`Url`] to destination [`Path`] and validates it matches
 /// the provided sha256sum
 pub async fn download_and_verify(url: Url, dest: impl AsRef<Path>, sha256sum: &str) -> Result<(), Error> {
     let mut stream = moss::request::get(url).await?;
 
-    let mut file = File::create(dest).await.map_err(Error::CreateFile)?;
-    let mut hasher = Sha256::default();
+    let dest = dest.as_ref().to_path_buf();
+    let mut file = File::create(&dest).await.map_err(Error::CreateFile)?;
 
     while let Some(bytes) = stream.next().await {
         let mut bytes = bytes?;
 
-        hasher.update(bytes.as_ref());
-
         file.write_all_buf(&mut bytes).await.map_err(Error::Write)?;
     }
 
     file.flush().await.map_err(Error::Write)?;
 
-    let hash = hex::encode(hasher.finalize());
+    let sha256sum = sha256sum.to_string();
 
-    if hash != sha256sum {
-        return Err(Error::Sha256Mismatch {
-            expected: sha256sum.to_string(),
-            actual: hash,
-        });
-    }
+    tokio::task::spawn_blocking(move || collectable::verify(&dest, &sha256sum))
+        .await
+        .map_err(|e| Error::Read(io::Error::other(e)))??;
 
     Ok(())
 }
@@ -52,14 +48,9 @@ pub enum Error {
     /// Error creating file
     #[error("create file")]
     CreateFile(#[source] io::Error),
-    /// Sha256 mismatch
-    #[error("invalid sha256, expected {expected} actual {actual}")]
-    Sha256Mismatch {
-        /// Expected hash
-        expected: String,
-        /// Actual hash
-        actual: String,
-    },
+    /// Downloaded file failed verification
+    #[error("verify")]
+    Verify(#[from] VerifyError),
 }
 
 impl From<moss::request::Error> for Error {
@@ -0,0 +1,78 @@
+//! Correlate requests across services via the [`HEADER`] header
+//!
+//! The server assigns each request an id, reusing the one sent by the caller
+//! if present, which is recorded on the request's tracing span. Any [`Client`]
+//! requests made while handling it propagate the same id.
+//!
+//! [`Client`]: crate::Client
+use std::future::Future;
+
+use derive_more::Display;
+use http::{HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+/// Header carrying the request id
+pub const HEADER: &str = "x-request-id";
+
+/// Ids longer than this sent by callers are ignored & regenerated
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// Id correlating a request and the work it causes across services
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Display)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Generate a new random id
+    pub fn generate() -> Self {
+        Self(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// Id of the request currently being handled, if any
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Id sent by the caller in the [`HEADER`] header, if present & valid
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get(HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= MAX_LEN)
+            .map(|value| Self(value.to_string()))
+    }
+
+    /// Run `future` with this as the [`RequestId::current`] id, such as when
+    /// handling work in the background on behalf of a request
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    /// Header value of this id
+    pub fn to_header_value(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.0).expect("visible ascii")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn current() {
+        let id = RequestId::generate();
+
+        assert_eq!(RequestId::current(), None);
+        assert_eq!(id.clone().scope(async { RequestId::current() }).await, Some(id));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, HeaderValue::from_static("abc"));
+        assert_eq!(RequestId::from_headers(&headers), Some(RequestId("abc".to_string())));
+
+        headers.insert(HEADER, HeaderValue::from_str(&"a".repeat(MAX_LEN + 1)).unwrap());
+        assert_eq!(RequestId::from_headers(&headers), None);
+    }
+}
@@ -1,18 +1,95 @@
 //! Batteries included server that provides common service APIs
 //! over http, with the ability to handle additional consumer
 //! defined APIs
-use std::{future::IntoFuture, io, path::Path, time::Duration};
+use std::{
+    fmt,
+    future::IntoFuture,
+    io,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use arc_swap::ArcSwap;
+use futures_util::future;
+
+use axum_server::tls_rustls::RustlsConfig;
+use http::{header, HeaderName, HeaderValue, Method};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+};
+use serde::Deserialize;
 use thiserror::Error;
-use tokio::net::ToSocketAddrs;
-use tracing::error;
+use tokio::net::{TcpListener, UnixListener};
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    set_header::SetResponseHeaderLayer,
+};
+use tracing::{error, info};
 
-use crate::{account, api, endpoint::enrollment, error, middleware, signal, task, token, Config, Role, State};
+use crate::{
+    account, api, audit, client, config, deadline,
+    endpoint::{builder, enrollment, keepalive},
+    error, metrics, middleware, request_id, signal, task, token, Role, State,
+};
 
 pub use crate::task::CancellationToken;
 
+/// Server configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Config {
+    /// Maximum requests handled concurrently, further requests are rejected
+    /// w/ `503 Service Unavailable` until one completes. Unlimited if not set.
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    /// Browser origins allowed to make cross-origin requests, such as
+    /// `https://dashboard.example.com`. CORS is disabled if empty.
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+    /// Only accept IPv6 connections when bound to an IPv6 address, otherwise
+    /// IPv4 connections are also accepted (dual-stack)
+    #[serde(default)]
+    pub ipv6_only: bool,
+}
+
+/// Address the [`Server`] listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddr {
+    /// TCP socket, either IPv4 or IPv6
+    Tcp(SocketAddr),
+    /// Unix domain socket at the provided path, replacing any stale socket
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for BindAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+impl From<(IpAddr, u16)> for BindAddr {
+    fn from(addr: (IpAddr, u16)) -> Self {
+        Self::Tcp(addr.into())
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => write!(f, "{addr}"),
+            BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
 /// Start the [`Server`] without additional configuration
-pub async fn start(addr: impl ToSocketAddrs, role: Role, config: &Config, state: &State) -> Result<(), Error> {
+pub async fn start(addr: impl Into<BindAddr>, role: Role, config: &crate::Config, state: &State) -> Result<(), Error> {
     Server::new(role, config, state).start(addr).await
 }
 
@@ -20,9 +97,20 @@ pub async fn start(addr: impl ToSocketAddrs, role: Role, config: &Config, state:
 /// the ability to handle additional consumer defined APIs via [`Server::merge_api`].
 pub struct Server<'a> {
     router: axum::Router,
-    config: &'a Config,
+    /// Static files, kept apart so they aren't compressed again
+    directories: axum::Router,
+    config: &'a crate::Config,
     state: &'a State,
     role: Role,
+    capabilities: Option<builder::Capabilities>,
+    operations: Vec<api::openapi::Entry>,
+    openapi: bool,
+    metrics: bool,
+    tls: Option<Tls>,
+    client_ca: Option<PathBuf>,
+    cors_origins: Vec<String>,
+    config_path: Option<PathBuf>,
+    maintenance: Arc<AtomicBool>,
     extract_token: middleware::ExtractToken,
     signals: Vec<signal::Kind>,
     runner: task::Runner,
@@ -30,26 +118,117 @@ pub struct Server<'a> {
 
 impl<'a> Server<'a> {
     /// Create a new [`Server`]
-    pub fn new(role: Role, config: &'a Config, state: &'a State) -> Self {
-        let shared_services = api::v1::services(role, config, state);
-        let router = axum::Router::new().merge(shared_services.into_router());
-
+    pub fn new(role: Role, config: &'a crate::Config, state: &'a State) -> Self {
         Self {
-            router,
+            router: axum::Router::new(),
+            directories: axum::Router::new(),
             config,
             state,
             role,
-            extract_token: middleware::ExtractToken {
-                pub_key: state.key_pair.public_key(),
-                validation: token::Validation::new().iss(role.service_name()),
-            },
+            capabilities: None,
+            operations: vec![],
+            openapi: false,
+            metrics: false,
+            tls: None,
+            client_ca: None,
+            cors_origins: config.server.cors_origins.clone(),
+            config_path: None,
+            maintenance: Arc::default(),
+            extract_token: extract_token(role, &config.token, state),
             signals: vec![signal::Kind::terminate(), signal::Kind::interrupt()],
             runner: task::Runner::new(),
         }
     }
 }
 
+/// Extracts tokens issued by this service for itself
+fn extract_token(role: Role, config: &token::Config, state: &State) -> middleware::ExtractToken {
+    let mut validation = token::Validation::new()
+        .iss(role.service_name())
+        .aud_service(role.service_name())
+        .not_before(config.leeway());
+
+    if config.legacy_audience {
+        // Holder's role was the audience of tokens issued by the previous release
+        for role in [Role::Builder, Role::RepositoryManager, Role::Hub] {
+            validation = validation.legacy_aud_service(role.service_name());
+        }
+    }
+
+    middleware::ExtractToken {
+        pub_key: state.key_pair.public_key(),
+        validation,
+        leeway: config.leeway(),
+    }
+}
+
 impl Server<'_> {
+    /// Capabilities advertised to the hub when enrolling, only applicable for [`Role::Builder`]
+    pub fn with_capabilities(self, capabilities: builder::Capabilities) -> Self {
+        Self {
+            capabilities: Some(capabilities),
+            ..self
+        }
+    }
+
+    /// Record request metrics and expose them at `/metrics` in prometheus text format
+    pub fn with_metrics(self) -> Self {
+        Self { metrics: true, ..self }
+    }
+
+    /// Serve an OpenAPI document describing all registered operations at `/openapi.json`
+    pub fn with_openapi(self) -> Self {
+        Self { openapi: true, ..self }
+    }
+
+    /// Terminate TLS directly using the PEM encoded certificate chain & private key
+    /// at the provided paths. Plain HTTP is served if not set.
+    pub fn with_tls(self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            tls: Some(Tls {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+            }),
+            ..self
+        }
+    }
+
+    /// Require clients to present a certificate issued by the PEM encoded CA
+    /// certificate(s) at `ca_path` (mutual TLS), on top of token auth.
+    /// Only applicable w/ [`Server::with_tls`].
+    pub fn with_client_auth(self, ca_path: impl Into<PathBuf>) -> Self {
+        Self {
+            client_ca: Some(ca_path.into()),
+            ..self
+        }
+    }
+
+    /// Allow cross-origin requests from browsers on the provided `origins`,
+    /// overriding [`Config::cors_origins`]. CORS is disabled if empty.
+    pub fn with_cors<T: Into<String>>(self, origins: impl IntoIterator<Item = T>) -> Self {
+        Self {
+            cors_origins: origins.into_iter().map(Into::into).collect(),
+            ..self
+        }
+    }
+
+    /// Reload configuration from `path` upon SIGHUP, see [`Config::reload`](crate::Config::reload)
+    pub fn with_config_reload(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            config_path: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Share the maintenance mode toggle, so it can also be set by the caller.
+    /// Mutating operations are rejected w/ `503 Service Unavailable` while set.
+    pub fn with_maintenance(self, enabled: Arc<AtomicBool>) -> Self {
+        Self {
+            maintenance: enabled,
+            ..self
+        }
+    }
+
     /// Override the default graceful shutdown duration (5s)
     pub fn with_graceful_shutdown(self, duration: Duration) -> Self {
         Self {
@@ -87,9 +266,13 @@ impl Server<'_> {
     }
 
     /// Merges an [`api::Service`] with the server
-    pub fn merge_api(self, service: api::Service) -> Self {
+    pub fn merge_api(mut self, service: api::Service) -> Self {
+        let (router, operations) = service.into_parts();
+
+        self.operations.extend(operations);
+
         Self {
-            router: self.router.merge(service.into_router()),
+            router: self.router.merge(router),
             ..self
         }
     }
@@ -102,10 +285,11 @@ impl Server<'_> {
         }
     }
 
-    /// Serve static files under `route` from the provided `directory`
+    /// Serve static files under `route` from the provided `directory`. Files are
+    /// served as is, or from a precompressed `.gz` sibling if present
     pub fn serve_directory(self, route: &str, directory: impl AsRef<Path>) -> Self {
         Self {
-            router: self.router.nest_service(
+            directories: self.directories.nest_service(
                 route,
                 tower_http::services::ServeDir::new(directory).precompressed_gzip(),
             ),
@@ -115,33 +299,210 @@ impl Server<'_> {
 
     /// Start the server and perform the following:
     ///
-    /// - Sync the defined [`Config::admin`] to the service [`Database`] to ensure
+    /// - Sync the defined [`Config::admin`](crate::Config::admin) to the service [`Database`] to ensure
     ///   it's credentials can authenticate and hit all admin endpoints.
-    /// - Send auto-enrollment for all [`Config::downstream`] targets defined when [`Role::Hub`]
+    /// - Send auto-enrollment for all [`Config::downstream`](crate::Config::downstream) targets defined when [`Role::Hub`],
+    ///   in the background so unreachable targets don't delay startup
+    /// - Periodically delete expired account tokens
+    /// - Periodically ping endpoints and mark non-responders unreachable / recovered ones
+    ///   operational when [`Role::Hub`]
     /// - Start the underlying server to handle endpoint API routes
     ///   and any additional API routes added via [`Server::merge_api`].
+    /// - Expose `/metrics` if enabled via [`Server::with_metrics`]
+    /// - Expose `/openapi.json` if enabled via [`Server::with_openapi`]
+    /// - Record auth decisions to the [`audit`](crate::audit) log
+    /// - Reject mutating operations while in maintenance mode, toggled upon SIGUSR1,
+    ///   via the admin `SetMaintenance` operation or [`Server::with_maintenance`]
+    /// - Reject requests beyond [`Config::max_concurrent_requests`]
+    /// - Allow cross-origin requests from [`Config::cors_origins`] or those set via [`Server::with_cors`]
+    /// - Transparently decompress gzip request bodies & compress responses, other than
+    ///   static files, unless [`Config::disable_compression`](crate::Config::disable_compression)
+    /// - Listen on a TCP or unix domain socket, see [`BindAddr`]
+    /// - Terminate TLS if enabled via [`Server::with_tls`], only supported over TCP
+    /// - Require client certificates if enabled via [`Server::with_client_auth`]
+    /// - Reload configuration upon SIGHUP if enabled via [`Server::with_config_reload`]
     ///
     /// [`Database`]: crate::Database
-    pub async fn start(self, addr: impl ToSocketAddrs) -> Result<(), Error> {
+    pub async fn start(self, addr: impl Into<BindAddr>) -> Result<(), Error> {
+        let cors = cors(&self.cors_origins)?;
+
+        if self.client_ca.is_some() && self.tls.is_none() {
+            return Err(Error::ClientAuthWithoutTls);
+        }
+
+        // Refuse to run against a database migrated by a newer version
+        self.state.check_migrations().await?;
+
+        client::configure(self.config.client.clone())?;
+
         account::sync_admin(&self.state.service_db, self.config.admin.clone()).await?;
 
+        let issuer = enrollment::Issuer {
+            capabilities: self.capabilities,
+            ..self.config.issuer(self.role, self.state.key_pair.clone())
+        };
+
+        // Stop auto enrollment in progress once the runner shuts down
+        let shutdown = self.runner.cancellation_token();
+
         if self.role == Role::Hub {
-            if let Err(e) = enrollment::auto_enrollment(
-                &self.config.downstream,
-                self.config.issuer(self.role, self.state.key_pair.clone()),
-                self.state,
+            spawn_auto_enrollment(
+                self.config.downstream.clone(),
+                self.config,
+                issuer.clone(),
+                self.state.clone(),
+                shutdown.clone(),
+            );
+        }
+
+        let live_config: config::Live = Arc::new(ArcSwap::from_pointee(self.config.clone()));
+
+        let shared_services = api::v1::services(
+            issuer.clone(),
+            live_config.clone(),
+            self.maintenance.clone(),
+            self.state,
+        );
+
+        let (shared_router, shared_operations) = shared_services.into_parts();
+
+        let mut router = self.router.merge(shared_router);
+
+        if self.openapi {
+            let operations = shared_operations.into_iter().chain(self.operations).collect::<Vec<_>>();
+
+            router = router.merge(api::openapi::router(api::openapi::document(
+                self.role.service_name(),
+                &operations,
+            )));
+        }
+
+        if self.metrics {
+            let handle = metrics::install()?;
+
+            router = router
+                .merge(metrics::router(handle, self.state.service_db.clone()))
+                .layer(middleware::Metrics);
+        }
+
+        if !self.config.disable_compression {
+            router = router
+                .layer(tower_http::decompression::RequestDecompressionLayer::new())
+                .layer(tower_http::compression::CompressionLayer::new())
+                // Clients only compress request bodies once advertised (RFC 7694)
+                .layer(SetResponseHeaderLayer::overriding(
+                    header::ACCEPT_ENCODING,
+                    HeaderValue::from_static("gzip"),
+                ));
+        }
+
+        // Clients only send MessagePack bodies once advertised
+        router = router.layer(SetResponseHeaderLayer::overriding(
+            HeaderName::from_static(api::encoding::HEADER),
+            HeaderValue::from_static(api::encoding::MESSAGEPACK),
+        ));
+
+        let router = router.merge(self.directories);
+
+        let listener = Listener::bind(&addr.into(), self.config.server.ipv6_only)?;
+        let mut router = router
+            .layer(self.extract_token)
+            .layer(middleware::Audit::new(self.state.service_db.clone()))
+            .layer(middleware::Maintenance::new(self.maintenance.clone()))
+            .layer(middleware::Deadline);
+
+        // Shed load before any per request work, but still log rejected requests
+        if let Some(max) = self.config.server.max_concurrent_requests {
+            router = router.layer(middleware::ConcurrencyLimit::new(max));
+        }
+
+        // Answer preflight requests before they're subject to load shedding
+        if let Some(cors) = cors {
+            router = router.layer(cors);
+        }
+
+        let router = router.layer(middleware::Log);
+
+        let mut runner = self
+            .runner
+            .with_task(
+                "account token sweep",
+                account::sweep_expired_tokens(self.state.service_db.clone()),
             )
-            .await
-            {
-                error!(error = %error::chain(e), "Auto enrollment failed");
-            }
+            .with_task("audit log sweep", audit::sweep(self.state.service_db.clone()));
+
+        if self.role == Role::Hub {
+            runner = runner.with_task(
+                "endpoint keepalive",
+                keepalive::run(self.state.service_db.clone(), live_config.clone()),
+            );
+        }
+
+        runner = runner.with_task(
+            "maintenance toggle",
+            signal::on_each(signal::Kind::user_defined1(), {
+                let maintenance = self.maintenance.clone();
+
+                move || {
+                    let enabled = !maintenance.fetch_xor(true, Ordering::Relaxed);
+                    info!(enabled, "Maintenance mode toggled");
+                    future::ready(())
+                }
+            }),
+        );
+
+        if let Some(path) = self.config_path {
+            let role = self.role;
+            let state = self.state.clone();
+
+            runner = runner.with_task(
+                "config reload",
+                signal::on_each(signal::Kind::hangup(), move || {
+                    reload_config(
+                        path.clone(),
+                        live_config.clone(),
+                        role,
+                        issuer.clone(),
+                        state.clone(),
+                        shutdown.clone(),
+                    )
+                }),
+            );
         }
 
-        let listener = tokio::net::TcpListener::bind(addr).await?;
-        let router = self.router.layer(self.extract_token).layer(middleware::Log);
+        match (listener, self.tls) {
+            (Listener::Tcp(listener), Some(tls)) => {
+                // Explicitly select the provider in case multiple are enabled
+                let _ = rustls::crypto::ring::default_provider().install_default();
 
-        self.runner
-            .with_task("http server", axum::serve(listener, router))
+                let config = match &self.client_ca {
+                    Some(ca_path) => {
+                        RustlsConfig::from_config(Arc::new(tls.client_auth_config(ca_path).map_err(Error::LoadTls)?))
+                    }
+                    None => RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                        .await
+                        .map_err(Error::LoadTls)?,
+                };
+
+                runner = runner.with_task(
+                    "https server",
+                    axum_server::from_tcp_rustls(listener.into_std()?, config)
+                        .serve(router.into_make_service_with_connect_info::<SocketAddr>()),
+                );
+            }
+            (Listener::Tcp(listener), None) => {
+                runner = runner.with_task(
+                    "http server",
+                    axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>()),
+                );
+            }
+            (Listener::Unix(_), Some(_)) => return Err(Error::UnixTls),
+            (Listener::Unix(listener), None) => {
+                runner = runner.with_task("http server", axum::serve(listener, router));
+            }
+        }
+
+        runner
             .with_task("signal capture", signal::capture(self.signals))
             .run()
             .await;
@@ -150,13 +511,348 @@ impl Server<'_> {
     }
 }
 
+/// CORS layer allowing the API's methods & headers from `origins`, if any
+fn cors(origins: &[String]) -> Result<Option<CorsLayer>, Error> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+
+    let origins = origins
+        .iter()
+        .map(|origin| HeaderValue::from_str(origin).map_err(|_| Error::InvalidCorsOrigin(origin.clone())))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([
+                header::ACCEPT,
+                header::AUTHORIZATION,
+                header::CONTENT_ENCODING,
+                header::CONTENT_TYPE,
+                HeaderName::from_static(request_id::HEADER),
+                HeaderName::from_static(deadline::HEADER),
+            ])
+            .expose_headers([HeaderName::from_static(request_id::HEADER)]),
+    ))
+}
+
+/// Reload the config at `path` and apply it to the running service
+async fn reload_config(
+    path: PathBuf,
+    live: config::Live,
+    role: Role,
+    issuer: enrollment::Issuer,
+    state: State,
+    shutdown: CancellationToken,
+) {
+    // Keep running w/ the current config rather than an inconsistent one
+    let reloaded = live.load().reload(&path).await;
+    let config = match reloaded.and_then(|config| config.validate(role).map(|()| config)) {
+        Ok(config) => config,
+        Err(e) => {
+            error!(error = %error::chain(e), path = %path.display(), "Failed to reload config");
+            return;
+        }
+    };
+
+    crate::tracing::reload(&config.tracing);
+
+    if let Err(e) = account::sync_admin(&state.service_db, config.admin.clone()).await {
+        error!(error = %error::chain(e), "Failed to sync admin account");
+    }
+
+    let issuer = enrollment::Issuer {
+        capabilities: issuer.capabilities,
+        ..config.issuer(role, issuer.key_pair)
+    };
+    let config = Arc::new(config);
+
+    let previous = live.swap(config.clone());
+
+    info!(path = %path.display(), "Config reloaded");
+
+    // Enroll with any newly added downstream targets
+    if role == Role::Hub {
+        let added = enrollment::added_targets(&previous.downstream, &config.downstream);
+
+        if !added.is_empty() {
+            spawn_auto_enrollment(added, &config, issuer, state, shutdown);
+        }
+    }
+}
+
+/// Send auto-enrollment to `targets` in the background, as retrying unreachable
+/// targets w/ backoff would otherwise delay serving requests. Stops once `shutdown`
+/// is cancelled.
+fn spawn_auto_enrollment(
+    targets: Vec<enrollment::Target>,
+    config: &crate::Config,
+    issuer: enrollment::Issuer,
+    state: State,
+    shutdown: CancellationToken,
+) {
+    let allowlist = config.downstream_allowlist.clone();
+    let retry = config.enrollment_retry;
+    let resolve = config.resolve_downstream;
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = shutdown.cancelled() => {}
+            res = enrollment::auto_enrollment(&targets, allowlist.as_deref(), retry, resolve, issuer, &state) => {
+                if let Err(e) = res {
+                    error!(error = %error::chain(e), "Auto enrollment failed");
+                }
+            }
+        }
+    });
+}
+
+/// Socket bound to a [`BindAddr`]
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn bind(addr: &BindAddr, ipv6_only: bool) -> Result<Self, io::Error> {
+        match addr {
+            BindAddr::Tcp(addr) => {
+                let socket = socket2::Socket::new(
+                    socket2::Domain::for_address(*addr),
+                    socket2::Type::STREAM,
+                    Some(socket2::Protocol::TCP),
+                )?;
+
+                // Explicitly set so dual-stack doesn't depend on the system default
+                if addr.is_ipv6() {
+                    socket.set_only_v6(ipv6_only)?;
+                }
+                socket.set_reuse_address(true)?;
+                socket.set_nonblocking(true)?;
+                socket.bind(&(*addr).into())?;
+                socket.listen(1024)?;
+
+                Ok(Self::Tcp(TcpListener::from_std(socket.into())?))
+            }
+            BindAddr::Unix(path) => {
+                // Remove socket left behind by a previous run, but never clobber other files
+                match std::fs::symlink_metadata(path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+                    Ok(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            "path exists and isn't a socket",
+                        ))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+
+                Ok(Self::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+}
+
+/// TLS certificate & key used to terminate TLS
+#[derive(Debug, Clone)]
+struct Tls {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl Tls {
+    /// Server config which requires clients present a certificate
+    /// issued by the CA certificate(s) at `ca_path`
+    fn client_auth_config(&self, ca_path: &Path) -> Result<rustls::ServerConfig, io::Error> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(io::Error::other)?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path).map_err(io::Error::other)?;
+
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca_path).map_err(io::Error::other)? {
+            roots.add(cert.map_err(io::Error::other)?).map_err(io::Error::other)?;
+        }
+
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+            .build()
+            .map_err(io::Error::other)?;
+
+        let mut config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .map_err(io::Error::other)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(config)
+    }
+}
+
 /// A server error
 #[derive(Debug, Error)]
 pub enum Error {
     /// Syncing admin account failed
     #[error("sync admin account")]
     SyncAdmin(#[from] account::Error),
+    /// Service database failed the migration check
+    #[error("check migrations")]
+    CheckMigrations(#[from] crate::state::Error),
+    /// Installing metrics recorder failed
+    #[error("install metrics")]
+    Metrics(#[from] metrics::Error),
+    /// Configuring the client failed
+    #[error("configure client")]
+    ConfigureClient(#[from] client::MtlsError),
+    /// Loading TLS certificate or private key failed
+    #[error("load tls certificate")]
+    LoadTls(#[source] io::Error),
+    /// Client auth was enabled without TLS
+    #[error("client auth requires tls")]
+    ClientAuthWithoutTls,
+    /// TLS was enabled when listening on a unix domain socket
+    #[error("tls isn't supported over unix domain sockets")]
+    UnixTls,
+    /// Configured CORS origin isn't a valid header value
+    #[error("invalid cors origin {0:?}")]
+    InvalidCorsOrigin(String),
     /// Axum IO error
     #[error(transparent)]
     Serve(#[from] io::Error),
 }
+
+#[cfg(test)]
+mod test {
+    use axum::{body::Body, routing::post};
+    use http::StatusCode;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bind() {
+        let addr = SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 0));
+        assert!(matches!(Listener::bind(&addr.into(), true).unwrap(), Listener::Tcp(_)));
+
+        let path = std::env::temp_dir().join(format!("server-{}.sock", std::process::id()));
+        let addr = BindAddr::Unix(path.clone());
+        assert_eq!(addr.to_string(), format!("unix:{}", path.display()));
+
+        // Stale socket from a previous bind is replaced
+        drop(Listener::bind(&addr, false).unwrap());
+        let listener = Listener::bind(&addr, false).unwrap();
+        assert!(matches!(listener, Listener::Unix(_)));
+        std::fs::remove_file(&path).unwrap();
+
+        // Other files are left alone
+        std::fs::write(&path, b"").unwrap();
+        let err = Listener::bind(&addr, false).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn cors_preflight() {
+        assert!(cors(&[]).unwrap().is_none());
+        assert!(matches!(
+            cors(&["bad\norigin".to_string()]),
+            Err(Error::InvalidCorsOrigin(_))
+        ));
+
+        let router = axum::Router::new()
+            .route("/api/v1/services/refresh_token", post(|| async {}))
+            .layer(cors(&["https://dashboard.example.com".to_string()]).unwrap().unwrap());
+
+        let preflight = |origin: &str| {
+            http::Request::options("/api/v1/services/refresh_token")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let resp = router
+            .clone()
+            .oneshot(preflight("https://dashboard.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dashboard.example.com"
+        );
+        assert!(resp.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap()
+            .contains("authorization"));
+
+        let resp = router
+            .oneshot(preflight("https://elsewhere.example.com"))
+            .await
+            .unwrap();
+        assert!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn extract_token_audience() {
+        let root = std::env::temp_dir().join(format!("server-{}", uuid::Uuid::new_v4()));
+        let state = State::load(&root).await.unwrap();
+
+        let token = |aud: &str| {
+            let now = chrono::Utc::now();
+
+            crate::Token::new(token::Payload {
+                aud: aud.to_string(),
+                exp: (now + chrono::Duration::hours(1)).timestamp(),
+                iat: now.timestamp(),
+                nbf: None,
+                iss: Role::Hub.service_name().to_string(),
+                sub: "test".to_string(),
+                purpose: token::Purpose::Authentication,
+                account_id: account::Id::generate(),
+                account_type: account::Kind::Service,
+                admin: false,
+                scope: None,
+            })
+            .sign(&state.key_pair)
+            .unwrap()
+        };
+        let extracted = |config: token::Config, aud: &str| {
+            let router = axum::Router::new()
+                .route(
+                    "/token",
+                    axum::routing::get(|request: axum::extract::Request| async move {
+                        request.extensions().get::<token::VerifiedToken>().is_some().to_string()
+                    }),
+                )
+                .layer(extract_token(Role::Hub, &config, &state));
+            let request = http::Request::get("/token")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token(aud)))
+                .body(Body::empty())
+                .unwrap();
+
+            async move {
+                let resp = router.oneshot(request).await.unwrap();
+                axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap()
+            }
+        };
+        let legacy = token::Config {
+            legacy_audience: true,
+            ..token::Config::default()
+        };
+
+        let own = extracted(token::Config::default(), Role::Hub.service_name()).await;
+        // Issued for vessel, can't be replayed against summit
+        let other = extracted(token::Config::default(), Role::RepositoryManager.service_name()).await;
+        let other_legacy = extracted(legacy, Role::RepositoryManager.service_name()).await;
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(own, "true");
+        assert_eq!(other, "false");
+        assert_eq!(other_legacy, "true");
+    }
+}
@@ -1,5 +1,5 @@
 //! Capture unix signals
-use std::io;
+use std::{future::Future, io};
 
 use futures_util::{future, FutureExt};
 use tokio::signal::unix::signal;
@@ -13,3 +13,26 @@ pub(crate) async fn capture(signals: impl IntoIterator<Item = Kind>) -> io::Resu
 
     Ok(())
 }
+
+/// Calls `f` each time the provided signal is captured, such as [`Kind::hangup`]
+/// to reload configuration
+///
+/// Services can run this as a [`Server::with_task`] to handle their own signals,
+/// such as [`Kind::user_defined2`] to poke a worker. SIGHUP & SIGUSR1 are
+/// already handled by the [`Server`].
+///
+/// [`Server`]: crate::Server
+/// [`Server::with_task`]: crate::Server::with_task
+pub async fn on_each<F, Fut>(kind: Kind, mut f: F) -> io::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut signal = signal(kind)?;
+
+    while signal.recv().await.is_some() {
+        f().await;
+    }
+
+    Ok(())
+}
@@ -1,11 +1,15 @@
 //! Shared service state
-use std::{io, path::PathBuf};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
 
 use thiserror::Error;
 use tokio::fs;
-use tracing::debug;
+use tracing::{debug, error, warn};
 
 use crate::{
+    atomic_file,
     crypto::{self, KeyPair},
     database,
     endpoint::{self, enrollment},
@@ -30,12 +34,28 @@ pub struct State {
     ///
     /// Only applicable for hub service
     pub(crate) pending_sent: SharedMap<endpoint::Id, enrollment::Sent>,
+    /// Received enrollment requests that haven't been accepted yet, keyed
+    /// by the subject of the issuer's bearer token
+    ///
+    /// Only applicable for non-hub services
+    pub(crate) pending_received: SharedMap<String, enrollment::Received>,
+    /// Versions of the migrations run via [`State::with_migrations`]
+    migrations: Vec<i64>,
 }
 
 impl State {
     /// Load state from the provided path. If no keypair and/or database exist, they will be created.
-    #[tracing::instrument(name = "load_state", skip_all)]
     pub async fn load(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        Self::load_with(root, false).await
+    }
+
+    /// Load state from the provided path, see [`State::load`].
+    ///
+    /// An existing keypair which can't be decoded is replaced w/ a newly generated one
+    /// if `regenerate_invalid_key`, otherwise [`Error::InvalidPrivateKey`] is returned.
+    /// Regenerating the keypair invalidates all tokens issued by the service.
+    #[tracing::instrument(name = "load_state", skip_all)]
+    pub async fn load_with(root: impl Into<PathBuf>, regenerate_invalid_key: bool) -> Result<Self, Error> {
         let root = root.into();
 
         let state_dir = root.join("state");
@@ -51,21 +71,32 @@ impl State {
 
         let key_path = state_dir.join(".privkey");
         let key_pair = if !key_path.exists() {
-            let key_pair = KeyPair::generate();
-            debug!(key_pair = %key_pair.public_key(), "Keypair generated");
-
-            fs::write(&key_path, &key_pair.to_bytes())
-                .await
-                .map_err(Error::SavePrivateKey)?;
-
-            key_pair
+            generate_key_pair(&key_path).await?
         } else {
             let bytes = fs::read(&key_path).await.map_err(Error::LoadPrivateKey)?;
 
-            let key_pair = KeyPair::try_from_bytes(&bytes).map_err(Error::DecodePrivateKey)?;
-            debug!(key_pair = %key_pair.public_key(), "Keypair loaded");
-
-            key_pair
+            match KeyPair::try_from_bytes(&bytes) {
+                Ok(key_pair) => {
+                    debug!(key_pair = %key_pair.public_key(), "Keypair loaded");
+                    key_pair
+                }
+                Err(e) if regenerate_invalid_key => {
+                    warn!(
+                        path = %key_path.display(),
+                        error = %crate::error::chain(&e),
+                        "Private key is invalid, regenerating it. All previously issued tokens are invalidated"
+                    );
+                    generate_key_pair(&key_path).await?
+                }
+                Err(e) => {
+                    error!(
+                        path = %key_path.display(),
+                        "Private key is invalid. Restore it from backup or regenerate it with \
+                         --force-regenerate-key, which invalidates all previously issued tokens"
+                    );
+                    return Err(Error::InvalidPrivateKey(key_path, e));
+                }
+            }
         };
 
         Ok(Self {
@@ -75,14 +106,66 @@ impl State {
             service_db,
             key_pair,
             pending_sent: Default::default(),
+            pending_received: Default::default(),
+            migrations: vec![],
         })
     }
 
+    /// Migration status of the service database under `root`, against the shared
+    /// service migrations and the provided `migrators`. The database isn't created
+    /// or migrated.
+    pub async fn migration_status(
+        root: impl Into<PathBuf>,
+        migrators: &[database::Migrator],
+    ) -> Result<database::MigrationStatus, Error> {
+        let path = root.into().join("state").join("db").join("service");
+
+        if !path.exists() {
+            return Ok(database::MigrationStatus::new([], migrators));
+        }
+
+        Ok(Database::open_read_only(&path)
+            .await?
+            .migration_status(migrators)
+            .await?)
+    }
+
     /// Run the provided migrations against the service database
+    ///
+    /// Fails w/ [`database::Error::NewerMigrations`] if it was migrated by a newer version
     pub async fn with_migrations(mut self, migrator: database::Migrator) -> Result<Self, Error> {
+        self.migrations
+            .extend(migrator.iter().map(|migration| migration.version));
+        self.check_migrations().await?;
+
         self.service_db = self.service_db.with_migrations(migrator).await?;
         Ok(self)
     }
+
+    /// Fails w/ [`database::Error::NewerMigrations`] if the service database has migrations
+    /// applied which are unknown to this version, including those run via [`State::with_migrations`]
+    pub async fn check_migrations(&self) -> Result<(), Error> {
+        let applied = self.service_db.applied_migrations().await?;
+
+        database::MigrationStatus::with_versions(applied, self.migrations.iter().copied()).check()?;
+
+        Ok(())
+    }
+}
+
+/// Generate a new keypair and save it to `path`
+async fn generate_key_pair(path: &Path) -> Result<KeyPair, Error> {
+    let key_pair = KeyPair::generate();
+    debug!(key_pair = %key_pair.public_key(), "Keypair generated");
+
+    let bytes = key_pair.to_bytes();
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || atomic_file::write(path, bytes))
+        .await
+        .map_err(|e| Error::SavePrivateKey(e.into()))?
+        .map_err(Error::SavePrivateKey)?;
+
+    Ok(key_pair)
 }
 
 /// A state error
@@ -100,7 +183,67 @@ pub enum Error {
     /// Loading private key failed
     #[error("load private key")]
     LoadPrivateKey(#[source] io::Error),
-    /// Decoding private key failed
-    #[error("decode private key")]
-    DecodePrivateKey(#[source] crypto::Error),
+    /// Existing private key is invalid
+    #[error("invalid private key {}", .0.display())]
+    InvalidPrivateKey(PathBuf, #[source] crypto::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn invalid_key_pair() {
+        let root = std::env::temp_dir().join(format!("state-{}", uuid::Uuid::new_v4()));
+        let key_path = root.join("state").join(".privkey");
+
+        let key_pair = State::load(&root).await.unwrap().key_pair;
+
+        // Existing key is reused
+        assert_eq!(
+            State::load(&root).await.unwrap().key_pair.to_bytes(),
+            key_pair.to_bytes()
+        );
+
+        std::fs::write(&key_path, b"truncated").unwrap();
+
+        let invalid = State::load(&root).await;
+        // Refused without explicit regeneration and left untouched
+        assert!(matches!(invalid, Err(Error::InvalidPrivateKey(path, _)) if path == key_path));
+        assert_eq!(std::fs::read(&key_path).unwrap(), b"truncated");
+
+        let regenerated = State::load_with(&root, true).await.unwrap().key_pair;
+        assert_ne!(regenerated.to_bytes(), key_pair.to_bytes());
+        assert_eq!(std::fs::read(&key_path).unwrap(), regenerated.to_bytes());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn newer_migrations() {
+        let root = std::env::temp_dir().join(format!("state-{}", uuid::Uuid::new_v4()));
+
+        let state = State::load(&root).await.unwrap();
+        assert!(state.check_migrations().await.is_ok());
+
+        // Applied by a newer binary
+        sqlx::query(
+            "
+            INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES (99990101000000, 'future', TRUE, x'00', 0);
+            ",
+        )
+        .execute(state.service_db.acquire().await.unwrap().as_mut())
+        .await
+        .unwrap();
+
+        let check = state.check_migrations().await;
+
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(matches!(
+            check,
+            Err(Error::LoadDatabase(database::Error::NewerMigrations(versions))) if versions == [99990101000000]
+        ));
+    }
 }
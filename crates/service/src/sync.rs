@@ -23,9 +23,31 @@ where
         self.0.lock().await.insert(key, value);
     }
 
+    /// Returns a clone of the value at the key, if any
+    pub async fn get(&self, key: &K) -> Option<V> {
+        self.0.lock().await.get(key).cloned()
+    }
+
     /// Removes a key from the map, returning the value at the key if the key
     /// was previously in the map.
     pub async fn remove(&self, key: &K) -> Option<V> {
         self.0.lock().await.remove(key)
     }
+
+    /// Returns true if any value matches `predicate`
+    pub async fn any(&self, predicate: impl Fn(&V) -> bool) -> bool {
+        self.0.lock().await.values().any(predicate)
+    }
+
+    /// Removes the first entry whose value matches `predicate`, returning it's value
+    pub async fn remove_where(&self, predicate: impl Fn(&V) -> bool) -> Option<V> {
+        let mut map = self.0.lock().await;
+
+        let key = map
+            .iter()
+            .find(|(_, value)| predicate(value))
+            .map(|(key, _)| key.clone())?;
+
+        map.remove(&key)
+    }
 }
@@ -41,6 +41,11 @@ impl Runner {
         }
     }
 
+    /// Token cancelled once the runner shuts down, for work spawned outside of it
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation_token.child_token()
+    }
+
     pub fn with_task<F, E>(self, name: &'static str, task: F) -> Self
     where
         F: IntoFuture<Output = Result<(), E>>,
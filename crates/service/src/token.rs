@@ -2,15 +2,20 @@
 use std::time::SystemTime;
 
 use chrono::{DateTime, Duration, Utc};
+pub use jsonwebtoken::Algorithm;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use thiserror::Error;
 
 use crate::{
-    account,
+    account, api,
     crypto::{self, KeyPair, PublicKey},
+    Role,
 };
 
+/// Separates the service & operation path of an operation scoped `aud`
+const OPERATION_SCOPE_SEPARATOR: char = '#';
+
 /// A decoded Json Web Token (JWT)
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Token {
@@ -20,7 +25,8 @@ pub struct Token {
 }
 
 impl Token {
-    /// Creates a new token from the provided [`Payload`]
+    /// Creates a new token from the provided [`Payload`], which is always signed
+    /// w/ [`Algorithm::EdDSA`]
     pub fn new(payload: Payload) -> Self {
         Self {
             header: Header::new(jsonwebtoken::Algorithm::EdDSA),
@@ -30,22 +36,48 @@ impl Token {
 
     /// Verify and return a decoded token
     pub fn verify(token: &str, public_key: &PublicKey, validation: &Validation) -> Result<VerifiedToken, Error> {
-        let decoded = jsonwebtoken::decode::<Payload>(
-            token,
-            // This actually takes the compressed bytes and not
-            // the der encoded pkcs#8 format bytes, such as
-            // on the sign / encoding side. Fails otherwise.
-            &DecodingKey::from_ed_der(public_key.as_ref()),
-            &validation.0,
-        )
-        .map_err(Error::decode)?;
+        Self::verify_with(token, VerifyingKey::Ed25519(public_key), validation)
+    }
+
+    /// Verify and return a decoded token signed by any of the [`Validation::algorithms`]
+    /// supported by the provided [`VerifyingKey`], such as those minted by an external issuer
+    pub fn verify_with(token: &str, key: VerifyingKey<'_>, validation: &Validation) -> Result<VerifiedToken, Error> {
+        let header = jsonwebtoken::decode_header(token).map_err(Error::decode)?;
+
+        if !validation.inner.algorithms.contains(&header.alg) {
+            return Err(Error::UnsupportedAlgorithm(header.alg));
+        }
+
+        // `jsonwebtoken` requires every accepted algorithm to belong to the key's
+        // family, so only validate against the one this token was signed with
+        let mut inner = validation.inner.clone();
+        inner.algorithms = vec![header.alg];
+
+        let decoded =
+            jsonwebtoken::decode::<Payload>(token, &key.decoding_key(header.alg)?, &inner).map_err(Error::decode)?;
+
+        let decoded = Token {
+            header: decoded.header,
+            payload: decoded.claims,
+        };
+
+        if let Some(service) = &validation.aud_service {
+            let audience = decoded.payload.audience_service();
+
+            if audience != service && !validation.legacy_aud_services.iter().any(|legacy| legacy == audience) {
+                return Err(Error::AudienceMismatch(decoded.payload.aud));
+            }
+        }
+
+        if let Some(leeway) = validation.not_before {
+            if decoded.is_not_yet_valid(leeway) {
+                return Err(Error::NotYetValid);
+            }
+        }
 
         Ok(VerifiedToken {
             encoded: token.to_string(),
-            decoded: Token {
-                header: decoded.header,
-                payload: decoded.claims,
-            },
+            decoded,
         })
     }
 
@@ -59,38 +91,47 @@ impl Token {
         .map_err(Error::SignToken)
     }
 
-    /// Returns true if the token is expired from [`SystemTime::now`]
-    pub fn is_expired(&self) -> bool {
+    /// Returns true if the token is expired from [`SystemTime::now`], tolerating
+    /// up to `leeway` of clock skew past it's expiration
+    pub fn is_expired(&self, leeway: std::time::Duration) -> bool {
+        self.is_expired_in(std::time::Duration::ZERO, leeway)
+    }
+
+    /// Returns true if the token is expired in [`Duration`] from now, tolerating
+    /// up to `leeway` of clock skew past it's expiration
+    pub fn is_expired_in(&self, duration: std::time::Duration, leeway: std::time::Duration) -> bool {
         let start = SystemTime::now();
-        let now = start
+        let now = (start
             .duration_since(std::time::UNIX_EPOCH)
             .expect("Time went backwards")
+            + duration)
             .as_secs();
 
-        self.payload.exp as u64 <= now
+        (self.payload.exp as u64).saturating_add(leeway.as_secs()) <= now
     }
 
-    /// Returns true if the token is expired in [`Duration`] from now
-    pub fn is_expired_in(&self, duration: std::time::Duration) -> bool {
-        let start = SystemTime::now();
-        let now = (start
+    /// Returns true if the token isn't valid yet as of [`SystemTime::now`], tolerating
+    /// up to `leeway` of clock skew before it's [`Payload::not_before`] time
+    pub fn is_not_yet_valid(&self, leeway: std::time::Duration) -> bool {
+        let now = SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .expect("Time went backwards")
-            + duration)
             .as_secs();
 
-        self.payload.exp as u64 <= now
+        (self.payload.not_before().max(0) as u64) > now.saturating_add(leeway.as_secs())
     }
 
-    /// Refresh this token with a new expiration & issue time
-    pub fn refresh(&self) -> Self {
+    /// Refresh this token with a new expiration & issue time, using the
+    /// lifetime configured for it's [`Purpose`]
+    pub fn refresh(&self, config: &Config) -> Self {
         let now = Utc::now();
-        let expires_on = now + self.payload.purpose.duration();
+        let expires_on = now + config.duration(self.payload.purpose);
 
         Self {
             payload: Payload {
                 exp: expires_on.timestamp(),
                 iat: now.timestamp(),
+                nbf: Some(now.timestamp()),
                 ..self.payload.clone()
             },
             ..self.clone()
@@ -107,6 +148,17 @@ impl Token {
             },
         }
     }
+
+    /// Change the audience of this token
+    pub fn with_audience(self, audience: Audience<'_>) -> Self {
+        Self {
+            header: self.header,
+            payload: Payload {
+                aud: audience.encode(),
+                ..self.payload
+            },
+        }
+    }
 }
 
 /// A token that's been verified via [`Token::verify`]
@@ -125,9 +177,61 @@ impl VerifiedToken {
     }
 }
 
+/// Public key used to verify the signature of a [`Token`]
+#[derive(Debug, Clone, Copy)]
+pub enum VerifyingKey<'a> {
+    /// Ed25519 public key, verifies [`Algorithm::EdDSA`] tokens
+    Ed25519(&'a PublicKey),
+    /// RSA public key from it's base64url encoded modulus & exponent,
+    /// as published in a JWK. Verifies `RS*` & `PS*` tokens.
+    Rsa {
+        /// Modulus
+        n: &'a str,
+        /// Exponent
+        e: &'a str,
+    },
+    /// EC public key from it's base64url encoded curve coordinates,
+    /// as published in a JWK. Verifies `ES*` tokens.
+    Ec {
+        /// X coordinate
+        x: &'a str,
+        /// Y coordinate
+        y: &'a str,
+    },
+}
+
+impl VerifyingKey<'_> {
+    fn decoding_key(&self, algorithm: Algorithm) -> Result<DecodingKey, Error> {
+        match (self, algorithm) {
+            // This actually takes the compressed bytes and not
+            // the der encoded pkcs#8 format bytes, such as
+            // on the sign / encoding side. Fails otherwise.
+            (VerifyingKey::Ed25519(public_key), Algorithm::EdDSA) => Ok(DecodingKey::from_ed_der(public_key.as_ref())),
+            (
+                VerifyingKey::Rsa { n, e },
+                Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512,
+            ) => DecodingKey::from_rsa_components(n, e).map_err(Error::DecodeToken),
+            (VerifyingKey::Ec { x, y }, Algorithm::ES256 | Algorithm::ES384) => {
+                DecodingKey::from_ec_components(x, y).map_err(Error::DecodeToken)
+            }
+            _ => Err(Error::UnsupportedAlgorithm(algorithm)),
+        }
+    }
+}
+
 /// Validation rules to use when running [`Token::verify`]
 #[derive(Debug, Clone)]
-pub struct Validation(jsonwebtoken::Validation);
+pub struct Validation {
+    inner: jsonwebtoken::Validation,
+    aud_service: Option<String>,
+    legacy_aud_services: Vec<String>,
+    not_before: Option<std::time::Duration>,
+}
 
 impl Default for Validation {
     fn default() -> Self {
@@ -137,7 +241,12 @@ impl Default for Validation {
         validation.validate_aud = false;
         validation.required_spec_claims = ["aud", "exp", "iss", "sub"].into_iter().map(String::from).collect();
 
-        Self(validation)
+        Self {
+            inner: validation,
+            aud_service: None,
+            legacy_aud_services: vec![],
+            not_before: None,
+        }
     }
 }
 
@@ -147,18 +256,43 @@ impl Validation {
         Self::default()
     }
 
+    /// Validation will accept tokens signed with any of the provided algorithms,
+    /// instead of only [`Algorithm::EdDSA`]
+    pub fn algorithms(mut self, algorithms: impl IntoIterator<Item = Algorithm>) -> Self {
+        self.inner.algorithms = algorithms.into_iter().collect();
+        self
+    }
+
     /// Validation will check that the `aud` field is is equal to
     /// the provided value
     pub fn aud(mut self, aud: impl ToString) -> Self {
-        self.0.validate_aud = true;
-        self.0.aud = Some([aud.to_string()].into_iter().collect());
+        self.inner.validate_aud = true;
+        self.inner.aud = Some([aud.to_string()].into_iter().collect());
+        self
+    }
+
+    /// Validation will check that the service of the `aud` field, ignoring
+    /// any operation scope, is equal to the provided value
+    pub fn aud_service(mut self, service: impl ToString) -> Self {
+        self.aud_service = Some(service.to_string());
+        self
+    }
+
+    /// Validation will also accept the provided service as the service of the `aud`
+    /// field when checking [`Validation::aud_service`]
+    ///
+    /// Tokens used to be issued w/ the holder's role as their audience, rather than
+    /// the issuer's. These are accepted while [`Config::legacy_audience`] is enabled,
+    /// so enrolled endpoints can refresh onto the new audience instead of re-enrolling.
+    pub fn legacy_aud_service(mut self, service: impl ToString) -> Self {
+        self.legacy_aud_services.push(service.to_string());
         self
     }
 
     /// Validation will check that the `iss` field is is equal to
     /// the provided value
     pub fn iss(mut self, iss: impl ToString) -> Self {
-        self.0.iss = Some([iss.to_string()].into_iter().collect());
+        self.inner.iss = Some([iss.to_string()].into_iter().collect());
         self
     }
 
@@ -166,7 +300,14 @@ impl Validation {
     /// the provided value
     #[allow(clippy::should_implement_trait)]
     pub fn sub(mut self, sub: impl ToString) -> Self {
-        self.0.sub = Some(sub.to_string());
+        self.inner.sub = Some(sub.to_string());
+        self
+    }
+
+    /// Validation will reject tokens which aren't valid yet per
+    /// [`Payload::not_before`], tolerating up to `leeway` of clock skew
+    pub fn not_before(mut self, leeway: std::time::Duration) -> Self {
+        self.not_before = Some(leeway);
         self
     }
 }
@@ -174,12 +315,15 @@ impl Validation {
 /// Payload of a [`Token`] which defines various claims
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Payload {
-    /// Audience - Recipient for which the JWT is intended
+    /// Audience - Recipient for which the JWT is intended, see [`Audience`]
     pub aud: String,
     /// Expiration - Time after which the JWT expires
     pub exp: i64,
     /// Issued at - Time at which the JWT was issued; can be used to determine age of the JWT
     pub iat: i64,
+    /// Not before - Time before which the JWT must not be accepted, `iat` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
     /// Issuer - Issuer of the JWT
     pub iss: String,
     /// Subject - Subject of the JWT (the user)
@@ -197,6 +341,73 @@ pub struct Payload {
     /// This is needed by legacy infra since it
     /// doesn't define admin as an [`account::Kind`]
     pub admin: bool,
+    /// Scope the token is restricted to, unrestricted if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<Scope>,
+}
+
+impl Payload {
+    /// Time before which the token must not be accepted, defaulting
+    /// to when it was issued
+    pub fn not_before(&self) -> i64 {
+        self.nbf.unwrap_or(self.iat)
+    }
+
+    /// Returns true if this token is restricted to read-only operations,
+    /// such as for dashboards observing a service
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.scope, Some(Scope::Read))
+    }
+
+    /// Service the token is intended to be presented to, see [`Audience`]
+    pub fn audience_service(&self) -> &str {
+        self.aud
+            .split_once(OPERATION_SCOPE_SEPARATOR)
+            .map_or(self.aud.as_str(), |(service, _)| service)
+    }
+
+    /// Returns true if the audience of this token permits it to be
+    /// used for the operation at `path`
+    ///
+    /// Service wide tokens permit all operations
+    pub fn permits(&self, path: &str) -> bool {
+        match self.aud.split_once(OPERATION_SCOPE_SEPARATOR) {
+            Some((_, scope)) => scope == path,
+            None => true,
+        }
+    }
+}
+
+/// Scope of operations a token is restricted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Only operations which don't mutate state
+    Read,
+}
+
+/// Audience a token is issued for, the service it's presented to
+///
+/// Tokens are presented back to the service which issued them,
+/// so this is the issuer's own role
+#[derive(Debug, Clone, Copy)]
+pub enum Audience<'a> {
+    /// Token is usable for any operation of the service
+    Service(Role),
+    /// Token is only usable for the operation of the service at the provided path
+    Operation(Role, &'a str),
+}
+
+impl Audience<'_> {
+    /// Encode as the `aud` claim of a token
+    pub fn encode(&self) -> String {
+        match self {
+            Audience::Service(role) => role.service_name().to_string(),
+            Audience::Operation(role, path) => {
+                format!("{}{OPERATION_SCOPE_SEPARATOR}{path}", role.service_name())
+            }
+        }
+    }
 }
 
 /// Purpose of the token
@@ -211,18 +422,112 @@ pub enum Purpose {
     Authentication,
 }
 
-impl Purpose {
-    /// Duration used for the expiration of a token with this purpose
-    pub fn duration(&self) -> Duration {
-        match self {
-            Purpose::Authorization => Duration::days(7),
-            Purpose::Authentication => Duration::hours(1),
+/// Token lifetime & expiration configuration
+///
+/// Durations are formatted as a number followed by a unit, such as
+/// `30s`, `15m`, `1h`, `7d` or `2w`
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Config {
+    /// Lifetime of [`Purpose::Authorization`] (bearer) tokens
+    #[serde(default = "default_authorization", deserialize_with = "deserialize_lifetime")]
+    pub authorization: Duration,
+    /// Lifetime of [`Purpose::Authentication`] (access) tokens
+    #[serde(default = "default_authentication", deserialize_with = "deserialize_lifetime")]
+    pub authentication: Duration,
+    /// Clock skew tolerated past a token's expiration before
+    /// it's considered expired, `0s` disables it. Requires a restart to change
+    #[serde(default = "default_leeway", deserialize_with = "deserialize_duration")]
+    pub leeway: Duration,
+    /// Also accept tokens issued by the previous release, which have the holder's
+    /// role as their audience rather than this service. They're reissued w/ the
+    /// new audience when refreshed.
+    ///
+    /// Only enable while endpoints refresh their tokens after upgrading, as it lets
+    /// tokens issued for other services through the audience check. Requires a
+    /// restart to change
+    #[serde(default)]
+    pub legacy_audience: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            authorization: default_authorization(),
+            authentication: default_authentication(),
+            leeway: default_leeway(),
+            legacy_audience: false,
+        }
+    }
+}
+
+impl Config {
+    /// Duration used for the expiration of a token with the provided [`Purpose`]
+    pub fn duration(&self, purpose: Purpose) -> Duration {
+        match purpose {
+            Purpose::Authorization => self.authorization,
+            Purpose::Authentication => self.authentication,
         }
     }
+
+    /// Clock skew tolerated when checking token expiration, see [`Token::is_expired`]
+    pub fn leeway(&self) -> std::time::Duration {
+        self.leeway.to_std().unwrap_or_default()
+    }
+}
+
+fn default_authorization() -> Duration {
+    Duration::days(7)
+}
+
+fn default_authentication() -> Duration {
+    Duration::hours(1)
+}
+
+fn default_leeway() -> Duration {
+    Duration::seconds(30)
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value).ok_or_else(|| serde::de::Error::custom(format!("invalid duration {value:?}")))
+}
+
+/// Same as [`deserialize_duration`] but rejects zero, a token can't expire as it's issued
+fn deserialize_lifetime<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let lifetime = deserialize_duration(deserializer)?;
+
+    if lifetime.is_zero() {
+        return Err(serde::de::Error::custom("token lifetime must be greater than zero"));
+    }
+
+    Ok(lifetime)
+}
+
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let unit_start = value.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = value.split_at(unit_start);
+    let amount = amount.parse::<i64>().ok()?;
+
+    match unit.trim() {
+        "s" => Duration::try_seconds(amount),
+        "m" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => None,
+    }
 }
 
 /// A token error
-#[derive(Debug, Error)]
+#[derive(Debug, Error, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum Error {
     /// Token signature invalid
     #[error("Invalid signature")]
@@ -233,27 +538,60 @@ pub enum Error {
     /// Signing token failed
     #[error("sign token")]
     SignToken(#[source] jsonwebtoken::errors::Error),
+    /// Token is signed with an algorithm which isn't allowed
+    /// or supported by the verifying key
+    #[error("unsupported algorithm {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+    /// Token was issued for a time in the future beyond the tolerated
+    /// clock skew, see [`Validation::not_before`]
+    #[error("token not yet valid")]
+    NotYetValid,
+    /// Token was issued for a different service, see [`Validation::aud_service`]
+    #[error("audience mismatch {0:?}")]
+    AudienceMismatch(String),
     /// A crypto error
     #[error(transparent)]
     Crypto(#[from] crypto::Error),
 }
 
+impl api::ErrorCode for Error {
+    fn code(&self) -> &'static str {
+        self.into()
+    }
+}
+
 impl Error {
     fn decode(error: jsonwebtoken::errors::Error) -> Self {
         match error.kind() {
             jsonwebtoken::errors::ErrorKind::InvalidSignature => Self::InvalidSignature,
-            _ => Self::decode(error),
+            _ => Self::DecodeToken(error),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use base64::Engine;
     use chrono::{Duration, Utc};
-    use jsonwebtoken::Algorithm;
 
     use super::*;
 
+    fn payload() -> Payload {
+        Payload {
+            aud: "test".into(),
+            exp: 0,
+            iat: 0,
+            nbf: None,
+            iss: "test".into(),
+            sub: "test".into(),
+            purpose: Purpose::Authentication,
+            account_id: 0.into(),
+            account_type: account::Kind::Service,
+            admin: false,
+            scope: None,
+        }
+    }
+
     #[test]
     fn roundtrip() {
         let keypair = KeyPair::generate();
@@ -264,15 +602,12 @@ mod test {
         let token = Token {
             header: Header::new(Algorithm::EdDSA),
             payload: Payload {
-                aud: "test".into(),
                 exp: one_hour.timestamp(),
                 iat: now.timestamp(),
-                iss: "test".into(),
-                sub: "test".into(),
                 purpose: Purpose::Authorization,
-                account_id: 0.into(),
                 account_type: account::Kind::Admin,
                 admin: true,
+                ..payload()
             },
         };
 
@@ -282,4 +617,184 @@ mod test {
 
         assert_eq!(token, verified.decoded);
     }
+
+    #[test]
+    fn external_algorithm() {
+        // P-256 key pair of an external issuer, as PKCS#8 DER & JWK coordinates
+        const PRIVATE_KEY: &str = "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQgaKTYkG/I4lM8T/R+Y/bi1jn50FfiZLqJqdmH4SmD3e+hRANCAAScZjAw3/g+qadBCSuOccyRqhn+u+i73L1ncf7ChnmGU9lFgYDc6Bc/TbIRKpAHaIEdD+kqUChnClVGiwpfHB3/";
+        const X: &str = "nGYwMN_4PqmnQQkrjnHMkaoZ_rvou9y9Z3H-woZ5hlM";
+        const Y: &str = "2UWBgNzoFz9NshEqkAdogR0P6SpQKGcKVUaLCl8cHf8";
+
+        let payload = Payload {
+            iss: "idp".into(),
+            account_type: account::Kind::Admin,
+            ..payload()
+        };
+        let der = base64::prelude::BASE64_STANDARD.decode(PRIVATE_KEY).unwrap();
+        let encoded = jsonwebtoken::encode(
+            &Header::new(Algorithm::ES256),
+            &payload,
+            &EncodingKey::from_ec_der(&der),
+        )
+        .unwrap();
+        let key = VerifyingKey::Ec { x: X, y: Y };
+
+        // Only EdDSA is accepted by default
+        assert!(matches!(
+            Token::verify_with(&encoded, key, &Validation::new()),
+            Err(Error::UnsupportedAlgorithm(Algorithm::ES256))
+        ));
+
+        let verified = Token::verify_with(&encoded, key, &Validation::new().algorithms([Algorithm::ES256])).unwrap();
+        assert_eq!(verified.decoded.payload, payload);
+
+        // Key must match the algorithm
+        let keypair = KeyPair::generate();
+        assert!(matches!(
+            Token::verify_with(
+                &encoded,
+                VerifyingKey::Ed25519(&keypair.public_key()),
+                &Validation::new().algorithms([Algorithm::ES256, Algorithm::EdDSA])
+            ),
+            Err(Error::UnsupportedAlgorithm(Algorithm::ES256))
+        ));
+
+        // Algorithms of different families can be accepted together
+        let mixed = Validation::new().algorithms([Algorithm::ES256, Algorithm::EdDSA]);
+        let verified = Token::verify_with(&encoded, key, &mixed).unwrap();
+        assert_eq!(verified.decoded.payload, payload);
+
+        let token = Token::new(payload.clone());
+        let encoded = token.sign(&keypair).unwrap();
+        let verified = Token::verify(&encoded, &keypair.public_key(), &mixed).unwrap();
+        assert_eq!(verified.decoded, token);
+    }
+
+    #[test]
+    fn operation_scope() {
+        use service_core::api::{v1::summit, Operation};
+
+        let payload = |aud: Audience<'_>| Payload {
+            aud: aud.encode(),
+            iss: "summit".into(),
+            ..payload()
+        };
+
+        let scoped = payload(Audience::Operation(Role::Hub, summit::ImportSucceeded::PATH));
+        assert_eq!(scoped.aud, "summit#summit/importSucceeded");
+        assert_eq!(scoped.audience_service(), "summit");
+        assert!(scoped.permits(summit::ImportSucceeded::PATH));
+        assert!(!scoped.permits(summit::ImportFailed::PATH));
+        assert!(!scoped.permits(summit::BuildSucceeded::PATH));
+
+        let service = payload(Audience::Service(Role::Hub));
+        assert_eq!(service.aud, "summit");
+        assert_eq!(service.audience_service(), "summit");
+        assert!(service.permits(summit::ImportSucceeded::PATH));
+        assert!(service.permits(summit::BuildSucceeded::PATH));
+    }
+
+    #[test]
+    fn expiration_leeway() {
+        let token = |exp: chrono::DateTime<Utc>| {
+            Token::new(Payload {
+                exp: exp.timestamp(),
+                ..payload()
+            })
+        };
+        let leeway = std::time::Duration::from_secs(30);
+
+        // Just expired, but within clock skew tolerance
+        let skewed = token(Utc::now() - Duration::seconds(2));
+        assert!(skewed.is_expired(std::time::Duration::ZERO));
+        assert!(!skewed.is_expired(leeway));
+
+        let expired = token(Utc::now() - Duration::minutes(5));
+        assert!(expired.is_expired(leeway));
+
+        let valid = token(Utc::now() + Duration::minutes(5));
+        assert!(!valid.is_expired(leeway));
+        assert!(valid.is_expired_in(std::time::Duration::from_secs(10 * 60), leeway));
+    }
+
+    #[test]
+    fn audience_service() {
+        let keypair = KeyPair::generate();
+        let token = |aud: Audience<'_>| {
+            Token::new(Payload {
+                aud: aud.encode(),
+                iss: "summit".into(),
+                ..payload()
+            })
+            .sign(&keypair)
+            .unwrap()
+        };
+        let validation = Validation::new().aud_service(Role::Hub.service_name());
+        let verify = |encoded: &str| Token::verify(encoded, &keypair.public_key(), &validation);
+
+        assert!(verify(&token(Audience::Service(Role::Hub))).is_ok());
+        assert!(verify(&token(Audience::Operation(Role::Hub, "summit/buildSucceeded"))).is_ok());
+
+        // Issued for vessel, can't be replayed against summit
+        assert!(matches!(
+            verify(&token(Audience::Service(Role::RepositoryManager))),
+            Err(Error::AudienceMismatch(aud)) if aud == "vessel"
+        ));
+
+        // Unless accepted under the legacy audience convention
+        let validation = validation.legacy_aud_service(Role::RepositoryManager.service_name());
+        let verify = |encoded: &str| Token::verify(encoded, &keypair.public_key(), &validation);
+        assert!(verify(&token(Audience::Service(Role::RepositoryManager))).is_ok());
+        assert!(verify(&token(Audience::Service(Role::Builder))).is_err());
+    }
+
+    #[test]
+    fn not_before_leeway() {
+        let keypair = KeyPair::generate();
+        let token = |iat: chrono::DateTime<Utc>, nbf: Option<chrono::DateTime<Utc>>| {
+            Token::new(Payload {
+                exp: (iat + Duration::hours(1)).timestamp(),
+                iat: iat.timestamp(),
+                nbf: nbf.map(|nbf| nbf.timestamp()),
+                ..payload()
+            })
+            .sign(&keypair)
+            .unwrap()
+        };
+        let leeway = std::time::Duration::from_secs(30);
+        let validation = Validation::new().not_before(leeway);
+        let verify = |encoded: &str, validation: &Validation| Token::verify(encoded, &keypair.public_key(), validation);
+
+        // Issuer's clock is slightly ahead, but within clock skew tolerance
+        let skewed = token(Utc::now() + Duration::seconds(2), None);
+        assert!(verify(&skewed, &validation).is_ok());
+
+        // `nbf` defaults to `iat`
+        let future = token(Utc::now() + Duration::minutes(5), None);
+        assert!(matches!(verify(&future, &validation), Err(Error::NotYetValid)));
+        assert!(verify(&future, &Validation::new()).is_ok());
+
+        let not_before = token(Utc::now(), Some(Utc::now() + Duration::minutes(5)));
+        assert!(matches!(verify(&not_before, &validation), Err(Error::NotYetValid)));
+    }
+
+    #[test]
+    fn config() {
+        let config: Config = toml::from_str("authorization = \"2w\"").unwrap();
+
+        assert_eq!(config.duration(Purpose::Authorization), Duration::weeks(2));
+        assert_eq!(config.duration(Purpose::Authentication), Duration::hours(1));
+        assert_eq!(config.leeway(), std::time::Duration::from_secs(30));
+
+        assert_eq!(parse_duration("90s"), Some(Duration::seconds(90)));
+        assert_eq!(parse_duration("15m"), Some(Duration::minutes(15)));
+        assert_eq!(parse_duration("0h"), Some(Duration::zero()));
+        assert_eq!(parse_duration("7"), None);
+        assert_eq!(parse_duration("7y"), None);
+
+        let config: Config = toml::from_str("leeway = \"0s\"").unwrap();
+        assert_eq!(config.leeway(), std::time::Duration::ZERO);
+
+        assert!(toml::from_str::<Config>("authentication = \"0m\"").is_err());
+    }
 }
@@ -1,8 +1,13 @@
 //! Tracing support
-use std::env;
+use std::{env, sync::OnceLock};
 
 use serde::Deserialize;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{reload, EnvFilter};
+
+/// Swaps the level filter of the initialized subscriber
+type Reload = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+static RELOAD: OnceLock<Reload> = OnceLock::new();
 
 /// Output format
 #[derive(Debug, Clone, Copy, Deserialize, Default)]
@@ -51,19 +56,43 @@ pub fn init(config: &Config) {
 
     match config.format {
         Format::Compact => {
-            tracing_subscriber::fmt()
+            let builder = tracing_subscriber::fmt()
                 .compact()
                 .with_target(false)
                 .with_env_filter(EnvFilter::builder().parse_lossy(level_filter))
-                .init();
+                .with_filter_reloading();
+            let handle = builder.reload_handle();
+            let _ = RELOAD.set(Box::new(move |filter| handle.reload(filter)));
+
+            builder.init();
         }
         Format::Json => {
-            tracing_subscriber::fmt()
+            let builder = tracing_subscriber::fmt()
                 .json()
                 .with_target(false)
                 .flatten_event(true)
                 .with_env_filter(EnvFilter::builder().parse_lossy(level_filter))
-                .init();
+                .with_filter_reloading();
+            let handle = builder.reload_handle();
+            let _ = RELOAD.set(Box::new(move |filter| handle.reload(filter)));
+
+            builder.init();
+        }
+    }
+}
+
+/// Apply the [`Config::level_filter`] to the subscriber initialized via [`init`]
+///
+/// Has no effect if `RUST_LOG` is set, as it overrides the configured filter. The
+/// output format can't be changed once initialized.
+pub(crate) fn reload(config: &Config) {
+    if env::var("RUST_LOG").is_ok() {
+        return;
+    }
+
+    if let Some(reload) = RELOAD.get() {
+        if let Err(e) = reload(EnvFilter::builder().parse_lossy(&config.level_filter)) {
+            tracing::error!(error = %e, "Failed to reload tracing level filter");
         }
     }
 }
@@ -14,17 +14,34 @@ async fn main() -> Result<()> {
         port,
         config,
         root,
+        check_migrations,
+        force_regenerate_key,
     } = Args::parse();
 
-    let config = Config::load(config.unwrap_or_else(|| root.join("config.toml"))).await?;
+    if check_migrations {
+        let status = State::migration_status(&root, &[]).await?;
+
+        println!("{status}");
+
+        return Ok(status.check()?);
+    }
+
+    let config_path = config.unwrap_or_else(|| root.join("config.toml"));
+    let config = Config::load(&config_path).await?;
+    config.validate(Role::Hub)?;
 
     service::tracing::init(&config.tracing);
 
-    let state = State::load(root).await?;
+    let state = State::load_with(root, force_regenerate_key).await?;
 
     info!("summit listening on {host}:{port}");
 
-    Server::new(Role::Hub, &config, &state).start((host, port)).await?;
+    Server::new(Role::Hub, &config, &state)
+        .with_config_reload(config_path)
+        .with_openapi()
+        .with_metrics()
+        .start((host, port))
+        .await?;
 
     Ok(())
 }
@@ -33,10 +50,17 @@ async fn main() -> Result<()> {
 struct Args {
     #[arg(default_value = "127.0.0.1")]
     host: IpAddr,
-    #[arg(long, default_value = "5003")]
+    #[arg(long, default_value_t = Role::Hub.default_port())]
     port: u16,
     #[arg(long, short)]
     config: Option<PathBuf>,
     #[arg(long, short, default_value = ".")]
     root: PathBuf,
+    /// Print the database migration status and exit without starting the server
+    #[arg(long)]
+    check_migrations: bool,
+    /// Replace an invalid private key w/ a newly generated one. This invalidates
+    /// all tokens issued by this service, so endpoints must re-enroll
+    #[arg(long)]
+    force_regenerate_key: bool,
 }
@@ -0,0 +1,10 @@
+-- Worker messages which failed all retry attempts
+
+CREATE TABLE IF NOT EXISTS dead_letter (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  kind TEXT NOT NULL,
+  message TEXT NOT NULL,
+  error TEXT NOT NULL,
+  attempts INT NOT NULL,
+  created BIGINT NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Dead lettered messages are periodically redelivered until exhausted
+
+ALTER TABLE dead_letter ADD COLUMN redeliveries INT NOT NULL DEFAULT 0;
+-- NULL once all redeliveries are exhausted
+ALTER TABLE dead_letter ADD COLUMN next_redelivery BIGINT;
@@ -1,7 +1,13 @@
-use service::{api, collectable, database, endpoint, Database, Endpoint};
+use service::{
+    account, api, collectable,
+    crypto::{self, PublicKey},
+    database, endpoint,
+    request_id::RequestId,
+    Account, Database, Endpoint,
+};
 use thiserror::Error;
 use tokio::sync::mpsc;
-use tracing::{info, warn};
+use tracing::info;
 
 use crate::worker;
 
@@ -37,8 +43,15 @@ async fn import_packages(request: api::Request<api::v1::vessel::Build>, state: S
         .await
         .map_err(Error::LoadEndpoint)?;
 
+    // Packages must be signed w/ the key of the authenticated builder account,
+    // never a key supplied alongside them
+    let builder_public_key =
+        builder_public_key(state.db.acquire().await?.as_mut(), token.decoded.payload.account_id).await?;
+
     let body = request.body;
 
+    collectable::validate(&body.collectables).map_err(Error::Incomplete)?;
+
     let packages = body
         .collectables
         .into_iter()
@@ -46,15 +59,11 @@ async fn import_packages(request: api::Request<api::v1::vessel::Build>, state: S
             matches!(c.kind, collectable::Kind::Package).then_some(c.uri.parse().map(|url| worker::Package {
                 url,
                 sha256sum: c.sha256sum,
+                signature: c.signature,
             }))
         })
         .collect::<Result<Vec<_>, _>>()?;
 
-    if packages.is_empty() {
-        warn!(endpoint = %endpoint.id, "No packages to import");
-        return Ok(());
-    }
-
     info!(
         endpoint = %endpoint.id,
         num_packages = packages.len(),
@@ -65,15 +74,28 @@ async fn import_packages(request: api::Request<api::v1::vessel::Build>, state: S
         .worker
         .send(worker::Message::ImportPackages {
             task_id: body.task_id,
-            endpoint,
+            endpoint: endpoint.id,
             packages,
+            builder_public_key: builder_public_key.encode(),
+            request_id: RequestId::current(),
         })
         .map_err(Error::SendWorker)?;
 
     Ok(())
 }
 
-#[derive(Debug, Error)]
+/// Public key of the enrolled builder's service `account`
+async fn builder_public_key<'a, T>(conn: &'a mut T, account: account::Id) -> Result<PublicKey, Error>
+where
+    &'a mut T: database::Executor<'a>,
+{
+    let account = Account::get(conn, account).await.map_err(Error::LoadAccount)?;
+
+    account.public_key.decoded().map_err(Error::InvalidPublicKey)
+}
+
+#[derive(Debug, Error, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum Error {
     /// Required token is missing from the request
     #[error("Token missing from request")]
@@ -81,6 +103,15 @@ pub enum Error {
     /// Endpoint (UUIDv4) cannot be parsed from string
     #[error("invalid endpoint")]
     InvalidEndpoint(#[source] uuid::Error),
+    /// Stored builder public key cannot be decoded
+    #[error("invalid builder public key")]
+    InvalidPublicKey(#[source] crypto::Error),
+    /// Failed to load the builder's account from DB
+    #[error("load account")]
+    LoadAccount(#[source] account::Error),
+    /// Collectables of the build are incomplete
+    #[error("incomplete collectables")]
+    Incomplete(#[source] collectable::IncompleteError),
     /// Url cannot be parsed from string
     #[error("invalid url")]
     InvalidUrl(#[from] url::ParseError),
@@ -95,14 +126,74 @@ pub enum Error {
     Database(#[from] database::Error),
 }
 
+impl api::ErrorCode for Error {
+    fn code(&self) -> &'static str {
+        match self {
+            Error::Incomplete(error) => error.into(),
+            _ => self.into(),
+        }
+    }
+}
+
 impl From<&Error> for http::StatusCode {
     fn from(error: &Error) -> Self {
         match error {
             Error::MissingRequestToken => http::StatusCode::UNAUTHORIZED,
-            Error::InvalidEndpoint(_) | Error::InvalidUrl(_) => http::StatusCode::BAD_REQUEST,
-            Error::LoadEndpoint(_) | Error::SendWorker(_) | Error::Database(_) => {
-                http::StatusCode::INTERNAL_SERVER_ERROR
-            }
+            Error::InvalidEndpoint(_) | Error::Incomplete(_) | Error::InvalidUrl(_) => http::StatusCode::BAD_REQUEST,
+            Error::LoadEndpoint(database::Error::NotFound) => http::StatusCode::NOT_FOUND,
+            Error::LoadAccount(account::Error::Database(database::Error::NotFound)) => http::StatusCode::FORBIDDEN,
+            Error::LoadEndpoint(_)
+            | Error::LoadAccount(_)
+            | Error::InvalidPublicKey(_)
+            | Error::SendWorker(_)
+            | Error::Database(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use service::crypto::{EncodedSignature, KeyPair};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn builder_key_from_account() {
+        let path = std::env::temp_dir().join(format!("vessel-test-{}.db", uuid::Uuid::new_v4()));
+        let db = Database::new(&path).await.unwrap();
+
+        let builder = KeyPair::generate();
+        let account = account::Id::generate();
+
+        // Not enrolled
+        let error = builder_public_key(db.acquire().await.unwrap().as_mut(), account)
+            .await
+            .unwrap_err();
+        assert_eq!(http::StatusCode::from(&error), http::StatusCode::FORBIDDEN);
+
+        let mut tx = db.begin().await.unwrap();
+        Account::service(account, builder.public_key().encode())
+            .save(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let public_key = builder_public_key(db.acquire().await.unwrap().as_mut(), account)
+            .await
+            .unwrap();
+        assert_eq!(public_key, builder.public_key());
+
+        let package = |signer: Option<&KeyPair>| worker::Package {
+            url: "https://builder/a.stone".parse().unwrap(),
+            sha256sum: "a".to_string(),
+            signature: signer.map(|key_pair| EncodedSignature::encode(&key_pair.sign(b"a")).to_string()),
+        };
+
+        assert!(worker::verify_signatures(&[package(Some(&builder))], &public_key).is_ok());
+        // Signed by a key other than the enrolled builder's
+        assert!(worker::verify_signatures(&[package(Some(&KeyPair::generate()))], &public_key).is_err());
+        assert!(worker::verify_signatures(&[package(None)], &public_key).is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
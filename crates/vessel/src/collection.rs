@@ -1,3 +1,5 @@
+use std::collections::{hash_map::Entry, HashMap};
+
 use service::database::{self, Transaction};
 use sqlx::FromRow;
 use thiserror::Error;
@@ -21,6 +23,11 @@ impl Record {
             source_release: meta.source_release as i64,
         }
     }
+
+    /// Release ordering, matching the comparison used when importing
+    fn release(&self) -> (i64, i64) {
+        (self.source_release, self.build_release)
+    }
 }
 
 pub async fn lookup<'a, T>(conn: &'a mut T, name: &str) -> Result<Option<Record>, Error>
@@ -46,11 +53,12 @@ where
     .await?)
 }
 
+/// List the newest release of each package, ordered by source id then package name
 pub async fn list<'a, T>(conn: &'a mut T) -> Result<Vec<Record>, Error>
 where
     &'a mut T: database::Executor<'a>,
 {
-    Ok(sqlx::query_as(
+    let records = sqlx::query_as(
         "
         SELECT
           name,
@@ -63,7 +71,31 @@ where
         ",
     )
     .fetch_all(conn)
-    .await?)
+    .await?;
+
+    Ok(newest(records))
+}
+
+/// Keep only the newest release of each package name so stale duplicates
+/// never land in the index
+fn newest(records: Vec<Record>) -> Vec<Record> {
+    let mut newest = HashMap::<String, Record>::new();
+
+    for record in records {
+        match newest.entry(record.name.clone()) {
+            Entry::Occupied(mut entry) if record.release() > entry.get().release() => {
+                entry.insert(record);
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(record);
+            }
+        }
+    }
+
+    let mut records = newest.into_values().collect::<Vec<_>>();
+    records.sort_by(|a, b| a.source_id.cmp(&b.source_id).then_with(|| a.name.cmp(&b.name)));
+    records
 }
 
 pub async fn record(tx: &mut Transaction, record: Record) -> Result<(), Error> {
@@ -103,3 +135,31 @@ pub enum Error {
     #[error("sqlx migration")]
     Migrate(#[from] sqlx::migrate::MigrateError),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn newest_release() {
+        let record = |name: &str, source_id: &str, source_release, build_release| Record {
+            name: name.to_string(),
+            source_id: source_id.to_string(),
+            package_id: format!("{name}-{source_release}-{build_release}"),
+            build_release,
+            source_release,
+        };
+
+        let records = newest(vec![
+            record("zlib", "zlib", 2, 1),
+            record("nano", "nano", 1, 1),
+            record("zlib", "zlib", 3, 1),
+            record("zlib-devel", "zlib", 3, 1),
+            record("nano", "nano", 1, 2),
+        ]);
+
+        let ids = records.iter().map(|r| r.package_id.as_str()).collect::<Vec<_>>();
+
+        assert_eq!(ids, ["nano-1-2", "zlib-3-1", "zlib-devel-3-1"]);
+    }
+}
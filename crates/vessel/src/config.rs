@@ -0,0 +1,91 @@
+//! Vessel configuration
+
+use std::path::Path;
+
+use serde::Deserialize;
+use service::config::Error;
+use tokio::fs;
+
+/// Vessel configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Shared service configuration
+    #[serde(flatten)]
+    pub service: service::Config,
+    /// Vessel specific configuration
+    ///
+    /// Not reloaded upon SIGHUP, changes require a restart
+    #[serde(default)]
+    pub vessel: Vessel,
+}
+
+impl Config {
+    /// Load configuration from the provided `path`
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let content = fs::read_to_string(path).await?;
+        let config = toml::from_str(&content)?;
+        Ok(config)
+    }
+}
+
+/// Vessel specific configuration, under the `[vessel]` section
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Vessel {
+    /// Maximum packages downloaded concurrently per import
+    #[serde(default = "default_download_concurrency")]
+    pub download_concurrency: usize,
+    /// Format of the published repository index
+    #[serde(default)]
+    pub index_format: IndexFormat,
+    /// How staged packages are moved into the pool
+    #[serde(default)]
+    pub move_strategy: MoveStrategy,
+}
+
+impl Default for Vessel {
+    fn default() -> Self {
+        Self {
+            download_concurrency: default_download_concurrency(),
+            index_format: IndexFormat::default(),
+            move_strategy: MoveStrategy::default(),
+        }
+    }
+}
+
+/// Format of the published repository index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexFormat {
+    /// A single `stone.index` of every package
+    #[default]
+    Classic,
+    /// One `shards/<prefix>/stone.index` per package name prefix,
+    /// mirroring the pool layout, so clients only fetch what they need
+    Sharded,
+}
+
+/// How staged packages are moved into the pool
+///
+/// Every strategy falls back to [`MoveStrategy::Copy`] when it isn't
+/// supported, such as when staging & the pool are on different filesystems
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MoveStrategy {
+    /// Rename when the staged package can be consumed, otherwise
+    /// attempt a reflink then hardlink
+    #[default]
+    Auto,
+    /// Rename the staged package into the pool
+    Rename,
+    /// Clone the staged package's extents, supported by CoW filesystems
+    /// such as btrfs & xfs
+    Reflink,
+    /// Hardlink the staged package into the pool
+    Hardlink,
+    /// Copy the staged package into the pool
+    Copy,
+}
+
+fn default_download_concurrency() -> usize {
+    moss::environment::MAX_NETWORK_CONCURRENCY
+}
@@ -0,0 +1,165 @@
+use chrono::Utc;
+use service::database::{self, Transaction};
+use sqlx::FromRow;
+use thiserror::Error;
+
+use crate::worker;
+
+/// A worker message which failed to be handled after all retry attempts
+#[derive(Debug, Clone, FromRow)]
+pub struct Record {
+    pub id: i64,
+    pub kind: String,
+    /// JSON encoded [`worker::Message`]
+    pub message: String,
+    pub error: String,
+    pub attempts: i64,
+    pub created: i64,
+    pub redeliveries: i64,
+    /// Unix timestamp of the next redelivery, `None` once exhausted
+    pub next_redelivery: Option<i64>,
+}
+
+impl Record {
+    pub fn message(&self) -> Result<worker::Message, Error> {
+        Ok(serde_json::from_str(&self.message)?)
+    }
+}
+
+pub async fn list<'a, T>(conn: &'a mut T) -> Result<Vec<Record>, Error>
+where
+    &'a mut T: database::Executor<'a>,
+{
+    Ok(sqlx::query_as(
+        "
+        SELECT
+          id,
+          kind,
+          message,
+          error,
+          attempts,
+          created,
+          redeliveries,
+          next_redelivery
+        FROM
+          dead_letter
+        ORDER BY id;
+        ",
+    )
+    .fetch_all(conn)
+    .await?)
+}
+
+/// Records which are due for redelivery at `now`
+pub async fn due<'a, T>(conn: &'a mut T, now: i64) -> Result<Vec<Record>, Error>
+where
+    &'a mut T: database::Executor<'a>,
+{
+    Ok(sqlx::query_as(
+        "
+        SELECT
+          id,
+          kind,
+          message,
+          error,
+          attempts,
+          created,
+          redeliveries,
+          next_redelivery
+        FROM
+          dead_letter
+        WHERE
+          next_redelivery IS NOT NULL
+          AND next_redelivery <= ?
+        ORDER BY id;
+        ",
+    )
+    .bind(now)
+    .fetch_all(conn)
+    .await?)
+}
+
+pub async fn record(
+    tx: &mut Transaction,
+    message: &worker::Message,
+    attempts: u32,
+    error: &str,
+    next_redelivery: i64,
+) -> Result<(), Error> {
+    sqlx::query(
+        "
+        INSERT INTO dead_letter
+        (
+          kind,
+          message,
+          error,
+          attempts,
+          created,
+          next_redelivery
+        )
+        VALUES (?,?,?,?,?,?);
+        ",
+    )
+    .bind(message.to_string())
+    .bind(serde_json::to_string(message)?)
+    .bind(error)
+    .bind(attempts as i64)
+    .bind(Utc::now().timestamp())
+    .bind(next_redelivery)
+    .execute(tx.as_mut())
+    .await?;
+
+    Ok(())
+}
+
+/// Record a failed redelivery, scheduling the next one or marking
+/// the record as exhausted if `next_redelivery` is `None`
+pub async fn redelivery_failed(
+    tx: &mut Transaction,
+    id: i64,
+    attempts: u32,
+    error: &str,
+    next_redelivery: Option<i64>,
+) -> Result<(), Error> {
+    sqlx::query(
+        "
+        UPDATE dead_letter
+        SET
+          error = ?,
+          attempts = attempts + ?,
+          redeliveries = redeliveries + 1,
+          next_redelivery = ?
+        WHERE id = ?;
+        ",
+    )
+    .bind(error)
+    .bind(attempts as i64)
+    .bind(next_redelivery)
+    .bind(id)
+    .execute(tx.as_mut())
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete(tx: &mut Transaction, id: i64) -> Result<(), Error> {
+    sqlx::query(
+        "
+        DELETE FROM dead_letter
+        WHERE id = ?;
+        ",
+    )
+    .bind(id)
+    .execute(tx.as_mut())
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("sqlx")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("json")]
+    Json(#[from] serde_json::Error),
+}
@@ -4,11 +4,14 @@ use clap::Parser;
 use service::{Role, Server, State};
 use tracing::info;
 
+pub use self::config::Config;
+
 pub type Result<T, E = color_eyre::eyre::Error> = std::result::Result<T, E>;
-pub type Config = service::Config;
 
 mod api;
 mod collection;
+mod config;
+mod dead_letter;
 mod worker;
 
 #[tokio::main]
@@ -19,18 +22,30 @@ async fn main() -> Result<()> {
         config,
         root,
         import,
+        check_migrations,
+        force_regenerate_key,
     } = Args::parse();
 
-    let config = Config::load(config.unwrap_or_else(|| root.join("config.toml"))).await?;
+    if check_migrations {
+        let status = State::migration_status(&root, &[sqlx::migrate!("./migrations")]).await?;
+
+        println!("{status}");
+
+        return Ok(status.check()?);
+    }
 
-    service::tracing::init(&config.tracing);
+    let config_path = config.unwrap_or_else(|| root.join("config.toml"));
+    let config = Config::load(&config_path).await?;
+    config.service.validate(Role::RepositoryManager)?;
 
-    let state = State::load(root)
+    service::tracing::init(&config.service.tracing);
+
+    let state = State::load_with(root, force_regenerate_key)
         .await?
         .with_migrations(sqlx::migrate!("./migrations"))
         .await?;
 
-    let (worker_sender, worker_task) = worker::run(&state).await?;
+    let (worker_sender, worker_task, redelivery_task) = worker::run(&state, config.vessel).await?;
 
     if let Some(directory) = import {
         let _ = worker_sender.send(worker::Message::ImportDirectory(directory));
@@ -38,9 +53,13 @@ async fn main() -> Result<()> {
 
     info!("vessel listening on {host}:{port}");
 
-    Server::new(Role::RepositoryManager, &config, &state)
+    Server::new(Role::RepositoryManager, &config.service, &state)
+        .with_config_reload(config_path)
+        .with_openapi()
+        .with_metrics()
         .merge_api(api::service(state.service_db.clone(), worker_sender))
         .with_task("worker", worker_task)
+        .with_task("dead letter redelivery", redelivery_task)
         .start((host, port))
         .await?;
 
@@ -51,7 +70,7 @@ async fn main() -> Result<()> {
 struct Args {
     #[arg(default_value = "127.0.0.1")]
     host: IpAddr,
-    #[arg(long, default_value = "5003")]
+    #[arg(long, default_value_t = Role::RepositoryManager.default_port())]
     port: u16,
     #[arg(long, short)]
     config: Option<PathBuf>,
@@ -59,4 +78,11 @@ struct Args {
     root: PathBuf,
     #[arg(long)]
     import: Option<PathBuf>,
+    /// Print the database migration status and exit without starting the server
+    #[arg(long)]
+    check_migrations: bool,
+    /// Replace an invalid private key w/ a newly generated one. This invalidates
+    /// all tokens issued by this service, so endpoints must re-enroll
+    #[arg(long)]
+    force_regenerate_key: bool,
 }
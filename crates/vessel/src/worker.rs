@@ -4,59 +4,305 @@ use std::{
     future::Future,
     os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
+use chrono::Utc;
 use color_eyre::eyre::{self, eyre, Context, Result};
 use futures_util::{stream, StreamExt, TryStreamExt};
 use moss::db::meta;
-use service::{api, database, request, Endpoint};
-use sha2::{Digest, Sha256};
-use tokio::{fs, sync::mpsc, time::Instant};
-use tracing::{error, info, info_span, Instrument};
+use serde::{Deserialize, Serialize};
+use service::{
+    api, collectable,
+    crypto::{EncodedPublicKey, EncodedSignature, KeyPair, PublicKey},
+    database, endpoint, request,
+    request_id::RequestId,
+    Endpoint,
+};
+use tokio::{
+    fs,
+    sync::{mpsc, Mutex},
+    time::Instant,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use url::Url;
 
-use crate::collection;
+use crate::{
+    collection,
+    config::{IndexFormat, MoveStrategy, Vessel},
+    dead_letter,
+};
 
 pub type Sender = mpsc::UnboundedSender<Message>;
 
-#[derive(Debug, strum::Display)]
+/// Failed messages are retried this many times before being dead lettered
+const RETRY: Retry = Retry {
+    attempts: 3,
+    base_delay: Duration::from_secs(5),
+};
+/// Dead lettered messages are redelivered this many times before
+/// requiring manual inspection
+const REDELIVERY: Retry = Retry {
+    attempts: 5,
+    base_delay: Duration::from_secs(60),
+};
+/// How often dead letters are checked for a due redelivery
+const REDELIVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, strum::Display, Serialize, Deserialize)]
 #[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
 pub enum Message {
     ImportPackages {
         task_id: u64,
-        endpoint: Endpoint,
+        endpoint: endpoint::Id,
         packages: Vec<Package>,
+        /// Public key of the enrolled builder account which signed the packages
+        builder_public_key: EncodedPublicKey,
+        /// Id of the request which caused this import, for correlating logs
+        #[serde(default)]
+        request_id: Option<RequestId>,
     },
     ImportDirectory(PathBuf),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Package {
     pub url: Url,
     pub sha256sum: String,
+    /// Builder signature of the sha256sum
+    #[serde(default)]
+    pub signature: Option<String>,
 }
 
-pub async fn run(service_state: &service::State) -> Result<(Sender, impl Future<Output = Result<(), Infallible>>)> {
-    let state = State::new(service_state).await.context("construct state")?;
+pub async fn run(
+    service_state: &service::State,
+    config: Vessel,
+) -> Result<(
+    Sender,
+    impl Future<Output = Result<(), Infallible>>,
+    impl Future<Output = Result<(), Infallible>>,
+)> {
+    let state = State::new(service_state, config).await.context("construct state")?;
+
+    report_dead_letters(&state.service_db)
+        .await
+        .context("report dead letters")?;
 
     let (sender, mut receiver) = mpsc::unbounded_channel::<Message>();
 
-    let task = async move {
-        while let Some(message) = receiver.recv().await {
-            let kind = message.to_string();
+    let worker = {
+        let state = state.clone();
 
-            if let Err(e) = handle_message(&state, message).await {
-                let error = service::error::chain(e.as_ref() as &dyn std::error::Error);
-                error!(message = kind, %error, "Error handling message");
+        async move {
+            while let Some(message) = receiver.recv().await {
+                handle_or_dead_letter(&state, message).await;
             }
+
+            info!("Worker exiting");
+
+            Ok(())
         }
+    };
 
-        info!("Worker exiting");
+    // Separate from the worker so backoff between redeliveries doesn't hold up new messages
+    let redelivery = async move {
+        let mut interval = tokio::time::interval(REDELIVERY_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let redelivered = redeliver_dead_letters(&state.service_db, RETRY, REDELIVERY, |message| {
+                handle_message(&state, message)
+            })
+            .await;
 
-        Ok(())
+            if let Err(e) = redelivered {
+                let error = service::error::chain(e.as_ref() as &dyn std::error::Error);
+                error!(%error, "Failed to redeliver dead letters");
+            }
+        }
     };
 
-    Ok((sender, task))
+    Ok((sender, worker, redelivery))
+}
+
+async fn handle_or_dead_letter(state: &State, message: Message) {
+    let kind = message.to_string();
+
+    if let Err(e) = handle_with_retry(&message, RETRY, |message| handle_message(state, message)).await {
+        let error = service::error::chain(e.as_ref() as &dyn std::error::Error);
+        error!(message = kind, attempts = RETRY.attempts, %error, "Error handling message, dead lettering");
+
+        if let Err(e) = dead_letter(&state.service_db, &message, RETRY.attempts, &error).await {
+            let error = service::error::chain(e.as_ref() as &dyn std::error::Error);
+            error!(message = kind, %error, "Failed to dead letter message");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Retry {
+    attempts: u32,
+    base_delay: Duration,
+}
+
+impl Retry {
+    /// Delay after the provided (1-based) failed attempt
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay * 2u32.pow(attempt - 1)
+    }
+}
+
+/// Handle the message, retrying failures w/ exponential backoff. The
+/// error of the final attempt is returned if all attempts fail.
+async fn handle_with_retry<F, Fut>(message: &Message, retry: Retry, mut handle: F) -> Result<()>
+where
+    F: FnMut(Message) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match handle(message.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retry.attempts => {
+                let delay = retry.delay(attempt);
+                let error = service::error::chain(e.as_ref() as &dyn std::error::Error);
+
+                warn!(message = %message, attempt, %error, "Error handling message, retrying in {delay:?}");
+
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn dead_letter(db: &service::Database, message: &Message, attempts: u32, error: &str) -> Result<()> {
+    let mut tx = db.begin().await.context("start db tx")?;
+
+    let next_redelivery = Utc::now() + REDELIVERY.delay(1);
+
+    dead_letter::record(&mut tx, message, attempts, error, next_redelivery.timestamp())
+        .await
+        .context("record dead letter")?;
+
+    tx.commit().await.context("commit dead letter")?;
+
+    Ok(())
+}
+
+/// Redeliver dead lettered messages which are due, removing them once handled. Failures
+/// are rescheduled w/ exponential backoff until `redelivery` attempts are exhausted.
+async fn redeliver_dead_letters<F, Fut>(
+    db: &service::Database,
+    retry: Retry,
+    redelivery: Retry,
+    mut handle: F,
+) -> Result<()>
+where
+    F: FnMut(Message) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let records = dead_letter::due(
+        db.acquire().await.context("acquire database connection")?.as_mut(),
+        Utc::now().timestamp(),
+    )
+    .await
+    .context("list due dead letters")?;
+
+    for record in records {
+        let redeliveries = record.redeliveries as u32 + 1;
+
+        let result = match record.message() {
+            Ok(message) => {
+                info!(
+                    id = record.id,
+                    message = record.kind,
+                    redeliveries,
+                    "Redelivering dead lettered message"
+                );
+
+                handle_with_retry(&message, retry, &mut handle).await
+            }
+            Err(e) => Err(eyre::Report::from(e).wrap_err("decode dead lettered message")),
+        };
+
+        let mut tx = db.begin().await.context("start db tx")?;
+
+        match result {
+            Ok(()) => {
+                dead_letter::delete(&mut tx, record.id)
+                    .await
+                    .context("delete dead letter")?;
+
+                info!(id = record.id, message = record.kind, "Dead lettered message handled");
+            }
+            Err(e) => {
+                let error = service::error::chain(e.as_ref() as &dyn std::error::Error);
+                let next_redelivery = (redeliveries < redelivery.attempts)
+                    .then(|| (Utc::now() + redelivery.delay(redeliveries + 1)).timestamp());
+
+                if next_redelivery.is_some() {
+                    warn!(
+                        id = record.id,
+                        message = record.kind,
+                        redeliveries,
+                        %error,
+                        "Dead lettered message redelivery failed"
+                    );
+                } else {
+                    error!(
+                        id = record.id,
+                        message = record.kind,
+                        redeliveries,
+                        %error,
+                        "Dead lettered message redeliveries exhausted"
+                    );
+                }
+
+                dead_letter::redelivery_failed(&mut tx, record.id, retry.attempts, &error, next_redelivery)
+                    .await
+                    .context("record failed redelivery")?;
+            }
+        }
+
+        tx.commit().await.context("commit dead letter")?;
+    }
+
+    Ok(())
+}
+
+/// Surface any dead lettered messages so they can be manually inspected
+async fn report_dead_letters(db: &service::Database) -> Result<()> {
+    let records = dead_letter::list(db.acquire().await.context("acquire database connection")?.as_mut())
+        .await
+        .context("list dead letters")?;
+
+    for record in records {
+        let created = chrono::DateTime::from_timestamp(record.created, 0).unwrap_or_default();
+
+        warn!(
+            id = record.id,
+            message = record.kind,
+            attempts = record.attempts,
+            redeliveries = record.redeliveries,
+            error = record.error,
+            %created,
+            exhausted = record.next_redelivery.is_none(),
+            "Dead lettered message"
+        );
+        debug!(
+            id = record.id,
+            message = record.message,
+            "Dead lettered message contents"
+        );
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
@@ -64,10 +310,15 @@ struct State {
     state_dir: PathBuf,
     service_db: service::Database,
     meta_db: meta::Database,
+    key_pair: KeyPair,
+    config: Vessel,
+    /// Held while handling a message, so redelivered dead letters
+    /// aren't imported concurrently w/ new messages
+    handling: Arc<Mutex<()>>,
 }
 
 impl State {
-    async fn new(service_state: &service::State) -> Result<Self> {
+    async fn new(service_state: &service::State, config: Vessel) -> Result<Self> {
         let meta_db = meta::Database::new(service_state.db_dir.join("meta").to_string_lossy().as_ref())
             .context("failed to open meta database")?;
 
@@ -75,29 +326,58 @@ impl State {
             state_dir: service_state.state_dir.clone(),
             service_db: service_state.service_db.clone(),
             meta_db,
+            key_pair: service_state.key_pair.clone(),
+            config,
+            handling: Arc::default(),
         })
     }
 }
 
 async fn handle_message(state: &State, message: Message) -> Result<()> {
+    let _handling = state.handling.lock().await;
+
     match message {
         Message::ImportPackages {
             task_id,
             endpoint,
             packages,
+            builder_public_key,
+            request_id,
         } => {
+            let request_id = request_id.unwrap_or_else(RequestId::generate);
             let span = info_span!(
                 "import_packages",
                 task_id,
-                endpoint = %endpoint.id,
+                %endpoint,
                 num_packages = packages.len(),
+                %request_id,
             );
 
-            async move {
+            let import = async move {
+                let endpoint = Endpoint::get(
+                    state
+                        .service_db
+                        .acquire()
+                        .await
+                        .context("acquire database connection")?
+                        .as_mut(),
+                    endpoint,
+                )
+                .await
+                .context("load endpoint")?;
+
                 let client = service::Client::new(endpoint.host_address.clone())
-                    .with_endpoint_auth(endpoint.id, state.service_db.clone());
+                    .with_endpoint_auth(&endpoint, state.service_db.clone());
+
+                let imported = async {
+                    let public_key = builder_public_key.decoded().context("decode builder public key")?;
 
-                match import_packages(state, packages).await {
+                    verify_signatures(&packages, &public_key)?;
+
+                    import_packages(state, packages).await
+                };
+
+                match imported.await {
                     Ok(()) => {
                         info!("All packages imported");
 
@@ -118,9 +398,10 @@ async fn handle_message(state: &State, message: Message) -> Result<()> {
                 }
 
                 Ok(())
-            }
-            .instrument(span)
-            .await
+            };
+
+            // Propagated to summit when reporting the import result
+            request_id.scope(import.instrument(span)).await
         }
         Message::ImportDirectory(directory) => {
             let span = info_span!("import_directory", directory = directory.to_string_lossy().to_string());
@@ -151,10 +432,27 @@ async fn handle_message(state: &State, message: Message) -> Result<()> {
     }
 }
 
+/// Ensure every package was signed by the builder which produced it
+pub(crate) fn verify_signatures(packages: &[Package], public_key: &PublicKey) -> Result<()> {
+    for package in packages {
+        let signature = package
+            .signature
+            .as_deref()
+            .ok_or_else(|| eyre!("package {} is unsigned", package.url))?;
+        let signature = EncodedSignature::decode(signature).context("decode package signature")?;
+
+        public_key
+            .verify(package.sha256sum.as_bytes(), &signature)
+            .with_context(|| format!("package {} has an invalid signature", package.url))?;
+    }
+
+    Ok(())
+}
+
 async fn import_packages(state: &State, packages: Vec<Package>) -> Result<()> {
     let downloads = stream::iter(packages.into_iter())
         .map(|package| download_package(&state.state_dir, package))
-        .buffer_unordered(moss::environment::MAX_NETWORK_CONCURRENCY)
+        .buffer_unordered(state.config.download_concurrency.max(1))
         .try_collect::<Vec<(Package, PathBuf)>>()
         .await
         .context("download package")?;
@@ -248,6 +546,18 @@ fn import_package(
         .block_on(collection::lookup(tx.as_mut(), name.as_ref()))
         .context("lookup existing collection record")?;
 
+    // Summit may retry an import we've already handled, treat
+    // the identical package as already imported
+    if existing.as_ref().is_some_and(|e| e.package_id == package.sha256sum) && state.meta_db.get(&id).is_ok() {
+        if destructive_move {
+            fs::remove_file(download_path).context("remove staged stone")?;
+        }
+
+        info!(file_name = file_name.to_str(), source_id, "Package already imported");
+
+        return Ok(());
+    }
+
     match existing {
         Some(e) if e.source_release as u64 > meta.source_release => {
             return Err(eyre!("Newer candidate (rel: {}) exists already", e.source_release));
@@ -261,11 +571,23 @@ fn import_package(
         _ => {}
     }
 
-    if destructive_move {
-        fs::rename(download_path, &full_path).context("rename download to pool")?;
-    } else {
-        hardlink_or_copy(download_path, &full_path).context("link or copy download to pool")?;
-    }
+    // Identical content may already be pooled under another file name
+    let pooled = state
+        .meta_db
+        .get(&id)
+        .ok()
+        .and_then(|meta| meta.uri)
+        .map(|uri| state.state_dir.join("public").join(uri))
+        .filter(|path| *path != full_path && path.exists());
+
+    store_in_pool(
+        state.config.move_strategy,
+        download_path,
+        &full_path,
+        pooled.as_deref(),
+        destructive_move,
+    )
+    .context("store download in pool")?;
 
     // Adding meta records is idempotent as we delete / insert so
     // it doesn't matter we are adding them outside a TX if we encounter
@@ -313,36 +635,153 @@ async fn download_path(state_dir: &Path, hash: &str) -> Result<PathBuf> {
 
 fn relative_pool_dir(source_id: &str) -> Result<PathBuf> {
     let lower = source_id.to_lowercase();
+    let portion = shard_prefix(&lower)?;
+
+    Ok(Path::new("pool").join(portion).join(&lower))
+}
 
-    if lower.is_empty() {
+/// Prefix of the lowercase `name` used to shard the pool & index
+fn shard_prefix(name: &str) -> Result<&str> {
+    if name.is_empty() {
         return Err(eyre!("Invalid archive, package name is empty"));
     }
 
-    let mut portion = &lower[0..1];
+    if name.len() > 4 && name.starts_with("lib") {
+        Ok(&name[0..4])
+    } else {
+        Ok(&name[0..1])
+    }
+}
+
+/// Store the staged package at `to`, hardlinking the `pooled` file w/ identical
+/// content if any, otherwise moving it using `strategy`
+fn store_in_pool(
+    strategy: MoveStrategy,
+    staged: &Path,
+    to: &Path,
+    pooled: Option<&Path>,
+    destructive: bool,
+) -> Result<()> {
+    use std::fs;
+
+    if let Some(pooled) = pooled {
+        match replace_with(to, |partial| fs::hard_link(pooled, partial)) {
+            Ok(()) => {
+                if destructive {
+                    fs::remove_file(staged).context("remove staged stone")?;
+                }
+
+                debug!(pooled = %pooled.display(), "Linked identical package already in pool");
+
+                return Ok(());
+            }
+            Err(error) => {
+                debug!(%error, pooled = %pooled.display(), "Failed to link identical package, storing copy");
+            }
+        }
+    }
+
+    move_to_pool(strategy, staged, to, destructive).context("move to pool")?;
+
+    Ok(())
+}
+
+/// Move `from` to `to` using `strategy`, falling back through less efficient
+/// strategies when unsupported. `from` is only removed if `destructive`.
+fn move_to_pool(strategy: MoveStrategy, from: &Path, to: &Path, destructive: bool) -> Result<MoveStrategy> {
+    use std::fs;
+
+    let candidates: &[MoveStrategy] = match strategy {
+        MoveStrategy::Auto if destructive => &[MoveStrategy::Rename, MoveStrategy::Copy],
+        MoveStrategy::Auto => &[MoveStrategy::Reflink, MoveStrategy::Hardlink, MoveStrategy::Copy],
+        // Renaming consumes the staged file
+        MoveStrategy::Rename if !destructive => &[MoveStrategy::Reflink, MoveStrategy::Hardlink, MoveStrategy::Copy],
+        MoveStrategy::Copy => &[MoveStrategy::Copy],
+        strategy => &[strategy, MoveStrategy::Copy],
+    };
+
+    for &candidate in candidates {
+        let result = match candidate {
+            MoveStrategy::Auto => unreachable!("auto is resolved to candidates"),
+            MoveStrategy::Rename => fs::rename(from, to),
+            MoveStrategy::Reflink => replace_with(to, |partial| reflink(from, partial)),
+            MoveStrategy::Hardlink => replace_with(to, |partial| fs::hard_link(from, partial)),
+            MoveStrategy::Copy => replace_with(to, |partial| fs::copy(from, partial).map(|_| ())),
+        };
+
+        match result {
+            Ok(()) => {
+                if destructive && candidate != MoveStrategy::Rename {
+                    fs::remove_file(from).context("remove staged stone")?;
+                }
 
-    if lower.len() > 4 && lower.starts_with("lib") {
-        portion = &lower[0..4];
+                return Ok(candidate);
+            }
+            Err(error) if candidate == MoveStrategy::Copy => {
+                return Err(error).context("copy");
+            }
+            Err(error) => {
+                debug!(%error, strategy = ?candidate, "Move strategy unsupported, falling back");
+            }
+        }
     }
 
-    Ok(Path::new("pool").join(portion).join(lower))
+    unreachable!("copy is always the last candidate")
 }
 
-fn hardlink_or_copy(from: &Path, to: &Path) -> Result<()> {
+/// Create a new file at `to` by calling `create` w/ a temporary path beside it,
+/// which is then renamed over `to`.
+///
+/// An existing file at `to` may share it's inode w/ other pooled packages (see
+/// [`store_in_pool`]), so must be replaced rather than written in place.
+fn replace_with(to: &Path, create: impl FnOnce(&Path) -> std::io::Result<()>) -> std::io::Result<()> {
     use std::fs;
 
-    // Attempt hard link
-    let link_result = fs::hard_link(from, to);
+    let mut name = to.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    let partial = to.with_file_name(name);
+
+    // Left behind by an interrupted import
+    if let Err(error) = fs::remove_file(&partial) {
+        if error.kind() != std::io::ErrorKind::NotFound {
+            return Err(error);
+        }
+    }
+
+    let result = create(&partial).and_then(|()| fs::rename(&partial, to));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+
+    result
+}
+
+/// Clone the extents of `from` into a new file at `to`
+fn reflink(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::{fs::File, os::fd::AsRawFd};
+
+    let source = File::open(from)?;
+    // Never truncate an existing file, it's extents may be shared
+    let target = File::options().write(true).create_new(true).open(to)?;
+
+    // SAFETY: Both file descriptors are open for the duration of the call
+    let result = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+
+    if result != 0 {
+        let error = std::io::Error::last_os_error();
+
+        drop(target);
+        let _ = std::fs::remove_file(to);
 
-    // Copy instead
-    if link_result.is_err() {
-        fs::copy(from, to)?;
+        return Err(error);
     }
 
     Ok(())
 }
 
 async fn reindex(state: &State) -> Result<()> {
-    let mut records = collection::list(
+    let records = collection::list(
         state
             .service_db
             .acquire()
@@ -352,7 +791,6 @@ async fn reindex(state: &State) -> Result<()> {
     )
     .await
     .context("list records from collection db")?;
-    records.sort_by(|a, b| a.source_id.cmp(&b.source_id).then_with(|| a.name.cmp(&b.name)));
 
     let now = Instant::now();
 
@@ -363,42 +801,27 @@ async fn reindex(state: &State) -> Result<()> {
 
         move || {
             span.in_scope(|| {
-                use std::fs::{self, File};
+                use std::fs;
+
+                use service::atomic_file;
 
                 // TODO: Replace w/ configurable index path
                 let dir = state.state_dir.join("public/volatile/x86_64");
-                let path = dir.join("stone.index");
 
-                if !dir.exists() {
-                    fs::create_dir_all(&dir).context("create volatile directory")?;
+                match state.config.index_format {
+                    IndexFormat::Classic => write_index(&state, &dir, "../..", &records)?,
+                    IndexFormat::Sharded => write_sharded_index(&state, &dir, &records)?,
                 }
 
-                info!(?path, "Indexing");
-
-                let mut file = File::create(path).context("create index file")?;
-                let mut writer = stone::Writer::new(&mut file, stone::header::v1::FileType::Repository)
-                    .context("create stone writer")?;
-
-                for record in records {
-                    let mut meta = state
-                        .meta_db
-                        .get(&record.package_id.clone().into())
-                        .context("get package from meta db")?;
-
-                    // TODO: Replace hardcoded relative path
-                    // once we have non-hardcoded index path
-                    meta.uri = Some(format!(
-                        "../../{}",
-                        meta.uri
-                            .ok_or(eyre!("Package {} is missing URI in metadata", &record.package_id))?,
-                    ));
-
-                    writer
-                        .add_payload(meta.to_stone_payload().as_slice())
-                        .context("add meta payload")?;
+                let well_known = state.state_dir.join("public/.well-known");
+                if !well_known.exists() {
+                    fs::create_dir_all(&well_known).context("create well-known directory")?;
                 }
-
-                writer.finalize().context("finalize stone index")?;
+                atomic_file::write(
+                    well_known.join("public_key"),
+                    state.key_pair.public_key().encode().to_string(),
+                )
+                .context("write public key")?;
 
                 Result::<_, eyre::Report>::Ok(())
             })
@@ -414,9 +837,106 @@ async fn reindex(state: &State) -> Result<()> {
     Ok(())
 }
 
+/// Write `records` as a signed `stone.index` in `dir`, with package URIs
+/// relative to the `public` directory at `root`
+fn write_index<'a>(
+    state: &State,
+    dir: &Path,
+    root: &str,
+    records: impl IntoIterator<Item = &'a collection::Record>,
+) -> Result<()> {
+    use std::fs;
+
+    use service::atomic_file::{self, AtomicFile};
+
+    let path = dir.join("stone.index");
+
+    if !dir.exists() {
+        fs::create_dir_all(dir).context("create index directory")?;
+    }
+
+    info!(?path, "Indexing");
+
+    // Written atomically so clients never fetch a partial index
+    let mut file = AtomicFile::create(&path).context("create index file")?;
+    let mut writer =
+        stone::Writer::new(&mut file, stone::header::v1::FileType::Repository).context("create stone writer")?;
+
+    for record in records {
+        let mut meta = state
+            .meta_db
+            .get(&record.package_id.clone().into())
+            .context("get package from meta db")?;
+
+        // TODO: Replace hardcoded relative path
+        // once we have non-hardcoded index path
+        meta.uri = Some(format!(
+            "{root}/{}",
+            meta.uri
+                .ok_or(eyre!("Package {} is missing URI in metadata", &record.package_id))?,
+        ));
+
+        writer
+            .add_payload(meta.to_stone_payload().as_slice())
+            .context("add meta payload")?;
+    }
+
+    writer.finalize().context("finalize stone index")?;
+    file.commit().context("commit stone index")?;
+
+    // Detached signature so clients can verify the index came from us
+    let index = fs::read(&path).context("read stone index")?;
+    let signature = state.key_pair.sign(&index);
+    atomic_file::write(dir.join("stone.index.sig"), signature.to_bytes()).context("write index signature")?;
+
+    Ok(())
+}
+
+/// Write `records` as one index per package name prefix under `dir/shards`,
+/// using the same prefixes as the pool layout
+fn write_sharded_index(state: &State, dir: &Path, records: &[collection::Record]) -> Result<()> {
+    use std::{collections::BTreeMap, fs};
+
+    let mut shards = BTreeMap::<String, Vec<&collection::Record>>::new();
+
+    for record in records {
+        shards
+            .entry(shard_prefix(&record.name.to_lowercase())?.to_string())
+            .or_default()
+            .push(record);
+    }
+
+    let shards_dir = dir.join("shards");
+
+    for (prefix, records) in &shards {
+        write_index(state, &shards_dir.join(prefix), "../../../..", records.iter().copied())?;
+    }
+
+    // Remove shards for prefixes which no longer have any packages
+    if shards_dir.exists() {
+        for entry in fs::read_dir(&shards_dir).context("read shards directory")? {
+            let entry = entry.context("read shards directory entry")?;
+
+            if !shards.contains_key(entry.file_name().to_string_lossy().as_ref()) {
+                fs::remove_dir_all(entry.path()).context("remove stale shard")?;
+            }
+        }
+    }
+
+    // Don't leave a stale classic index behind if the format was switched
+    for file in ["stone.index", "stone.index.sig"] {
+        let path = dir.join(file);
+
+        if path.exists() {
+            fs::remove_file(&path).context("remove classic index")?;
+        }
+    }
+
+    Ok(())
+}
+
 fn enumerate_stones(dir: &Path) -> Result<Vec<Package>> {
-    use std::fs::{self, File};
-    use std::io;
+    use std::fs;
 
     let contents = fs::read_dir(dir).context("read directory")?;
 
@@ -432,13 +952,13 @@ fn enumerate_stones(dir: &Path) -> Result<Vec<Package>> {
                 .parse()
                 .context("invalid file uri")?;
 
-            let mut hasher = Sha256::default();
-
-            io::copy(&mut File::open(&path).context("open file")?, &mut hasher).context("hash file")?;
-
-            let sha256sum = hex::encode(hasher.finalize());
+            let sha256sum = collectable::sha256sum(&path).context("hash file")?;
 
-            files.push(Package { url, sha256sum });
+            files.push(Package {
+                url,
+                sha256sum,
+                signature: None,
+            });
         } else if meta.is_dir() {
             files.extend(enumerate_stones(&path)?);
         }
@@ -446,3 +966,252 @@ fn enumerate_stones(dir: &Path) -> Result<Vec<Package>> {
 
     Ok(files)
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    const NO_DELAY: Retry = Retry {
+        attempts: 3,
+        base_delay: Duration::ZERO,
+    };
+
+    fn message() -> Message {
+        Message::ImportDirectory(PathBuf::from("/srv/import"))
+    }
+
+    #[test]
+    fn package_signatures() {
+        let builder = KeyPair::generate();
+        let other = KeyPair::generate();
+
+        let package = |sha256sum: &str, signer: Option<&KeyPair>| Package {
+            url: format!("https://builder/{sha256sum}.stone").parse().unwrap(),
+            sha256sum: sha256sum.to_string(),
+            signature: signer
+                .map(|key_pair| EncodedSignature::encode(&key_pair.sign(sha256sum.as_bytes())).to_string()),
+        };
+
+        assert!(verify_signatures(&[package("a", Some(&builder))], &builder.public_key()).is_ok());
+
+        let error = verify_signatures(
+            &[package("a", Some(&builder)), package("b", None)],
+            &builder.public_key(),
+        );
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "package https://builder/b.stone is unsigned"
+        );
+
+        let error = verify_signatures(&[package("a", Some(&other))], &builder.public_key());
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "package https://builder/a.stone has an invalid signature"
+        );
+    }
+
+    #[test]
+    fn shard_prefixes() {
+        assert_eq!(shard_prefix("nano").unwrap(), "n");
+        assert_eq!(shard_prefix("libarchive").unwrap(), "liba");
+        assert_eq!(shard_prefix("lib").unwrap(), "l");
+        assert!(shard_prefix("").is_err());
+
+        assert_eq!(relative_pool_dir("LibXml2").unwrap(), Path::new("pool/libx/libxml2"));
+    }
+
+    #[test]
+    fn move_strategies() {
+        let dir = std::env::temp_dir().join(format!("vessel-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let staged = dir.join("staged");
+        let pooled = dir.join("pooled");
+        let stage = || std::fs::write(&staged, b"stone").unwrap();
+
+        // Same filesystem so the staged file is renamed
+        stage();
+        let used = move_to_pool(MoveStrategy::Auto, &staged, &pooled, true).unwrap();
+        assert_eq!(used, MoveStrategy::Rename);
+        assert!(!staged.exists());
+        assert_eq!(std::fs::read(&pooled).unwrap(), b"stone");
+        std::fs::remove_file(&pooled).unwrap();
+
+        // Staged file is kept, reflink depends on the filesystem
+        stage();
+        let used = move_to_pool(MoveStrategy::Auto, &staged, &pooled, false).unwrap();
+        assert!(matches!(used, MoveStrategy::Reflink | MoveStrategy::Hardlink));
+        assert!(staged.exists());
+        assert_eq!(std::fs::read(&pooled).unwrap(), b"stone");
+        std::fs::remove_file(&pooled).unwrap();
+
+        // Staged file is removed after linking
+        let used = move_to_pool(MoveStrategy::Hardlink, &staged, &pooled, true).unwrap();
+        assert_eq!(used, MoveStrategy::Hardlink);
+        assert!(!staged.exists());
+        assert_eq!(std::fs::read(&pooled).unwrap(), b"stone");
+        std::fs::remove_file(&pooled).unwrap();
+
+        // Nothing staged
+        assert!(move_to_pool(MoveStrategy::Copy, &staged, &pooled, true).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dedup_identical_packages() {
+        let dir = std::env::temp_dir().join(format!("vessel-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let first = dir.join("nano-1.stone");
+        let second = dir.join("nano-1-renamed.stone");
+
+        for (i, to) in [&first, &second].into_iter().enumerate() {
+            let staged = dir.join(format!("staged-{i}"));
+            std::fs::write(&staged, b"stone").unwrap();
+
+            let pooled = first.exists().then_some(first.as_path());
+            store_in_pool(MoveStrategy::Copy, &staged, to, pooled, true).unwrap();
+
+            assert!(!staged.exists());
+        }
+
+        let inode = |path: &Path| std::fs::metadata(path).unwrap().ino();
+        assert_eq!(inode(&first), inode(&second));
+        assert_eq!(std::fs::read(&second).unwrap(), b"stone");
+
+        // Storing over a deduplicated file replaces it, leaving the other link intact
+        for strategy in [MoveStrategy::Reflink, MoveStrategy::Hardlink, MoveStrategy::Copy] {
+            let staged = dir.join("staged");
+            std::fs::write(&staged, b"other").unwrap();
+
+            store_in_pool(strategy, &staged, &second, None, true).unwrap();
+
+            assert_eq!(std::fs::read(&first).unwrap(), b"stone");
+            assert_eq!(std::fs::read(&second).unwrap(), b"other");
+            assert_ne!(inode(&first), inode(&second));
+        }
+        assert!(!dir.join("nano-1-renamed.stone.partial").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_until_success() {
+        let calls = AtomicU32::new(0);
+
+        let result = handle_with_retry(&message(), NO_DELAY, |_| async {
+            if calls.fetch_add(1, Ordering::Relaxed) < 2 {
+                Err(eyre!("transient"))
+            } else {
+                Ok(())
+            }
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn dead_letter_when_exhausted() {
+        let calls = AtomicU32::new(0);
+        let message = message();
+
+        let error = handle_with_retry(&message, NO_DELAY, |_| async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Err(eyre!("always fails"))
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(calls.load(Ordering::Relaxed), NO_DELAY.attempts);
+
+        let path = std::env::temp_dir().join(format!("vessel-test-{}.db", uuid::Uuid::new_v4()));
+        let db = service::Database::new(&path)
+            .await
+            .unwrap()
+            .with_migrations(sqlx::migrate!("./migrations"))
+            .await
+            .unwrap();
+
+        dead_letter(&db, &message, NO_DELAY.attempts, &error.to_string())
+            .await
+            .unwrap();
+
+        let records = dead_letter::list(db.acquire().await.unwrap().as_mut()).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].kind, "import-directory");
+        assert_eq!(records[0].error, "always fails");
+        assert_eq!(records[0].attempts, 3);
+        assert!(matches!(
+            serde_json::from_str(&records[0].message).unwrap(),
+            Message::ImportDirectory(path) if path == Path::new("/srv/import")
+        ));
+    }
+
+    #[tokio::test]
+    async fn redeliver_until_exhausted() {
+        let path = std::env::temp_dir().join(format!("vessel-test-{}.db", uuid::Uuid::new_v4()));
+        let db = service::Database::new(&path)
+            .await
+            .unwrap()
+            .with_migrations(sqlx::migrate!("./migrations"))
+            .await
+            .unwrap();
+
+        let redelivery = Retry {
+            attempts: 2,
+            base_delay: Duration::ZERO,
+        };
+        let record = |message: &Message| {
+            let db = db.clone();
+            let message = message.clone();
+
+            async move {
+                let mut tx = db.begin().await.unwrap();
+                dead_letter::record(&mut tx, &message, 3, "failed", 0).await.unwrap();
+                tx.commit().await.unwrap();
+            }
+        };
+        let list = || async { dead_letter::list(db.acquire().await.unwrap().as_mut()).await.unwrap() };
+
+        // Handled on redelivery, record is removed
+        record(&message()).await;
+        redeliver_dead_letters(&db, NO_DELAY, redelivery, |_| async { Ok(()) })
+            .await
+            .unwrap();
+        assert!(list().await.is_empty());
+
+        // Keeps failing, rescheduled until redeliveries are exhausted
+        record(&message()).await;
+        let calls = AtomicU32::new(0);
+        let fail = |_| async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Err(eyre!("still failing"))
+        };
+
+        redeliver_dead_letters(&db, NO_DELAY, redelivery, fail).await.unwrap();
+        let records = list().await;
+        assert_eq!(records[0].redeliveries, 1);
+        assert_eq!(records[0].attempts, 6);
+        assert_eq!(records[0].error, "still failing");
+        assert!(records[0].next_redelivery.is_some());
+
+        redeliver_dead_letters(&db, NO_DELAY, redelivery, fail).await.unwrap();
+        let records = list().await;
+        assert_eq!(records[0].redeliveries, 2);
+        assert!(records[0].next_redelivery.is_none());
+
+        // Exhausted records are no longer redelivered
+        redeliver_dead_letters(&db, NO_DELAY, redelivery, fail).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), NO_DELAY.attempts * 2);
+        assert_eq!(list().await[0].redeliveries, 2);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}